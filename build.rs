@@ -0,0 +1,182 @@
+//
+// Build script
+//
+// Turns the PCI ID database subset in `src/pci/pci.ids`
+// into sorted static tables the kernel can search without
+// parsing anything at runtime.
+//
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+const PCI_IDS: &str = "src/pci/pci.ids";
+
+struct Vendor {
+    id: u16,
+    name: String,
+    devices: Vec<(u16, String)>,
+}
+
+struct Class {
+    id: u8,
+    name: String,
+    subclasses: Vec<Subclass>,
+}
+
+struct Subclass {
+    id: u8,
+    name: String,
+    prog_ifs: Vec<(u8, String)>,
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", PCI_IDS);
+
+    let source = fs::read_to_string(PCI_IDS).expect("Unable to read the PCI ID database");
+    let (vendors, classes) = parse(&source);
+
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("pci_ids.rs");
+    let mut file = fs::File::create(out).expect("Unable to create the PCI ID tables");
+    write_tables(&mut file, &vendors, &classes).expect("Unable to write the PCI ID tables");
+}
+
+/// Split a line into its hexadecimal ID and name
+fn split_entry(line: &str, lineno: usize) -> (u16, String) {
+    let mut parts = line.trim().splitn(2, char::is_whitespace);
+    let id = parts.next().unwrap_or("");
+    let name = parts.next().unwrap_or("").trim();
+    let id = u16::from_str_radix(id, 16)
+        .unwrap_or_else(|_| panic!("{}:{}: bad ID {:?}", PCI_IDS, lineno, id));
+    if name.is_empty() {
+        panic!("{}:{}: missing name", PCI_IDS, lineno);
+    }
+    (id, name.to_string())
+}
+
+fn parse(source: &str) -> (Vec<Vendor>, Vec<Class>) {
+    let mut vendors: Vec<Vendor> = Vec::new();
+    let mut classes: Vec<Class> = Vec::new();
+    let mut in_classes = false;
+
+    for (index, line) in source.lines().enumerate() {
+        let lineno = index + 1;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let depth = line.chars().take_while(|&c| c == '\t').count();
+
+        if depth == 0 && line.starts_with("C ") {
+            in_classes = true;
+            let (id, name) = split_entry(&line[2..], lineno);
+            classes.push(Class {
+                id: id as u8,
+                name,
+                subclasses: Vec::new(),
+            });
+            continue;
+        }
+
+        match (in_classes, depth) {
+            (false, 0) => {
+                let (id, name) = split_entry(line, lineno);
+                vendors.push(Vendor {
+                    id,
+                    name,
+                    devices: Vec::new(),
+                });
+            }
+            (false, 1) => {
+                let entry = split_entry(line, lineno);
+                match vendors.last_mut() {
+                    Some(vendor) => vendor.devices.push(entry),
+                    None => panic!("{}:{}: device without vendor", PCI_IDS, lineno),
+                }
+            }
+            // Subsystems are not needed
+            (false, _) => (),
+            (true, 1) => {
+                let (id, name) = split_entry(line, lineno);
+                match classes.last_mut() {
+                    Some(class) => class.subclasses.push(Subclass {
+                        id: id as u8,
+                        name,
+                        prog_ifs: Vec::new(),
+                    }),
+                    None => panic!("{}:{}: subclass without class", PCI_IDS, lineno),
+                }
+            }
+            (true, 2) => {
+                let (id, name) = split_entry(line, lineno);
+                match classes.last_mut().and_then(|c| c.subclasses.last_mut()) {
+                    Some(subclass) => subclass.prog_ifs.push((id as u8, name)),
+                    None => panic!("{}:{}: prog-if without subclass", PCI_IDS, lineno),
+                }
+            }
+            _ => panic!("{}:{}: unexpected line", PCI_IDS, lineno),
+        }
+    }
+
+    // The kernel looks entries up with binary searches
+    vendors.sort_by_key(|vendor| vendor.id);
+    for vendor in vendors.iter_mut() {
+        vendor.devices.sort_by_key(|device| device.0);
+    }
+    classes.sort_by_key(|class| class.id);
+    for class in classes.iter_mut() {
+        class.subclasses.sort_by_key(|subclass| subclass.id);
+        for subclass in class.subclasses.iter_mut() {
+            subclass.prog_ifs.sort_by_key(|prog_if| prog_if.0);
+        }
+    }
+
+    (vendors, classes)
+}
+
+fn write_tables<W: Write>(
+    out: &mut W,
+    vendors: &[Vendor],
+    classes: &[Class],
+) -> std::io::Result<()> {
+    writeln!(out, "// Generated by build.rs from {}", PCI_IDS)?;
+    writeln!(out)?;
+
+    writeln!(out, "pub static VENDORS: &[VendorEntry] = &[")?;
+    for vendor in vendors {
+        writeln!(out, "    VendorEntry {{")?;
+        writeln!(out, "        id: 0x{:04x},", vendor.id)?;
+        writeln!(out, "        name: {:?},", vendor.name)?;
+        writeln!(out, "        devices: &[")?;
+        for (id, name) in vendor.devices.iter() {
+            writeln!(out, "            (0x{:04x}, {:?}),", id, name)?;
+        }
+        writeln!(out, "        ],")?;
+        writeln!(out, "    }},")?;
+    }
+    writeln!(out, "];")?;
+    writeln!(out)?;
+
+    writeln!(out, "pub static CLASSES: &[ClassEntry] = &[")?;
+    for class in classes {
+        writeln!(out, "    ClassEntry {{")?;
+        writeln!(out, "        id: 0x{:02x},", class.id)?;
+        writeln!(out, "        name: {:?},", class.name)?;
+        writeln!(out, "        subclasses: &[")?;
+        for subclass in class.subclasses.iter() {
+            writeln!(out, "            SubclassEntry {{")?;
+            writeln!(out, "                id: 0x{:02x},", subclass.id)?;
+            writeln!(out, "                name: {:?},", subclass.name)?;
+            writeln!(out, "                prog_ifs: &[")?;
+            for (id, name) in subclass.prog_ifs.iter() {
+                writeln!(out, "                    (0x{:02x}, {:?}),", id, name)?;
+            }
+            writeln!(out, "                ],")?;
+            writeln!(out, "            }},")?;
+        }
+        writeln!(out, "        ],")?;
+        writeln!(out, "    }},")?;
+    }
+    writeln!(out, "];")?;
+    Ok(())
+}
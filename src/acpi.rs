@@ -0,0 +1,164 @@
+//
+// Advanced Configuration and Power Interface
+//
+// The firmware leaves a root pointer (RSDP) in the BIOS area,
+// which leads to the root table (RSDT, or XSDT on ACPI 2.0+).
+// That lists the physical addresses of all other tables.
+// Tables are identity-mapped when they are first looked at.
+//
+
+use alloc::prelude::*;
+use core::slice;
+use lazy_static::lazy_static;
+use x86_64::{structures::paging::PageTableFlags, PhysAddr};
+
+use crate::paging::PAGING;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// The size of the root pointer of ACPI 1.0
+const RSDP_V1_SIZE: usize = 20;
+
+/// The size of the header all tables start with
+const HEADER_SIZE: usize = 36;
+
+/// The BIOS data area word holding the EBDA segment
+const EBDA_POINTER: u64 = 0x40E;
+
+lazy_static! {
+    static ref TABLES: Vec<u64> = find_tables();
+}
+
+/// A system description table
+#[derive(Debug, Clone, Copy)]
+pub struct Table {
+    pub addr: u64,
+    pub len: usize,
+}
+
+impl Table {
+    /// Map the table at `addr` and check its checksum
+    fn load(addr: u64) -> Option<Table> {
+        map(addr, HEADER_SIZE);
+        let len = read_u32(unsafe { memory(addr, HEADER_SIZE) }, 4) as usize;
+        if len < HEADER_SIZE {
+            return None;
+        }
+        map(addr, len);
+        let table = Table { addr, len };
+        if !checksum_ok(table.data()) {
+            log!(warn: "ACPI table at 0x{:x} has a bad checksum.", addr);
+            return None;
+        }
+        Some(table)
+    }
+
+    pub fn signature(&self) -> &'static [u8] {
+        &self.data()[0..4]
+    }
+
+    /// Get the whole table, including the header
+    pub fn data(&self) -> &'static [u8] {
+        unsafe { memory(self.addr, self.len) }
+    }
+
+    /// Get the table without the header
+    pub fn body(&self) -> &'static [u8] {
+        &self.data()[HEADER_SIZE..]
+    }
+}
+
+/// Find a table by its signature, such as `MCFG`
+pub fn find_table(signature: &[u8; 4]) -> Option<Table> {
+    TABLES
+        .iter()
+        .filter_map(|&addr| Table::load(addr))
+        .find(|table| table.signature() == signature)
+}
+
+/// Get the addresses of the tables listed in the root table
+fn find_tables() -> Vec<u64> {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => {
+            log!(debug: "No ACPI root pointer found.");
+            return Vec::new();
+        }
+    };
+
+    // ACPI 2.0 adds the XSDT with 64-bit pointers
+    let data = unsafe { memory(rsdp, RSDP_V1_SIZE) };
+    let revision = data[15];
+    let (root, entry_size) = if revision >= 2 {
+        map(rsdp, 36);
+        let data = unsafe { memory(rsdp, 36) };
+        (read_u64(data, 24), 8)
+    } else {
+        (u64::from(read_u32(data, 16)), 4)
+    };
+
+    let root = match Table::load(root) {
+        Some(root) => root,
+        None => {
+            log!(warn: "The ACPI root table is damaged.");
+            return Vec::new();
+        }
+    };
+    let body = root.body();
+    let tables: Vec<u64> = (0..body.len() / entry_size)
+        .map(|i| match entry_size {
+            8 => read_u64(body, i * 8),
+            _ => u64::from(read_u32(body, i * 4)),
+        })
+        .collect();
+    log!(
+        debug: "ACPI revision {} root table at 0x{:x} lists {} tables.",
+        revision,
+        root.addr,
+        tables.len()
+    );
+    tables
+}
+
+/// Search the BIOS areas for the root pointer
+fn find_rsdp() -> Option<u64> {
+    map(EBDA_POINTER, 2);
+    let ebda = u64::from(unsafe { *(EBDA_POINTER as *const u16) }) << 4;
+    let areas = [(ebda, 1024), (0xE0000, 0x20000)];
+
+    for &(start, len) in areas.iter().filter(|&&(start, _)| start != 0) {
+        map(start, len);
+        let area = unsafe { memory(start, len) };
+        for offset in (0..len - RSDP_V1_SIZE).step_by(16) {
+            let candidate = &area[offset..offset + RSDP_V1_SIZE];
+            if &candidate[0..8] == RSDP_SIGNATURE && checksum_ok(candidate) {
+                return Some(start + offset as u64);
+            }
+        }
+    }
+    None
+}
+
+/// Identity map physical memory so it can be read
+fn map(addr: u64, len: usize) {
+    PAGING
+        .lock()
+        .identity_map_region(PhysAddr::new(addr), len as u64, PageTableFlags::PRESENT);
+}
+
+/// Get identity-mapped physical memory as a slice
+unsafe fn memory(addr: u64, len: usize) -> &'static [u8] {
+    slice::from_raw_parts(addr as *const u8, len)
+}
+
+fn checksum_ok(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    (0..4).fold(0, |acc, i| acc | (buf[offset + i] as u32) << (8 * i))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    (0..8).fold(0, |acc, i| acc | (buf[offset + i] as u64) << (8 * i))
+}
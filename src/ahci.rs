@@ -0,0 +1,682 @@
+//
+// Advanced Host Controller Interface
+//
+// SATA disks behind an AHCI controller are driven through
+// command lists in memory. Each port has up to 32 command
+// slots, so disks that support Native Command Queueing get
+// several transfers in flight at once.
+//
+// Completion is signaled by the controller interrupt. The
+// driver also checks the port registers on every timer tick,
+// so it keeps working if the interrupt never arrives.
+//
+
+use alloc::{prelude::*, sync::Arc};
+use core::cmp::min;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::ata::Identify;
+use crate::block::{self, BlockDevice, DiskNames};
+use crate::dma::DMABuffer;
+use crate::hal::{BusDevice, DeviceError, DeviceMatch, Driver};
+use crate::idt::IDT;
+use crate::pci::{PCICommand, PCIDevice, PCIFind};
+use crate::pit::PIT;
+use crate::sync::Event;
+
+const AHCI_SECTOR_SIZE: usize = 512;
+
+/// The largest number of sectors a single command transfers
+const AHCI_COMMAND_SECTORS: usize = 128;
+
+/// The time a command may take, in milliseconds
+const AHCI_TIMEOUT_MS: usize = 5000;
+
+// HBA registers
+const HBA_CAP: usize = 0x00;
+const HBA_GHC: usize = 0x04;
+const HBA_IS: usize = 0x08;
+const HBA_PI: usize = 0x0C;
+const HBA_CAP2: usize = 0x24;
+const HBA_BOHC: usize = 0x28;
+
+const HBA_CAP_SNCQ: u32 = 1 << 30;
+const HBA_CAP2_BOH: u32 = 1 << 0;
+const HBA_GHC_IE: u32 = 1 << 1;
+const HBA_GHC_AE: u32 = 1 << 31;
+const HBA_BOHC_BOS: u32 = 1 << 0;
+const HBA_BOHC_OOS: u32 = 1 << 1;
+
+// Port registers, relative to the port's register block
+const PORT_BASE: usize = 0x100;
+const PORT_SIZE: usize = 0x80;
+const PX_CLB: usize = 0x00;
+const PX_CLBU: usize = 0x04;
+const PX_FB: usize = 0x08;
+const PX_FBU: usize = 0x0C;
+const PX_IS: usize = 0x10;
+const PX_IE: usize = 0x14;
+const PX_CMD: usize = 0x18;
+const PX_TFD: usize = 0x20;
+const PX_SIG: usize = 0x24;
+const PX_SSTS: usize = 0x28;
+const PX_SERR: usize = 0x30;
+const PX_SACT: usize = 0x34;
+const PX_CI: usize = 0x38;
+
+const PX_CMD_ST: u32 = 1 << 0;
+const PX_CMD_FRE: u32 = 1 << 4;
+const PX_CMD_FR: u32 = 1 << 14;
+const PX_CMD_CR: u32 = 1 << 15;
+
+// Device to host register, PIO setup, DMA setup, set device bits, task file error
+const PX_IE_DEFAULT: u32 = 1 << 0 | 1 << 1 | 1 << 2 | 1 << 3 | 1 << 30;
+
+const PX_TFD_ERR: u32 = 0x01;
+const PX_TFD_DRQ: u32 = 0x08;
+const PX_TFD_BSY: u32 = 0x80;
+
+const SATA_SIG_ATA: u32 = 0x0000_0101;
+const SSTS_DET_PRESENT: u32 = 0x3;
+const SSTS_IPM_ACTIVE: u32 = 0x1;
+
+// Frame Information Structure
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const FIS_H2D_COMMAND: u8 = 0x80;
+
+// Commands
+const ATA_CMD_READ_DMA_EXT: u8 = 0x25;
+const ATA_CMD_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_CMD_READ_FPDMA_QUEUED: u8 = 0x60;
+const ATA_CMD_WRITE_FPDMA_QUEUED: u8 = 0x61;
+const ATA_CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const ATA_CMD_IDENTIFY: u8 = 0xEC;
+
+// Command list and table layout
+const COMMAND_HEADER_SIZE: usize = 32;
+const COMMAND_LIST_SIZE: usize = 32 * COMMAND_HEADER_SIZE;
+const RECEIVED_FIS_SIZE: usize = 256;
+const COMMAND_TABLE_PRDT: usize = 0x80;
+const COMMAND_TABLE_SIZE: usize = 0x100;
+
+lazy_static! {
+    static ref CONTROLLERS: Mutex<Vec<Arc<AHCIController>>> = Mutex::new(Vec::new());
+    static ref AHCI_MATCHES: Vec<DeviceMatch> =
+        vec![DeviceMatch::PCI(PCIFind::with_class(0x01, 0x06))];
+    /// The controller and disks of each bound device
+    static ref BOUND: Mutex<Vec<(BusDevice, Arc<AHCIController>, Vec<&'static str>)>> =
+        Mutex::new(Vec::new());
+    static ref NAMES: Mutex<DiskNames> = Mutex::new(DiskNames::new("sd"));
+}
+
+/// The number of disks registered so far
+static DISKS: AtomicUsize = AtomicUsize::new(0);
+
+/// A memory-mapped register block
+#[derive(Clone, Copy)]
+struct HBA {
+    base: usize,
+}
+
+impl HBA {
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + reg) as *const u32) }
+    }
+
+    fn write(&self, reg: usize, val: u32) {
+        unsafe { ptr::write_volatile((self.base + reg) as *mut u32, val) }
+    }
+
+    fn port(&self, port: usize) -> HBA {
+        HBA {
+            base: self.base + PORT_BASE + port * PORT_SIZE,
+        }
+    }
+}
+
+/// State shared with the interrupt handler
+struct AHCIController {
+    hba: HBA,
+    /// The IRQ line, or the MSI vector if the controller has one
+    irq: u8,
+    msi: bool,
+    /// Completion events, one per port
+    events: Vec<Event>,
+}
+
+/// Handle an AHCI interrupt on an IRQ line or MSI vector
+fn handle_interrupt(irq: u8) {
+    for controller in CONTROLLERS.lock().iter().filter(|c| c.irq == irq) {
+        let pending = controller.hba.read(HBA_IS);
+        if pending == 0 {
+            continue;
+        }
+        for port in (0..32).filter(|port| pending & (1 << port) != 0) {
+            let regs = controller.hba.port(port);
+            regs.write(PX_IS, regs.read(PX_IS));
+            controller.events[port].set();
+        }
+        controller.hba.write(HBA_IS, pending);
+    }
+}
+
+/// A transfer occupying a command slot
+struct Command {
+    slot: usize,
+    buf: Option<DMABuffer>,
+}
+
+/// A SATA disk on an AHCI port
+pub struct AHCIDisk {
+    controller: Arc<AHCIController>,
+    port: usize,
+    regs: HBA,
+    command_list: DMABuffer,
+    received_fis: DMABuffer,
+    tables: Vec<DMABuffer>,
+    /// The number of commands that may be in flight
+    queue_depth: usize,
+    ncq: bool,
+    sectors: u64,
+    model: String,
+}
+
+impl AHCIDisk {
+    fn new(controller: Arc<AHCIController>, port: usize, slots: usize) -> Self {
+        let regs = controller.hba.port(port);
+        AHCIDisk {
+            controller,
+            port,
+            regs,
+            command_list: DMABuffer::new(COMMAND_LIST_SIZE, 1024),
+            received_fis: DMABuffer::new(RECEIVED_FIS_SIZE, 256),
+            tables: (0..slots)
+                .map(|_| DMABuffer::new(COMMAND_TABLE_SIZE, 128))
+                .collect(),
+            queue_depth: 1,
+            ncq: false,
+            sectors: 0,
+            model: String::new(),
+        }
+    }
+
+    /// Stop the command engine
+    fn stop(&self) -> Result<(), DeviceError> {
+        let cmd = self.regs.read(PX_CMD);
+        self.regs.write(PX_CMD, cmd & !(PX_CMD_ST | PX_CMD_FRE));
+        self.wait_clear(PX_CMD, PX_CMD_CR | PX_CMD_FR)
+    }
+
+    /// Start the command engine
+    fn start(&self) -> Result<(), DeviceError> {
+        self.wait_clear(PX_TFD, PX_TFD_BSY | PX_TFD_DRQ)?;
+        let cmd = self.regs.read(PX_CMD);
+        self.regs.write(PX_CMD, cmd | PX_CMD_FRE);
+        self.regs.write(PX_CMD, cmd | PX_CMD_FRE | PX_CMD_ST);
+        Ok(())
+    }
+
+    fn wait_clear(&self, reg: usize, mask: u32) -> Result<(), DeviceError> {
+        let deadline = PIT::deadline(PIT::ms_to_ticks(AHCI_TIMEOUT_MS));
+        while self.regs.read(reg) & mask != 0 {
+            if PIT::has_passed(deadline) {
+                return Err(DeviceError::Timeout);
+            }
+        }
+        Ok(())
+    }
+
+    /// Point the port at our command list and FIS area and start it
+    fn init(&mut self) -> Result<(), DeviceError> {
+        self.stop()?;
+
+        let clb = self.command_list.phys_addr();
+        let fb = self.received_fis.phys_addr();
+        self.regs.write(PX_CLB, clb as u32);
+        self.regs.write(PX_CLBU, (clb >> 32) as u32);
+        self.regs.write(PX_FB, fb as u32);
+        self.regs.write(PX_FBU, (fb >> 32) as u32);
+
+        // Command headers point at their tables for good
+        for (slot, table) in self.tables.iter().enumerate() {
+            let ctba = table.phys_addr();
+            let header = slot * COMMAND_HEADER_SIZE;
+            self.command_list.write::<u32>(header + 8, ctba as u32);
+            self.command_list
+                .write::<u32>(header + 12, (ctba >> 32) as u32);
+        }
+
+        // Clear stale errors and interrupts
+        self.regs.write(PX_SERR, 0xFFFF_FFFF);
+        self.regs.write(PX_IS, 0xFFFF_FFFF);
+        self.regs.write(PX_IE, PX_IE_DEFAULT);
+
+        self.start()
+    }
+
+    /// Identify the disk and set up queueing
+    fn identify(&mut self, hba_ncq: bool) -> Result<(), DeviceError> {
+        let cmd = self.prepare(0, ATA_CMD_IDENTIFY, 0, 0, AHCI_SECTOR_SIZE, false)?;
+        let mut done = self.execute(vec![cmd], false)?;
+        let buf = done.pop().and_then(|cmd| cmd.buf).unwrap();
+
+        let mut data = [0u16; 256];
+        for (i, word) in data.iter_mut().enumerate() {
+            *word = buf.read::<u16>(i * 2);
+        }
+        let info = Identify::parse(&data);
+
+        self.sectors = info.sectors;
+        self.model = info.model;
+        if let (true, Some(depth)) = (hba_ncq, info.queue_depth) {
+            self.ncq = true;
+            self.queue_depth = min(depth as usize, self.tables.len());
+        }
+        Ok(())
+    }
+
+    /// Fill a command slot
+    fn prepare(
+        &mut self,
+        slot: usize,
+        command: u8,
+        lba: u64,
+        count: usize,
+        len: usize,
+        write: bool,
+    ) -> Result<Command, DeviceError> {
+        let buf = if len > 0 {
+            Some(DMABuffer::new(len, 4))
+        } else {
+            None
+        };
+
+        // Command header
+        let header = slot * COMMAND_HEADER_SIZE;
+        let prdtl = if buf.is_some() { 1u32 } else { 0 };
+        let flags = 5 | if write { 1 << 6 } else { 0 } | prdtl << 16;
+        self.command_list.write::<u32>(header, flags);
+        self.command_list.write::<u32>(header + 4, 0);
+
+        // Command FIS
+        let table = &mut self.tables[slot];
+        table.clear();
+        table.write::<u8>(0, FIS_TYPE_REG_H2D);
+        table.write::<u8>(1, FIS_H2D_COMMAND);
+        table.write::<u8>(2, command);
+        for i in 0..3 {
+            table.write::<u8>(4 + i, (lba >> (8 * i)) as u8);
+            table.write::<u8>(8 + i, (lba >> (8 * (i + 3))) as u8);
+        }
+        if command != ATA_CMD_IDENTIFY {
+            table.write::<u8>(7, 1 << 6);
+        }
+        match command {
+            ATA_CMD_READ_FPDMA_QUEUED | ATA_CMD_WRITE_FPDMA_QUEUED => {
+                // Queued commands carry the count in the features field
+                // and the tag in the count field
+                table.write::<u8>(3, count as u8);
+                table.write::<u8>(11, (count >> 8) as u8);
+                table.write::<u8>(12, (slot << 3) as u8);
+            }
+            _ => {
+                table.write::<u8>(12, count as u8);
+                table.write::<u8>(13, (count >> 8) as u8);
+            }
+        }
+
+        // Physical region descriptor
+        if let Some(ref buf) = buf {
+            let dba = buf.phys_addr();
+            table.write::<u32>(COMMAND_TABLE_PRDT, dba as u32);
+            table.write::<u32>(COMMAND_TABLE_PRDT + 4, (dba >> 32) as u32);
+            table.write::<u32>(COMMAND_TABLE_PRDT + 12, (len - 1) as u32);
+        }
+
+        Ok(Command { slot, buf })
+    }
+
+    /// Issue commands and wait for all of them to complete
+    fn execute(
+        &mut self,
+        commands: Vec<Command>,
+        queued: bool,
+    ) -> Result<Vec<Command>, DeviceError> {
+        let mask = commands.iter().fold(0u32, |mask, cmd| mask | 1 << cmd.slot);
+
+        if queued {
+            self.regs.write(PX_SACT, mask);
+        }
+        self.regs.write(PX_CI, mask);
+
+        let deadline = PIT::deadline(PIT::ms_to_ticks(AHCI_TIMEOUT_MS));
+        loop {
+            if self.regs.read(PX_TFD) & PX_TFD_ERR != 0 {
+                self.recover();
+                return Err(DeviceError::IOError);
+            }
+            if (self.regs.read(PX_SACT) | self.regs.read(PX_CI)) & mask == 0 {
+                return Ok(commands);
+            }
+            if PIT::has_passed(deadline) {
+                self.recover();
+                return Err(DeviceError::Timeout);
+            }
+            // Woken by the interrupt handler, or re-checked on the next tick
+            self.controller.events[self.port].wait_timeout(1);
+        }
+    }
+
+    /// Restart the port after an error
+    fn recover(&self) {
+        if let Err(err) = self.stop().and_then(|_| {
+            self.regs.write(PX_SERR, 0xFFFF_FFFF);
+            self.regs.write(PX_IS, 0xFFFF_FFFF);
+            self.start()
+        }) {
+            log!(error: "AHCI port {} failed to recover: {}", self.port, err);
+        }
+    }
+
+    fn check(&self, lba: u64, len: usize) -> Result<(), DeviceError> {
+        if len % AHCI_SECTOR_SIZE != 0 {
+            return Err(DeviceError::InvalidArgument);
+        }
+        match lba.checked_add((len / AHCI_SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.sectors => Ok(()),
+            _ => Err(DeviceError::OutOfRange),
+        }
+    }
+
+    /// Transfer `len` bytes as a series of command batches.
+    ///
+    /// Each batch fills as many slots as the queue depth allows.
+    /// `fill` writes data into a command buffer before it is issued,
+    /// `drain` takes data from it after completion.
+    fn transfer<F, D>(
+        &mut self,
+        lba: u64,
+        len: usize,
+        write: bool,
+        mut fill: F,
+        mut drain: D,
+    ) -> Result<(), DeviceError>
+    where
+        F: FnMut(usize, &mut DMABuffer),
+        D: FnMut(usize, &DMABuffer),
+    {
+        let command = match (write, self.ncq) {
+            (false, false) => ATA_CMD_READ_DMA_EXT,
+            (true, false) => ATA_CMD_WRITE_DMA_EXT,
+            (false, true) => ATA_CMD_READ_FPDMA_QUEUED,
+            (true, true) => ATA_CMD_WRITE_FPDMA_QUEUED,
+        };
+        let chunk = AHCI_COMMAND_SECTORS * AHCI_SECTOR_SIZE;
+        let mut offset = 0;
+
+        while offset < len {
+            let mut commands = Vec::with_capacity(self.queue_depth);
+            for slot in 0..self.queue_depth {
+                let start = offset + slot * chunk;
+                if start >= len {
+                    break;
+                }
+                let size = min(chunk, len - start);
+                let sector = lba + (start / AHCI_SECTOR_SIZE) as u64;
+                let mut cmd =
+                    self.prepare(slot, command, sector, size / AHCI_SECTOR_SIZE, size, write)?;
+                fill(start, cmd.buf.as_mut().unwrap());
+                commands.push(cmd);
+            }
+
+            let issued = commands.len();
+            for cmd in self.execute(commands, self.ncq)? {
+                drain(offset + cmd.slot * chunk, cmd.buf.as_ref().unwrap());
+            }
+            offset += issued * chunk;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AHCIDisk {
+    fn sector_size(&self) -> usize {
+        AHCI_SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        self.check(lba, buf.len())?;
+        let len = buf.len();
+        self.transfer(
+            lba,
+            len,
+            false,
+            |_, _| (),
+            |start, dma| {
+                let size = dma.len();
+                buf[start..start + size].copy_from_slice(dma.as_slice());
+            },
+        )
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        self.check(lba, buf.len())?;
+        self.transfer(
+            lba,
+            buf.len(),
+            true,
+            |start, dma| {
+                let size = dma.len();
+                dma.as_mut_slice()
+                    .copy_from_slice(&buf[start..start + size]);
+            },
+            |_, _| (),
+        )
+    }
+
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        let cmd = self.prepare(0, ATA_CMD_CACHE_FLUSH_EXT, 0, 0, 0, false)?;
+        self.execute(vec![cmd], false).map(|_| ())
+    }
+
+    fn max_transfer_sectors(&self) -> u64 {
+        (AHCI_COMMAND_SECTORS * self.queue_depth) as u64
+    }
+
+    fn shutdown(&mut self) -> Result<(), DeviceError> {
+        self.regs.write(PX_IE, 0);
+        // The port must let go of our buffers before they are freed
+        if let Err(err) = self.stop() {
+            log!(warn: "AHCI port {} did not stop: {}", self.port, err);
+            return Err(DeviceError::Timeout);
+        }
+        self.regs.write(PX_IS, 0xFFFF_FFFF);
+        Ok(())
+    }
+}
+
+/// AHCI controller driver.
+///
+/// Disks take the lowest free name of `sda` to `sdz`, in port order.
+pub struct AHCI;
+
+impl Driver for AHCI {
+    fn name(&self) -> &'static str {
+        "ahci"
+    }
+
+    fn match_table(&self) -> &[DeviceMatch] {
+        &AHCI_MATCHES
+    }
+
+    fn probe(&self, dev: &BusDevice) -> Result<(), &'static str> {
+        let pci = match *dev {
+            BusDevice::PCI(ref dev) => dev,
+            _ => return Err("not a PCI device"),
+        };
+        let (controller, names) = AHCI::init_controller(pci)?;
+        DISKS.fetch_add(names.len(), Ordering::Relaxed);
+        BOUND.lock().push((*dev, controller, names));
+        Ok(())
+    }
+
+    fn remove(&self, dev: &BusDevice) -> Result<(), &'static str> {
+        let (controller, names) = {
+            let mut bound = BOUND.lock();
+            let index = bound
+                .iter()
+                .position(|(other, _, _)| other.same_as(dev))
+                .ok_or("controller is not bound")?;
+            let (_, controller, names) = bound.remove(index);
+            (controller, names)
+        };
+
+        // Unregistering stops the ports
+        for name in names {
+            if let Err(err) = block::unregister(name) {
+                log!(warn: "Unable to unregister AHCI disk: {}", err);
+            }
+            NAMES.lock().free(name);
+        }
+
+        let hba = controller.hba;
+        hba.write(HBA_GHC, hba.read(HBA_GHC) & !HBA_GHC_IE);
+        hba.write(HBA_IS, 0xFFFF_FFFF);
+        interrupts::without_interrupts(|| {
+            CONTROLLERS
+                .lock()
+                .retain(|other| !Arc::ptr_eq(other, &controller))
+        });
+        if controller.msi {
+            if let BusDevice::PCI(ref pci) = *dev {
+                if let Some(msi) = pci.msi() {
+                    msi.disable();
+                }
+            }
+            IDT::free_vector(controller.irq);
+        }
+        Ok(())
+    }
+}
+
+impl AHCI {
+    /// Get the number of disks registered so far
+    pub fn disks() -> usize {
+        DISKS.load(Ordering::Relaxed)
+    }
+
+    /// Set up a controller and register its disks.
+    ///
+    /// Returns the controller and the names of the disks.
+    fn init_controller(
+        dev: &PCIDevice,
+    ) -> Result<(Arc<AHCIController>, Vec<&'static str>), &'static str> {
+        dev.enable(PCICommand::MEMORY_SPACE | PCICommand::BUS_MASTER);
+
+        let abar = dev.get_bar(5);
+        abar.identity_map()?;
+        let hba = HBA {
+            base: abar.addr() as usize,
+        };
+
+        // Take the controller over from the firmware
+        if hba.read(HBA_CAP2) & HBA_CAP2_BOH != 0 {
+            hba.write(HBA_BOHC, hba.read(HBA_BOHC) | HBA_BOHC_OOS);
+            let deadline = PIT::deadline(PIT::ms_to_ticks(AHCI_TIMEOUT_MS));
+            while hba.read(HBA_BOHC) & HBA_BOHC_BOS != 0 && !PIT::has_passed(deadline) {}
+        }
+        hba.write(HBA_GHC, hba.read(HBA_GHC) | HBA_GHC_AE);
+
+        let cap = hba.read(HBA_CAP);
+        let slots = ((cap >> 8) & 0x1F) as usize + 1;
+        let ncq = cap & HBA_CAP_SNCQ != 0;
+
+        // Prefer a vector of our own over a shared IRQ line
+        let msi = dev.msi();
+        let vector = msi.and_then(|_| IDT::allocate_vector(handle_interrupt).ok());
+        let irq = vector.unwrap_or_else(|| dev.read8(0x3C));
+
+        let controller = Arc::new(AHCIController {
+            hba,
+            irq,
+            msi: vector.is_some(),
+            events: (0..32).map(|_| Event::new(true)).collect(),
+        });
+        let shared_irq = interrupts::without_interrupts(|| {
+            let mut controllers = CONTROLLERS.lock();
+            let shared = controllers.iter().any(|c| c.irq == irq);
+            controllers.push(controller.clone());
+            shared
+        });
+        match (msi, vector) {
+            (Some(msi), Some(vector)) => {
+                msi.configure(vector);
+                msi.enable();
+                log!(debug: "AHCI controller {} uses MSI vector {}.", dev.address, vector);
+            }
+            _ if !shared_irq => {
+                if let Err(err) = IDT::register_irq(irq, handle_interrupt) {
+                    log!(warn: "AHCI IRQ {} unavailable ({}), polling instead.", irq, err);
+                }
+            }
+            _ => (),
+        }
+
+        // Acknowledge stale interrupts, then enable them
+        hba.write(HBA_IS, 0xFFFF_FFFF);
+        hba.write(HBA_GHC, hba.read(HBA_GHC) | HBA_GHC_IE);
+
+        let implemented = hba.read(HBA_PI);
+        let mut found = Vec::new();
+        for port in (0..32).filter(|port| implemented & (1 << port) != 0) {
+            let regs = hba.port(port);
+            let ssts = regs.read(PX_SSTS);
+            if ssts & 0x0F != SSTS_DET_PRESENT || (ssts >> 8) & 0x0F != SSTS_IPM_ACTIVE {
+                continue;
+            }
+            if regs.read(PX_SIG) != SATA_SIG_ATA {
+                continue;
+            }
+
+            let name = match NAMES.lock().alloc() {
+                Some(name) => name,
+                None => {
+                    log!(warn: "AHCI port {} skipped: out of disk names.", port);
+                    continue;
+                }
+            };
+            let mut disk = AHCIDisk::new(controller.clone(), port, slots);
+            if let Err(err) = disk.init().and_then(|_| disk.identify(ncq)) {
+                log!(error: "AHCI port {} failed to initialize: {}", port, err);
+                NAMES.lock().free(name);
+                continue;
+            }
+
+            log!(
+                debug: "AHCI {} on port {}: {} ({} sectors, queue depth {}).",
+                name,
+                port,
+                disk.model,
+                disk.sectors,
+                disk.queue_depth
+            );
+            match block::register(name, box disk) {
+                Ok(_) => found.push(name),
+                Err(err) => {
+                    log!(error: "Unable to register AHCI disk: {}", err);
+                    NAMES.lock().free(name);
+                }
+            }
+        }
+
+        Ok((controller, found))
+    }
+}
@@ -0,0 +1,543 @@
+//
+// ATA PIO / IDE
+//
+// Disks on an IDE controller are accessed through two
+// register blocks per channel. Transfers use PIO with
+// interrupts disabled on the drive side (nIEN), so the
+// driver polls the status register instead.
+//
+
+use alloc::{prelude::*, sync::Arc};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::block::{self, BlockDevice, DiskNames};
+use crate::hal::{BusDevice, DeviceError, DeviceMatch, Driver};
+use crate::pci::{PCIDevice, PCIFind};
+use crate::pit::PIT;
+
+const ATA_SECTOR_SIZE: usize = 512;
+
+lazy_static! {
+    static ref IDE_MATCHES: Vec<DeviceMatch> =
+        vec![DeviceMatch::PCI(PCIFind::with_class(0x01, 0x01))];
+    /// The disks registered for each bound controller
+    static ref BOUND: Mutex<Vec<(BusDevice, Vec<&'static str>)>> = Mutex::new(Vec::new());
+    static ref NAMES: Mutex<DiskNames> = Mutex::new(DiskNames::new("hd"));
+}
+
+/// The number of disks registered so far
+static DISKS: AtomicUsize = AtomicUsize::new(0);
+
+// Legacy (compatibility mode) ports
+const ATA_PRIMARY_IO: u16 = 0x1F0;
+const ATA_PRIMARY_CTRL: u16 = 0x3F6;
+const ATA_SECONDARY_IO: u16 = 0x170;
+const ATA_SECONDARY_CTRL: u16 = 0x376;
+
+// Status register bits
+const ATA_SR_ERR: u8 = 0x01;
+const ATA_SR_DRQ: u8 = 0x08;
+const ATA_SR_DF: u8 = 0x20;
+const ATA_SR_BSY: u8 = 0x80;
+
+// Device control register bits
+const ATA_CTRL_NIEN: u8 = 0x02;
+
+// Commands
+const ATA_CMD_READ_PIO: u8 = 0x20;
+const ATA_CMD_READ_PIO_EXT: u8 = 0x24;
+const ATA_CMD_WRITE_PIO: u8 = 0x30;
+const ATA_CMD_WRITE_PIO_EXT: u8 = 0x34;
+const ATA_CMD_CACHE_FLUSH: u8 = 0xE7;
+const ATA_CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const ATA_CMD_IDENTIFY: u8 = 0xEC;
+
+/// The time a drive may stay busy, in milliseconds
+const ATA_TIMEOUT_MS: usize = 5000;
+
+/// Status reads per millisecond, for timing without timer ticks.
+///
+/// An I/O port read takes about a microsecond.
+const ATA_POLLS_PER_MS: usize = 1000;
+
+/// The largest LBA reachable with 28-bit commands
+const ATA_LBA28_LIMIT: u64 = 1 << 28;
+
+/// A timeout that also expires while interrupts are disabled.
+///
+/// The PIT only ticks with interrupts enabled, so the
+/// number of status polls is bounded as well.
+struct Timeout {
+    deadline: usize,
+    polls: usize,
+}
+
+impl Timeout {
+    fn new(ms: usize) -> Self {
+        Timeout {
+            deadline: PIT::deadline(PIT::ms_to_ticks(ms)),
+            polls: ms * ATA_POLLS_PER_MS,
+        }
+    }
+
+    /// Count a poll and test whether the timeout expired
+    fn expired(&mut self) -> bool {
+        self.polls = self.polls.saturating_sub(1);
+        self.polls == 0 || PIT::has_passed(self.deadline)
+    }
+}
+
+/// The register blocks of an IDE channel
+struct ATAChannel {
+    data: Port<u16>,
+    sector_count: Port<u8>,
+    lba_lo: Port<u8>,
+    lba_mid: Port<u8>,
+    lba_hi: Port<u8>,
+    drive: Port<u8>,
+    command: Port<u8>,
+    control: Port<u8>,
+}
+
+impl ATAChannel {
+    fn new(io: u16, ctrl: u16) -> Self {
+        ATAChannel {
+            data: Port::new(io),
+            sector_count: Port::new(io + 2),
+            lba_lo: Port::new(io + 3),
+            lba_mid: Port::new(io + 4),
+            lba_hi: Port::new(io + 5),
+            drive: Port::new(io + 6),
+            command: Port::new(io + 7),
+            control: Port::new(ctrl),
+        }
+    }
+
+    fn status(&self) -> u8 {
+        unsafe { self.command.read() }
+    }
+
+    fn alt_status(&self) -> u8 {
+        unsafe { self.control.read() }
+    }
+
+    /// Wait 400ns for the drive to update its status
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    /// Wait for the drive to clear BSY
+    fn wait_idle(&self) -> Result<u8, DeviceError> {
+        let mut timeout = Timeout::new(ATA_TIMEOUT_MS);
+        loop {
+            let status = self.status();
+            if status & ATA_SR_BSY == 0 {
+                return Ok(status);
+            }
+            if timeout.expired() {
+                return Err(DeviceError::Timeout);
+            }
+        }
+    }
+
+    /// Wait for the drive to request data
+    fn wait_drq(&self) -> Result<(), DeviceError> {
+        let mut timeout = Timeout::new(ATA_TIMEOUT_MS);
+        loop {
+            let status = self.wait_idle()?;
+            if status & (ATA_SR_ERR | ATA_SR_DF) != 0 {
+                return Err(DeviceError::IOError);
+            }
+            if status & ATA_SR_DRQ != 0 {
+                return Ok(());
+            }
+            if timeout.expired() {
+                return Err(DeviceError::Timeout);
+            }
+        }
+    }
+
+    fn select(&mut self, value: u8) {
+        unsafe { self.drive.write(value) };
+        self.delay();
+    }
+
+    /// Set up the task file for a transfer
+    fn setup(&mut self, slave: bool, lba: u64, count: u16, lba48: bool) {
+        let slave_bit = if slave { 0x10 } else { 0x00 };
+        unsafe {
+            if lba48 {
+                self.select(0x40 | slave_bit);
+                // High bytes go first
+                self.sector_count.write((count >> 8) as u8);
+                self.lba_lo.write((lba >> 24) as u8);
+                self.lba_mid.write((lba >> 32) as u8);
+                self.lba_hi.write((lba >> 40) as u8);
+            } else {
+                self.select(0xE0 | slave_bit | ((lba >> 24) as u8 & 0x0F));
+            }
+            self.sector_count.write(count as u8);
+            self.lba_lo.write(lba as u8);
+            self.lba_mid.write((lba >> 8) as u8);
+            self.lba_hi.write((lba >> 16) as u8);
+        }
+    }
+
+    fn read_words(&self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(2) {
+            let word = unsafe { self.data.read() };
+            chunk[0] = word as u8;
+            chunk[1] = (word >> 8) as u8;
+        }
+    }
+
+    fn write_words(&mut self, buf: &[u8]) {
+        for chunk in buf.chunks(2) {
+            let word = chunk[0] as u16 | (chunk[1] as u16) << 8;
+            unsafe { self.data.write(word) };
+        }
+    }
+
+    /// Identify a drive.
+    ///
+    /// Returns `None` if no ATA drive is attached.
+    fn identify(&mut self, slave: bool) -> Option<[u16; 256]> {
+        self.select(if slave { 0xB0 } else { 0xA0 });
+        unsafe {
+            self.sector_count.write(0);
+            self.lba_lo.write(0);
+            self.lba_mid.write(0);
+            self.lba_hi.write(0);
+            self.command.write(ATA_CMD_IDENTIFY);
+        }
+        self.delay();
+
+        // A floating bus reads 0xFF, a missing drive 0x00
+        match self.status() {
+            0x00 | 0xFF => return None,
+            _ => (),
+        }
+        self.wait_idle().ok()?;
+
+        // ATAPI and SATA devices set the LBA mid and high registers
+        let (mid, hi) = unsafe { (self.lba_mid.read(), self.lba_hi.read()) };
+        if mid != 0 || hi != 0 {
+            return None;
+        }
+        self.wait_drq().ok()?;
+
+        let mut data = [0u16; 256];
+        for word in data.iter_mut() {
+            *word = unsafe { self.data.read() };
+        }
+        Some(data)
+    }
+}
+
+/// The fields of IDENTIFY DEVICE data drivers care about
+pub struct Identify {
+    pub lba48: bool,
+    pub sectors: u64,
+    pub model: String,
+    /// The NCQ queue depth, if supported
+    pub queue_depth: Option<u32>,
+}
+
+impl Identify {
+    pub fn parse(data: &[u16; 256]) -> Self {
+        let lba48 = data[83] & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (0..4).fold(0u64, |acc, i| acc | (data[100 + i] as u64) << (16 * i))
+        } else {
+            data[60] as u64 | (data[61] as u64) << 16
+        };
+
+        // The model string is stored with swapped bytes
+        let mut model = String::new();
+        for word in &data[27..47] {
+            model.push((word >> 8) as u8 as char);
+            model.push(*word as u8 as char);
+        }
+        let model = model.trim().to_string();
+
+        let queue_depth = if data[76] & (1 << 8) != 0 {
+            Some((data[75] & 0x1F) as u32 + 1)
+        } else {
+            None
+        };
+
+        Identify {
+            lba48,
+            sectors,
+            model,
+            queue_depth,
+        }
+    }
+}
+
+/// A disk attached to an IDE channel
+pub struct ATADrive {
+    channel: Arc<Mutex<ATAChannel>>,
+    slave: bool,
+    lba48: bool,
+    sectors: u64,
+    model: String,
+}
+
+impl ATADrive {
+    fn from_identify(channel: Arc<Mutex<ATAChannel>>, slave: bool, data: &[u16; 256]) -> Self {
+        let info = Identify::parse(data);
+        ATADrive {
+            channel,
+            slave,
+            lba48: info.lba48,
+            sectors: info.sectors,
+            model: info.model,
+        }
+    }
+
+    /// Get the largest number of sectors one command can transfer
+    fn max_sectors(&self) -> u64 {
+        if self.lba48 {
+            65536
+        } else {
+            256
+        }
+    }
+
+    fn command_for(&self, lba: u64, count: u64, write: bool) -> Result<(u8, bool), DeviceError> {
+        let use_lba48 = lba + count > ATA_LBA28_LIMIT;
+        if use_lba48 && !self.lba48 {
+            return Err(DeviceError::OutOfRange);
+        }
+        let cmd = match (write, use_lba48) {
+            (false, false) => ATA_CMD_READ_PIO,
+            (false, true) => ATA_CMD_READ_PIO_EXT,
+            (true, false) => ATA_CMD_WRITE_PIO,
+            (true, true) => ATA_CMD_WRITE_PIO_EXT,
+        };
+        Ok((cmd, use_lba48))
+    }
+
+    fn check(&self, lba: u64, len: usize) -> Result<u64, DeviceError> {
+        if len % ATA_SECTOR_SIZE != 0 {
+            return Err(DeviceError::InvalidArgument);
+        }
+        let count = (len / ATA_SECTOR_SIZE) as u64;
+        match lba.checked_add(count) {
+            Some(end) if end <= self.sectors => Ok(count),
+            _ => Err(DeviceError::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for ATADrive {
+    fn sector_size(&self) -> usize {
+        ATA_SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        self.check(lba, buf.len())?;
+        let chunk_len = self.max_sectors() as usize * ATA_SECTOR_SIZE;
+        let mut channel = self.channel.lock();
+
+        for (i, chunk) in buf.chunks_mut(chunk_len).enumerate() {
+            let lba = lba + (i * chunk_len / ATA_SECTOR_SIZE) as u64;
+            let count = (chunk.len() / ATA_SECTOR_SIZE) as u64;
+            let (cmd, lba48) = self.command_for(lba, count, false)?;
+
+            channel.wait_idle()?;
+            // A count of 0 means the maximum
+            channel.setup(self.slave, lba, count as u16, lba48);
+            unsafe { channel.command.write(cmd) };
+
+            for sector in chunk.chunks_mut(ATA_SECTOR_SIZE) {
+                channel.delay();
+                channel.wait_drq()?;
+                channel.read_words(sector);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        self.check(lba, buf.len())?;
+        let chunk_len = self.max_sectors() as usize * ATA_SECTOR_SIZE;
+        let mut channel = self.channel.lock();
+
+        for (i, chunk) in buf.chunks(chunk_len).enumerate() {
+            let lba = lba + (i * chunk_len / ATA_SECTOR_SIZE) as u64;
+            let count = (chunk.len() / ATA_SECTOR_SIZE) as u64;
+            let (cmd, lba48) = self.command_for(lba, count, true)?;
+
+            channel.wait_idle()?;
+            channel.setup(self.slave, lba, count as u16, lba48);
+            unsafe { channel.command.write(cmd) };
+
+            for sector in chunk.chunks(ATA_SECTOR_SIZE) {
+                channel.delay();
+                channel.wait_drq()?;
+                channel.write_words(sector);
+            }
+            channel.delay();
+            let status = channel.wait_idle()?;
+            if status & (ATA_SR_ERR | ATA_SR_DF) != 0 {
+                return Err(DeviceError::IOError);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        let mut channel = self.channel.lock();
+        channel.wait_idle()?;
+        channel.select(if self.slave { 0xB0 } else { 0xA0 });
+        let cmd = if self.lba48 {
+            ATA_CMD_CACHE_FLUSH_EXT
+        } else {
+            ATA_CMD_CACHE_FLUSH
+        };
+        unsafe { channel.command.write(cmd) };
+        channel.delay();
+        let status = channel.wait_idle()?;
+        if status & (ATA_SR_ERR | ATA_SR_DF) != 0 {
+            return Err(DeviceError::IOError);
+        }
+        Ok(())
+    }
+}
+
+/// IDE controller driver.
+///
+/// Disks take the lowest free name of `hda` to `hdz`,
+/// in the order primary master, primary slave, secondary
+/// master, secondary slave of each controller.
+pub struct IDE;
+
+impl Driver for IDE {
+    fn name(&self) -> &'static str {
+        "ide"
+    }
+
+    fn match_table(&self) -> &[DeviceMatch] {
+        &IDE_MATCHES
+    }
+
+    fn probe(&self, dev: &BusDevice) -> Result<(), &'static str> {
+        let pci = match *dev {
+            BusDevice::PCI(ref dev) => dev,
+            _ => return Err("not a PCI device"),
+        };
+        let mut names = Vec::new();
+        for &(io, ctrl) in IDE::channel_ports(pci).iter() {
+            let found = IDE::probe_channel(io, ctrl);
+            DISKS.fetch_add(found.len(), Ordering::Relaxed);
+            names.extend(found);
+        }
+        BOUND.lock().push((*dev, names));
+        Ok(())
+    }
+
+    fn remove(&self, dev: &BusDevice) -> Result<(), &'static str> {
+        let names = {
+            let mut bound = BOUND.lock();
+            let index = bound
+                .iter()
+                .position(|(other, _)| other.same_as(dev))
+                .ok_or("controller is not bound")?;
+            bound.remove(index).1
+        };
+        for name in names {
+            if let Err(err) = block::unregister(name) {
+                log!(warn: "Unable to unregister ATA disk: {}", err);
+            }
+            NAMES.lock().free(name);
+        }
+        Ok(())
+    }
+}
+
+impl IDE {
+    /// Get the number of disks registered so far
+    pub fn disks() -> usize {
+        DISKS.load(Ordering::Relaxed)
+    }
+
+    /// Get the register ports of both channels.
+    ///
+    /// Channels in native mode take their ports from the BARs.
+    fn channel_ports(dev: &PCIDevice) -> [(u16, u16); 2] {
+        let prog_if = dev.prog_if();
+        let native = |bar: u8| {
+            (
+                dev.get_bar(bar).addr() as u16,
+                dev.get_bar(bar + 1).addr() as u16 + 2,
+            )
+        };
+        [
+            if prog_if & 0x01 != 0 {
+                native(0)
+            } else {
+                (ATA_PRIMARY_IO, ATA_PRIMARY_CTRL)
+            },
+            if prog_if & 0x04 != 0 {
+                native(2)
+            } else {
+                (ATA_SECONDARY_IO, ATA_SECONDARY_CTRL)
+            },
+        ]
+    }
+
+    /// Register the disks on a channel and return their names
+    fn probe_channel(io: u16, ctrl: u16) -> Vec<&'static str> {
+        let channel = Arc::new(Mutex::new(ATAChannel::new(io, ctrl)));
+
+        // We poll, so keep the drives from raising interrupts
+        unsafe { channel.lock().control.write(ATA_CTRL_NIEN) };
+
+        let mut found = Vec::new();
+        for &slave in [false, true].iter() {
+            let data = match channel.lock().identify(slave) {
+                Some(data) => data,
+                None => continue,
+            };
+            let drive = ATADrive::from_identify(channel.clone(), slave, &data);
+            if drive.sectors == 0 {
+                continue;
+            }
+
+            let name = match NAMES.lock().alloc() {
+                Some(name) => name,
+                None => {
+                    log!(warn: "ATA disk at 0x{:x} skipped: out of disk names.", io);
+                    continue;
+                }
+            };
+            log!(
+                debug: "ATA {} at 0x{:x}: {} ({} sectors, LBA48: {}).",
+                name,
+                io,
+                drive.model,
+                drive.sectors,
+                drive.lba48
+            );
+            match block::register(name, box drive) {
+                Ok(_) => found.push(name),
+                Err(err) => {
+                    log!(error: "Unable to register ATA disk: {}", err);
+                    NAMES.lock().free(name);
+                }
+            }
+        }
+        found
+    }
+}
@@ -8,7 +8,10 @@ use rlibc::memcpy;
 
 use crate::ansi::{Ansi, AnsiEscape};
 
+use crate::hal::{BusDevice, DeviceMatch, Driver};
+use crate::logger::{self, ConsoleSink, Level};
 use crate::pci::{PCIDevice, PCIFind, PCIBAR};
+use crate::tty::{self, Output, SharedOutput};
 
 const VBE_DISPI_GETCAPS: u16 = 2;
 const VBE_DISPI_NUM_REGISTERS: u16 = 10;
@@ -26,7 +29,8 @@ const VBE_DISPI_LFB_ENABLED: u16 = 64;
 const VBE_DISPI_NOCLEAR: u16 = 128;
 
 lazy_static! {
-    static ref BGA_SIGNATURE: PCIFind = PCIFind::new(0x1234, 0x1111);
+    static ref BGA_MATCHES: Vec<DeviceMatch> =
+        vec![DeviceMatch::PCI(PCIFind::new(0x1234, 0x1111))];
     static ref DEFAULT_VIDEO_MODE: VideoMode = VideoMode {
         width: 1280,
         height: 720,
@@ -44,6 +48,9 @@ pub trait TerminalProvider {
     fn get_char_height(&self) -> usize;
 
     fn draw_char(&mut self, x: usize, y: usize, character: char, fg: u32, bg: u32);
+
+    /// Make what was drawn visible
+    fn flush(&self) {}
 }
 
 pub struct TerminalDriver<'a> {
@@ -110,6 +117,8 @@ impl<'a> TerminalDriver<'a> {
     pub fn write_car(&mut self, c: char) {
         match c {
             '\n' => self.new_line(),
+            '\r' => self.x = 0,
+            '\x08' => self.x = self.x.saturating_sub(1),
             _ => {
                 if self.x >= self.provider.get_width() {
                     self.new_line();
@@ -126,6 +135,10 @@ impl<'a> TerminalDriver<'a> {
         }
     }
 
+    pub fn flush(&self) {
+        self.provider.flush();
+    }
+
     pub fn reset(&mut self) {
         self.fg = self.fg_def;
         self.bg = self.bg_def;
@@ -149,6 +162,15 @@ impl<'a> TerminalDriver<'a> {
     }
 }
 
+impl Output for TerminalDriver<'static> {
+    fn write(&mut self, val: &[u8]) {
+        self.write_str(&String::from_utf8_lossy(val));
+        self.flush();
+    }
+}
+
+unsafe impl<'a> Send for TerminalDriver<'a> {}
+
 impl<'a> core::fmt::Write for TerminalDriver<'a> {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         self.write_str(s);
@@ -176,6 +198,10 @@ where
         16
     }
 
+    fn flush(&self) {
+        VideoDevice::flush(self);
+    }
+
     fn draw_char(&mut self, x: usize, y: usize, character: char, fg: u32, bg: u32) {
         if x + 8 <= self.mode.width && y + 16 <= self.mode.height {
             let font_i = 16 * (character as usize);
@@ -347,11 +373,51 @@ impl BochsGraphicsAdapter {
         self.write_reg(VBE_DISPI_INDEX_ENABLE, was_enabled);
         cap
     }
+}
 
-    pub fn detect() -> Result<PCIDevice, &'static str> {
-        match PCIDevice::search(&BGA_SIGNATURE, None) {
-            Some(dev) => Ok(dev),
-            None => Err("Could not find Bochs Graphics Adapter"),
-        }
+/// Bochs Graphics Adapter driver.
+///
+/// The adapter is switched to the default mode and shows
+/// the second virtual console and the log.
+pub struct BGADriver;
+
+impl Driver for BGADriver {
+    fn name(&self) -> &'static str {
+        "bga"
+    }
+
+    fn match_table(&self) -> &[DeviceMatch] {
+        &BGA_MATCHES
+    }
+
+    fn probe(&self, dev: &BusDevice) -> Result<(), &'static str> {
+        let dev = match *dev {
+            BusDevice::PCI(ref dev) => dev,
+            _ => return Err("not a PCI device"),
+        };
+        let mut bga = BochsGraphicsAdapter::new(dev).init();
+        log!(
+            debug: "BGA at 0x{:08x}: version 0x{:04x}, up to {}x{}x{}.",
+            bga.addr(),
+            bga.version(),
+            bga.max_width,
+            bga.max_height,
+            bga.max_bpp
+        );
+        let mode = bga
+            .get_default_mode()
+            .ok_or("default video mode not supported")?;
+        bga.set_video_mode(&mode, true);
+
+        // The terminal lives on as an output of tty1
+        let bga: &'static BochsGraphicsAdapter = Box::leak(box bga);
+        let video = Box::leak(box VideoDevice::new(bga, &mode));
+        let term = TerminalDriver::new(video);
+
+        // Show the first virtual console and the log on the framebuffer too
+        let term = SharedOutput::new(term);
+        tty::attach_output("tty1", box term.clone())?;
+        logger::add_sink("fb0", Level::Info, box ConsoleSink::new(term)).ok();
+        Ok(())
     }
 }
@@ -0,0 +1,206 @@
+use alloc::{collections::BTreeMap, prelude::*};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::BlockDeviceHandle;
+
+/// The number of sectors the global cache holds
+const CACHE_SECTORS: usize = 2048;
+
+lazy_static! {
+    pub static ref BUFFER_CACHE: Mutex<BufferCache> = Mutex::new(BufferCache::new(CACHE_SECTORS));
+}
+
+/// Identifies a sector: (disk id, lba on the disk)
+type CacheKey = (usize, u64);
+
+struct CacheEntry {
+    data: Vec<u8>,
+    dirty: bool,
+    handle: BlockDeviceHandle,
+    stamp: u64,
+}
+
+/// A write-back sector cache with LRU eviction.
+///
+/// The cache never talks to a device itself, so its lock is
+/// not held during transfers. Callers fetch missing sectors
+/// and write back dirty ones, then report back.
+pub struct BufferCache {
+    entries: BTreeMap<CacheKey, CacheEntry>,
+    /// Entries by last use, oldest first
+    lru: BTreeMap<u64, CacheKey>,
+    /// Dirty sectors evicted but not written back yet
+    evicted: BTreeMap<CacheKey, (BlockDeviceHandle, Vec<u8>)>,
+    clock: u64,
+    capacity: usize,
+}
+
+impl BufferCache {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0);
+        BufferCache {
+            entries: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            evicted: BTreeMap::new(),
+            clock: 0,
+            capacity,
+        }
+    }
+
+    /// Copy cached sectors into `buf`.
+    ///
+    /// Returns the indices of the sectors that are not cached.
+    /// Sectors are addressed on the whole disk, here and below.
+    pub fn lookup(&mut self, handle: &BlockDeviceHandle, lba: u64, buf: &mut [u8]) -> Vec<usize> {
+        let mut misses = Vec::new();
+        for (i, chunk) in buf.chunks_mut(handle.sector_size()).enumerate() {
+            let key = (handle.disk, lba + i as u64);
+            if self.entries.contains_key(&key) {
+                self.touch(key);
+                chunk.copy_from_slice(&self.entries[&key].data);
+            } else if let Some((_, data)) = self.evicted.get(&key) {
+                chunk.copy_from_slice(data);
+            } else {
+                misses.push(i);
+            }
+        }
+        misses
+    }
+
+    /// Add a sector read from the device.
+    ///
+    /// A sector written in the meantime is kept.
+    pub fn fill(&mut self, handle: &BlockDeviceHandle, lba: u64, data: Vec<u8>) {
+        let key = (handle.disk, lba);
+        if !self.entries.contains_key(&key) && !self.evicted.contains_key(&key) {
+            self.insert(handle, lba, data, false);
+        }
+    }
+
+    /// Write sectors into the cache, marking them dirty
+    pub fn write(&mut self, handle: &BlockDeviceHandle, lba: u64, buf: &[u8]) {
+        for (i, chunk) in buf.chunks(handle.sector_size()).enumerate() {
+            self.evicted.remove(&(handle.disk, lba + i as u64));
+            self.insert(handle, lba + i as u64, chunk.to_vec(), true);
+        }
+    }
+
+    /// Get the dirty sectors of a disk as (lba, data)
+    pub fn dirty_sectors(&self, handle: &BlockDeviceHandle) -> Vec<(u64, Vec<u8>)> {
+        let range = (handle.disk, 0)..=(handle.disk, u64::max_value());
+        let cached = self
+            .entries
+            .range(range.clone())
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(key, entry)| (key.1, entry.data.clone()));
+        let evicted = self
+            .evicted
+            .range(range)
+            .map(|(key, (_, data))| (key.1, data.clone()));
+        cached.chain(evicted).collect()
+    }
+
+    /// Get the dirty sectors evicted from the cache as (handle, lba, data)
+    pub fn evicted(&self) -> Vec<(BlockDeviceHandle, u64, Vec<u8>)> {
+        self.evicted
+            .iter()
+            .map(|(key, (handle, data))| (handle.clone(), key.1, data.clone()))
+            .collect()
+    }
+
+    /// Record that `data` reached the device.
+    ///
+    /// A sector written again since `data` was taken stays dirty.
+    pub fn written_back(&mut self, handle: &BlockDeviceHandle, lba: u64, data: &[u8]) {
+        let key = (handle.disk, lba);
+        if let Some(entry) = self.entries.get_mut(&key) {
+            if entry.data[..] == *data {
+                entry.dirty = false;
+            }
+        }
+        let done = match self.evicted.get(&key) {
+            Some((_, evicted)) => evicted[..] == *data,
+            None => false,
+        };
+        if done {
+            self.evicted.remove(&key);
+        }
+    }
+
+    /// Drop all cached sectors of a disk without writing them back
+    pub fn invalidate(&mut self, handle: &BlockDeviceHandle) {
+        let range = (handle.disk, 0)..=(handle.disk, u64::max_value());
+        let keys: Vec<CacheKey> = self
+            .entries
+            .range(range.clone())
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            if let Some(entry) = self.entries.remove(&key) {
+                self.lru.remove(&entry.stamp);
+            }
+        }
+        let keys: Vec<CacheKey> = self.evicted.range(range).map(|(key, _)| *key).collect();
+        for key in keys {
+            self.evicted.remove(&key);
+        }
+    }
+
+    fn insert(&mut self, handle: &BlockDeviceHandle, lba: u64, data: Vec<u8>, dirty: bool) {
+        let key = (handle.disk, lba);
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.data = data;
+            entry.dirty |= dirty;
+            self.touch(key);
+            return;
+        }
+
+        while self.entries.len() >= self.capacity {
+            self.evict();
+        }
+
+        let stamp = self.next_stamp();
+        self.lru.insert(stamp, key);
+        self.entries.insert(
+            key,
+            CacheEntry {
+                data,
+                dirty,
+                handle: handle.clone(),
+                stamp,
+            },
+        );
+    }
+
+    /// Remove the least recently used sector.
+    ///
+    /// A dirty sector moves to `evicted` until it is written back.
+    fn evict(&mut self) {
+        let (stamp, key) = match self.lru.iter().next() {
+            Some((&stamp, &key)) => (stamp, key),
+            None => return,
+        };
+
+        self.lru.remove(&stamp);
+        if let Some(entry) = self.entries.remove(&key) {
+            if entry.dirty {
+                self.evicted.insert(key, (entry.handle, entry.data));
+            }
+        }
+    }
+
+    /// Mark a sector as most recently used
+    fn touch(&mut self, key: CacheKey) {
+        let stamp = self.next_stamp();
+        let entry = self.entries.get_mut(&key).unwrap();
+        self.lru.remove(&entry.stamp);
+        entry.stamp = stamp;
+        self.lru.insert(stamp, key);
+    }
+
+    fn next_stamp(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
@@ -0,0 +1,495 @@
+//
+// Block Device Layer
+//
+// Disk drivers implement `BlockDevice` and register it here.
+// Everything else accesses disks through a `BlockDeviceHandle`,
+// which goes through the shared buffer cache. Requests that
+// reach the hardware are batched by a `RequestQueue`.
+//
+
+mod cache;
+mod names;
+mod queue;
+
+pub use self::cache::{BufferCache, BUFFER_CACHE};
+pub use self::names::{DiskNames, MAX_DISKS};
+pub use self::queue::{Completion, Operation, RequestId, RequestQueue};
+
+use alloc::{boxed::Box, prelude::*, sync::Arc};
+use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::hal::{Device, DeviceCapabilities, DeviceError, DeviceType, DEVICE_MANAGER};
+
+lazy_static! {
+    static ref BLOCK_DEVICES: Mutex<Vec<BlockDeviceHandle>> = Mutex::new(Vec::new());
+}
+
+/// The next block device id
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+//
+// Block device control requests
+//
+
+/// Get the sector size in bytes
+pub const BLK_IOCTL_GET_SECTOR_SIZE: u32 = 0x1001;
+
+/// Get the number of sectors
+pub const BLK_IOCTL_GET_SECTOR_COUNT: u32 = 0x1002;
+
+/// Write back cached data and flush the device
+pub const BLK_IOCTL_SYNC: u32 = 0x1003;
+
+/// A device that stores data in fixed-size sectors
+pub trait BlockDevice: Send {
+    /// Get the size of a sector in bytes
+    fn sector_size(&self) -> usize;
+
+    /// Get the number of sectors
+    fn sector_count(&self) -> u64;
+
+    /// Read whole sectors starting at `lba` into `buf`
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError>;
+
+    /// Write whole sectors starting at `lba` from `buf`
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), DeviceError>;
+
+    /// Make sure written data reached the medium
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+
+    /// Get the largest number of sectors a single transfer may span
+    fn max_transfer_sectors(&self) -> u64 {
+        128
+    }
+
+    /// Stop the hardware once the device is unregistered.
+    ///
+    /// No transfer runs anymore when this is called. If the
+    /// hardware does not stop, the device is never freed, as
+    /// it may still access the device's memory.
+    fn shutdown(&mut self) -> Result<(), DeviceError> {
+        Ok(())
+    }
+}
+
+/// Stands in for a disk after it was unregistered.
+///
+/// Handles kept by the disk's users fail from then on.
+struct RemovedDevice {
+    sector_size: usize,
+    sector_count: u64,
+}
+
+impl BlockDevice for RemovedDevice {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    fn read_sectors(&mut self, _lba: u64, _buf: &mut [u8]) -> Result<(), DeviceError> {
+        Err(DeviceError::IOError)
+    }
+
+    fn write_sectors(&mut self, _lba: u64, _buf: &[u8]) -> Result<(), DeviceError> {
+        Err(DeviceError::IOError)
+    }
+}
+
+/// A shared reference to a registered block device.
+///
+/// Partitions are handles onto their disk's device with an
+/// offset, so they share the disk's cache entries.
+#[derive(Clone)]
+pub struct BlockDeviceHandle {
+    id: usize,
+    /// The id of the whole disk, which keys the cache
+    disk: usize,
+    name: &'static str,
+    sector_size: usize,
+    /// The first sector on the underlying device
+    offset: u64,
+    sector_count: u64,
+    dev: Arc<Mutex<Box<dyn BlockDevice>>>,
+}
+
+impl BlockDeviceHandle {
+    /// Get the name the device is registered under
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    pub fn sector_count(&self) -> u64 {
+        self.sector_count
+    }
+
+    /// Test whether this is a partition of another device
+    pub fn is_partition(&self) -> bool {
+        self.id != self.disk
+    }
+
+    /// Read sectors through the buffer cache
+    pub fn read(&self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        self.check_range(lba, buf.len())?;
+        let lba = self.offset + lba;
+        let misses = BUFFER_CACHE.lock().lookup(self, lba, buf);
+
+        if !misses.is_empty() {
+            // Adjacent misses become a single transfer
+            let size = self.sector_size;
+            let mut queue = RequestQueue::new();
+            let ids: Vec<(RequestId, usize)> = misses
+                .into_iter()
+                .map(|i| (queue.submit_read(lba + i as u64, 1, size), i))
+                .collect();
+            for completion in self.run_queue(&mut queue) {
+                let data = completion.result?;
+                let i = ids
+                    .iter()
+                    .find(|&&(id, _)| id == completion.id)
+                    .map(|&(_, i)| i)
+                    .unwrap();
+                buf[i * size..(i + 1) * size].copy_from_slice(&data);
+                BUFFER_CACHE.lock().fill(self, lba + i as u64, data);
+            }
+        }
+        self.write_back_evicted()
+    }
+
+    /// Write sectors through the buffer cache.
+    ///
+    /// The data reaches the device on `sync` or on eviction.
+    pub fn write(&self, lba: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        self.check_range(lba, buf.len())?;
+        BUFFER_CACHE.lock().write(self, self.offset + lba, buf);
+        self.write_back_evicted()
+    }
+
+    /// Write back cached data of the whole disk and flush it
+    pub fn sync(&self) -> Result<(), DeviceError> {
+        self.write_back()?;
+        self.dev.lock().flush()
+    }
+
+    /// Write back the dirty cached sectors of the whole disk
+    fn write_back(&self) -> Result<(), DeviceError> {
+        let dirty = BUFFER_CACHE.lock().dirty_sectors(self);
+        if dirty.is_empty() {
+            return Ok(());
+        }
+
+        let mut queue = RequestQueue::new();
+        let pending: Vec<(RequestId, u64, Vec<u8>)> = dirty
+            .into_iter()
+            .map(|(lba, data)| {
+                let id = queue.submit_write(lba, data.clone(), self.sector_size);
+                (id, lba, data)
+            })
+            .collect();
+
+        let mut result = Ok(());
+        for completion in self.run_queue(&mut queue) {
+            match completion.result {
+                Ok(_) => {
+                    let (_, lba, data) = pending
+                        .iter()
+                        .find(|(id, _, _)| *id == completion.id)
+                        .unwrap();
+                    BUFFER_CACHE.lock().written_back(self, *lba, data);
+                }
+                // Failed sectors stay dirty
+                Err(err) => result = Err(err),
+            }
+        }
+        result
+    }
+
+    /// Write back the sectors the cache evicted while dirty.
+    ///
+    /// Failures of other disks are left for their own `sync`.
+    fn write_back_evicted(&self) -> Result<(), DeviceError> {
+        let evicted = BUFFER_CACHE.lock().evicted();
+        let mut result = Ok(());
+        for (handle, lba, data) in evicted {
+            let written = handle.dev.lock().write_sectors(lba, &data);
+            match written {
+                Ok(()) => BUFFER_CACHE.lock().written_back(&handle, lba, &data),
+                Err(err) => {
+                    if handle.disk == self.disk {
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Read bytes at any position through the buffer cache
+    pub fn read_bytes(&self, pos: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        self.for_each_sector(pos, buf.len(), |lba, sector, offset, count, done| {
+            self.read(lba, sector)?;
+            buf[done..done + count].copy_from_slice(&sector[offset..offset + count]);
+            Ok(())
+        })
+    }
+
+    /// Write bytes at any position through the buffer cache.
+    ///
+    /// Partially written sectors are read first.
+    pub fn write_bytes(&self, pos: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        self.for_each_sector(pos, buf.len(), |lba, sector, offset, count, done| {
+            if count != sector.len() {
+                self.read(lba, sector)?;
+            }
+            sector[offset..offset + count].copy_from_slice(&buf[done..done + count]);
+            self.write(lba, sector)
+        })
+    }
+
+    /// Visit the sectors touched by a byte range.
+    ///
+    /// `f` receives the sector number, a sector buffer, the range
+    /// within the sector and the matching offset in the caller's buffer.
+    fn for_each_sector<F>(&self, pos: u64, len: usize, mut f: F) -> Result<(), DeviceError>
+    where
+        F: FnMut(u64, &mut [u8], usize, usize, usize) -> Result<(), DeviceError>,
+    {
+        let size = self.sector_size;
+        let mut sector = vec![0u8; size];
+        let mut done = 0;
+        while done < len {
+            let at = pos
+                .checked_add(done as u64)
+                .ok_or(DeviceError::OutOfRange)?;
+            let offset = (at % size as u64) as usize;
+            let count = (size - offset).min(len - done);
+            f(at / size as u64, &mut sector, offset, count, done)?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Run the requests in `queue` against the device.
+    ///
+    /// The requests address sectors of the whole disk.
+    pub fn run_queue(&self, queue: &mut RequestQueue) -> Vec<Completion> {
+        queue.run(&mut **self.dev.lock())
+    }
+
+    fn check_range(&self, lba: u64, len: usize) -> Result<(), DeviceError> {
+        if len % self.sector_size != 0 {
+            return Err(DeviceError::InvalidArgument);
+        }
+        let count = (len / self.sector_size) as u64;
+        match lba.checked_add(count) {
+            Some(end) if end <= self.sector_count => Ok(()),
+            _ => Err(DeviceError::OutOfRange),
+        }
+    }
+}
+
+/// Register a block device.
+///
+/// The device also becomes available by name in the `DeviceManager`.
+pub fn register(
+    name: &'static str,
+    dev: Box<dyn BlockDevice>,
+) -> Result<BlockDeviceHandle, String> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let handle = BlockDeviceHandle {
+        id,
+        disk: id,
+        name,
+        sector_size: dev.sector_size(),
+        offset: 0,
+        sector_count: dev.sector_count(),
+        dev: Arc::new(Mutex::new(dev)),
+    };
+    add_handle(handle)
+}
+
+/// Register a range of sectors of a disk as a device of its own
+pub fn register_partition(
+    disk: &BlockDeviceHandle,
+    name: &'static str,
+    start: u64,
+    count: u64,
+) -> Result<BlockDeviceHandle, String> {
+    if disk.is_partition() {
+        return Err(format!("{} is a partition itself", disk.name));
+    }
+    match start.checked_add(count) {
+        Some(end) if end <= disk.sector_count => (),
+        _ => return Err(format!("Partition {} exceeds {}", name, disk.name)),
+    }
+
+    add_handle(BlockDeviceHandle {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        disk: disk.id,
+        name,
+        sector_size: disk.sector_size,
+        offset: start,
+        sector_count: count,
+        dev: disk.dev.clone(),
+    })
+}
+
+fn add_handle(handle: BlockDeviceHandle) -> Result<BlockDeviceHandle, String> {
+    let name = handle.name;
+    DEVICE_MANAGER.lock().register_device(
+        name,
+        box BlockDeviceNode {
+            handle: handle.clone(),
+        },
+    )?;
+    BLOCK_DEVICES.lock().push(handle.clone());
+
+    log!(
+        debug: "Registered block device {} ({} sectors of {} bytes).",
+        name,
+        handle.sector_count,
+        handle.sector_size
+    );
+    Ok(handle)
+}
+
+/// Unregister a block device.
+///
+/// Removing a disk removes its partitions too. Cached data
+/// is written back if the disk still answers, and the driver
+/// then gets to stop the hardware.
+pub fn unregister(name: &str) -> Result<(), String> {
+    let handle = get(name).ok_or_else(|| format!("Block device {} is not registered.", name))?;
+    let removed = {
+        let mut devices = BLOCK_DEVICES.lock();
+        let removed: Vec<BlockDeviceHandle> = devices
+            .iter()
+            .filter(|other| other.id == handle.id || other.disk == handle.id)
+            .cloned()
+            .collect();
+        devices.retain(|other| removed.iter().all(|r| r.id != other.id));
+        removed
+    };
+    for other in removed.iter() {
+        DEVICE_MANAGER.lock().unregister_device(other.name);
+        log!(debug: "Unregistered block device {}.", other.name);
+    }
+    if handle.is_partition() {
+        return Ok(());
+    }
+
+    if let Err(err) = handle.write_back() {
+        log!(warn: "{}: unable to write back cached data: {}", name, err);
+    }
+    BUFFER_CACHE.lock().invalidate(&handle);
+
+    let mut dev = handle.dev.lock();
+    let stopped = dev.shutdown();
+    let old = core::mem::replace(
+        &mut *dev,
+        box RemovedDevice {
+            sector_size: handle.sector_size,
+            sector_count: handle.sector_count,
+        },
+    );
+    if stopped.is_err() {
+        log!(warn: "{} did not stop, keeping its memory.", name);
+        core::mem::forget(old);
+    }
+    Ok(())
+}
+
+/// Find a block device by name
+pub fn get(name: &str) -> Option<BlockDeviceHandle> {
+    BLOCK_DEVICES
+        .lock()
+        .iter()
+        .find(|handle| handle.name == name)
+        .cloned()
+}
+
+/// Get the partitions registered on a disk
+pub fn partitions(disk: &BlockDeviceHandle) -> Vec<BlockDeviceHandle> {
+    BLOCK_DEVICES
+        .lock()
+        .iter()
+        .filter(|handle| handle.is_partition() && handle.disk == disk.id)
+        .cloned()
+        .collect()
+}
+
+/// Get all registered block devices
+pub fn devices() -> Vec<BlockDeviceHandle> {
+    BLOCK_DEVICES.lock().clone()
+}
+
+/// Write back all cached data and flush every device
+#[allow(dead_code)]
+pub fn sync_all() -> Result<(), DeviceError> {
+    for handle in devices().iter().filter(|handle| !handle.is_partition()) {
+        handle.sync()?;
+    }
+    Ok(())
+}
+
+/// Byte-level access to a block device through the `DeviceManager`
+struct BlockDeviceNode {
+    handle: BlockDeviceHandle,
+}
+
+impl BlockDeviceNode {
+    /// Clamp a byte range to the end of the device
+    fn clamp(&self, at: usize, len: usize) -> usize {
+        let total = self.handle.sector_count as usize * self.handle.sector_size;
+        len.min(total.saturating_sub(at))
+    }
+}
+
+impl Device for BlockDeviceNode {
+    fn get_type(&self) -> DeviceType {
+        DeviceType::BlockDevice
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities::READ
+            | DeviceCapabilities::WRITE
+            | DeviceCapabilities::RANDOM_ACCESS
+            | DeviceCapabilities::CONTROL
+    }
+
+    fn read_at(&mut self, at: usize, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        let len = self.clamp(at, buf.len());
+        self.handle.read_bytes(at as u64, &mut buf[..len])?;
+        Ok(len)
+    }
+
+    fn write_at(&mut self, at: usize, buf: &[u8]) -> Result<usize, DeviceError> {
+        let len = self.clamp(at, buf.len());
+        self.handle.write_bytes(at as u64, &buf[..len])?;
+        Ok(len)
+    }
+
+    fn ioctl(&mut self, request: u32, _arg: usize) -> Result<usize, DeviceError> {
+        match request {
+            BLK_IOCTL_GET_SECTOR_SIZE => Ok(self.handle.sector_size),
+            BLK_IOCTL_GET_SECTOR_COUNT => Ok(self.handle.sector_count as usize),
+            BLK_IOCTL_SYNC => self.handle.sync().map(|_| 0),
+            _ => Err(DeviceError::Unsupported),
+        }
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
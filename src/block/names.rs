@@ -0,0 +1,44 @@
+use alloc::prelude::*;
+
+/// The number of disks a driver can name
+pub const MAX_DISKS: usize = 26;
+
+/// Hands out disk names `<prefix>a` to `<prefix>z`.
+///
+/// The lowest free name is picked. Names of removed disks
+/// are handed out again, so each is only allocated once.
+pub struct DiskNames {
+    prefix: &'static str,
+    names: Vec<&'static str>,
+    /// A bit for each name in use
+    used: u32,
+}
+
+impl DiskNames {
+    pub fn new(prefix: &'static str) -> Self {
+        DiskNames {
+            prefix,
+            names: Vec::new(),
+            used: 0,
+        }
+    }
+
+    /// Take a name, or `None` if all are in use
+    pub fn alloc(&mut self) -> Option<&'static str> {
+        let index = (0..MAX_DISKS).find(|i| self.used & (1 << i) == 0)?;
+        while self.names.len() <= index {
+            let letter = (b'a' + self.names.len() as u8) as char;
+            let name = format!("{}{}", self.prefix, letter);
+            self.names.push(Box::leak(name.into_boxed_str()));
+        }
+        self.used |= 1 << index;
+        Some(self.names[index])
+    }
+
+    /// Give a name back
+    pub fn free(&mut self, name: &str) {
+        if let Some(index) = self.names.iter().position(|other| *other == name) {
+            self.used &= !(1 << index);
+        }
+    }
+}
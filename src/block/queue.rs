@@ -0,0 +1,163 @@
+use alloc::prelude::*;
+
+use super::BlockDevice;
+use crate::hal::DeviceError;
+
+/// Identifies a submitted request
+pub type RequestId = u64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Read,
+    Write,
+}
+
+/// A pending transfer
+struct Request {
+    id: RequestId,
+    op: Operation,
+    lba: u64,
+    sectors: u64,
+    data: Vec<u8>,
+}
+
+/// The outcome of a request.
+///
+/// For reads, `data` holds the sectors read.
+pub struct Completion {
+    pub id: RequestId,
+    pub result: Result<Vec<u8>, DeviceError>,
+}
+
+/// Collects requests and hands them to the device in batches.
+///
+/// Requests are kept in submission order, except that a run of
+/// requests with the same operation is sorted by sector, and
+/// neighbouring requests in it are merged into one transfer.
+pub struct RequestQueue {
+    requests: Vec<Request>,
+    next_id: RequestId,
+}
+
+impl RequestQueue {
+    pub fn new() -> Self {
+        RequestQueue {
+            requests: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Queue a read of `sectors` sectors of `sector_size` bytes
+    pub fn submit_read(&mut self, lba: u64, sectors: u64, sector_size: usize) -> RequestId {
+        let data = vec![0u8; sectors as usize * sector_size];
+        self.submit(Operation::Read, lba, sectors, data)
+    }
+
+    /// Queue a write of whole sectors
+    pub fn submit_write(&mut self, lba: u64, data: Vec<u8>, sector_size: usize) -> RequestId {
+        let sectors = (data.len() / sector_size) as u64;
+        self.submit(Operation::Write, lba, sectors, data)
+    }
+
+    fn submit(&mut self, op: Operation, lba: u64, sectors: u64, data: Vec<u8>) -> RequestId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.requests.push(Request {
+            id,
+            op,
+            lba,
+            sectors,
+            data,
+        });
+        id
+    }
+
+    /// Run all queued requests against `dev`
+    pub fn run(&mut self, dev: &mut dyn BlockDevice) -> Vec<Completion> {
+        let mut completions = Vec::with_capacity(self.requests.len());
+        let mut pending = core::mem::replace(&mut self.requests, Vec::new());
+
+        while !pending.is_empty() {
+            // Take the longest run of requests with the same operation
+            let op = pending[0].op;
+            let len = pending.iter().take_while(|r| r.op == op).count();
+            let mut batch: Vec<Request> = pending.drain(..len).collect();
+            batch.sort_by_key(|r| r.lba);
+
+            let mut start = 0;
+            while start < batch.len() {
+                let end = RequestQueue::merge_end(&batch, start, dev.max_transfer_sectors());
+                RequestQueue::transfer(dev, op, &mut batch[start..end], &mut completions);
+                start = end;
+            }
+        }
+
+        completions
+    }
+
+    /// Find the end of the run of requests that can be merged with `start`
+    fn merge_end(batch: &[Request], start: usize, max_sectors: u64) -> usize {
+        let mut end = start + 1;
+        let mut sectors = batch[start].sectors;
+        while end < batch.len() {
+            let prev = &batch[end - 1];
+            let next = &batch[end];
+            if next.lba != prev.lba + prev.sectors || sectors + next.sectors > max_sectors {
+                break;
+            }
+            sectors += next.sectors;
+            end += 1;
+        }
+        end
+    }
+
+    /// Execute adjacent requests as a single transfer
+    fn transfer(
+        dev: &mut dyn BlockDevice,
+        op: Operation,
+        run: &mut [Request],
+        completions: &mut Vec<Completion>,
+    ) {
+        let lba = run[0].lba;
+        let result = if run.len() == 1 {
+            match op {
+                Operation::Read => dev.read_sectors(lba, &mut run[0].data),
+                Operation::Write => dev.write_sectors(lba, &run[0].data),
+            }
+        } else {
+            let total = run.iter().map(|r| r.data.len()).sum();
+            let mut buf = Vec::with_capacity(total);
+            match op {
+                Operation::Read => {
+                    buf.resize(total, 0);
+                    let res = dev.read_sectors(lba, &mut buf);
+                    // Scatter the data back into the requests
+                    let mut offset = 0;
+                    for req in run.iter_mut() {
+                        let len = req.data.len();
+                        req.data.copy_from_slice(&buf[offset..offset + len]);
+                        offset += len;
+                    }
+                    res
+                }
+                Operation::Write => {
+                    for req in run.iter() {
+                        buf.extend_from_slice(&req.data);
+                    }
+                    dev.write_sectors(lba, &buf)
+                }
+            }
+        };
+
+        for req in run.iter_mut() {
+            completions.push(Completion {
+                id: req.id,
+                result: match (result, op) {
+                    (Ok(()), Operation::Read) => Ok(core::mem::replace(&mut req.data, Vec::new())),
+                    (Ok(()), Operation::Write) => Ok(Vec::new()),
+                    (Err(err), _) => Err(err),
+                },
+            });
+        }
+    }
+}
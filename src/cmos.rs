@@ -162,6 +162,24 @@ impl CMOSDateTime {
             hour: self.hour,
         }
     }
+
+    /// Get the seconds since 1970-01-01 00:00:00
+    pub fn as_unix_time(&self) -> u64 {
+        // Count years from March, so leap days end a year
+        let (year, month) = if self.month <= 2 {
+            (u64::from(self.year) - 1, u64::from(self.month) + 12)
+        } else {
+            (u64::from(self.year), u64::from(self.month))
+        };
+        let days = 365 * year + year / 4 - year / 100
+            + year / 400
+            + (153 * (month - 3) + 2) / 5
+            + u64::from(self.day_of_month)
+            - 719_469;
+        let seconds =
+            u64::from(self.hour) * 3600 + u64::from(self.minute) * 60 + u64::from(self.second);
+        days * 86400 + seconds
+    }
 }
 
 impl core::fmt::Display for CMOSDateTime {
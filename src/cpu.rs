@@ -0,0 +1,473 @@
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use bitflags::bitflags;
+use core::arch::x86_64::{__cpuid, __cpuid_count, CpuidResult};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use x86_64::structures::idt::ExceptionStackFrame;
+
+//
+// Control register bits
+//
+
+const CR0_MP: u64 = 1 << 1;
+const CR0_EM: u64 = 1 << 2;
+const CR0_TS: u64 = 1 << 3;
+const CR0_WP: u64 = 1 << 16;
+
+const CR4_OSFXSR: u64 = 1 << 9;
+const CR4_OSXMMEXCPT: u64 = 1 << 10;
+const CR4_OSXSAVE: u64 = 1 << 18;
+
+//
+// XSAVE state components
+//
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+/// The size of the legacy FXSAVE area
+const FXSAVE_SIZE: usize = 512;
+
+/// The alignment XSAVE requires
+const FPU_STATE_ALIGN: usize = 64;
+
+// Default control words loaded into fresh FPU states
+const FPU_DEFAULT_FCW: u16 = 0x037F;
+const FPU_DEFAULT_MXCSR: u32 = 0x1F80;
+
+lazy_static! {
+    pub static ref CPU_INFO: CPUInfo = CPUInfo::read();
+}
+
+bitflags! {
+
+    /// CPU features reported by CPUID
+    pub struct CPUFeatures: u64 {
+        const FPU          = 1 << 0;
+        const TSC          = 1 << 1;
+        const MSR          = 1 << 2;
+        const PAE          = 1 << 3;
+        const APIC         = 1 << 4;
+        const PAT          = 1 << 5;
+        const FXSR         = 1 << 6;
+        const SSE          = 1 << 7;
+        const SSE2         = 1 << 8;
+        const SSE3         = 1 << 9;
+        const SSSE3        = 1 << 10;
+        const SSE4_1       = 1 << 11;
+        const SSE4_2       = 1 << 12;
+        const X2APIC       = 1 << 13;
+        const TSC_DEADLINE = 1 << 14;
+        const XSAVE        = 1 << 15;
+        const AVX          = 1 << 16;
+        const RDRAND       = 1 << 17;
+        const NX           = 1 << 18;
+        const PAGE_1GB     = 1 << 19;
+    }
+}
+
+/// Feature names, in the order they are reported
+const FEATURE_NAMES: &[(CPUFeatures, &str)] = &[
+    (CPUFeatures::FPU, "fpu"),
+    (CPUFeatures::TSC, "tsc"),
+    (CPUFeatures::MSR, "msr"),
+    (CPUFeatures::PAE, "pae"),
+    (CPUFeatures::APIC, "apic"),
+    (CPUFeatures::PAT, "pat"),
+    (CPUFeatures::FXSR, "fxsr"),
+    (CPUFeatures::SSE, "sse"),
+    (CPUFeatures::SSE2, "sse2"),
+    (CPUFeatures::SSE3, "sse3"),
+    (CPUFeatures::SSSE3, "ssse3"),
+    (CPUFeatures::SSE4_1, "sse4_1"),
+    (CPUFeatures::SSE4_2, "sse4_2"),
+    (CPUFeatures::X2APIC, "x2apic"),
+    (CPUFeatures::TSC_DEADLINE, "tsc_deadline"),
+    (CPUFeatures::XSAVE, "xsave"),
+    (CPUFeatures::AVX, "avx"),
+    (CPUFeatures::RDRAND, "rdrand"),
+    (CPUFeatures::NX, "nx"),
+    (CPUFeatures::PAGE_1GB, "pdpe1gb"),
+];
+
+impl core::fmt::Display for CPUFeatures {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let mut first = true;
+        for (feature, name) in FEATURE_NAMES {
+            if self.contains(*feature) {
+                if !first {
+                    write!(f, " ")?;
+                }
+                write!(f, "{}", name)?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Processor identification
+pub struct CPUInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub features: CPUFeatures,
+}
+
+impl CPUInfo {
+    /// Decode the CPUID leaves
+    fn read() -> CPUInfo {
+        let leaf0 = cpuid(0);
+        let max_leaf = leaf0.eax;
+        let mut vendor = [0u8; 12];
+        copy_register(&mut vendor[0..4], leaf0.ebx);
+        copy_register(&mut vendor[4..8], leaf0.edx);
+        copy_register(&mut vendor[8..12], leaf0.ecx);
+
+        let mut features = CPUFeatures::empty();
+        let (mut family, mut model, mut stepping) = (0, 0, 0);
+        if max_leaf >= 1 {
+            let leaf1 = cpuid(1);
+
+            // Family and model are extended on newer processors
+            stepping = leaf1.eax & 0xF;
+            model = (leaf1.eax >> 4) & 0xF;
+            family = (leaf1.eax >> 8) & 0xF;
+            if family == 0xF {
+                family += (leaf1.eax >> 20) & 0xFF;
+            }
+            if family >= 0x6 {
+                model |= ((leaf1.eax >> 16) & 0xF) << 4;
+            }
+
+            let edx_bits = [
+                (0, CPUFeatures::FPU),
+                (4, CPUFeatures::TSC),
+                (5, CPUFeatures::MSR),
+                (6, CPUFeatures::PAE),
+                (9, CPUFeatures::APIC),
+                (16, CPUFeatures::PAT),
+                (24, CPUFeatures::FXSR),
+                (25, CPUFeatures::SSE),
+                (26, CPUFeatures::SSE2),
+            ];
+            let ecx_bits = [
+                (0, CPUFeatures::SSE3),
+                (9, CPUFeatures::SSSE3),
+                (19, CPUFeatures::SSE4_1),
+                (20, CPUFeatures::SSE4_2),
+                (21, CPUFeatures::X2APIC),
+                (24, CPUFeatures::TSC_DEADLINE),
+                (26, CPUFeatures::XSAVE),
+                (28, CPUFeatures::AVX),
+                (30, CPUFeatures::RDRAND),
+            ];
+            features |= decode_bits(leaf1.edx, &edx_bits);
+            features |= decode_bits(leaf1.ecx, &ecx_bits);
+        }
+
+        let max_ext_leaf = cpuid(0x8000_0000).eax;
+        if max_ext_leaf >= 0x8000_0001 {
+            let ext_bits = [(20, CPUFeatures::NX), (26, CPUFeatures::PAGE_1GB)];
+            features |= decode_bits(cpuid(0x8000_0001).edx, &ext_bits);
+        }
+
+        let mut brand = [0u8; 48];
+        if max_ext_leaf >= 0x8000_0004 {
+            for (i, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+                let res = cpuid(leaf);
+                let chunk = &mut brand[i * 16..(i + 1) * 16];
+                copy_register(&mut chunk[0..4], res.eax);
+                copy_register(&mut chunk[4..8], res.ebx);
+                copy_register(&mut chunk[8..12], res.ecx);
+                copy_register(&mut chunk[12..16], res.edx);
+            }
+        }
+
+        CPUInfo {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping,
+            features,
+        }
+    }
+
+    /// Get the vendor string, e.g. `GenuineIntel`
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    /// Get the brand string, if the processor has one
+    pub fn brand(&self) -> Option<&str> {
+        let len = self.brand.iter().position(|b| *b == 0).unwrap_or(48);
+        match core::str::from_utf8(&self.brand[..len]) {
+            Ok(s) if !s.trim().is_empty() => Some(s.trim()),
+            _ => None,
+        }
+    }
+
+    /// Test whether the processor supports a feature
+    pub fn has(&self, features: CPUFeatures) -> bool {
+        self.features.contains(features)
+    }
+}
+
+fn cpuid(leaf: u32) -> CpuidResult {
+    unsafe { __cpuid(leaf) }
+}
+
+fn copy_register(dst: &mut [u8], reg: u32) {
+    for (i, b) in dst.iter_mut().enumerate() {
+        *b = (reg >> (8 * i)) as u8;
+    }
+}
+
+fn decode_bits(reg: u32, bits: &[(u32, CPUFeatures)]) -> CPUFeatures {
+    bits.iter()
+        .filter(|&&(bit, _)| reg & (1 << bit) != 0)
+        .fold(CPUFeatures::empty(), |acc, &(_, feature)| acc | feature)
+}
+
+//
+// FPU, SSE and AVX state
+//
+// The kernel is built without SSE, so only user tasks use
+// the FPU. Their state is switched lazily: a task switch
+// only sets CR0.TS, and the first FPU instruction of the
+// next task traps into `handle_device_not_available`, which
+// saves the previous owner's state and loads the new one.
+//
+
+/// The state components saved and restored with XSAVE
+static XSAVE_MASK: AtomicUsize = AtomicUsize::new(0);
+
+/// The size of an FPU state area
+static FPU_STATE_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+
+/// The state area whose registers are loaded in the FPU
+static FPU_OWNER: AtomicUsize = AtomicUsize::new(0);
+
+/// The state area of the running task
+static FPU_CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+/// A saved FPU register state
+pub struct FPUState {
+    area: *mut u8,
+    layout: Option<Layout>,
+}
+
+unsafe impl Send for FPUState {}
+unsafe impl Sync for FPUState {}
+
+impl FPUState {
+    /// Create a state with default control words and cleared registers.
+    ///
+    /// The save area is allocated when the state is first
+    /// switched to, by which time `FPU::init` has settled its size.
+    pub fn new() -> Self {
+        FPUState {
+            area: core::ptr::null_mut(),
+            layout: None,
+        }
+    }
+
+    /// Make sure the save area exists and is big enough
+    fn prepare(&mut self) {
+        let size = FPU_STATE_SIZE.load(Ordering::Relaxed);
+        match self.layout {
+            Some(layout) if layout.size() >= size => return,
+            _ => self.release(),
+        }
+
+        let layout = Layout::from_size_align(size, FPU_STATE_ALIGN).unwrap();
+        let area = unsafe { alloc_zeroed(layout) };
+        assert!(!area.is_null(), "Unable to allocate FPU state!");
+
+        // A zeroed XSAVE header restores the remaining components to
+        // their initial state, but MXCSR and FCW are always loaded.
+        unsafe {
+            *(area as *mut u16) = FPU_DEFAULT_FCW;
+            *(area.add(24) as *mut u32) = FPU_DEFAULT_MXCSR;
+        }
+
+        self.area = area;
+        self.layout = Some(layout);
+    }
+
+    /// Free the save area
+    fn release(&mut self) {
+        if let Some(layout) = self.layout.take() {
+            // Never leave a dangling owner behind
+            let addr = self.area as usize;
+            let _ = FPU_OWNER.compare_exchange(addr, 0, Ordering::SeqCst, Ordering::SeqCst);
+            let _ = FPU_CURRENT.compare_exchange(addr, 0, Ordering::SeqCst, Ordering::SeqCst);
+            unsafe { dealloc(self.area, layout) };
+            self.area = core::ptr::null_mut();
+        }
+    }
+}
+
+impl Drop for FPUState {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+/// x87 Floating Point Unit
+pub struct FPU;
+impl FPU {
+    /// Enable SSE, and AVX where supported, for user tasks.
+    ///
+    /// The FPU starts out trapping, so the first user
+    /// instruction touching it loads a proper state.
+    pub fn init() {
+        let info = &*CPU_INFO;
+        if !info.has(CPUFeatures::FPU | CPUFeatures::FXSR | CPUFeatures::SSE) {
+            log!(warn: "CPU lacks SSE support, user FPU use will fault.");
+            return;
+        }
+
+        unsafe {
+            write_cr0((read_cr0() & !CR0_EM) | CR0_MP | CR0_TS);
+            let mut cr4 = read_cr4() | CR4_OSFXSR | CR4_OSXMMEXCPT;
+
+            if info.has(CPUFeatures::XSAVE) {
+                cr4 |= CR4_OSXSAVE;
+                write_cr4(cr4);
+
+                let mut mask = XCR0_X87 | XCR0_SSE;
+                if info.has(CPUFeatures::AVX) {
+                    mask |= XCR0_AVX;
+                }
+                xsetbv(0, mask);
+
+                // The area size depends on the enabled components
+                let size = __cpuid_count(0xD, 0).ebx as usize;
+                FPU_STATE_SIZE.store(size, Ordering::Relaxed);
+                XSAVE_MASK.store(mask as usize, Ordering::Relaxed);
+            } else {
+                write_cr4(cr4);
+            }
+        }
+    }
+
+    /// Make `state` the FPU state of the running task.
+    ///
+    /// Called on every task switch, see `process::set_current`.
+    pub fn switch_to(state: &mut FPUState) {
+        state.prepare();
+        FPU_CURRENT.store(state.area as usize, Ordering::SeqCst);
+        unsafe { write_cr0(read_cr0() | CR0_TS) };
+    }
+}
+
+/// Called when a task touches the FPU while CR0.TS is set
+pub extern "x86-interrupt" fn handle_device_not_available(stack_frame: &mut ExceptionStackFrame) {
+    unsafe {
+        clts();
+
+        let owner = FPU_OWNER.load(Ordering::SeqCst);
+        let current = FPU_CURRENT.load(Ordering::SeqCst);
+        if current == 0 {
+            log!(fault: "*** FPU USE WITHOUT STATE\r\n{:#?}", stack_frame);
+            loop {}
+        }
+        if owner == current {
+            return;
+        }
+
+        if owner != 0 {
+            save_fpu(owner as *mut u8);
+        }
+        restore_fpu(current as *const u8);
+        FPU_OWNER.store(current, Ordering::SeqCst);
+    }
+}
+
+/// Run `f` with writes to read-only pages allowed.
+///
+/// The debugger uses this to put breakpoints into kernel code.
+pub fn without_write_protect<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    unsafe {
+        let cr0 = read_cr0();
+        write_cr0(cr0 & !CR0_WP);
+        let res = f();
+        write_cr0(cr0);
+        res
+    }
+}
+
+unsafe fn save_fpu(area: *mut u8) {
+    match XSAVE_MASK.load(Ordering::Relaxed) as u64 {
+        0 => fxsave(area),
+        mask => xsave(area, mask),
+    }
+}
+
+unsafe fn restore_fpu(area: *const u8) {
+    match XSAVE_MASK.load(Ordering::Relaxed) as u64 {
+        0 => fxrstor(area),
+        mask => xrstor(area, mask),
+    }
+}
+
+//
+// Register access
+//
+
+unsafe fn read_cr0() -> u64 {
+    let value: u64;
+    asm!("mov %cr0, $0" : "=r"(value));
+    value
+}
+
+unsafe fn write_cr0(value: u64) {
+    asm!("mov $0, %cr0" :: "r"(value) : "memory" : "volatile");
+}
+
+unsafe fn read_cr4() -> u64 {
+    let value: u64;
+    asm!("mov %cr4, $0" : "=r"(value));
+    value
+}
+
+unsafe fn write_cr4(value: u64) {
+    asm!("mov $0, %cr4" :: "r"(value) : "memory" : "volatile");
+}
+
+unsafe fn clts() {
+    asm!("clts" :::: "volatile");
+}
+
+#[target_feature(enable = "xsave")]
+unsafe fn xsetbv(index: u32, value: u64) {
+    core::arch::x86_64::_xsetbv(index, value);
+}
+
+#[target_feature(enable = "xsave")]
+unsafe fn xsave(area: *mut u8, mask: u64) {
+    core::arch::x86_64::_xsave64(area, mask);
+}
+
+#[target_feature(enable = "xsave")]
+unsafe fn xrstor(area: *const u8, mask: u64) {
+    core::arch::x86_64::_xrstor64(area, mask);
+}
+
+#[target_feature(enable = "fxsr")]
+unsafe fn fxsave(area: *mut u8) {
+    core::arch::x86_64::_fxsave64(area);
+}
+
+#[target_feature(enable = "fxsr")]
+unsafe fn fxrstor(area: *const u8) {
+    core::arch::x86_64::_fxrstor64(area);
+}
@@ -0,0 +1,72 @@
+//
+// DMA Memory
+//
+// The heap is identity-mapped, so the address of a heap
+// allocation is also its physical address. Devices can
+// access buffers allocated here directly.
+//
+
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use core::ptr;
+use core::slice;
+
+/// A zeroed, aligned buffer devices can access
+pub struct DMABuffer {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+// The buffer is owned memory like a `Box<[u8]>`
+unsafe impl Send for DMABuffer {}
+
+impl DMABuffer {
+    /// Allocate `size` bytes aligned to `align`
+    pub fn new(size: usize, align: usize) -> Self {
+        let layout = Layout::from_size_align(size, align).expect("Invalid DMA buffer layout!");
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            handle_alloc_error(layout);
+        }
+        DMABuffer { ptr, layout }
+    }
+
+    /// Get the physical address of the buffer
+    pub fn phys_addr(&self) -> u64 {
+        self.ptr as u64
+    }
+
+    pub fn len(&self) -> usize {
+        self.layout.size()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len()) }
+    }
+
+    /// Read a value the device may have written
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        assert!(offset + core::mem::size_of::<T>() <= self.len());
+        unsafe { ptr::read_volatile(self.ptr.add(offset) as *const T) }
+    }
+
+    /// Write a value the device will read
+    pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        assert!(offset + core::mem::size_of::<T>() <= self.len());
+        unsafe { ptr::write_volatile(self.ptr.add(offset) as *mut T, value) }
+    }
+
+    /// Zero the whole buffer
+    pub fn clear(&mut self) {
+        unsafe { ptr::write_bytes(self.ptr, 0, self.len()) }
+    }
+}
+
+impl Drop for DMABuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) }
+    }
+}
@@ -0,0 +1,504 @@
+//
+// GDB remote serial protocol stub
+//
+// Lets GDB debug the kernel over a serial port, without help
+// from the hypervisor. Breakpoint and debug exceptions enter
+// the stub, which then talks to GDB with interrupts disabled
+// until it is told to continue or to step.
+//
+// Software breakpoints are `int3` instructions written over
+// the code; single steps use the trap flag. There is only one
+// thread until the kernel has a scheduler.
+//
+// With QEMU, put the port on a socket and attach to it:
+//   -serial stdio -serial tcp::1234,server,nowait
+//   (gdb) target remote localhost:1234
+//
+
+mod packet;
+
+use alloc::{collections::btree_map::BTreeMap, prelude::*};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{instructions::interrupts, VirtAddr};
+
+use self::packet::{decode_hex, decode_le, encode_hex, encode_le, parse_hex, Connection};
+use crate::hal::DEVICE_MANAGER;
+use crate::idt::TrapFrame;
+use crate::paging::PAGING;
+use crate::serial::SerialDevice;
+use crate::workqueue::Work;
+
+lazy_static! {
+    static ref STUB: Mutex<Option<Stub>> = Mutex::new(None);
+}
+
+/// The trap flag, which raises a debug exception after each instruction
+const RFLAGS_TRAP: u64 = 1 << 8;
+
+/// The instruction software breakpoints are made of
+const INT3: u8 = 0xCC;
+
+//
+// Signals reported to GDB
+//
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// The exception that entered the stub
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trap {
+    /// A debug exception, raised by single steps
+    Debug,
+    /// A breakpoint exception, raised by `int3`
+    Breakpoint,
+}
+
+/// How the kernel goes on after a stop
+enum Resume {
+    Continue,
+    Step,
+}
+
+struct Stub {
+    conn: Connection,
+    /// Inserted breakpoints and the bytes they replaced
+    breakpoints: BTreeMap<u64, u8>,
+    /// Whether GDB waits for a stop reply
+    running: bool,
+}
+
+/// Start the stub on a serial port.
+///
+/// The kernel only stops for GDB once it hits a breakpoint
+/// or GDB sends something.
+pub fn init(device: &str) -> Result<(), &'static str> {
+    let line = DEVICE_MANAGER
+        .lock()
+        .with_device_cast(device, |dev: &mut SerialDevice| dev.line())
+        .ok_or("not a serial port")?;
+
+    interrupts::without_interrupts(|| {
+        *STUB.lock() = Some(Stub {
+            conn: Connection::new(line.clone()),
+            breakpoints: BTreeMap::new(),
+            running: false,
+        })
+    });
+    line.set_input_work(Some(Work::new(handle_input, 0)));
+    Ok(())
+}
+
+/// Stop in the debugger
+pub fn breakpoint() {
+    unsafe { asm!("int3" :::: "volatile") };
+}
+
+/// Check for GDB talking to the running kernel.
+///
+/// This runs as deferred work whenever the port receives data.
+fn handle_input(_: usize) {
+    let stop = interrupts::without_interrupts(|| match STUB.lock().as_mut() {
+        Some(stub) => stub.conn.receive_pending(),
+        None => false,
+    });
+    if stop {
+        breakpoint();
+    }
+}
+
+/// Handle a breakpoint or debug exception.
+///
+/// Returns `false` if the stub is not running, so the
+/// exception is not meant for it.
+pub fn handle_trap(frame: &mut TrapFrame, trap: Trap) -> bool {
+    // A trap inside the stub itself cannot be debugged
+    let mut stub = match STUB.try_lock() {
+        Some(stub) => stub,
+        None => return false,
+    };
+    let stub = match stub.as_mut() {
+        Some(stub) => stub,
+        None => return false,
+    };
+
+    frame.rflags &= !RFLAGS_TRAP;
+    if trap == Trap::Breakpoint {
+        // The trap points past the `int3`
+        let addr = frame.rip.wrapping_sub(1);
+        if stub.breakpoints.contains_key(&addr) {
+            frame.rip = addr;
+        }
+    }
+
+    let signal = match stub.conn.take_interrupt() {
+        true => SIGINT,
+        false => SIGTRAP,
+    };
+    if stub.running {
+        stub.send_stop_reply(signal);
+    }
+
+    match stub.session(frame, signal) {
+        Resume::Continue => (),
+        Resume::Step => frame.rflags |= RFLAGS_TRAP,
+    }
+    true
+}
+
+impl Stub {
+    fn send_stop_reply(&mut self, signal: u8) {
+        let mut reply = vec![b'S'];
+        encode_hex(&mut reply, &[signal]);
+        self.conn.send(&reply);
+    }
+
+    /// Answer packets until GDB resumes the kernel
+    fn session(&mut self, frame: &mut TrapFrame, signal: u8) -> Resume {
+        loop {
+            let packet = self.conn.receive();
+            let (&command, args) = match packet.split_first() {
+                Some(split) => split,
+                None => {
+                    self.conn.send(b"");
+                    continue;
+                }
+            };
+
+            let reply = match command {
+                b'?' => {
+                    let mut reply = vec![b'S'];
+                    encode_hex(&mut reply, &[signal]);
+                    reply
+                }
+                b'g' => read_registers(frame),
+                b'G' => match write_registers(frame, args) {
+                    true => b"OK".to_vec(),
+                    false => b"E01".to_vec(),
+                },
+                b'p' => match parse_hex(args).and_then(|reg| read_register(frame, reg as usize)) {
+                    Some(reply) => reply,
+                    None => b"E01".to_vec(),
+                },
+                b'P' => match write_register_command(frame, args) {
+                    true => b"OK".to_vec(),
+                    false => b"E01".to_vec(),
+                },
+                b'm' => match parse_range(args).and_then(|(addr, len)| read_memory(addr, len)) {
+                    Some(data) => {
+                        let mut reply = Vec::with_capacity(data.len() * 2);
+                        encode_hex(&mut reply, &data);
+                        reply
+                    }
+                    None => b"E14".to_vec(),
+                },
+                b'M' => match self.write_memory_command(args) {
+                    true => b"OK".to_vec(),
+                    false => b"E14".to_vec(),
+                },
+                b'c' | b's' => {
+                    if !args.is_empty() {
+                        match parse_hex(args) {
+                            Some(addr) => frame.rip = addr,
+                            None => {
+                                self.conn.send(b"E01");
+                                continue;
+                            }
+                        }
+                    }
+                    self.running = true;
+                    return match command {
+                        b'c' => Resume::Continue,
+                        _ => Resume::Step,
+                    };
+                }
+                b'Z' | b'z' => match self.breakpoint_command(command == b'Z', args) {
+                    Some(true) => b"OK".to_vec(),
+                    Some(false) => b"E14".to_vec(),
+                    None => Vec::new(),
+                },
+                b'D' => {
+                    self.remove_breakpoints();
+                    self.running = false;
+                    self.conn.send(b"OK");
+                    return Resume::Continue;
+                }
+                // The kernel cannot be killed, so this only ends the session
+                b'k' => {
+                    self.remove_breakpoints();
+                    self.running = false;
+                    return Resume::Continue;
+                }
+                b'H' | b'T' => b"OK".to_vec(),
+                b'q' => query(args),
+                _ => Vec::new(),
+            };
+            self.conn.send(&reply);
+        }
+    }
+
+    /// Handle `M addr,length:XX...`
+    fn write_memory_command(&mut self, args: &[u8]) -> bool {
+        let colon = match args.iter().position(|&b| b == b':') {
+            Some(colon) => colon,
+            None => return false,
+        };
+        let (addr, len) = match parse_range(&args[..colon]) {
+            Some(range) => range,
+            None => return false,
+        };
+        match decode_hex(&args[colon + 1..]) {
+            Some(ref data) if data.len() == len => write_memory(addr, data),
+            _ => false,
+        }
+    }
+
+    /// Handle `Z0,addr,kind` and `z0,addr,kind`.
+    ///
+    /// Returns `None` for breakpoint types other than software.
+    fn breakpoint_command(&mut self, insert: bool, args: &[u8]) -> Option<bool> {
+        let mut fields = args.split(|&b| b == b',');
+        if fields.next() != Some(&b"0"[..]) {
+            return None;
+        }
+        let addr = match fields.next().and_then(parse_hex) {
+            Some(addr) => addr,
+            None => return Some(false),
+        };
+        Some(match insert {
+            true => self.insert_breakpoint(addr),
+            false => self.remove_breakpoint(addr),
+        })
+    }
+
+    fn insert_breakpoint(&mut self, addr: u64) -> bool {
+        if self.breakpoints.contains_key(&addr) {
+            return true;
+        }
+        let original = match read_memory(addr, 1) {
+            Some(data) => data[0],
+            None => return false,
+        };
+        if !write_memory(addr, &[INT3]) {
+            return false;
+        }
+        self.breakpoints.insert(addr, original);
+        true
+    }
+
+    fn remove_breakpoint(&mut self, addr: u64) -> bool {
+        match self.breakpoints.remove(&addr) {
+            Some(original) => write_memory(addr, &[original]),
+            None => true,
+        }
+    }
+
+    fn remove_breakpoints(&mut self) {
+        let addrs: Vec<u64> = self.breakpoints.keys().cloned().collect();
+        for addr in addrs {
+            self.remove_breakpoint(addr);
+        }
+    }
+}
+
+/// Answer a `q` query
+fn query(args: &[u8]) -> Vec<u8> {
+    let name = match args.iter().position(|&b| b == b':') {
+        Some(colon) => &args[..colon],
+        None => args,
+    };
+    match name {
+        b"Supported" => format!("PacketSize={:x}", packet::MAX_PACKET).into_bytes(),
+        // Attached to an existing process, so detaching keeps it alive
+        b"Attached" => b"1".to_vec(),
+        b"C" => b"QC1".to_vec(),
+        b"fThreadInfo" => b"m1".to_vec(),
+        b"sThreadInfo" => b"l".to_vec(),
+        _ => Vec::new(),
+    }
+}
+
+/// Parse `addr,length`
+fn parse_range(args: &[u8]) -> Option<(u64, usize)> {
+    let comma = args.iter().position(|&b| b == b',')?;
+    let addr = parse_hex(&args[..comma])?;
+    let len = parse_hex(&args[comma + 1..])? as usize;
+    if len > packet::MAX_PACKET / 2 {
+        return None;
+    }
+    Some((addr, len))
+}
+
+//
+// Registers
+//
+// GDB numbers the registers of x86-64 as rax, rbx, rcx, rdx,
+// rsi, rdi, rbp, rsp, r8 to r15, rip, eflags, cs, ss, ds,
+// es, fs and gs. The segment registers and eflags are 32 bits
+// wide. The floating point registers that follow are left out,
+// which GDB shows as unavailable.
+//
+
+/// The number of registers the stub knows
+const REGISTER_COUNT: usize = 24;
+
+/// Get a register and its size in bytes
+fn register(frame: &TrapFrame, reg: usize) -> Option<(u64, usize)> {
+    Some(match reg {
+        0 => (frame.rax, 8),
+        1 => (frame.rbx, 8),
+        2 => (frame.rcx, 8),
+        3 => (frame.rdx, 8),
+        4 => (frame.rsi, 8),
+        5 => (frame.rdi, 8),
+        6 => (frame.rbp, 8),
+        7 => (frame.rsp, 8),
+        8 => (frame.r8, 8),
+        9 => (frame.r9, 8),
+        10 => (frame.r10, 8),
+        11 => (frame.r11, 8),
+        12 => (frame.r12, 8),
+        13 => (frame.r13, 8),
+        14 => (frame.r14, 8),
+        15 => (frame.r15, 8),
+        16 => (frame.rip, 8),
+        17 => (frame.rflags, 4),
+        18 => (frame.cs, 4),
+        19 => (frame.ss, 4),
+        // The data segments are unused in long mode
+        20...23 => (0, 4),
+        _ => return None,
+    })
+}
+
+/// Change a register.
+///
+/// Segment registers cannot be changed.
+fn set_register(frame: &mut TrapFrame, reg: usize, value: u64) -> bool {
+    let field = match reg {
+        0 => &mut frame.rax,
+        1 => &mut frame.rbx,
+        2 => &mut frame.rcx,
+        3 => &mut frame.rdx,
+        4 => &mut frame.rsi,
+        5 => &mut frame.rdi,
+        6 => &mut frame.rbp,
+        7 => &mut frame.rsp,
+        8 => &mut frame.r8,
+        9 => &mut frame.r9,
+        10 => &mut frame.r10,
+        11 => &mut frame.r11,
+        12 => &mut frame.r12,
+        13 => &mut frame.r13,
+        14 => &mut frame.r14,
+        15 => &mut frame.r15,
+        16 => &mut frame.rip,
+        17 => {
+            frame.rflags = (frame.rflags & !0xFFFF_FFFF) | (value & 0xFFFF_FFFF);
+            return true;
+        }
+        // Accept writes that leave a segment as it is
+        18...23 => return register(frame, reg).map(|(old, _)| old) == Some(value),
+        _ => return false,
+    };
+    *field = value;
+    true
+}
+
+fn read_registers(frame: &TrapFrame) -> Vec<u8> {
+    let mut reply = Vec::new();
+    for reg in 0..REGISTER_COUNT {
+        let (value, size) = register(frame, reg).unwrap();
+        encode_le(&mut reply, value, size);
+    }
+    reply
+}
+
+fn read_register(frame: &TrapFrame, reg: usize) -> Option<Vec<u8>> {
+    let (value, size) = register(frame, reg)?;
+    let mut reply = Vec::new();
+    encode_le(&mut reply, value, size);
+    Some(reply)
+}
+
+/// Handle `G XX...`, which may leave out registers at the end
+fn write_registers(frame: &mut TrapFrame, args: &[u8]) -> bool {
+    let mut rest = args;
+    let mut updated = *frame;
+    for reg in 0..REGISTER_COUNT {
+        let size = register(frame, reg).unwrap().1 * 2;
+        if rest.len() < size {
+            break;
+        }
+        let value = match decode_le(&rest[..size]) {
+            Some(value) => value,
+            None => return false,
+        };
+        // Changes to segments are ignored
+        set_register(&mut updated, reg, value);
+        rest = &rest[size..];
+    }
+    *frame = updated;
+    true
+}
+
+/// Handle `P n=XX...`
+fn write_register_command(frame: &mut TrapFrame, args: &[u8]) -> bool {
+    let equals = match args.iter().position(|&b| b == b'=') {
+        Some(equals) => equals,
+        None => return false,
+    };
+    let reg = parse_hex(&args[..equals]).map(|reg| reg as usize);
+    let value = decode_le(&args[equals + 1..]);
+    match (reg, value) {
+        (Some(reg), Some(value)) => set_register(frame, reg, value),
+        _ => false,
+    }
+}
+
+//
+// Memory
+//
+
+/// Test whether memory can be accessed without faulting.
+///
+/// If the page tables are being changed, nothing can be.
+fn is_accessible(addr: u64, len: usize) -> bool {
+    let last = match addr.checked_add(len.max(1) as u64 - 1) {
+        Some(last) => last,
+        None => return false,
+    };
+    // Both ends must be canonical, so the range cannot span the hole
+    let start = match (VirtAddr::try_new(addr), VirtAddr::try_new(last)) {
+        (Ok(start), Ok(_)) => start,
+        _ => return false,
+    };
+    match PAGING.try_lock() {
+        Some(paging) => paging.is_mapped(start, len as u64),
+        None => false,
+    }
+}
+
+fn read_memory(addr: u64, len: usize) -> Option<Vec<u8>> {
+    if !is_accessible(addr, len) {
+        return None;
+    }
+    let data = (0..len)
+        .map(|i| unsafe { core::ptr::read_volatile((addr + i as u64) as *const u8) })
+        .collect();
+    Some(data)
+}
+
+fn write_memory(addr: u64, data: &[u8]) -> bool {
+    if !is_accessible(addr, data.len()) {
+        return false;
+    }
+    // Breakpoints go into code, which is mapped read-only
+    crate::cpu::without_write_protect(|| {
+        for (i, &byte) in data.iter().enumerate() {
+            unsafe { core::ptr::write_volatile((addr + i as u64) as *mut u8, byte) };
+        }
+    });
+    true
+}
@@ -0,0 +1,189 @@
+use alloc::{collections::VecDeque, prelude::*};
+
+use crate::serial::SerialLine;
+
+/// The character GDB sends to interrupt the target
+pub const INTERRUPT: u8 = 0x03;
+
+/// Packets longer than this are refused
+pub const MAX_PACKET: usize = 0x1000;
+
+/// A serial line speaking the packet layer of the remote protocol.
+///
+/// Everything here polls, since the stub runs with interrupts
+/// disabled.
+pub struct Connection {
+    line: SerialLine,
+    /// Bytes received while the kernel was running
+    pending: VecDeque<u8>,
+}
+
+impl Connection {
+    pub fn new(line: SerialLine) -> Self {
+        Connection {
+            line,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Keep bytes that arrived while the kernel was running
+    pub fn push_pending(&mut self, val: &[u8]) {
+        self.pending.extend(val.iter().cloned());
+    }
+
+    /// Take the pending bytes out of the line.
+    ///
+    /// Returns whether GDB wants to stop the kernel.
+    pub fn receive_pending(&mut self) -> bool {
+        let mut buf = [0u8; 64];
+        loop {
+            let count = self.line.read(&mut buf);
+            if count == 0 {
+                break;
+            }
+            self.push_pending(&buf[..count]);
+        }
+        self.pending
+            .iter()
+            .any(|&byte| byte == INTERRUPT || byte == b'$')
+    }
+
+    /// Drop an interrupt request, which is answered by stopping
+    pub fn take_interrupt(&mut self) -> bool {
+        let before = self.pending.len();
+        self.pending.retain(|&byte| byte != INTERRUPT);
+        self.pending.len() != before
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        if let Some(byte) = self.pending.pop_front() {
+            return byte;
+        }
+        let mut buf = [0u8; 1];
+        while self.line.read(&mut buf) == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        buf[0]
+    }
+
+    /// Wait for a packet with a valid checksum and acknowledge it
+    pub fn receive(&mut self) -> Vec<u8> {
+        loop {
+            // Skip acknowledgements and anything else between packets
+            while self.read_byte() != b'$' {}
+
+            let mut data = Vec::new();
+            let mut sum = 0u8;
+            let mut overflow = false;
+            loop {
+                match self.read_byte() {
+                    b'#' => break,
+                    byte => {
+                        sum = sum.wrapping_add(byte);
+                        if data.len() < MAX_PACKET {
+                            data.push(byte);
+                        } else {
+                            overflow = true;
+                        }
+                    }
+                }
+            }
+            let high = hex_value(self.read_byte());
+            let low = hex_value(self.read_byte());
+            match (high, low) {
+                (Some(high), Some(low)) if high << 4 | low == sum && !overflow => {
+                    self.line.write(b"+");
+                    return data;
+                }
+                _ => self.line.write(b"-"),
+            }
+        }
+    }
+
+    /// Send a packet until GDB acknowledges it
+    pub fn send(&mut self, data: &[u8]) {
+        let sum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(data);
+        packet.push(b'#');
+        packet.push(HEX_DIGITS[(sum >> 4) as usize]);
+        packet.push(HEX_DIGITS[(sum & 0xF) as usize]);
+        loop {
+            self.line.write(&packet);
+            match self.read_byte() {
+                b'+' => return,
+                // A new packet means GDB gave up on this one
+                b'$' => {
+                    self.pending.push_front(b'$');
+                    return;
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+//
+// Hexadecimal encoding
+//
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+pub fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'...b'9' => Some(digit - b'0'),
+        b'a'...b'f' => Some(digit - b'a' + 10),
+        b'A'...b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Parse a big-endian hexadecimal number, like an address
+pub fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |value, &digit| {
+        hex_value(digit).map(|digit| value << 4 | u64::from(digit))
+    })
+}
+
+/// Decode pairs of hexadecimal digits into bytes
+pub fn decode_hex(digits: &[u8]) -> Option<Vec<u8>> {
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| Some(hex_value(pair[0])? << 4 | hex_value(pair[1])?))
+        .collect()
+}
+
+/// Append bytes as pairs of hexadecimal digits
+pub fn encode_hex(out: &mut Vec<u8>, val: &[u8]) {
+    for &byte in val {
+        out.push(HEX_DIGITS[(byte >> 4) as usize]);
+        out.push(HEX_DIGITS[(byte & 0xF) as usize]);
+    }
+}
+
+/// Append the lowest `size` bytes of a value in target byte order
+pub fn encode_le(out: &mut Vec<u8>, value: u64, size: usize) {
+    let bytes: Vec<u8> = (0..size).map(|i| (value >> (8 * i)) as u8).collect();
+    encode_hex(out, &bytes);
+}
+
+/// Parse a value sent in target byte order
+pub fn decode_le(digits: &[u8]) -> Option<u64> {
+    let bytes = decode_hex(digits)?;
+    if bytes.len() > 8 {
+        return None;
+    }
+    Some(
+        bytes
+            .iter()
+            .rev()
+            .fold(0u64, |value, &byte| value << 8 | u64::from(byte)),
+    )
+}
@@ -0,0 +1,139 @@
+use alloc::prelude::*;
+use core::fmt;
+
+use crate::pci::{self, PCIDevice, PCIFind};
+
+/// A device found on a bus
+#[derive(Debug, Clone, Copy)]
+pub enum BusDevice {
+    /// A PCI function
+    PCI(PCIDevice),
+    /// A legacy device at fixed resources
+    Platform(PlatformDevice),
+    /// A device on a port of the 8042 controller
+    PS2(u8),
+}
+
+impl BusDevice {
+    /// Test whether two values describe the same device
+    pub fn same_as(&self, other: &BusDevice) -> bool {
+        match (self, other) {
+            (BusDevice::PCI(a), BusDevice::PCI(b)) => a.address == b.address,
+            (BusDevice::Platform(a), BusDevice::Platform(b)) => a == b,
+            (BusDevice::PS2(a), BusDevice::PS2(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for BusDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BusDevice::PCI(ref dev) => write!(f, "pci {}", dev.address),
+            BusDevice::Platform(ref dev) => write!(f, "platform {}", dev.name),
+            BusDevice::PS2(port) => write!(f, "ps2 port {}", port),
+        }
+    }
+}
+
+/// A device that cannot be discovered, described by the kernel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlatformDevice {
+    /// The name the device is registered under
+    pub name: &'static str,
+    /// The kind of hardware, which drivers match on
+    pub compatible: &'static str,
+    /// The first I/O port or physical address
+    pub base: usize,
+    /// The legacy IRQ line, if the device has one
+    pub irq: Option<u8>,
+}
+
+/// An entry of a driver's match table
+#[derive(Debug)]
+pub enum DeviceMatch {
+    /// PCI functions matching the pattern
+    PCI(PCIFind),
+    /// Platform devices of the given kind
+    Platform(&'static str),
+    /// The device on the given 8042 port
+    PS2(u8),
+}
+
+impl DeviceMatch {
+    pub fn matches(&self, dev: &BusDevice) -> bool {
+        match (self, dev) {
+            (DeviceMatch::PCI(find), BusDevice::PCI(dev)) => find.matches_device(dev),
+            (DeviceMatch::Platform(kind), BusDevice::Platform(dev)) => *kind == dev.compatible,
+            (DeviceMatch::PS2(a), BusDevice::PS2(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// A bus that can list the devices attached to it
+pub trait Bus: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Get all devices currently present
+    fn enumerate(&self) -> Vec<BusDevice>;
+}
+
+/// The PCI bus.
+///
+/// Functions come from the enumeration at boot, so cards
+/// added later are not seen.
+pub struct PCIBus;
+
+impl Bus for PCIBus {
+    fn name(&self) -> &'static str {
+        "pci"
+    }
+
+    fn enumerate(&self) -> Vec<BusDevice> {
+        pci::functions()
+            .map(|function| BusDevice::PCI(function.device))
+            .collect()
+    }
+}
+
+/// Legacy devices at well-known resources
+pub struct PlatformBus {
+    devices: Vec<PlatformDevice>,
+}
+
+impl PlatformBus {
+    pub fn new(devices: Vec<PlatformDevice>) -> Self {
+        PlatformBus { devices }
+    }
+}
+
+impl Bus for PlatformBus {
+    fn name(&self) -> &'static str {
+        "platform"
+    }
+
+    fn enumerate(&self) -> Vec<BusDevice> {
+        self.devices
+            .iter()
+            .cloned()
+            .map(BusDevice::Platform)
+            .collect()
+    }
+}
+
+/// The ports of the 8042 controller.
+///
+/// Devices are not identified, so the first port is
+/// assumed to hold a keyboard.
+pub struct PS2Bus;
+
+impl Bus for PS2Bus {
+    fn name(&self) -> &'static str {
+        "ps2"
+    }
+
+    fn enumerate(&self) -> Vec<BusDevice> {
+        vec![BusDevice::PS2(1)]
+    }
+}
@@ -0,0 +1,217 @@
+use alloc::{boxed::Box, prelude::*};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::bus::{Bus, BusDevice, DeviceMatch};
+use super::event::{emit, DeviceEvent};
+
+lazy_static! {
+    static ref BUSES: Mutex<Vec<Box<dyn Bus>>> = Mutex::new(Vec::new());
+    static ref DRIVERS: Mutex<Vec<&'static dyn Driver>> = Mutex::new(Vec::new());
+    static ref DEVICES: Mutex<Vec<Binding>> = Mutex::new(Vec::new());
+}
+
+/// A driver that takes control of bus devices
+pub trait Driver: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Get the devices the driver can handle
+    fn match_table(&self) -> &[DeviceMatch];
+
+    /// Take control of a device
+    fn probe(&self, dev: &BusDevice) -> Result<(), &'static str>;
+
+    /// Release a device, which may already be gone
+    fn remove(&self, _dev: &BusDevice) -> Result<(), &'static str> {
+        Err("driver does not support removal")
+    }
+}
+
+/// A known device and the driver bound to it
+struct Binding {
+    device: BusDevice,
+    driver: Option<&'static dyn Driver>,
+}
+
+/// Add a bus and the devices on it.
+///
+/// Returns the number of devices found.
+pub fn register_bus(bus: Box<dyn Bus>) -> usize {
+    let devices = bus.enumerate();
+    let name = bus.name();
+    BUSES.lock().push(bus);
+
+    // The bus may provide the devices logging goes to
+    let count = devices.len();
+    for dev in devices {
+        add_device(dev);
+    }
+    log!(debug: "Found {} devices on the {} bus.", count, name);
+    count
+}
+
+/// Add a driver and bind it to every matching unbound device.
+///
+/// Returns the number of devices bound.
+pub fn register_driver(driver: &'static dyn Driver) -> usize {
+    DRIVERS.lock().push(driver);
+    let unbound: Vec<BusDevice> = DEVICES
+        .lock()
+        .iter()
+        .filter(|binding| binding.driver.is_none())
+        .map(|binding| binding.device)
+        .collect();
+    unbound.iter().filter(|dev| bind(dev, driver)).count()
+}
+
+/// Remove a driver, releasing its devices
+#[allow(dead_code)]
+pub fn unregister_driver(name: &str) -> Result<(), String> {
+    let driver = {
+        let mut drivers = DRIVERS.lock();
+        match drivers.iter().position(|driver| driver.name() == name) {
+            Some(index) => drivers.remove(index),
+            None => return Err(format!("Driver {} is not registered.", name)),
+        }
+    };
+    let bound: Vec<BusDevice> = DEVICES
+        .lock()
+        .iter()
+        .filter(|binding| is_bound_to(binding, driver))
+        .map(|binding| binding.device)
+        .collect();
+    for dev in bound.iter() {
+        unbind(dev, driver);
+    }
+    Ok(())
+}
+
+/// Add a device and bind the first matching driver to it.
+///
+/// Devices that are already known are ignored.
+pub fn add_device(dev: BusDevice) {
+    {
+        let mut devices = DEVICES.lock();
+        if devices.iter().any(|binding| binding.device.same_as(&dev)) {
+            return;
+        }
+        devices.push(Binding {
+            device: dev,
+            driver: None,
+        });
+    }
+    emit(DeviceEvent::Added(dev));
+
+    let drivers = DRIVERS.lock().clone();
+    for driver in drivers {
+        if bind(&dev, driver) {
+            break;
+        }
+    }
+}
+
+/// Remove a device, unbinding its driver first
+pub fn remove_device(dev: &BusDevice) {
+    let driver = match DEVICES
+        .lock()
+        .iter()
+        .find(|binding| binding.device.same_as(dev))
+    {
+        Some(binding) => binding.driver,
+        None => return,
+    };
+    if let Some(driver) = driver {
+        unbind(dev, driver);
+    }
+
+    DEVICES
+        .lock()
+        .retain(|binding| !binding.device.same_as(dev));
+    emit(DeviceEvent::Removed(*dev));
+}
+
+/// Enumerate all buses again, adding new and removing vanished devices.
+///
+/// Buses without hotplug notifications rely on this to
+/// pick up changes.
+#[allow(dead_code)]
+pub fn rescan() {
+    let present: Vec<BusDevice> = BUSES
+        .lock()
+        .iter()
+        .flat_map(|bus| bus.enumerate())
+        .collect();
+
+    let known: Vec<BusDevice> = DEVICES
+        .lock()
+        .iter()
+        .map(|binding| binding.device)
+        .collect();
+    for dev in known.iter() {
+        if !present.iter().any(|other| other.same_as(dev)) {
+            remove_device(dev);
+        }
+    }
+    for dev in present {
+        add_device(dev);
+    }
+}
+
+/// Get all known devices and the names of their drivers
+#[allow(dead_code)]
+pub fn devices() -> Vec<(BusDevice, Option<&'static str>)> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|binding| (binding.device, binding.driver.map(|driver| driver.name())))
+        .collect()
+}
+
+fn is_bound_to(binding: &Binding, driver: &'static dyn Driver) -> bool {
+    match binding.driver {
+        Some(bound) => bound.name() == driver.name(),
+        None => false,
+    }
+}
+
+/// Try to bind a driver to a device
+fn bind(dev: &BusDevice, driver: &'static dyn Driver) -> bool {
+    if !driver.match_table().iter().any(|entry| entry.matches(dev)) {
+        return false;
+    }
+
+    // Probing registers devices, so no lock may be held
+    if let Err(err) = driver.probe(dev) {
+        log!(warn: "{}: unable to bind {}: {}", dev, driver.name(), err);
+        return false;
+    }
+
+    let mut devices = DEVICES.lock();
+    match devices
+        .iter_mut()
+        .find(|binding| binding.device.same_as(dev))
+    {
+        Some(binding) => binding.driver = Some(driver),
+        None => return false,
+    }
+    drop(devices);
+
+    log!(debug: "Bound {} to {}.", dev, driver.name());
+    emit(DeviceEvent::Bound(*dev, driver.name()));
+    true
+}
+
+/// Release a device from its driver
+fn unbind(dev: &BusDevice, driver: &'static dyn Driver) {
+    if let Err(err) = driver.remove(dev) {
+        log!(warn: "{}: {} did not release it cleanly: {}", dev, driver.name(), err);
+    }
+    if let Some(binding) = DEVICES
+        .lock()
+        .iter_mut()
+        .find(|binding| binding.device.same_as(dev))
+    {
+        binding.driver = None;
+    }
+    emit(DeviceEvent::Unbound(*dev, driver.name()));
+}
@@ -0,0 +1,77 @@
+use alloc::{collections::VecDeque, prelude::*};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::bus::BusDevice;
+use crate::workqueue::{Work, WorkQueues};
+
+lazy_static! {
+    static ref PENDING: Mutex<VecDeque<DeviceEvent>> = Mutex::new(VecDeque::new());
+    static ref SUBSCRIBERS: Mutex<Vec<fn(&DeviceEvent)>> = Mutex::new(Vec::new());
+}
+
+/// Whether a dispatch is queued on the workqueue
+static DISPATCH_QUEUED: AtomicBool = AtomicBool::new(false);
+
+/// A change in the set of devices
+#[derive(Debug, Clone)]
+pub enum DeviceEvent {
+    /// A device appeared on a bus
+    Added(BusDevice),
+    /// A device disappeared from a bus
+    Removed(BusDevice),
+    /// A driver took control of a device
+    Bound(BusDevice, &'static str),
+    /// A driver released a device
+    Unbound(BusDevice, &'static str),
+    /// A device was registered with the `DeviceManager`
+    Registered(&'static str),
+    /// A device was unregistered from the `DeviceManager`
+    Unregistered(&'static str),
+}
+
+impl fmt::Display for DeviceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DeviceEvent::Added(ref dev) => write!(f, "added {}", dev),
+            DeviceEvent::Removed(ref dev) => write!(f, "removed {}", dev),
+            DeviceEvent::Bound(ref dev, driver) => write!(f, "bound {} to {}", dev, driver),
+            DeviceEvent::Unbound(ref dev, driver) => write!(f, "unbound {} from {}", dev, driver),
+            DeviceEvent::Registered(name) => write!(f, "registered {}", name),
+            DeviceEvent::Unregistered(name) => write!(f, "unregistered {}", name),
+        }
+    }
+}
+
+/// Call `f` for every future device event.
+///
+/// Events are delivered from the workqueue, so subscribers
+/// may use the `DeviceManager` and the driver core freely.
+pub fn subscribe(f: fn(&DeviceEvent)) {
+    SUBSCRIBERS.lock().push(f);
+}
+
+/// Queue an event for delivery to the subscribers
+pub(super) fn emit(event: DeviceEvent) {
+    interrupts::without_interrupts(|| PENDING.lock().push_back(event));
+    if !DISPATCH_QUEUED.swap(true, Ordering::AcqRel)
+        && WorkQueues::queue(Work::new(dispatch, 0)).is_err()
+    {
+        // The next event tries again
+        DISPATCH_QUEUED.store(false, Ordering::Release);
+    }
+}
+
+/// Deliver all pending events
+fn dispatch(_: usize) {
+    DISPATCH_QUEUED.store(false, Ordering::Release);
+    let subscribers = SUBSCRIBERS.lock().clone();
+    while let Some(event) = interrupts::without_interrupts(|| PENDING.lock().pop_front()) {
+        for subscriber in subscribers.iter() {
+            subscriber(&event);
+        }
+    }
+}
@@ -1,6 +1,30 @@
-use alloc::{boxed::Box, collections::btree_map::BTreeMap, format, prelude::*};
+//
+// Hardware Abstraction Layer
+//
+// Buses list the devices attached to them and drivers
+// declare match tables. Whenever a device or a driver is
+// added, the driver core binds matching pairs by calling the
+// driver's probe function, which registers the resulting
+// devices with the `DeviceManager` by name.
+//
+// Subscribers learn about added, removed, bound and
+// registered devices through deferred events.
+//
+
+mod bus;
+mod driver;
+mod event;
+
+pub use self::bus::{Bus, BusDevice, DeviceMatch, PCIBus, PS2Bus, PlatformBus, PlatformDevice};
+pub use self::driver::{
+    add_device, devices, register_bus, register_driver, remove_device, rescan, unregister_driver,
+    Driver,
+};
+pub use self::event::{subscribe, DeviceEvent};
+
+use alloc::{boxed::Box, collections::btree_map::BTreeMap, format, prelude::*, sync::Arc};
+use bitflags::bitflags;
 use core::any::Any;
-use core::{cell::RefCell, ptr::NonNull};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
@@ -10,17 +34,113 @@ lazy_static! {
     });
 }
 
+/// Device error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    /// The device does not support the operation
+    Unsupported,
+    /// No data is available right now
+    WouldBlock,
+    /// An argument is invalid for this device
+    InvalidArgument,
+    /// The position lies outside the device
+    OutOfRange,
+    /// The device did not respond in time
+    Timeout,
+    /// The hardware reported an error
+    IOError,
+}
+
+impl core::fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let s = match *self {
+            DeviceError::Unsupported => "operation not supported",
+            DeviceError::WouldBlock => "operation would block",
+            DeviceError::InvalidArgument => "invalid argument",
+            DeviceError::OutOfRange => "position out of range",
+            DeviceError::Timeout => "device timed out",
+            DeviceError::IOError => "I/O error",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+bitflags! {
+
+    /// Operations a device supports
+    pub struct DeviceCapabilities: u32 {
+        const READ          = 0b_0000_0001;
+        const WRITE         = 0b_0000_0010;
+        const RANDOM_ACCESS = 0b_0000_0100;
+        const CONTROL       = 0b_0000_1000;
+        const POLL          = 0b_0001_0000;
+    }
+}
+
+bitflags! {
+
+    /// Device readiness
+    pub struct PollFlags: u32 {
+        const READABLE = 0b_0000_0001;
+        const WRITABLE = 0b_0000_0010;
+        const ERROR    = 0b_0000_0100;
+    }
+}
+
 pub trait Device {
     fn get_type(&self) -> DeviceType;
 
-    fn write_byte(&mut self, at: usize, val: u8);
-    fn write_bytes(&mut self, at: usize, val: &[u8], len: usize);
+    /// Get the operations the device supports
+    fn capabilities(&self) -> DeviceCapabilities;
+
+    /// Read whatever data is available.
+    ///
+    /// Fails with `WouldBlock` if there is none.
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, DeviceError> {
+        Err(DeviceError::Unsupported)
+    }
+
+    /// Read data at a position
+    fn read_at(&mut self, _at: usize, _buf: &mut [u8]) -> Result<usize, DeviceError> {
+        Err(DeviceError::Unsupported)
+    }
+
+    /// Write a single byte
+    fn write_byte(&mut self, val: u8) -> Result<(), DeviceError> {
+        self.write_bytes(&[val]).map(|_| ())
+    }
+
+    /// Write data and return how much was written
+    fn write_bytes(&mut self, _val: &[u8]) -> Result<usize, DeviceError> {
+        Err(DeviceError::Unsupported)
+    }
+
+    /// Write data at a position
+    fn write_at(&mut self, _at: usize, _val: &[u8]) -> Result<usize, DeviceError> {
+        Err(DeviceError::Unsupported)
+    }
+
+    /// Perform a device-specific control operation
+    fn ioctl(&mut self, _request: u32, _arg: usize) -> Result<usize, DeviceError> {
+        Err(DeviceError::Unsupported)
+    }
+
+    /// Test whether the device is ready for reading or writing
+    fn poll(&mut self) -> PollFlags {
+        PollFlags::empty()
+    }
 
     fn as_any(&mut self) -> &mut dyn Any;
 }
 
+/// A registered device.
+///
+/// Users clone it out of the `DeviceManager`, so the
+/// manager does not stay locked during device I/O.
+pub type SharedDevice = Arc<Mutex<Box<dyn Device + Sync + Send>>>;
+
 pub struct DeviceManager {
-    devices: BTreeMap<&'static str, Mutex<Box<dyn Device + Sync + Send>>>,
+    devices: BTreeMap<&'static str, SharedDevice>,
 }
 
 impl DeviceManager {
@@ -32,25 +152,40 @@ impl DeviceManager {
         if self.devices.contains_key(name) {
             return Err(format!("Device {} already registered.", name));
         }
-        self.devices.insert(name, Mutex::new(dev));
+        self.devices.insert(name, Arc::new(Mutex::new(dev)));
+        event::emit(DeviceEvent::Registered(name));
         Ok(())
     }
 
-    pub fn get_device(
-        &mut self,
-        name: &'static str,
-    ) -> Option<&Mutex<Box<dyn Device + Sync + Send>>> {
-        let dev = self.devices.get(name).unwrap();
+    /// Remove a device, handing it back to the caller.
+    ///
+    /// Users that looked it up before may still hold a reference.
+    pub fn unregister_device(&mut self, name: &str) -> Option<SharedDevice> {
+        let name = *self.devices.keys().find(|key| **key == name)?;
+        let dev = self.devices.remove(name)?;
+        event::emit(DeviceEvent::Unregistered(name));
         Some(dev)
     }
 
-    pub fn with_device_cast<T, D: 'static>(&mut self, dev: &str, f: T)
+    pub fn get_device(&self, name: &str) -> Option<SharedDevice> {
+        self.devices.get(name).cloned()
+    }
+
+    /// Get the names of all registered devices
+    pub fn device_names(&self) -> Vec<&'static str> {
+        self.devices.keys().cloned().collect()
+    }
+
+    /// Call `f` with a device of type `D`.
+    ///
+    /// Returns `None` if there is no such device.
+    pub fn with_device_cast<T, D: 'static, R>(&self, dev: &str, f: T) -> Option<R>
     where
-        T: Fn(&mut D),
+        T: FnOnce(&mut D) -> R,
     {
-        let mut boxed = self.devices.get(dev).unwrap().lock();
-        let dev = boxed.as_any().downcast_mut::<D>().unwrap();
-        f(dev);
+        let mut boxed = self.devices.get(dev)?.lock();
+        let dev = boxed.as_any().downcast_mut::<D>()?;
+        Some(f(dev))
     }
 }
 
@@ -1,5 +1,17 @@
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{ExceptionStackFrame, InterruptDescriptorTable, PageFaultErrorCode};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{
+    ExceptionStackFrame, HandlerFunc, InterruptDescriptorTable, PageFaultErrorCode,
+    PageFaultHandlerFunc,
+};
+use x86_64::PrivilegeLevel;
+
+use crate::gdb::Trap;
+use crate::syscall::INT_SYSCALL;
+
+use crate::lapic::{LocalAPIC, SPURIOUS_VECTOR};
+use crate::pic::{PIC8259, PIC_1_OFFSET};
 
 //
 // Constants
@@ -11,18 +23,285 @@ pub const INT_PIT: u8 = crate::pic::PIC_1_OFFSET;
 /// PS/2 Keyboard interrupt code
 pub const INT_KBD: u8 = crate::pic::PIC_1_OFFSET + 1;
 
+/// The first IRQ line drivers can register handlers for
+const IRQ_FIRST_DYNAMIC: u8 = 3;
+
+/// The number of IRQ lines
+const IRQ_COUNT: usize = 16;
+
+/// The number of handlers that can share an IRQ line
+const IRQ_SHARED_MAX: usize = 4;
+
+/// Driver handlers, called with the IRQ number
+static IRQ_HANDLERS: Mutex<[[Option<fn(u8)>; IRQ_SHARED_MAX]; IRQ_COUNT]> =
+    Mutex::new([[None; IRQ_SHARED_MAX]; IRQ_COUNT]);
+
+/// The first vector handed out for message signalled interrupts
+pub const VECTOR_FIRST_DYNAMIC: u8 = 0x50;
+
+/// The number of vectors handed out for message signalled interrupts
+const VECTOR_COUNT: usize = 16;
+
+/// Driver handlers for allocated vectors, called with the vector
+static VECTOR_HANDLERS: Mutex<[Option<fn(u8)>; VECTOR_COUNT]> = Mutex::new([None; VECTOR_COUNT]);
+
+// Generate an interrupt handler that dispatches an IRQ
+// to the handlers registered for it.
+macro_rules! irq_handler {
+    ($name:ident, $irq:expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: &mut ExceptionStackFrame) {
+            dispatch_irq($irq);
+        }
+    };
+}
+
+irq_handler!(irq3_handler, 3);
+irq_handler!(irq4_handler, 4);
+irq_handler!(irq5_handler, 5);
+irq_handler!(irq6_handler, 6);
+irq_handler!(irq7_handler, 7);
+irq_handler!(irq8_handler, 8);
+irq_handler!(irq9_handler, 9);
+irq_handler!(irq10_handler, 10);
+irq_handler!(irq11_handler, 11);
+irq_handler!(irq12_handler, 12);
+irq_handler!(irq13_handler, 13);
+irq_handler!(irq14_handler, 14);
+irq_handler!(irq15_handler, 15);
+
+// Generate an interrupt handler for an allocated vector,
+// which the local APIC delivers.
+macro_rules! vector_handler {
+    ($name:ident, $index:expr) => {
+        extern "x86-interrupt" fn $name(_stack_frame: &mut ExceptionStackFrame) {
+            dispatch_vector($index);
+        }
+    };
+}
+
+vector_handler!(vector0_handler, 0);
+vector_handler!(vector1_handler, 1);
+vector_handler!(vector2_handler, 2);
+vector_handler!(vector3_handler, 3);
+vector_handler!(vector4_handler, 4);
+vector_handler!(vector5_handler, 5);
+vector_handler!(vector6_handler, 6);
+vector_handler!(vector7_handler, 7);
+vector_handler!(vector8_handler, 8);
+vector_handler!(vector9_handler, 9);
+vector_handler!(vector10_handler, 10);
+vector_handler!(vector11_handler, 11);
+vector_handler!(vector12_handler, 12);
+vector_handler!(vector13_handler, 13);
+vector_handler!(vector14_handler, 14);
+vector_handler!(vector15_handler, 15);
+
+/// The registers saved by `trap_entry!`, above the frame the CPU pushed
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// Test whether the trap was taken from user mode
+    pub fn is_user(&self) -> bool {
+        self.cs & 3 == 3
+    }
+}
+
+// Generate an exception entry that saves all general purpose
+// registers in a `TrapFrame` and calls the handler with it.
+//
+// The x86-interrupt ABI keeps these registers out of reach,
+// but a debugger needs to read and change them.
+macro_rules! trap_entry {
+    ($name:ident, $handler:ident) => {
+        #[naked]
+        extern "C" fn $name() -> ! {
+            unsafe {
+                asm!("push rax
+                      push rbx
+                      push rcx
+                      push rdx
+                      push rsi
+                      push rdi
+                      push rbp
+                      push r8
+                      push r9
+                      push r10
+                      push r11
+                      push r12
+                      push r13
+                      push r14
+                      push r15
+                      mov rdi, rsp
+                      cld
+                      call $0
+                      pop r15
+                      pop r14
+                      pop r13
+                      pop r12
+                      pop r11
+                      pop r10
+                      pop r9
+                      pop r8
+                      pop rbp
+                      pop rdi
+                      pop rsi
+                      pop rdx
+                      pop rcx
+                      pop rbx
+                      pop rax
+                      iretq"
+                     :: "i"($handler as extern "C" fn(&mut TrapFrame))
+                     :: "intel", "volatile");
+                core::hint::unreachable_unchecked();
+            }
+        }
+    };
+    // Exceptions that push an error code pass it as a second
+    // argument. It is swapped with rax, which leaves the same
+    // frame behind as for the other exceptions.
+    ($name:ident, $handler:ident, error_code) => {
+        #[naked]
+        extern "C" fn $name() -> ! {
+            unsafe {
+                asm!("xchg rax, [rsp]
+                      push rbx
+                      push rcx
+                      push rdx
+                      push rsi
+                      push rdi
+                      push rbp
+                      push r8
+                      push r9
+                      push r10
+                      push r11
+                      push r12
+                      push r13
+                      push r14
+                      push r15
+                      mov rdi, rsp
+                      mov rsi, rax
+                      cld
+                      call $0
+                      pop r15
+                      pop r14
+                      pop r13
+                      pop r12
+                      pop r11
+                      pop r10
+                      pop r9
+                      pop r8
+                      pop rbp
+                      pop rdi
+                      pop rsi
+                      pop rdx
+                      pop rcx
+                      pop rbx
+                      pop rax
+                      iretq"
+                     :: "i"($handler as extern "C" fn(&mut TrapFrame, u64))
+                     :: "intel", "volatile");
+                core::hint::unreachable_unchecked();
+            }
+        }
+    };
+}
+
+trap_entry!(debug_entry, debug_handler);
+trap_entry!(breakpoint_entry, breakpoint_handler);
+trap_entry!(syscall_entry, syscall_handler);
+trap_entry!(page_fault_entry, page_fault_handler, error_code);
+
 lazy_static! {
     static ref STATIC_IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
         unsafe {
-            idt.page_fault.set_handler_fn(page_fault_handler);
+            let debug: HandlerFunc = core::mem::transmute(debug_entry as extern "C" fn() -> !);
+            idt.debug.set_handler_fn(debug);
+            let breakpoint: HandlerFunc =
+                core::mem::transmute(breakpoint_entry as extern "C" fn() -> !);
+            idt.breakpoint.set_handler_fn(breakpoint);
+            let syscall: HandlerFunc = core::mem::transmute(syscall_entry as extern "C" fn() -> !);
+            idt[usize::from(INT_SYSCALL)]
+                .set_handler_fn(syscall)
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
+        idt.device_not_available
+            .set_handler_fn(crate::cpu::handle_device_not_available);
+        unsafe {
+            let page_fault: PageFaultHandlerFunc =
+                core::mem::transmute(page_fault_entry as extern "C" fn() -> !);
+            idt.page_fault.set_handler_fn(page_fault);
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
         }
         idt[usize::from(INT_PIT)].set_handler_fn(crate::pit::handle_interrupt);
         idt[usize::from(INT_KBD)].set_handler_fn(crate::ps2kbd::handle_interrupt);
+        let irq_handlers: [extern "x86-interrupt" fn(&mut ExceptionStackFrame); 13] = [
+            irq3_handler,
+            irq4_handler,
+            irq5_handler,
+            irq6_handler,
+            irq7_handler,
+            irq8_handler,
+            irq9_handler,
+            irq10_handler,
+            irq11_handler,
+            irq12_handler,
+            irq13_handler,
+            irq14_handler,
+            irq15_handler,
+        ];
+        for (i, &handler) in irq_handlers.iter().enumerate() {
+            let vector = PIC_1_OFFSET + IRQ_FIRST_DYNAMIC + i as u8;
+            idt[usize::from(vector)].set_handler_fn(handler);
+        }
+        let vector_handlers: [extern "x86-interrupt" fn(&mut ExceptionStackFrame); VECTOR_COUNT] = [
+            vector0_handler,
+            vector1_handler,
+            vector2_handler,
+            vector3_handler,
+            vector4_handler,
+            vector5_handler,
+            vector6_handler,
+            vector7_handler,
+            vector8_handler,
+            vector9_handler,
+            vector10_handler,
+            vector11_handler,
+            vector12_handler,
+            vector13_handler,
+            vector14_handler,
+            vector15_handler,
+        ];
+        for (i, &handler) in vector_handlers.iter().enumerate() {
+            let vector = VECTOR_FIRST_DYNAMIC + i as u8;
+            idt[usize::from(vector)].set_handler_fn(handler);
+        }
+        idt[usize::from(SPURIOUS_VECTOR)].set_handler_fn(spurious_handler);
         idt
     };
 }
@@ -35,18 +314,126 @@ impl IDT {
         // Load the IDT
         STATIC_IDT.load();
     }
+
+    /// Register a handler for an IRQ line and unmask it.
+    ///
+    /// Handlers run in interrupt context with interrupts
+    /// disabled. A line can be shared by several handlers,
+    /// which must check whether their device raised it.
+    pub fn register_irq(irq: u8, handler: fn(u8)) -> Result<(), &'static str> {
+        if irq < IRQ_FIRST_DYNAMIC || irq as usize >= IRQ_COUNT {
+            return Err("IRQ line is not available to drivers");
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut handlers = IRQ_HANDLERS.lock();
+            let slot = handlers[irq as usize]
+                .iter_mut()
+                .find(|slot| slot.is_none())
+                .ok_or("IRQ line has too many handlers")?;
+            *slot = Some(handler);
+            Ok(())
+        })?;
+
+        PIC8259::unmask(irq);
+        Ok(())
+    }
+
+    /// Allocate a vector of its own for a handler.
+    ///
+    /// This is meant for message signalled interrupts, which
+    /// are delivered by the local APIC and never shared.
+    pub fn allocate_vector(handler: fn(u8)) -> Result<u8, &'static str> {
+        if !LocalAPIC::is_enabled() {
+            return Err("The local APIC is not enabled");
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut handlers = VECTOR_HANDLERS.lock();
+            let index = handlers
+                .iter()
+                .position(|slot| slot.is_none())
+                .ok_or("No interrupt vectors left")?;
+            handlers[index] = Some(handler);
+            Ok(VECTOR_FIRST_DYNAMIC + index as u8)
+        })
+    }
+
+    /// Release a vector from `allocate_vector`.
+    ///
+    /// The device must not raise it anymore.
+    pub fn free_vector(vector: u8) {
+        let index = vector.wrapping_sub(VECTOR_FIRST_DYNAMIC) as usize;
+        assert!(
+            index < VECTOR_COUNT,
+            "Vector {} was never allocated",
+            vector
+        );
+        interrupts::without_interrupts(|| VECTOR_HANDLERS.lock()[index] = None);
+    }
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut ExceptionStackFrame) {
-    log!(fault: "*** BREAKPOINT EXCEPTION\r\n{:#?}", stack_frame);
-    loop {}
+fn dispatch_irq(irq: u8) {
+    let handlers = *IRQ_HANDLERS.lock();
+    for handler in handlers[irq as usize].iter().filter_map(|h| *h) {
+        handler(irq);
+    }
+
+    unsafe {
+        PIC8259::get_chained_pics()
+            .lock()
+            .notify_end_of_interrupt(PIC_1_OFFSET + irq);
+    }
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: &mut ExceptionStackFrame,
-    error: PageFaultErrorCode,
-) {
-    log!(fault: "*** PAGE FAULT\r\n{:#?}\r\n{:#?}", stack_frame, error);
+fn dispatch_vector(index: usize) {
+    let handler = VECTOR_HANDLERS.lock()[index];
+    if let Some(handler) = handler {
+        handler(VECTOR_FIRST_DYNAMIC + index as u8);
+    }
+    LocalAPIC::end_of_interrupt();
+}
+
+/// Spurious interrupts must not be acknowledged
+extern "x86-interrupt" fn spurious_handler(_stack_frame: &mut ExceptionStackFrame) {}
+
+/// Single steps and hardware breakpoints end up here
+extern "C" fn debug_handler(frame: &mut TrapFrame) {
+    if !crate::gdb::handle_trap(frame, Trap::Debug) {
+        log!(fault: "*** DEBUG EXCEPTION\r\n{:#?}", frame);
+        loop {}
+    }
+}
+
+extern "C" fn breakpoint_handler(frame: &mut TrapFrame) {
+    if !crate::gdb::handle_trap(frame, Trap::Breakpoint) {
+        log!(fault: "*** BREAKPOINT EXCEPTION\r\n{:#?}", frame);
+        loop {}
+    }
+}
+
+/// User tasks enter the kernel here with `int 0x80`
+extern "C" fn syscall_handler(frame: &mut TrapFrame) {
+    crate::syscall::dispatch(frame);
+    if frame.is_user() {
+        crate::process::return_to_user(frame);
+    }
+}
+
+extern "C" fn page_fault_handler(frame: &mut TrapFrame, error: u64) {
+    let error = PageFaultErrorCode::from_bits_truncate(error);
+
+    // Faults in userspace only take down the faulting process
+    if error.contains(PageFaultErrorCode::USER_MODE) {
+        let pid = crate::process::current();
+        log!(fault: "*** SEGMENTATION FAULT in process {}\r\n{:#?}", pid, frame);
+        if crate::process::force_signal(pid, crate::process::Signal::SIGSEGV).is_ok() {
+            crate::process::return_to_user(frame);
+            return;
+        }
+    }
+
+    log!(fault: "*** PAGE FAULT\r\n{:#?}\r\n{:#?}", frame, error);
     loop {}
 }
 
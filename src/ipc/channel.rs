@@ -0,0 +1,183 @@
+use alloc::{collections::VecDeque, prelude::*, sync::Arc};
+use spin::Mutex;
+
+use super::{IPCError, KernelObject, Rights};
+use crate::sync::WaitQueue;
+
+/// A message sent over a userspace channel.
+///
+/// Besides its payload, a message may carry kernel objects.
+/// They are removed from the sender's handle table on send
+/// and installed into the receiver's handle table on receive.
+pub struct Message {
+    pub data: Vec<u8>,
+    pub objects: Vec<(KernelObject, Rights)>,
+}
+
+impl Message {
+    pub fn new(data: Vec<u8>) -> Self {
+        Message {
+            data,
+            objects: Vec::new(),
+        }
+    }
+}
+
+/// Shared channel state
+struct ChannelState<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receivers: usize,
+}
+
+/// A channel shared by its senders and receivers
+struct Channel<T> {
+    state: Mutex<ChannelState<T>>,
+    readable: WaitQueue,
+    writable: WaitQueue,
+}
+
+/// Create a new bounded channel
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0);
+    let chan = Arc::new(Channel {
+        state: Mutex::new(ChannelState {
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            senders: 1,
+            receivers: 1,
+        }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    (Sender { chan: chan.clone() }, Receiver { chan })
+}
+
+/// The sending side of a channel
+pub struct Sender<T> {
+    chan: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Send a message, blocking while the channel is full.
+    ///
+    /// On failure, the message is handed back to the caller.
+    pub fn send(&self, msg: T) -> Result<(), (IPCError, T)> {
+        let mut msg = Some(msg);
+        self.chan
+            .writable
+            .wait_until(
+                || match self.try_send(msg.take().unwrap()) {
+                    Err((IPCError::WouldBlock, m)) => {
+                        msg = Some(m);
+                        None
+                    }
+                    res => Some(res),
+                },
+                None,
+            )
+            .unwrap()
+    }
+
+    /// Send a message without blocking
+    pub fn try_send(&self, msg: T) -> Result<(), (IPCError, T)> {
+        let mut state = self.chan.state.lock();
+        if state.receivers == 0 {
+            return Err((IPCError::Disconnected, msg));
+        }
+        if state.queue.len() >= state.capacity {
+            return Err((IPCError::WouldBlock, msg));
+        }
+        state.queue.push_back(msg);
+        self.chan.readable.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.chan.state.lock().senders += 1;
+        Sender {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.chan.state.lock().senders -= 1;
+        self.chan.readable.notify_all();
+    }
+}
+
+/// The receiving side of a channel
+pub struct Receiver<T> {
+    chan: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Receive a message, blocking until one is available
+    #[allow(dead_code)]
+    pub fn recv(&self) -> Result<T, IPCError> {
+        self.recv_checked(|_| Ok(()))
+    }
+
+    /// Receive a message without blocking
+    #[allow(dead_code)]
+    pub fn try_recv(&self) -> Result<T, IPCError> {
+        self.try_recv_checked(|_| Ok(()))
+    }
+
+    /// Receive a message if `check` accepts it, blocking until one is available.
+    ///
+    /// A rejected message stays queued and the error from
+    /// `check` is returned.
+    pub fn recv_checked<F>(&self, check: F) -> Result<T, IPCError>
+    where
+        F: Fn(&T) -> Result<(), IPCError>,
+    {
+        self.chan
+            .readable
+            .wait_until(
+                || match self.try_recv_checked(&check) {
+                    Err(IPCError::WouldBlock) => None,
+                    res => Some(res),
+                },
+                None,
+            )
+            .unwrap()
+    }
+
+    /// Receive a message if `check` accepts it, without blocking
+    pub fn try_recv_checked<F>(&self, check: F) -> Result<T, IPCError>
+    where
+        F: Fn(&T) -> Result<(), IPCError>,
+    {
+        let mut state = self.chan.state.lock();
+        match state.queue.front() {
+            Some(msg) => check(msg)?,
+            None if state.senders == 0 => return Err(IPCError::Disconnected),
+            None => return Err(IPCError::WouldBlock),
+        }
+        let msg = state.queue.pop_front().unwrap();
+        self.chan.writable.notify_one();
+        Ok(msg)
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.chan.state.lock().receivers += 1;
+        Receiver {
+            chan: self.chan.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.chan.state.lock().receivers -= 1;
+        self.chan.writable.notify_all();
+    }
+}
@@ -0,0 +1,240 @@
+use alloc::{collections::btree_map::BTreeMap, prelude::*, sync::Arc};
+use bitflags::bitflags;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::process::Pid;
+
+use super::{channel, pipe, IPCError, Message, PipeReader, PipeWriter, Receiver, Sender};
+
+/// The number of messages a userspace channel can buffer
+const CHANNEL_CAPACITY: usize = 64;
+
+lazy_static! {
+    /// Handles owned by user tasks
+    static ref TASK_HANDLES: Mutex<BTreeMap<Pid, Arc<Mutex<HandleTable>>>> =
+        Mutex::new(BTreeMap::new());
+}
+
+/// Get the handle table of a task, creating it on first use
+pub fn task_handles(pid: Pid) -> Arc<Mutex<HandleTable>> {
+    TASK_HANDLES
+        .lock()
+        .entry(pid)
+        .or_insert_with(|| Arc::new(Mutex::new(HandleTable::new())))
+        .clone()
+}
+
+/// Drop the handle table of a task that is gone, closing its handles
+pub fn release_task_handles(pid: Pid) {
+    let table = TASK_HANDLES.lock().remove(&pid);
+
+    // Closing pipe and channel ends wakes their peers,
+    // so the handles are dropped after the lock is released.
+    drop(table);
+}
+
+bitflags! {
+
+    /// Handle rights
+    pub struct Rights: u32 {
+        const READ      = 0b_0000_0001;
+        const WRITE     = 0b_0000_0010;
+        const DUPLICATE = 0b_0000_0100;
+        const TRANSFER  = 0b_0000_1000;
+    }
+}
+
+/// An opaque reference to a kernel object
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Handle(pub u32);
+
+/// An object that can be referred to by a handle
+#[derive(Clone)]
+pub enum KernelObject {
+    PipeReader(PipeReader),
+    PipeWriter(PipeWriter),
+    ChannelSender(Sender<Message>),
+    ChannelReceiver(Receiver<Message>),
+}
+
+struct HandleEntry {
+    object: KernelObject,
+    rights: Rights,
+}
+
+/// A table mapping handles to kernel objects.
+///
+/// Its methods are the operations exposed to userspace
+/// by the IPC syscalls, with one table per task. Those that
+/// may block take the locked table, and hold the lock only
+/// while looking up handles.
+pub struct HandleTable {
+    entries: BTreeMap<Handle, HandleEntry>,
+    next: u32,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        HandleTable {
+            entries: BTreeMap::new(),
+            next: 1,
+        }
+    }
+
+    /// Install an object and return its new handle
+    pub fn insert(&mut self, object: KernelObject, rights: Rights) -> Handle {
+        let handle = Handle(self.next);
+        self.next += 1;
+        self.entries.insert(handle, HandleEntry { object, rights });
+        handle
+    }
+
+    /// Remove a handle, dropping the reference to its object
+    pub fn close(&mut self, handle: Handle) -> Result<(), IPCError> {
+        self.take(handle, Rights::empty()).map(|_| ())
+    }
+
+    /// Create a new handle to the same object with a subset of the rights
+    pub fn duplicate(&mut self, handle: Handle, rights: Rights) -> Result<Handle, IPCError> {
+        let object = {
+            let entry = self.get(handle, Rights::DUPLICATE)?;
+            if !entry.rights.contains(rights) {
+                return Err(IPCError::AccessDenied);
+            }
+            entry.object.clone()
+        };
+        Ok(self.insert(object, rights))
+    }
+
+    /// Create a pipe and return its (read, write) handles
+    pub fn create_pipe(&mut self) -> (Handle, Handle) {
+        let (reader, writer) = pipe();
+        let rights = Rights::DUPLICATE | Rights::TRANSFER;
+        (
+            self.insert(KernelObject::PipeReader(reader), rights | Rights::READ),
+            self.insert(KernelObject::PipeWriter(writer), rights | Rights::WRITE),
+        )
+    }
+
+    /// Create a channel and return its (send, receive) handles
+    pub fn create_channel(&mut self) -> (Handle, Handle) {
+        let (sender, receiver) = channel(CHANNEL_CAPACITY);
+        let rights = Rights::DUPLICATE | Rights::TRANSFER;
+        (
+            self.insert(KernelObject::ChannelSender(sender), rights | Rights::WRITE),
+            self.insert(
+                KernelObject::ChannelReceiver(receiver),
+                rights | Rights::READ,
+            ),
+        )
+    }
+
+    /// Read from a pipe.
+    ///
+    /// The table is only locked to look the pipe up. Blocking
+    /// happens without it, since the writer may need it too.
+    pub fn read(table: &Mutex<Self>, handle: Handle, buf: &mut [u8]) -> Result<usize, IPCError> {
+        let reader = match table.lock().get(handle, Rights::READ)?.object {
+            KernelObject::PipeReader(ref reader) => reader.clone(),
+            _ => return Err(IPCError::WrongType),
+        };
+        reader.read(buf)
+    }
+
+    /// Write to a pipe, blocking without the table locked
+    pub fn write(table: &Mutex<Self>, handle: Handle, buf: &[u8]) -> Result<usize, IPCError> {
+        let writer = match table.lock().get(handle, Rights::WRITE)?.object {
+            KernelObject::PipeWriter(ref writer) => writer.clone(),
+            _ => return Err(IPCError::WrongType),
+        };
+        writer.write(buf)
+    }
+
+    /// Send a message, transferring the given handles along with it.
+    ///
+    /// The transferred handles are removed before blocking
+    /// on a full channel and put back if sending fails.
+    pub fn send(
+        table: &Mutex<Self>,
+        handle: Handle,
+        data: Vec<u8>,
+        transfer: &[Handle],
+    ) -> Result<(), IPCError> {
+        let (sender, msg) = {
+            let mut table = table.lock();
+            let sender = match table.get(handle, Rights::WRITE)?.object {
+                KernelObject::ChannelSender(ref sender) => sender.clone(),
+                _ => return Err(IPCError::WrongType),
+            };
+
+            // Validate all transferred handles before touching any of them
+            for (i, h) in transfer.iter().enumerate() {
+                if *h == handle || transfer[..i].contains(h) {
+                    return Err(IPCError::AccessDenied);
+                }
+                table.get(*h, Rights::TRANSFER)?;
+            }
+
+            let mut msg = Message::new(data);
+            for h in transfer {
+                let entry = table.take(*h, Rights::TRANSFER)?;
+                msg.objects.push((entry.object, entry.rights));
+            }
+            (sender, msg)
+        };
+
+        sender.send(msg).map_err(|(err, msg)| {
+            // Give the transferred objects back on failure
+            let mut table = table.lock();
+            for (object, rights) in msg.objects {
+                table.insert(object, rights);
+            }
+            err
+        })
+    }
+
+    /// Receive a message, installing any transferred objects.
+    ///
+    /// A message with more than `max_size` bytes or `max_objects`
+    /// objects is left queued. Returns the payload and the
+    /// handles of the received objects.
+    pub fn recv(
+        table: &Mutex<Self>,
+        handle: Handle,
+        max_size: usize,
+        max_objects: usize,
+    ) -> Result<(Vec<u8>, Vec<Handle>), IPCError> {
+        let receiver = match table.lock().get(handle, Rights::READ)?.object {
+            KernelObject::ChannelReceiver(ref receiver) => receiver.clone(),
+            _ => return Err(IPCError::WrongType),
+        };
+        let msg = receiver.recv_checked(|msg| {
+            if msg.data.len() > max_size || msg.objects.len() > max_objects {
+                return Err(IPCError::TooLarge);
+            }
+            Ok(())
+        })?;
+
+        let mut table = table.lock();
+        let handles = msg
+            .objects
+            .into_iter()
+            .map(|(object, rights)| table.insert(object, rights))
+            .collect();
+        Ok((msg.data, handles))
+    }
+
+    fn get(&self, handle: Handle, rights: Rights) -> Result<&HandleEntry, IPCError> {
+        let entry = self.entries.get(&handle).ok_or(IPCError::InvalidHandle)?;
+        if !entry.rights.contains(rights) {
+            return Err(IPCError::AccessDenied);
+        }
+        Ok(entry)
+    }
+
+    fn take(&mut self, handle: Handle, rights: Rights) -> Result<HandleEntry, IPCError> {
+        self.get(handle, rights)?;
+        Ok(self.entries.remove(&handle).unwrap())
+    }
+}
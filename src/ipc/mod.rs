@@ -0,0 +1,50 @@
+//
+// Inter-Process Communication
+//
+// Pipes move unstructured bytes between a writer and
+// a reader, channels move whole messages. Both are
+// reachable from kernel code directly and from userspace
+// through handles stored in a `HandleTable`, using the
+// syscalls in the syscall module.
+//
+
+mod channel;
+mod handle;
+mod pipe;
+
+pub use self::channel::{channel, Message, Receiver, Sender};
+pub use self::handle::{
+    release_task_handles, task_handles, Handle, HandleTable, KernelObject, Rights,
+};
+pub use self::pipe::{pipe, PipeReader, PipeWriter, PIPE_CAPACITY};
+
+/// IPC error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IPCError {
+    /// The operation would have to block
+    WouldBlock,
+    /// The other side of the pipe or channel is gone
+    Disconnected,
+    /// The handle does not exist
+    InvalidHandle,
+    /// The handle lacks the rights for the operation
+    AccessDenied,
+    /// The handle refers to the wrong kind of object
+    WrongType,
+    /// The message does not fit the receive buffers
+    TooLarge,
+}
+
+impl core::fmt::Display for IPCError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let s = match *self {
+            IPCError::WouldBlock => "operation would block",
+            IPCError::Disconnected => "peer disconnected",
+            IPCError::InvalidHandle => "invalid handle",
+            IPCError::AccessDenied => "access denied",
+            IPCError::WrongType => "wrong object type",
+            IPCError::TooLarge => "message too large",
+        };
+        write!(f, "{}", s)
+    }
+}
@@ -0,0 +1,193 @@
+use alloc::{boxed::Box, sync::Arc};
+use core::cmp::min;
+use spin::Mutex;
+
+use super::IPCError;
+use crate::sync::WaitQueue;
+
+/// The number of bytes a pipe can buffer
+pub const PIPE_CAPACITY: usize = 4096;
+
+/// Shared pipe state
+struct PipeState {
+    buf: Box<[u8]>,
+    head: usize,
+    len: usize,
+    readers: usize,
+    writers: usize,
+}
+
+impl PipeState {
+    fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Move as many buffered bytes as possible into `out`
+    fn pop(&mut self, out: &mut [u8]) -> usize {
+        let count = min(out.len(), self.len);
+        for b in out[..count].iter_mut() {
+            *b = self.buf[self.head];
+            self.head = (self.head + 1) % self.capacity();
+        }
+        self.len -= count;
+        count
+    }
+
+    /// Buffer as many bytes of `data` as there is room for
+    fn push(&mut self, data: &[u8]) -> usize {
+        let count = min(data.len(), self.capacity() - self.len);
+        for (i, b) in data[..count].iter().enumerate() {
+            let tail = (self.head + self.len + i) % self.capacity();
+            self.buf[tail] = *b;
+        }
+        self.len += count;
+        count
+    }
+}
+
+/// A pipe shared by its readers and writers
+struct Pipe {
+    state: Mutex<PipeState>,
+    readable: WaitQueue,
+    writable: WaitQueue,
+}
+
+/// Create a new pipe
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        state: Mutex::new(PipeState {
+            buf: vec![0u8; PIPE_CAPACITY].into_boxed_slice(),
+            head: 0,
+            len: 0,
+            readers: 1,
+            writers: 1,
+        }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    });
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+/// The reading end of a pipe
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+impl PipeReader {
+    /// Read into `buf`, blocking until at least one byte is available.
+    ///
+    /// Returns `Ok(0)` once the pipe is empty and all writers are closed.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, IPCError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.pipe
+            .readable
+            .wait_until(
+                || match self.try_read(buf) {
+                    Err(IPCError::WouldBlock) => None,
+                    res => Some(res),
+                },
+                None,
+            )
+            .unwrap()
+    }
+
+    /// Read into `buf` without blocking
+    pub fn try_read(&self, buf: &mut [u8]) -> Result<usize, IPCError> {
+        let mut state = self.pipe.state.lock();
+        match state.pop(buf) {
+            0 if state.writers == 0 => Ok(0),
+            0 if !buf.is_empty() => Err(IPCError::WouldBlock),
+            count => {
+                self.pipe.writable.notify_all();
+                Ok(count)
+            }
+        }
+    }
+}
+
+impl Clone for PipeReader {
+    fn clone(&self) -> Self {
+        self.pipe.state.lock().readers += 1;
+        PipeReader {
+            pipe: self.pipe.clone(),
+        }
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        self.pipe.state.lock().readers -= 1;
+
+        // Let blocked writers notice the pipe is broken
+        self.pipe.writable.notify_all();
+    }
+}
+
+/// The writing end of a pipe
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+impl PipeWriter {
+    /// Write all of `buf`, blocking while the pipe is full.
+    ///
+    /// If the readers go away after part of `buf` was written,
+    /// the number of bytes written so far is returned.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, IPCError> {
+        let mut written = 0;
+        while written < buf.len() {
+            let res = self
+                .pipe
+                .writable
+                .wait_until(
+                    || match self.try_write(&buf[written..]) {
+                        Err(IPCError::WouldBlock) => None,
+                        res => Some(res),
+                    },
+                    None,
+                )
+                .unwrap();
+            match res {
+                Ok(count) => written += count,
+                Err(_) if written > 0 => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(written)
+    }
+
+    /// Write as much of `buf` as fits without blocking
+    pub fn try_write(&self, buf: &[u8]) -> Result<usize, IPCError> {
+        let mut state = self.pipe.state.lock();
+        if state.readers == 0 {
+            return Err(IPCError::Disconnected);
+        }
+        match state.push(buf) {
+            0 if !buf.is_empty() => Err(IPCError::WouldBlock),
+            count => {
+                self.pipe.readable.notify_all();
+                Ok(count)
+            }
+        }
+    }
+}
+
+impl Clone for PipeWriter {
+    fn clone(&self) -> Self {
+        self.pipe.state.lock().writers += 1;
+        PipeWriter {
+            pipe: self.pipe.clone(),
+        }
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        self.pipe.state.lock().writers -= 1;
+
+        // Let blocked readers see the end of file
+        self.pipe.readable.notify_all();
+    }
+}
@@ -2,9 +2,17 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::instructions::port::Port;
 
+use crate::pit::PIT;
+
 const KBC_DATA: u16 = 0x60;
 const KBC_STATUS: u16 = 0x64;
 
+/// The time the controller may stay busy, in milliseconds
+const KBC_TIMEOUT_MS: usize = 100;
+
+/// Status reads per millisecond, for timing without timer ticks
+const KBC_POLLS_PER_MS: usize = 1000;
+
 //
 // Keyboard I/O ports
 //
@@ -22,13 +30,30 @@ lazy_static! {
 // Keyboard Controller
 pub struct KBC;
 impl KBC {
-    /// Wait for the KBC to become ready
-    pub unsafe fn wait_ready() {
-        KBC::with_ports(|data, status| {
-            while status.read() & 0x02 != 0 {
+    /// Wait for the KBC to become ready.
+    ///
+    /// The controller raises no interrupt for this, so the
+    /// CPU idles between polls. Returns `false` on timeout.
+    pub unsafe fn wait_ready() -> bool {
+        let deadline = PIT::deadline(PIT::ms_to_ticks(KBC_TIMEOUT_MS));
+        let mut polls = KBC_TIMEOUT_MS * KBC_POLLS_PER_MS;
+        loop {
+            let busy = KBC::with_ports(|data, status| {
+                if status.read() & 0x02 == 0 {
+                    return false;
+                }
                 data.read(); // discard
+                true
+            });
+            if !busy {
+                return true;
+            }
+            polls -= 1;
+            if polls == 0 || PIT::has_passed(deadline) {
+                return false;
             }
-        });
+            crate::sync::idle();
+        }
     }
 
     /// Read the keyboard data port
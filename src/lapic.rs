@@ -0,0 +1,118 @@
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::{structures::paging::PageTableFlags, PhysAddr};
+
+use crate::cpu::{CPUFeatures, CPU_INFO};
+use crate::paging::PAGING;
+
+//
+// Constants
+//
+
+/// The vector of spurious interrupts
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_MASK: u64 = 0xF_FFFF_F000;
+
+// Register offsets
+const LAPIC_ID: usize = 0x20;
+const LAPIC_EOI: usize = 0xB0;
+const LAPIC_SVR: usize = 0xF0;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_LINT1: usize = 0x360;
+
+const SVR_ENABLE: u32 = 1 << 8;
+
+// Local vector table delivery modes
+const LVT_NMI: u32 = 0x4 << 8;
+const LVT_EXTINT: u32 = 0x7 << 8;
+
+/// The base address MSI messages are written to
+const MSI_ADDRESS_BASE: u64 = 0xFEE0_0000;
+
+/// The virtual address of the register page, 0 if disabled
+static BASE: AtomicUsize = AtomicUsize::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The local APIC of the boot processor.
+///
+/// The 8259 PIC stays in charge of the legacy IRQ lines and
+/// reaches the CPU through LINT0. The local APIC is only
+/// enabled so that message signalled interrupts get through.
+pub struct LocalAPIC;
+impl LocalAPIC {
+    /// Map and software-enable the local APIC
+    pub fn init() -> Result<(), &'static str> {
+        if !CPU_INFO.features.contains(CPUFeatures::APIC) {
+            return Err("The CPU has no local APIC");
+        }
+
+        let msr = unsafe { rdmsr(IA32_APIC_BASE) };
+        if msr & APIC_BASE_ENABLE == 0 {
+            return Err("The local APIC is disabled by the firmware");
+        }
+        let base = msr & APIC_BASE_MASK;
+        PAGING.lock().identity_map_region(
+            PhysAddr::new(base),
+            0x1000,
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::WRITE_THROUGH,
+        );
+        BASE.store(base as usize, Ordering::Release);
+
+        // Keep the PIC working in virtual wire mode
+        LocalAPIC::write(LAPIC_LVT_LINT0, LVT_EXTINT);
+        LocalAPIC::write(LAPIC_LVT_LINT1, LVT_NMI);
+        LocalAPIC::write(LAPIC_SVR, SVR_ENABLE | u32::from(SPURIOUS_VECTOR));
+        ENABLED.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Whether the local APIC can receive interrupts
+    pub fn is_enabled() -> bool {
+        ENABLED.load(Ordering::Acquire)
+    }
+
+    pub fn id() -> u8 {
+        (LocalAPIC::read(LAPIC_ID) >> 24) as u8
+    }
+
+    /// Signal the end of an interrupt the local APIC delivered
+    pub fn end_of_interrupt() {
+        LocalAPIC::write(LAPIC_EOI, 0);
+    }
+
+    /// Get the message address and data that raise `vector`
+    /// on this processor
+    pub fn msi_message(vector: u8) -> (u64, u32) {
+        let address = MSI_ADDRESS_BASE | u64::from(LocalAPIC::id()) << 12;
+        // Fixed delivery, edge triggered
+        (address, u32::from(vector))
+    }
+
+    fn read(reg: usize) -> u32 {
+        let base = BASE.load(Ordering::Acquire);
+        assert!(base != 0, "The local APIC is not initialized");
+        unsafe { ptr::read_volatile((base + reg) as *const u32) }
+    }
+
+    fn write(reg: usize, val: u32) {
+        let base = BASE.load(Ordering::Acquire);
+        assert!(base != 0, "The local APIC is not initialized");
+        unsafe { ptr::write_volatile((base + reg) as *mut u32, val) }
+    }
+}
+
+//
+// Register access
+//
+
+unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr" : "={eax}"(low), "={edx}"(high) : "{ecx}"(msr) :: "volatile");
+    u64::from(high) << 32 | u64::from(low)
+}
@@ -0,0 +1,475 @@
+//
+// Kernel log
+//
+// Every message has a level and the module it comes from.
+// Those that pass the level filters are kept in the kernel
+// message buffer, which works before the heap or any device
+// exists, and handed to each sink whose level they meet.
+// Sinks are added as their devices come up; until then the
+// buffer is the only record, and `kmsg` reads it back.
+//
+// Only the buffer is written with interrupts disabled. Sinks
+// can be slow, so they run after the logger lock is released.
+//
+// Messages sent through the `log` crate facade end up here
+// as well.
+//
+
+mod ring;
+mod sink;
+
+pub use self::ring::RingBuffer;
+pub use self::sink::{ConsoleSink, MemorySink, SerialSink, Sink};
+
+use alloc::prelude::*;
+use core::any::Any;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::hal::{Device, DeviceCapabilities, DeviceError, DeviceType, PollFlags};
+use crate::pit::{PIT, PIT_FREQUENCY};
+
+/// The size of the kernel message buffer in bytes
+pub const LOG_BUFFER_SIZE: usize = 16384;
+
+/// The storage of the kernel message buffer
+static mut LOG_BUFFER: [u8; LOG_BUFFER_SIZE] = [0; LOG_BUFFER_SIZE];
+
+lazy_static! {
+    static ref LOGGER: Mutex<Logger> = {
+        let buf: &'static mut [u8] = unsafe { &mut LOG_BUFFER };
+        Mutex::new(Logger {
+            level: Level::Debug,
+            filters: Vec::new(),
+            buffer: RingBuffer::new(buf),
+        })
+    };
+    static ref SINKS: Mutex<Vec<SinkEntry>> = Mutex::new(Vec::new());
+}
+
+/// The number of messages logged while the sinks were busy
+static MISSED: AtomicUsize = AtomicUsize::new(0);
+
+//
+// Kernel log control requests
+//
+
+/// Drop the messages in the buffer
+pub const KMSG_IOCTL_CLEAR: u32 = 0x4001;
+
+/// Get the default level, as by `Level::index`
+pub const KMSG_IOCTL_GET_LEVEL: u32 = 0x4002;
+
+/// Change the default level, as by `Level::index`
+pub const KMSG_IOCTL_SET_LEVEL: u32 = 0x4003;
+
+/// Set the level of the module last written to the device
+pub const KMSG_IOCTL_SET_FILTER: u32 = 0x4004;
+
+/// Let the module last written to the device log at the default level
+pub const KMSG_IOCTL_CLEAR_FILTER: u32 = 0x4005;
+
+/// How severe a message is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    /// A CPU exception the kernel cannot recover from
+    Fault,
+}
+
+impl Level {
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Trace => "trace",
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+            Level::Fault => "fault",
+        }
+    }
+
+    /// Get the level's place in order of severity, from 0
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn from_index(index: usize) -> Option<Level> {
+        match index {
+            0 => Some(Level::Trace),
+            1 => Some(Level::Debug),
+            2 => Some(Level::Info),
+            3 => Some(Level::Warn),
+            4 => Some(Level::Error),
+            5 => Some(Level::Fault),
+            _ => None,
+        }
+    }
+}
+
+impl From<log::Level> for Level {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Trace => Level::Trace,
+            log::Level::Debug => Level::Debug,
+            log::Level::Info => Level::Info,
+            log::Level::Warn => Level::Warn,
+            log::Level::Error => Level::Error,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A message on its way to the sinks
+pub struct Record<'a> {
+    pub level: Level,
+    /// The module path of the sender
+    pub target: &'a str,
+    pub file: &'a str,
+    pub line: u32,
+    /// Timer ticks since boot
+    pub ticks: usize,
+    pub args: fmt::Arguments<'a>,
+}
+
+impl<'a> Record<'a> {
+    /// Write the message with its time, source and level
+    pub fn write_full<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        let hz = PIT_FREQUENCY as usize;
+        write!(
+            out,
+            "[{:5}.{:03}] {}:{} [{}] {}",
+            self.ticks / hz,
+            self.ticks % hz * 1000 / hz,
+            self.file,
+            self.line,
+            self.level,
+            self.args
+        )
+    }
+
+    /// Write the message with only its level
+    pub fn write_short<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        write!(out, "[{}] {}", self.level, self.args)
+    }
+}
+
+struct SinkEntry {
+    name: &'static str,
+    /// The least severe level the sink gets
+    level: Level,
+    sink: Box<dyn Sink>,
+}
+
+struct Logger {
+    /// The least severe level logged where no filter applies
+    level: Level,
+    /// Levels for module paths and everything below them
+    filters: Vec<(String, Level)>,
+    buffer: RingBuffer<&'static mut [u8]>,
+}
+
+impl Logger {
+    /// Get the level a module logs at, from the longest filter
+    fn level_for(&self, target: &str) -> Level {
+        self.filters
+            .iter()
+            .filter(|(module, _)| is_within(target, module))
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.level, |&(_, level)| level)
+    }
+
+    fn write(&mut self, record: &Record) {
+        let _ = record.write_full(&mut self.buffer);
+        self.buffer.write(b"\n");
+    }
+}
+
+/// Test whether a module path is a module or below it
+fn is_within(target: &str, module: &str) -> bool {
+    target.starts_with(module)
+        && (target.len() == module.len() || target[module.len()..].starts_with("::"))
+}
+
+/// Call `f` with the logger.
+///
+/// Returns `None` if it is in use further up the stack, which
+/// only happens when logging faults.
+fn with_logger<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut Logger) -> R,
+{
+    interrupts::without_interrupts(|| LOGGER.try_lock().map(|mut logger| f(&mut *logger)))
+}
+
+/// Log a message; this is what `log!` expands to
+pub fn log(level: Level, target: &str, file: &str, line: u32, args: fmt::Arguments) {
+    let record = Record {
+        level,
+        target,
+        file,
+        line,
+        ticks: PIT::ticks(),
+        args,
+    };
+    let logged = with_logger(|logger| {
+        let logged = level >= logger.level_for(target);
+        if logged {
+            logger.write(&record);
+        }
+        logged
+    });
+    if logged == Some(true) {
+        write_sinks(&record);
+    }
+}
+
+/// Hand a message to the sinks.
+///
+/// Interrupts stay as the caller had them. A message logged
+/// while the sinks are busy further up the stack, like from an
+/// interrupt handler, is only kept in the buffer.
+fn write_sinks(record: &Record) {
+    let mut sinks = match SINKS.try_lock() {
+        Some(sinks) => sinks,
+        None => {
+            MISSED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    feed(&mut sinks, record);
+
+    let missed = MISSED.swap(0, Ordering::Relaxed);
+    if missed > 0 {
+        feed(
+            &mut sinks,
+            &Record {
+                level: Level::Warn,
+                target: module_path!(),
+                file: file!(),
+                line: line!(),
+                ticks: PIT::ticks(),
+                args: format_args!("{} messages were only logged to kmsg.", missed),
+            },
+        );
+    }
+}
+
+fn feed(sinks: &mut [SinkEntry], record: &Record) {
+    for entry in sinks.iter_mut().filter(|entry| record.level >= entry.level) {
+        entry.sink.write(record);
+    }
+}
+
+/// Test whether a message would be logged
+pub fn enabled(level: Level, target: &str) -> bool {
+    with_logger(|logger| level >= logger.level_for(target)).unwrap_or(false)
+}
+
+/// Set the level modules without a filter log at
+pub fn set_level(level: Level) {
+    with_logger(|logger| logger.level = level);
+}
+
+pub fn level() -> Level {
+    with_logger(|logger| logger.level).unwrap_or(Level::Debug)
+}
+
+/// Set the level of a module and the modules below it.
+///
+/// Modules are named by path, like `hydroxide::pci`.
+pub fn set_filter(module: &str, level: Level) {
+    with_logger(|logger| {
+        let filter = logger.filters.iter_mut().find(|(name, _)| name == module);
+        match filter {
+            Some(filter) => filter.1 = level,
+            None => logger.filters.push((module.to_string(), level)),
+        }
+    });
+}
+
+/// Let a module log at the default level again
+pub fn clear_filter(module: &str) {
+    with_logger(|logger| logger.filters.retain(|(name, _)| name != module));
+}
+
+/// Send messages of at least `level` to a sink
+pub fn add_sink(name: &'static str, level: Level, sink: Box<dyn Sink>) -> Result<(), &'static str> {
+    let mut sinks = SINKS.try_lock().ok_or("logger is busy")?;
+    if sinks.iter().any(|entry| entry.name == name) {
+        return Err("sink name already taken");
+    }
+    sinks.push(SinkEntry { name, level, sink });
+    Ok(())
+}
+
+#[allow(dead_code)]
+pub fn remove_sink(name: &str) -> Option<Box<dyn Sink>> {
+    let mut sinks = SINKS.try_lock()?;
+    let index = sinks.iter().position(|entry| entry.name == name)?;
+    Some(sinks.remove(index).sink)
+}
+
+/// Change the least severe level a sink gets
+#[allow(dead_code)]
+pub fn set_sink_level(name: &str, level: Level) -> Result<(), &'static str> {
+    let mut sinks = SINKS.try_lock().ok_or("logger is busy")?;
+    let entry = sinks
+        .iter_mut()
+        .find(|entry| entry.name == name)
+        .ok_or("no such sink")?;
+    entry.level = level;
+    Ok(())
+}
+
+/// Copy messages out of the kernel buffer.
+///
+/// Returns the position to read on from and the number of
+/// bytes copied; see `RingBuffer::read_at`.
+pub fn read_buffer(pos: u64, out: &mut [u8]) -> (u64, usize) {
+    with_logger(|logger| logger.buffer.read_at(pos, out)).unwrap_or((pos, 0))
+}
+
+/// Drop the messages in the kernel buffer
+pub fn clear_buffer() {
+    with_logger(|logger| logger.buffer.clear());
+}
+
+//
+// The `log` crate facade
+//
+
+struct Facade;
+
+static FACADE: Facade = Facade;
+
+impl log::Log for Facade {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        enabled(metadata.level().into(), metadata.target())
+    }
+
+    fn log(&self, record: &log::Record) {
+        log(
+            record.level().into(),
+            record.target(),
+            record.file().unwrap_or("?"),
+            record.line().unwrap_or(0),
+            *record.args(),
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Take messages from crates that use the `log` facade
+pub fn init() -> Result<(), &'static str> {
+    log::set_logger(&FACADE).map_err(|_| "another logger is installed")?;
+    // Filtering is done here, where it can change per module
+    log::set_max_level(log::LevelFilter::Trace);
+    Ok(())
+}
+
+/// The kernel message buffer as a readable device.
+///
+/// Reads go on from where the last one stopped and return 0
+/// once they catch up. Writing a module path selects the
+/// module the filter requests apply to.
+#[derive(Default)]
+pub struct KmsgDevice {
+    pos: u64,
+    module: Option<String>,
+}
+
+impl KmsgDevice {
+    pub fn new() -> Self {
+        KmsgDevice {
+            pos: 0,
+            module: None,
+        }
+    }
+
+    fn module(&self) -> Result<&str, DeviceError> {
+        self.module
+            .as_ref()
+            .map(|module| module.as_str())
+            .ok_or(DeviceError::InvalidArgument)
+    }
+}
+
+impl Device for KmsgDevice {
+    fn get_type(&self) -> DeviceType {
+        DeviceType::CharDevice
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities::READ
+            | DeviceCapabilities::WRITE
+            | DeviceCapabilities::CONTROL
+            | DeviceCapabilities::POLL
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        let (pos, count) = read_buffer(self.pos, buf);
+        self.pos = pos;
+        Ok(count)
+    }
+
+    fn write_bytes(&mut self, val: &[u8]) -> Result<usize, DeviceError> {
+        let module = core::str::from_utf8(val)
+            .map_err(|_| DeviceError::InvalidArgument)?
+            .trim();
+        if module.is_empty() {
+            return Err(DeviceError::InvalidArgument);
+        }
+        self.module = Some(module.to_string());
+        Ok(val.len())
+    }
+
+    fn ioctl(&mut self, request: u32, arg: usize) -> Result<usize, DeviceError> {
+        match request {
+            KMSG_IOCTL_CLEAR => {
+                clear_buffer();
+                Ok(0)
+            }
+            KMSG_IOCTL_GET_LEVEL => Ok(level().index()),
+            KMSG_IOCTL_SET_LEVEL => {
+                set_level(Level::from_index(arg).ok_or(DeviceError::InvalidArgument)?);
+                Ok(0)
+            }
+            KMSG_IOCTL_SET_FILTER => {
+                let level = Level::from_index(arg).ok_or(DeviceError::InvalidArgument)?;
+                set_filter(self.module()?, level);
+                Ok(0)
+            }
+            KMSG_IOCTL_CLEAR_FILTER => {
+                clear_filter(self.module()?);
+                Ok(0)
+            }
+            _ => Err(DeviceError::Unsupported),
+        }
+    }
+
+    fn poll(&mut self) -> PollFlags {
+        let end = with_logger(|logger| logger.buffer.end()).unwrap_or(self.pos);
+        match end > self.pos {
+            true => PollFlags::READABLE,
+            false => PollFlags::empty(),
+        }
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
@@ -0,0 +1,93 @@
+use core::fmt;
+
+/// A byte buffer that overwrites its oldest contents when full.
+///
+/// Positions count every byte ever written, so a reader that
+/// fell behind can tell what it missed. The storage is static
+/// for the kernel buffer, which exists before the heap does.
+pub struct RingBuffer<B: AsRef<[u8]> + AsMut<[u8]>> {
+    buf: B,
+    /// The position of the next byte written
+    head: u64,
+    /// The position before which the buffer was cleared
+    tail: u64,
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> RingBuffer<B> {
+    pub fn new(buf: B) -> Self {
+        RingBuffer {
+            buf,
+            head: 0,
+            tail: 0,
+        }
+    }
+
+    /// Get the position of the oldest byte still there
+    pub fn start(&self) -> u64 {
+        core::cmp::max(
+            self.tail,
+            self.head.saturating_sub(self.buf.as_ref().len() as u64),
+        )
+    }
+
+    /// Get the position after the newest byte
+    pub fn end(&self) -> u64 {
+        self.head
+    }
+
+    pub fn write(&mut self, val: &[u8]) {
+        let len = self.buf.as_ref().len();
+        if len == 0 {
+            return;
+        }
+        // Only the end of a write larger than the buffer survives
+        let skip = val.len().saturating_sub(len);
+        self.head += skip as u64;
+        for &byte in &val[skip..] {
+            self.buf.as_mut()[(self.head % len as u64) as usize] = byte;
+            self.head += 1;
+        }
+    }
+
+    /// Drop everything written so far
+    pub fn clear(&mut self) {
+        self.tail = self.head;
+    }
+
+    /// Copy bytes starting at a position.
+    ///
+    /// If they were overwritten, this starts at the first whole
+    /// line still there instead. Returns the position after the
+    /// bytes copied and their count.
+    pub fn read_at(&self, pos: u64, out: &mut [u8]) -> (u64, usize) {
+        let start = self.start();
+        let mut pos = pos;
+        if pos < start {
+            pos = start;
+            // The line at the start lost its beginning
+            if start > self.tail {
+                while pos < self.head && self.byte_at(pos) != b'\n' {
+                    pos += 1;
+                }
+                pos = core::cmp::min(pos + 1, self.head);
+            }
+        }
+        let count = core::cmp::min(self.head.saturating_sub(pos), out.len() as u64) as usize;
+        for (i, byte) in out[..count].iter_mut().enumerate() {
+            *byte = self.byte_at(pos + i as u64);
+        }
+        (pos + count as u64, count)
+    }
+
+    fn byte_at(&self, pos: u64) -> u8 {
+        let buf = self.buf.as_ref();
+        buf[(pos % buf.len() as u64) as usize]
+    }
+}
+
+impl<B: AsRef<[u8]> + AsMut<[u8]>> fmt::Write for RingBuffer<B> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
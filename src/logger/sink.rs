@@ -0,0 +1,143 @@
+use alloc::{prelude::*, sync::Arc};
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::ring::RingBuffer;
+use super::Record;
+use crate::serial::SerialLine;
+use crate::tty::Output;
+
+/// Somewhere log messages go
+pub trait Sink: Send {
+    fn write(&mut self, record: &Record);
+}
+
+/// The most bytes gathered before they are written out
+const LINE_BUFFER_SIZE: usize = 256;
+
+/// Gathers formatted text, so outputs see few large writes
+struct LineWriter<'a, O: 'a + Output> {
+    output: &'a mut O,
+    buf: [u8; LINE_BUFFER_SIZE],
+    len: usize,
+}
+
+impl<'a, O: Output> LineWriter<'a, O> {
+    fn new(output: &'a mut O) -> Self {
+        LineWriter {
+            output,
+            buf: [0; LINE_BUFFER_SIZE],
+            len: 0,
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.len > 0 {
+            self.output.write(&self.buf[..self.len]);
+            self.len = 0;
+        }
+    }
+}
+
+impl<'a, O: Output> Write for LineWriter<'a, O> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Strings are never split, so characters stay whole
+        if self.len + s.len() > LINE_BUFFER_SIZE {
+            self.flush();
+        }
+        if s.len() > LINE_BUFFER_SIZE {
+            self.output.write(s.as_bytes());
+        } else {
+            self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+            self.len += s.len();
+        }
+        Ok(())
+    }
+}
+
+impl<'a, O: Output> Drop for LineWriter<'a, O> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Writes messages with their time and source to a serial line
+pub struct SerialSink {
+    line: SerialLine,
+}
+
+impl SerialSink {
+    pub fn new(line: SerialLine) -> Self {
+        SerialSink { line }
+    }
+}
+
+impl Sink for SerialSink {
+    fn write(&mut self, record: &Record) {
+        let mut out = LineWriter::new(&mut self.line);
+        let _ = record.write_full(&mut out);
+        let _ = out.write_str("\r\n");
+    }
+}
+
+/// Writes messages with their level to a screen.
+///
+/// Works with anything a TTY writes to, like a VGA text
+/// console or a framebuffer terminal.
+pub struct ConsoleSink<O: Output> {
+    output: O,
+}
+
+impl<O: Output> ConsoleSink<O> {
+    pub fn new(output: O) -> Self {
+        ConsoleSink { output }
+    }
+}
+
+impl<O: Output> Sink for ConsoleSink<O> {
+    fn write(&mut self, record: &Record) {
+        let mut out = LineWriter::new(&mut self.output);
+        let _ = record.write_short(&mut out);
+        let _ = out.write_str("\n");
+    }
+}
+
+/// Keeps the latest messages in memory.
+///
+/// Clones share the messages, so one can be kept for reading
+/// after another is added to the logger.
+#[derive(Clone)]
+pub struct MemorySink {
+    ring: Arc<Mutex<RingBuffer<Box<[u8]>>>>,
+}
+
+#[allow(dead_code)]
+impl MemorySink {
+    /// Create a sink keeping the last `size` bytes of messages
+    pub fn new(size: usize) -> Self {
+        let ring = RingBuffer::new(vec![0; size].into_boxed_slice());
+        MemorySink {
+            ring: Arc::new(Mutex::new(ring)),
+        }
+    }
+
+    /// Copy messages out, as by `RingBuffer::read_at`
+    pub fn read_at(&self, pos: u64, out: &mut [u8]) -> (u64, usize) {
+        interrupts::without_interrupts(|| self.ring.lock().read_at(pos, out))
+    }
+
+    pub fn clear(&self) {
+        interrupts::without_interrupts(|| self.ring.lock().clear());
+    }
+}
+
+impl Sink for MemorySink {
+    fn write(&mut self, record: &Record) {
+        interrupts::without_interrupts(|| {
+            let mut ring = self.ring.lock();
+            let _ = record.write_full(&mut *ring);
+            ring.write(b"\n");
+        });
+    }
+}
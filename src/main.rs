@@ -48,6 +48,20 @@
 // and helpful messages in case of kernel panics.
 //
 #![feature(panic_info_message)]
+//
+// Enable inline assembly
+//
+// This is needed for accessing control
+// registers the x86_64 crate doesn't cover.
+//
+#![feature(asm)]
+//
+// Enable naked functions
+//
+// The debugger's exception entries save all
+// registers themselves before calling into Rust.
+//
+#![feature(naked_functions)]
 #![feature(alloc)]
 #![feature(extern_crate_item_prelude)]
 #![feature(box_syntax)]
@@ -67,6 +81,7 @@
 extern crate bitflags;
 extern crate bootloader;
 extern crate linked_list_allocator;
+extern crate log;
 extern crate pc_keyboard;
 extern crate pic8259_simple;
 extern crate spin;
@@ -92,48 +107,41 @@ use linked_list_allocator::LockedHeap;
 //
 
 // A macro to write a string to a device.
+//
+// Devices can be unregistered at any time, so writes
+// to a device that is gone are silently dropped.
 macro_rules! device_write {
     ($dev:expr, $($arg:tt)*) => {
         device_write!(__formatted $dev, format!($($arg)*));
     };
-    (__formatted $dev:expr, $fmt:expr) => {
-        (**crate::hal::DEVICE_MANAGER
-            .lock()
-            .get_device($dev)
-            .unwrap()
-            .lock())
-        .write_bytes(0, $fmt.as_bytes(), $fmt.len());
-    };
+    (__formatted $dev:expr, $fmt:expr) => {{
+        let dev = crate::hal::DEVICE_MANAGER.lock().get_device($dev);
+        if let Some(dev) = dev {
+            let _ = (**dev.lock()).write_bytes($fmt.as_bytes());
+        }
+    }};
 }
 
 // A macro for kernel-level logging.
+//
+// Messages go to the kernel log, which decides
+// where they are shown; see the logger module.
 macro_rules! log {
-    (__ [$($device:expr),*] => $prefix:expr; $fmt:expr) => {{
-        $(
-            device_write!(__formatted $device, match $device {
-                dev if dev.starts_with("com") => {
-                    format!(
-                        "{filename}\t[{prefix}] {fmt}\r\n",
-                        filename=file!(),
-                        prefix=$prefix,
-                        fmt=$fmt
-                    )
-                }
-                _ => {
-                    format!(
-                        "[{prefix}] {fmt}\r\n",
-                        prefix=$prefix,
-                        fmt=$fmt
-                    )
-                }
-            });
-        )*
-    }};
-    (debug: $($arg:tt)*) => (log!(__ ["com1"] => "debug"; format!($($arg)*)));
-    ( info: $($arg:tt)*) => (log!(__ ["com1", "tty0"] => "info"; format!($($arg)*)));
-    ( warn: $($arg:tt)*) => (log!(__ ["com1", "tty0"] => "warn"; format!($($arg)*)));
-    (error: $($arg:tt)*) => (log!(__ ["com1", "tty0"] => "error"; format!($($arg)*)));
-    (fault: $($arg:tt)*) => (log!(__ ["com1", "tty0"] => "fault"; format!($($arg)*)));
+    (__ $level:ident; $($arg:tt)*) => {
+        crate::logger::log(
+            crate::logger::Level::$level,
+            module_path!(),
+            file!(),
+            line!(),
+            format_args!($($arg)*),
+        )
+    };
+    (trace: $($arg:tt)*) => (log!(__ Trace; $($arg)*));
+    (debug: $($arg:tt)*) => (log!(__ Debug; $($arg)*));
+    ( info: $($arg:tt)*) => (log!(__ Info; $($arg)*));
+    ( warn: $($arg:tt)*) => (log!(__ Warn; $($arg)*));
+    (error: $($arg:tt)*) => (log!(__ Error; $($arg)*));
+    (fault: $($arg:tt)*) => (log!(__ Fault; $($arg)*));
     ($($arg:tt)*) => (log!(info: $($arg)*));
 }
 
@@ -166,6 +174,11 @@ static ALLOCATOR: LockedHeap = LockedHeap::empty();
 //
 //
 
+// Kernel Log
+mod logger;
+
+use self::logger::{ConsoleSink, KmsgDevice, Level, SerialSink};
+
 // Global Descriptor Table
 mod gdt;
 
@@ -187,10 +200,17 @@ use self::pic::PIC8259;
 // Programmable Interrupt Timer
 mod pit;
 
+use self::pit::PIT;
+
+// Local Advanced Programmable Interrupt Controller
+mod lapic;
+
+use self::lapic::LocalAPIC;
+
 // VGA Terminal Screen Buffer
 mod vgaterm;
 
-use self::vgaterm::{TerminalDevice, VGA_PTR};
+use self::vgaterm::{Console, VGATextDriver, VGA_PTR};
 
 // Intel 8042
 // Keyboard Controller
@@ -199,7 +219,7 @@ mod kbc;
 // Generic PS/2 Keyboard
 mod ps2kbd;
 
-use self::ps2kbd::PS2Keyboard;
+use self::ps2kbd::PS2KeyboardDriver;
 
 // Page Allocator
 mod paging;
@@ -211,13 +231,16 @@ mod heap;
 
 use self::heap::{find_heap_space, map_heap};
 
+// Advanced Configuration and Power Interface
+mod acpi;
+
 // Peripheral Component Interconnect
 mod pci;
 
 // Bochs Graphics Adapter
 mod bga;
 
-use self::bga::{BochsGraphicsAdapter, VideoDevice};
+use self::bga::BGADriver;
 
 // CMOS
 mod cmos;
@@ -227,15 +250,70 @@ use self::cmos::{POSTData, CMOS};
 // Hardware Abstraction Layer
 mod hal;
 
-use self::hal::DEVICE_MANAGER;
+use self::hal::{BusDevice, PCIBus, PS2Bus, PlatformBus, PlatformDevice, DEVICE_MANAGER};
 
 // Serial Bus
 mod serial;
 
-use self::serial::{SerialDevice, SerialPort};
+use self::serial::{SerialDevice, SerialDriver, SerialPort};
+
+// Terminals and Line Discipline
+mod tty;
+
+// GDB Remote Serial Protocol Stub
+mod gdb;
 
 mod ansi;
 
+// Synchronization Primitives
+mod sync;
+
+// Inter-Process Communication
+mod ipc;
+
+// System Calls
+mod syscall;
+
+// Process Lifecycle and Signals
+mod process;
+
+// Deferred Work
+mod workqueue;
+
+// CPU Identification and FPU State
+mod cpu;
+
+use self::cpu::{CPU_INFO, FPU};
+
+use self::workqueue::WorkQueues;
+
+// Block Device Layer
+mod block;
+
+// ATA PIO / IDE Disks
+mod ata;
+
+use self::ata::IDE;
+
+// DMA Memory
+mod dma;
+
+// Advanced Host Controller Interface
+mod ahci;
+
+use self::ahci::AHCI;
+
+// Virtio Devices
+mod virtio;
+
+use self::virtio::VirtioBlkDriver;
+
+// MBR and GPT Partition Tables
+mod partition;
+
+// Virtual File System
+mod vfs;
+
 //
 //
 // Main entry point
@@ -249,6 +327,9 @@ pub extern "C" fn _start(bootinfo: &'static mut BootInfo) -> ! {
     GDT::init();
     IDT::init();
 
+    // Messages are kept in the kernel log from here on
+    logger::init().ok();
+
     // Initialize paging and heap allocation
     let (heap_start, heap_end) = find_heap_space(bootinfo);
     Paging::init(bootinfo);
@@ -259,28 +340,128 @@ pub extern "C" fn _start(bootinfo: &'static mut BootInfo) -> ! {
         (heap_end - heap_start) as usize,
     );
 
-    // Initialize devices
-    SerialDevice::init("com1", SerialPort::COM1).unwrap();
+    // Initialize the serial port and the screen first, so we can log
+    hal::register_driver(&SerialDriver);
+    hal::register_driver(&VGATextDriver);
+    hal::register_bus(box PlatformBus::new(vec![
+        PlatformDevice {
+            name: "com1",
+            compatible: "ns16550",
+            base: SerialPort::COM1 as usize,
+            irq: Some(4),
+        },
+        PlatformDevice {
+            name: "tty0",
+            compatible: "vga-text",
+            base: VGA_PTR,
+            irq: None,
+        },
+    ]));
+
+    // The second serial port is for the debugger, if there is one
+    if SerialDevice::is_present(SerialPort::COM2 as u16) {
+        hal::add_device(BusDevice::Platform(PlatformDevice {
+            name: "com2",
+            compatible: "ns16550",
+            base: SerialPort::COM2 as usize,
+            irq: Some(3),
+        }));
+    }
+
+    // Show log messages on the serial port and the screen
+    let com1 = DEVICE_MANAGER
+        .lock()
+        .with_device_cast("com1", |dev: &mut SerialDevice| dev.line());
+    if let Some(line) = com1 {
+        logger::add_sink("com1", Level::Debug, box SerialSink::new(line)).ok();
+    }
+    logger::add_sink("tty0", Level::Info, box ConsoleSink::new(Console(0))).ok();
+    DEVICE_MANAGER
+        .lock()
+        .register_device("kmsg", box KmsgDevice::new())
+        .ok();
     log!(debug: "GDT and IDT initialization complete.");
     log!(debug: "Heap initialization complete.");
-    TerminalDevice::init("tty0", VGA_PTR);
     log!(debug: "VGA text screen initialization complete.");
 
-    // Print POST status
+    // Log device changes once the worker runs
+    hal::subscribe(|event| log!(debug: "Device event: {}.", event));
+
+    // Print POST status and CPU information
     print_post_status();
+    print_cpu_info();
+
+    // Enable SSE and AVX for user tasks
+    FPU::init();
+    log!(debug: "FPU initialization complete.");
+
+    // Set up deferred work before any interrupt handler can queue it
+    WorkQueues::init();
+    log!(debug: "Workqueue initialization complete.");
 
     // Remap the PIC
     PIC8259::init();
     log!(debug: "PIC remapping complete.");
 
+    // Program the timer
+    PIT::init();
+    log!(debug: "PIT initialization complete.");
+
+    // Enable the local APIC for message signalled interrupts
+    match LocalAPIC::init() {
+        Ok(_) => log!(debug: "Local APIC initialization complete."),
+        Err(err) => log!(warn: "Local APIC unavailable: {}", err),
+    }
+
     // Enable interrupts
     x86_64::instructions::interrupts::enable();
     log!(debug: "Interrupts enabled.");
 
+    // Put TTYs on the virtual consoles and the serial port
+    match tty::init() {
+        Ok(_) => log!(debug: "TTY initialization complete."),
+        Err(err) => log!(warn: "Unable to set up all TTYs: {}", err),
+    }
+
+    // Let GDB attach through the second serial port
+    match gdb::init("com2") {
+        Ok(_) => log!(debug: "GDB stub listening on com2."),
+        Err(err) => log!(debug: "GDB stub unavailable: {}", err),
+    }
+
     // Initialize the PS/2 keyboard
-    PS2Keyboard::init();
+    hal::register_driver(&PS2KeyboardDriver);
+    hal::register_bus(box PS2Bus);
     log!(debug: "Keyboard initialization complete.");
 
+    // Detect IDE, SATA and virtio disks and the BGA on the PCI bus
+    hal::register_driver(&IDE);
+    hal::register_driver(&AHCI);
+    hal::register_driver(&VirtioBlkDriver);
+    hal::register_driver(&BGADriver);
+    hal::register_bus(box PCIBus);
+    log!(debug: "IDE initialization complete ({} disks).", IDE::disks());
+    log!(debug: "AHCI initialization complete ({} disks).", AHCI::disks());
+    log!(
+        debug: "Virtio block initialization complete ({} disks).",
+        VirtioBlkDriver::disks()
+    );
+
+    // List the PCI functions if tracing them is enabled
+    pci::dump();
+
+    // Register the partitions of all disks
+    let partitions = partition::probe_all();
+    log!(debug: "Partition scan complete ({} partitions).", partitions);
+
+    // Set up the file system tree with /dev
+    vfs::init().unwrap();
+    log!(debug: "VFS initialization complete.");
+
+    // Mount the filesystems found on disks
+    let mounted = vfs::automount();
+    log!(debug: "Mounted {} filesystems.", mounted);
+
     // Say hello
     println!("Hello from Hydroxide.");
 
@@ -292,77 +473,8 @@ pub extern "C" fn _start(bootinfo: &'static mut BootInfo) -> ! {
         time = datetime.as_time(),
     );
 
-    // Detect a Bochs Graphics Adapter
-    let bga = match BochsGraphicsAdapter::detect() {
-        Ok(device) => {
-            let mut dev = BochsGraphicsAdapter::new(&device).init();
-            println!("[BGA @ 0x{:08x}] Found", dev.addr());
-            println!(
-                "[BGA @ 0x{:08x}] Version: 0x{:04x}",
-                dev.addr(),
-                dev.version()
-            );
-            println!("[BGA @ 0x{:08x}] Max BPP: {}", dev.addr(), dev.max_bpp);
-            println!("[BGA @ 0x{:08x}] Max Width: {}", dev.addr(), dev.max_width);
-            println!(
-                "[BGA @ 0x{:08x}] Max Height: {}",
-                dev.addr(),
-                dev.max_height
-            );
-            let mode = dev
-                .get_default_mode()
-                .and_then(|mode| {
-                    println!(
-                        "[BGA @ 0x{:08x}] Supports resolution: {}x{}x{}",
-                        dev.addr(),
-                        mode.width,
-                        mode.height,
-                        mode.bpp
-                    );
-                    Some(mode)
-                })
-                .unwrap();
-
-            dev.set_video_mode(&mode, true);
-
-            #[inline(always)]
-            fn get_col(r: u8, g: u8, b: u8) -> u32 {
-                (u32::from(r) << 16) | (u32::from(g) << 8) | u32::from(b)
-            }
-
-            use crate::bga::{GraphicsProvider, TerminalDriver};
-            use core::fmt::Write;
-            let mut video = VideoDevice::new(&dev, &mode);
-            let mut term = TerminalDriver::new(&mut video);
-            Write::write_str(&mut term, "Hello World! [\x1b[32mOK\x1b[0m]\n");
-            Write::write_str(&mut term, "This should fail! [\x1b[31mFAIL\x1b[0m]\n");
-            Write::write_str(
-                &mut term,
-                "\x1b[44;37mThis simulates a dark BSOD as we have no light colors :(\n",
-            );
-            Write::write_str(
-                &mut term,
-                "\x1b[37;1;44mThis simulates a light BSOD as we have light colors :)\n",
-            );
-
-            video.flush();
-            Some(dev)
-        }
-        Err(err) => {
-            println!(
-                "{}
-            ",
-                err
-            );
-            None
-        }
-    }
-        .unwrap();
-
-    // Idle
-    loop {
-        x86_64::instructions::hlt();
-    }
+    // Idle, running deferred work as it comes in
+    WorkQueues::run_worker();
 }
 
 fn print_post_status() {
@@ -387,6 +499,21 @@ fn print_post_status() {
     };
 }
 
+fn print_cpu_info() {
+    let info = &*CPU_INFO;
+    log!(debug: "CPU vendor: {}", info.vendor());
+    if let Some(brand) = info.brand() {
+        log!(debug: "CPU brand: {}", brand);
+    }
+    log!(
+        debug: "CPU family: 0x{:x}; model: 0x{:x}; stepping: {}",
+        info.family,
+        info.model,
+        info.stepping
+    );
+    log!(debug: "CPU features: {}", info.features);
+}
+
 //
 //
 // Panic and OOM handlers
@@ -398,27 +525,18 @@ fn print_post_status() {
 #[panic_handler]
 #[allow(clippy::empty_loop)]
 fn panic(info: &PanicInfo) -> ! {
-    (*crate::hal::DEVICE_MANAGER
-        .lock()
-        .get_device("tty0")
-        .unwrap()
-        .lock())
-        .as_any()
-        .downcast_mut::<crate::vgaterm::TerminalDevice>()
-        .unwrap()
-        .clear();
-    println!(" * **KERNEL PANIC");
+    use core::fmt::Write;
+
+    let mut console = crate::vgaterm::panic_console();
+    write!(console, " * **KERNEL PANIC").ok();
     if let Some(location) = info.location() {
-        println!(" at {}", location);
+        write!(console, " at {}", location).ok();
     }
+    writeln!(console).ok();
     if let Some(message) = info.message() {
-        println!(
-            "    {}
-            ",
-            message
-        );
+        writeln!(console, "    {}", message).ok();
     } else {
-        println!("Unknown cause.");
+        writeln!(console, "Unknown cause.").ok();
     }
     loop {
         x86_64::instructions::hlt();
@@ -430,16 +548,10 @@ fn panic(info: &PanicInfo) -> ! {
 #[alloc_error_handler]
 #[no_mangle]
 pub extern "C" fn oom(_: ::core::alloc::Layout) -> ! {
-    (*crate::hal::DEVICE_MANAGER
-        .lock()
-        .get_device("tty0")
-        .unwrap()
-        .lock())
-        .as_any()
-        .downcast_mut::<crate::vgaterm::TerminalDevice>()
-        .unwrap()
-        .clear();
-    println!(" * **OUT OF MEMORY");
+    use core::fmt::Write;
+
+    let mut console = crate::vgaterm::panic_console();
+    writeln!(console, " * **OUT OF MEMORY").ok();
     loop {
         x86_64::instructions::hlt();
     }
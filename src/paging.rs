@@ -1,12 +1,13 @@
+use alloc::prelude::*;
 use bootloader::bootinfo::{BootInfo, FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::{
     structures::paging::{
-        FrameAllocator, Mapper, PageTable, PageTableFlags, PhysFrame, PhysFrameRange,
-        RecursivePageTable, Size4KiB,
+        FrameAllocator, MapToError, Mapper, Page, PageTable, PageTableFlags, PhysFrame,
+        PhysFrameRange, RecursivePageTable, Size4KiB,
     },
-    PhysAddr,
+    PhysAddr, VirtAddr,
 };
 
 /// Memory allocator
@@ -162,6 +163,7 @@ lazy_static! {
     pub static ref PAGING: Mutex<Paging> = Mutex::new(Paging {
         allocator: None,
         page_table: None,
+        recursive_index: 0,
     });
 }
 
@@ -169,6 +171,8 @@ lazy_static! {
 pub struct Paging {
     allocator: Option<Allocator>,
     page_table: Option<RecursivePageTable<'static>>,
+    /// The P4 entry that maps the page tables themselves
+    recursive_index: u64,
 }
 
 impl Paging {
@@ -180,6 +184,7 @@ impl Paging {
         let paging: &mut Paging = &mut *PAGING.lock();
         paging.allocator = Some(Allocator { memory_map: mmap });
         paging.page_table = page_table;
+        paging.recursive_index = (info.p4_table_addr >> 12) & 0o777;
     }
 
     /// Identity map the specified physical memory range
@@ -221,4 +226,118 @@ impl Paging {
             table.identity_map(frame, flags, alloc).unwrap().flush();
         }
     }
+
+    /// Get the regions of the boot memory map.
+    ///
+    /// Empty until paging is initialized.
+    pub fn memory_regions(&self) -> Vec<MemoryRegion> {
+        match self.allocator.as_ref() {
+            Some(alloc) => alloc.memory_map.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Test whether every page of a virtual memory range is mapped
+    pub fn is_mapped(&self, start: VirtAddr, size: u64) -> bool {
+        let table = match self.page_table.as_ref() {
+            Some(table) => table,
+            None => return false,
+        };
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(start + (size.max(1) - 1));
+        Page::range_inclusive(first, last).all(|page| table.translate_page(page).is_some())
+    }
+
+    /// Test whether user mode may access every page of a range.
+    ///
+    /// With `write`, the pages must be writable as well.
+    pub fn is_user_accessible(&self, start: VirtAddr, size: u64, write: bool) -> bool {
+        let mut needed = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if write {
+            needed |= PageTableFlags::WRITABLE;
+        }
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::<Size4KiB>::containing_address(start + (size.max(1) - 1));
+        Page::range_inclusive(first, last).all(|page| match self.page_flags(page) {
+            Some(flags) => flags.contains(needed),
+            None => false,
+        })
+    }
+
+    /// Get the flags that apply to a page.
+    ///
+    /// User access and writes are only allowed if every level
+    /// of the walk allows them, so those flags are combined.
+    fn page_flags(&self, page: Page) -> Option<PageTableFlags> {
+        if self.page_table.is_none() {
+            return None;
+        }
+        let addr = page.start_address().as_u64();
+        let indices = [
+            (addr >> 39) & 0o777,
+            (addr >> 30) & 0o777,
+            (addr >> 21) & 0o777,
+            (addr >> 12) & 0o777,
+        ];
+        let inherited = PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
+
+        let mut allowed = inherited;
+        for level in 0..4 {
+            let table = unsafe { &*(self.table_address(&indices[..level]) as *const PageTable) };
+            let flags = table[indices[level] as usize].flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            allowed &= flags;
+            // A huge page ends the walk early
+            if level == 3 || (level > 0 && flags.contains(PageTableFlags::HUGE_PAGE)) {
+                return Some(flags - inherited | allowed);
+            }
+        }
+        None
+    }
+
+    /// Get the address the page table at `indices` is mapped at.
+    ///
+    /// The recursive entry stands in for the missing levels.
+    fn table_address(&self, indices: &[u64]) -> u64 {
+        let mut addr = 0;
+        for level in 0..4 {
+            let index = match level.checked_sub(4 - indices.len()) {
+                Some(i) => indices[i],
+                None => self.recursive_index,
+            };
+            addr |= index << (39 - 9 * level);
+        }
+        // Sign extend like any canonical address
+        if addr & (1 << 47) != 0 {
+            addr |= 0xFFFF_0000_0000_0000;
+        }
+        addr
+    }
+
+    /// Identity map the frames holding a physical memory range.
+    ///
+    /// Frames that are mapped already are left alone, so this
+    /// also works for firmware memory the bootloader mapped.
+    pub fn identity_map_region(&mut self, start: PhysAddr, size: u64, flags: PageTableFlags) {
+        let table = self
+            .page_table
+            .as_mut()
+            .expect("Unable to unwrap page table. Initialize paging first!");
+        let alloc = self
+            .allocator
+            .as_mut()
+            .expect("Unable to unwrap memory allocator. Initialize paging first!");
+
+        let first = PhysFrame::<Size4KiB>::containing_address(start);
+        let last = PhysFrame::<Size4KiB>::containing_address(start + (size.max(1) - 1));
+        for frame in PhysFrame::range_inclusive(first, last) {
+            match table.identity_map(frame, flags, alloc) {
+                Ok(flush) => flush.flush(),
+                Err(MapToError::PageAlreadyMapped) => (),
+                Err(err) => panic!("Unable to map {:?}: {:?}", frame, err),
+            }
+        }
+    }
 }
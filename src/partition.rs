@@ -0,0 +1,373 @@
+//
+// Partition Tables
+//
+// Disks are scanned for a GUID Partition Table first. If
+// there is none, the MBR and its chain of extended boot
+// records is used. Each partition found is registered as
+// a block device of its own, named after its disk.
+//
+
+use alloc::prelude::*;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::block::{self, BlockDeviceHandle};
+use crate::hal::DeviceError;
+
+const MBR_SIGNATURE_OFFSET: usize = 510;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+
+// MBR partition types
+const MBR_TYPE_EMPTY: u8 = 0x00;
+const MBR_TYPE_EXTENDED_CHS: u8 = 0x05;
+const MBR_TYPE_EXTENDED_LBA: u8 = 0x0F;
+const MBR_TYPE_EXTENDED_LINUX: u8 = 0x85;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+
+/// The number of logical partitions we follow in an EBR chain
+const MBR_MAX_LOGICAL: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+const GPT_MAX_ENTRIES: usize = 1024;
+
+lazy_static! {
+    /// Every partition name handed out so far
+    static ref NAMES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+}
+
+/// What kind of partition an entry describes
+#[derive(Debug, Clone)]
+pub enum PartitionKind {
+    /// An MBR partition with its system id
+    MBR(u8),
+    /// A GPT partition
+    GPT {
+        type_guid: [u8; 16],
+        unique_guid: [u8; 16],
+        name: String,
+    },
+}
+
+/// A partition found on a disk
+#[derive(Debug, Clone)]
+pub struct Partition {
+    /// The partition number, starting at 1.
+    ///
+    /// Logical MBR partitions start at 5.
+    pub number: usize,
+    pub start: u64,
+    pub count: u64,
+    pub kind: PartitionKind,
+}
+
+/// Read the partitions of a disk.
+///
+/// Returns an empty list if the disk has no partition table.
+pub fn scan(disk: &BlockDeviceHandle) -> Result<Vec<Partition>, DeviceError> {
+    let size = disk.sector_size();
+    if size < 512 || disk.sector_count() < 2 {
+        return Ok(Vec::new());
+    }
+
+    let mut mbr = vec![0u8; size];
+    disk.read(0, &mut mbr)?;
+    if mbr[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+
+    let protective = mbr_entries(&mbr)
+        .iter()
+        .any(|&(kind, _, _)| kind == MBR_TYPE_GPT_PROTECTIVE);
+    if protective {
+        if let Some(partitions) = scan_gpt(disk)? {
+            return Ok(partitions);
+        }
+        log!(warn: "{} has a protective MBR but no valid GPT.", disk.name());
+    }
+
+    scan_mbr(disk, &mbr)
+}
+
+/// Scan all disks and register their partitions.
+///
+/// Returns the number of partitions registered.
+pub fn probe_all() -> usize {
+    let mut registered = 0;
+    for disk in block::devices().iter().filter(|d| !d.is_partition()) {
+        let partitions = match scan(disk) {
+            Ok(partitions) => partitions,
+            Err(err) => {
+                log!(error: "Unable to read partition table of {}: {}", disk.name(), err);
+                continue;
+            }
+        };
+
+        for part in partitions {
+            // Disks ending in a digit get a separator, like nvme0n1p1
+            let separator = match disk.name().chars().last() {
+                Some(c) if c.is_ascii_digit() => "p",
+                _ => "",
+            };
+            let name = partition_name(&format!("{}{}{}", disk.name(), separator, part.number));
+
+            match block::register_partition(disk, name, part.start, part.count) {
+                Ok(_) => {
+                    log!(debug: "Partition {}: {:?}", name, part.kind);
+                    registered += 1;
+                }
+                Err(err) => log!(error: "Unable to register partition: {}", err),
+            }
+        }
+    }
+    registered
+}
+
+/// Get a static copy of a partition name.
+///
+/// Disk names are reused, so the names of their partitions
+/// repeat as well and are only allocated the first time.
+fn partition_name(name: &str) -> &'static str {
+    let mut names = NAMES.lock();
+    if let Some(&known) = names.iter().find(|known| **known == name) {
+        return known;
+    }
+    let name: &'static str = Box::leak(name.to_string().into_boxed_str());
+    names.push(name);
+    name
+}
+
+//
+// MBR
+//
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    (0..4).fold(0, |acc, i| acc | (buf[offset + i] as u32) << (8 * i))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    (0..8).fold(0, |acc, i| acc | (buf[offset + i] as u64) << (8 * i))
+}
+
+/// Get the (type, start, count) triples of an MBR or EBR
+fn mbr_entries(sector: &[u8]) -> [(u8, u64, u64); 4] {
+    let mut entries = [(MBR_TYPE_EMPTY, 0, 0); 4];
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let offset = MBR_TABLE_OFFSET + i * MBR_ENTRY_SIZE;
+        *entry = (
+            sector[offset + 4],
+            read_u32(sector, offset + 8) as u64,
+            read_u32(sector, offset + 12) as u64,
+        );
+    }
+    entries
+}
+
+fn is_extended(kind: u8) -> bool {
+    kind == MBR_TYPE_EXTENDED_CHS
+        || kind == MBR_TYPE_EXTENDED_LBA
+        || kind == MBR_TYPE_EXTENDED_LINUX
+}
+
+fn scan_mbr(disk: &BlockDeviceHandle, mbr: &[u8]) -> Result<Vec<Partition>, DeviceError> {
+    let mut partitions = Vec::new();
+
+    for (i, &(kind, start, count)) in mbr_entries(mbr).iter().enumerate() {
+        if kind == MBR_TYPE_EMPTY || count == 0 || kind == MBR_TYPE_GPT_PROTECTIVE {
+            continue;
+        }
+        if is_extended(kind) {
+            scan_ebr_chain(disk, start, &mut partitions)?;
+            continue;
+        }
+        if start + count > disk.sector_count() {
+            log!(warn: "{}: partition {} exceeds the disk.", disk.name(), i + 1);
+            continue;
+        }
+        partitions.push(Partition {
+            number: i + 1,
+            start,
+            count,
+            kind: PartitionKind::MBR(kind),
+        });
+    }
+
+    Ok(partitions)
+}
+
+/// Follow the linked list of extended boot records.
+///
+/// Logical partitions are relative to their EBR, links
+/// to the next EBR are relative to the extended partition.
+fn scan_ebr_chain(
+    disk: &BlockDeviceHandle,
+    base: u64,
+    partitions: &mut Vec<Partition>,
+) -> Result<(), DeviceError> {
+    let mut sector = vec![0u8; disk.sector_size()];
+    let mut ebr = base;
+
+    for number in 5..5 + MBR_MAX_LOGICAL {
+        if ebr >= disk.sector_count() {
+            break;
+        }
+        disk.read(ebr, &mut sector)?;
+        if sector[MBR_SIGNATURE_OFFSET..MBR_SIGNATURE_OFFSET + 2] != MBR_SIGNATURE {
+            break;
+        }
+
+        let entries = mbr_entries(&sector);
+        let (kind, start, count) = entries[0];
+        if kind != MBR_TYPE_EMPTY && count != 0 && ebr + start + count <= disk.sector_count() {
+            partitions.push(Partition {
+                number,
+                start: ebr + start,
+                count,
+                kind: PartitionKind::MBR(kind),
+            });
+        }
+
+        let (next_kind, next, _) = entries[1];
+        // A link pointing backwards would loop forever
+        if !is_extended(next_kind) || next == 0 || base + next <= ebr {
+            break;
+        }
+        ebr = base + next;
+    }
+
+    Ok(())
+}
+
+//
+// GPT
+//
+
+/// CRC-32 as used by GPT (IEEE 802.3, reflected)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Read the GPT, falling back to the backup header.
+///
+/// Returns `None` if neither header is valid.
+fn scan_gpt(disk: &BlockDeviceHandle) -> Result<Option<Vec<Partition>>, DeviceError> {
+    let last = disk.sector_count() - 1;
+    for &lba in [1, last].iter() {
+        match read_gpt(disk, lba)? {
+            Some(partitions) => {
+                if lba != 1 {
+                    log!(warn: "{}: primary GPT is damaged, using the backup.", disk.name());
+                }
+                return Ok(Some(partitions));
+            }
+            None => continue,
+        }
+    }
+    Ok(None)
+}
+
+/// Read and validate the GPT header at `lba` and its entries
+fn read_gpt(disk: &BlockDeviceHandle, lba: u64) -> Result<Option<Vec<Partition>>, DeviceError> {
+    let size = disk.sector_size();
+    let mut header = vec![0u8; size];
+    disk.read(lba, &mut header)?;
+
+    if &header[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+    let header_size = read_u32(&header, 12) as usize;
+    if header_size < GPT_HEADER_MIN_SIZE || header_size > size {
+        return Ok(None);
+    }
+
+    // The checksum covers the header with the checksum field zeroed
+    let header_crc = read_u32(&header, 16);
+    let mut check = header[..header_size].to_vec();
+    check[16..20].copy_from_slice(&[0; 4]);
+    if crc32(&check) != header_crc || read_u64(&header, 24) != lba {
+        return Ok(None);
+    }
+
+    let first_usable = read_u64(&header, 40);
+    let last_usable = read_u64(&header, 48);
+    if first_usable > last_usable || last_usable >= disk.sector_count() {
+        return Ok(None);
+    }
+    let entries_lba = read_u64(&header, 72);
+    let entry_count = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+    let entries_crc = read_u32(&header, 88);
+    if entry_size < GPT_ENTRY_MIN_SIZE || entry_size % 8 != 0 || entry_count > GPT_MAX_ENTRIES {
+        return Ok(None);
+    }
+
+    // Read the entry array
+    let bytes = entry_count * entry_size;
+    let sectors = (bytes + size - 1) / size;
+    match entries_lba.checked_add(sectors as u64) {
+        Some(end) if end <= disk.sector_count() => (),
+        _ => return Ok(None),
+    }
+    let mut entries = vec![0u8; sectors * size];
+    disk.read(entries_lba, &mut entries)?;
+    if crc32(&entries[..bytes]) != entries_crc {
+        return Ok(None);
+    }
+
+    let mut partitions = Vec::new();
+    for i in 0..entry_count {
+        let entry = &entries[i * entry_size..(i + 1) * entry_size];
+        let mut type_guid = [0u8; 16];
+        type_guid.copy_from_slice(&entry[0..16]);
+        if type_guid == [0; 16] {
+            continue;
+        }
+        let mut unique_guid = [0u8; 16];
+        unique_guid.copy_from_slice(&entry[16..32]);
+
+        let start = read_u64(entry, 32);
+        let end = read_u64(entry, 40);
+        // `last_usable` is on the disk, so the count cannot overflow
+        if start < first_usable || end > last_usable || end < start {
+            log!(warn: "{}: GPT entry {} is out of bounds.", disk.name(), i + 1);
+            continue;
+        }
+
+        // The name is UTF-16LE, padded with zeros
+        let units: Vec<u16> = entry[56..128]
+            .chunks(2)
+            .map(|c| c[0] as u16 | (c[1] as u16) << 8)
+            .take_while(|&unit| unit != 0)
+            .collect();
+        let name = core::char::decode_utf16(units.iter().cloned())
+            .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+            .collect();
+
+        partitions.push(Partition {
+            number: i + 1,
+            start,
+            count: end - start + 1,
+            kind: PartitionKind::GPT {
+                type_guid,
+                unique_guid,
+                name,
+            },
+        });
+    }
+
+    Ok(Some(partitions))
+}
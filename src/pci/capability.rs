@@ -0,0 +1,639 @@
+use alloc::{collections::BTreeMap, prelude::*};
+use core::ptr;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{structures::paging::PageTableFlags, PhysAddr};
+
+use super::{
+    PCIDevice, PCIDeviceAddress, PCIStatus, LEGACY_SPACE_SIZE, PCIFIELD_CAPABILITIES_POINTER,
+};
+use crate::idt::IDT;
+use crate::lapic::LocalAPIC;
+use crate::paging::PAGING;
+
+/// Power Management
+pub const CAP_PM: u8 = 0x01;
+/// Message Signalled Interrupts
+pub const CAP_MSI: u8 = 0x05;
+/// Extended Message Signalled Interrupts
+pub const CAP_MSIX: u8 = 0x11;
+
+/// At most this many capabilities fit in the legacy space
+const CAP_MAX: usize = (256 - 64) / 4;
+
+// Power management registers, relative to the capability
+const PM_CAPABILITIES: u8 = 0x02;
+const PM_CONTROL: u8 = 0x04;
+
+const PM_CAP_D1: u16 = 1 << 9;
+const PM_CAP_D2: u16 = 1 << 10;
+const PM_CONTROL_STATE_MASK: u16 = 0x3;
+const PM_CONTROL_PME_STATUS: u16 = 1 << 15;
+
+// MSI registers, relative to the capability
+const MSI_CONTROL: u8 = 0x02;
+const MSI_ADDRESS_LOW: u8 = 0x04;
+const MSI_ADDRESS_HIGH: u8 = 0x08;
+
+const MSI_CONTROL_ENABLE: u16 = 1 << 0;
+const MSI_CONTROL_MULTIPLE_ENABLE: u16 = 0x7 << 4;
+const MSI_CONTROL_64BIT: u16 = 1 << 7;
+const MSI_CONTROL_PER_VECTOR_MASK: u16 = 1 << 8;
+
+// MSI-X registers, relative to the capability
+const MSIX_CONTROL: u8 = 0x02;
+const MSIX_TABLE: u8 = 0x04;
+const MSIX_PBA: u8 = 0x08;
+
+const MSIX_CONTROL_TABLE_SIZE: u16 = 0x7FF;
+const MSIX_CONTROL_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_CONTROL_ENABLE: u16 = 1 << 15;
+const MSIX_BIR_MASK: u32 = 0x7;
+
+// MSI-X table entries
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_ADDRESS_LOW: u64 = 0x0;
+const MSIX_ENTRY_ADDRESS_HIGH: u64 = 0x4;
+const MSIX_ENTRY_DATA: u64 = 0x8;
+const MSIX_ENTRY_CONTROL: u64 = 0xC;
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+lazy_static! {
+    /// The mapped MSI-X vector table of each function
+    static ref MSIX_TABLES: Mutex<BTreeMap<PCIDeviceAddress, u64>> = Mutex::new(BTreeMap::new());
+}
+
+/// Advanced Error Reporting
+pub const EXT_CAP_AER: u16 = 0x0001;
+/// Single Root I/O Virtualization
+pub const EXT_CAP_SRIOV: u16 = 0x0010;
+
+/// Extended capabilities start right after the legacy space
+const EXT_CAP_START: u16 = LEGACY_SPACE_SIZE as u16;
+
+/// At most this many extended capabilities fit in 4 KiB
+const EXT_CAP_MAX: usize = (4096 - 256) / 4;
+
+// AER registers, relative to the capability
+const AER_UNCORRECTABLE_STATUS: u16 = 0x04;
+const AER_UNCORRECTABLE_MASK: u16 = 0x08;
+const AER_UNCORRECTABLE_SEVERITY: u16 = 0x0C;
+const AER_CORRECTABLE_STATUS: u16 = 0x10;
+const AER_CORRECTABLE_MASK: u16 = 0x14;
+
+// SR-IOV registers, relative to the capability
+const SRIOV_CONTROL: u16 = 0x08;
+const SRIOV_INITIAL_VFS: u16 = 0x0C;
+const SRIOV_TOTAL_VFS: u16 = 0x0E;
+const SRIOV_NUM_VFS: u16 = 0x10;
+const SRIOV_VF_OFFSET: u16 = 0x14;
+const SRIOV_VF_STRIDE: u16 = 0x16;
+const SRIOV_VF_DEVICE_ID: u16 = 0x1A;
+
+/// A capability in the extended configuration space
+#[derive(Debug, Clone, Copy)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    #[allow(dead_code)]
+    pub version: u8,
+    /// The offset of the capability header
+    pub offset: u16,
+}
+
+/// A capability in the standard capability list
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    pub id: u8,
+    /// The offset of the capability header
+    pub offset: u8,
+}
+
+impl PCIDevice {
+    /// Get the capabilities in the standard capability list
+    pub fn capabilities(&self) -> Vec<Capability> {
+        let mut caps = Vec::new();
+        if !self.status().contains(PCIStatus::CAPABILITIES) {
+            return caps;
+        }
+
+        // The bottom two bits are reserved; offsets below 0x40 end the list
+        let mut offset = self.read8(PCIFIELD_CAPABILITIES_POINTER) & !0x3;
+        while offset >= 0x40 && caps.len() < CAP_MAX {
+            caps.push(Capability {
+                id: self.read8(offset),
+                offset,
+            });
+            offset = self.read8(offset + 1) & !0x3;
+        }
+        caps
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<Capability> {
+        self.capabilities().into_iter().find(|cap| cap.id == id)
+    }
+
+    /// Get the power management registers, if any
+    #[allow(dead_code)]
+    pub fn power_management(&self) -> Option<PowerManagement> {
+        self.find_capability(CAP_PM).map(|cap| PowerManagement {
+            device: *self,
+            offset: cap.offset,
+        })
+    }
+
+    /// Get the MSI registers, if any
+    pub fn msi(&self) -> Option<MSI> {
+        self.find_capability(CAP_MSI).map(|cap| MSI {
+            device: *self,
+            offset: cap.offset,
+        })
+    }
+
+    /// Get the MSI-X registers, if any.
+    ///
+    /// This maps the vector table on the first lookup. Functions
+    /// whose table cannot be reached are treated as not having MSI-X.
+    #[allow(dead_code)]
+    pub fn msix(&self) -> Option<MSIX> {
+        let cap = self.find_capability(CAP_MSIX)?;
+        match MSIX::new(*self, cap.offset) {
+            Ok(msix) => Some(msix),
+            Err(err) => {
+                log!(warn: "{}: MSI-X unusable: {}", self.address, err);
+                None
+            }
+        }
+    }
+
+    /// Get the extended capabilities of a PCI Express function.
+    ///
+    /// Empty if the extended configuration space is not reachable.
+    pub fn extended_capabilities(&self) -> Vec<ExtendedCapability> {
+        let mut caps = Vec::new();
+        if self.config_space_size() <= LEGACY_SPACE_SIZE {
+            return caps;
+        }
+
+        let mut offset = EXT_CAP_START;
+        while offset >= EXT_CAP_START && caps.len() < EXT_CAP_MAX {
+            let header = self.read_extended32(offset & !0x3);
+            // Functions without extended capabilities read as 0 or all ones
+            if header == 0 || header == 0xFFFF_FFFF {
+                break;
+            }
+            caps.push(ExtendedCapability {
+                id: header as u16,
+                version: (header >> 16 & 0xF) as u8,
+                offset: offset & !0x3,
+            });
+            offset = (header >> 20) as u16;
+        }
+        caps
+    }
+
+    pub fn find_extended_capability(&self, id: u16) -> Option<ExtendedCapability> {
+        self.extended_capabilities()
+            .into_iter()
+            .find(|cap| cap.id == id)
+    }
+
+    /// Get the Advanced Error Reporting registers, if any
+    #[allow(dead_code)]
+    pub fn aer(&self) -> Option<AER> {
+        self.find_extended_capability(EXT_CAP_AER).map(|cap| AER {
+            device: *self,
+            offset: cap.offset,
+        })
+    }
+
+    /// Get the SR-IOV registers, if any
+    #[allow(dead_code)]
+    pub fn sriov(&self) -> Option<SRIOV> {
+        self.find_extended_capability(EXT_CAP_SRIOV)
+            .map(|cap| SRIOV {
+                device: *self,
+                offset: cap.offset,
+            })
+    }
+}
+
+/// A device power state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+/// The power management capability of a function
+#[derive(Debug, Clone, Copy)]
+pub struct PowerManagement {
+    device: PCIDevice,
+    offset: u8,
+}
+
+#[allow(dead_code)]
+impl PowerManagement {
+    pub fn supports(&self, state: PowerState) -> bool {
+        let caps = self.device.read16(self.offset + PM_CAPABILITIES);
+        match state {
+            PowerState::D1 => caps & PM_CAP_D1 != 0,
+            PowerState::D2 => caps & PM_CAP_D2 != 0,
+            PowerState::D0 | PowerState::D3Hot => true,
+        }
+    }
+
+    pub fn state(&self) -> PowerState {
+        match self.device.read16(self.offset + PM_CONTROL) & PM_CONTROL_STATE_MASK {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+
+    /// Put the function into a power state.
+    ///
+    /// Functions need up to 10 ms to leave D3hot.
+    pub fn set_state(&self, state: PowerState) -> Result<(), &'static str> {
+        if !self.supports(state) {
+            return Err("Power state is not supported");
+        }
+        let bits = match state {
+            PowerState::D0 => 0,
+            PowerState::D1 => 1,
+            PowerState::D2 => 2,
+            PowerState::D3Hot => 3,
+        };
+        // Writing the PME status back would clear it
+        let control = self.device.read16(self.offset + PM_CONTROL)
+            & !(PM_CONTROL_STATE_MASK | PM_CONTROL_PME_STATUS);
+        self.device
+            .write16(self.offset + PM_CONTROL, control | bits);
+        Ok(())
+    }
+}
+
+/// The MSI capability of a function.
+///
+/// Only single-message MSI is used, so every function gets
+/// one vector. Functions needing more should use MSI-X.
+#[derive(Debug, Clone, Copy)]
+pub struct MSI {
+    device: PCIDevice,
+    offset: u8,
+}
+
+impl MSI {
+    fn control(&self) -> u16 {
+        self.device.read16(self.offset + MSI_CONTROL)
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        self.control() & MSI_CONTROL_64BIT != 0
+    }
+
+    #[allow(dead_code)]
+    pub fn is_enabled(&self) -> bool {
+        self.control() & MSI_CONTROL_ENABLE != 0
+    }
+
+    /// Whether the vectors can be masked individually
+    #[allow(dead_code)]
+    pub fn has_per_vector_mask(&self) -> bool {
+        self.control() & MSI_CONTROL_PER_VECTOR_MASK != 0
+    }
+
+    /// Get the number of vectors the function asks for
+    #[allow(dead_code)]
+    pub fn requested_vectors(&self) -> usize {
+        1 << ((self.control() >> 1) & 0x7)
+    }
+
+    fn data_offset(&self) -> u8 {
+        self.offset + if self.is_64bit() { 0x0C } else { 0x08 }
+    }
+
+    /// Make the function raise `vector` on this processor
+    pub fn configure(&self, vector: u8) {
+        let (address, data) = LocalAPIC::msi_message(vector);
+        self.device
+            .write32(self.offset + MSI_ADDRESS_LOW, address as u32);
+        if self.is_64bit() {
+            self.device
+                .write32(self.offset + MSI_ADDRESS_HIGH, (address >> 32) as u32);
+        }
+        self.device.write16(self.data_offset(), data as u16);
+    }
+
+    pub fn enable(&self) {
+        let control = self.control() & !MSI_CONTROL_MULTIPLE_ENABLE;
+        self.device
+            .write16(self.offset + MSI_CONTROL, control | MSI_CONTROL_ENABLE);
+        self.device.disable_intx();
+    }
+
+    pub fn disable(&self) {
+        let control = self.control();
+        self.device
+            .write16(self.offset + MSI_CONTROL, control & !MSI_CONTROL_ENABLE);
+    }
+
+    /// Allocate a vector for `handler` and switch the function to MSI.
+    ///
+    /// Returns the vector, which is passed to the handler.
+    pub fn allocate(&self, handler: fn(u8)) -> Result<u8, &'static str> {
+        let vector = IDT::allocate_vector(handler)?;
+        self.configure(vector);
+        self.enable();
+        Ok(vector)
+    }
+}
+
+/// The MSI-X capability of a function.
+///
+/// The vector table lives in one of the function's memory BARs
+/// and is mapped once, when the capability is first looked up.
+#[derive(Debug, Clone, Copy)]
+pub struct MSIX {
+    device: PCIDevice,
+    offset: u8,
+    /// The address of the mapped vector table
+    table: u64,
+    entries: usize,
+}
+
+#[allow(dead_code)]
+impl MSIX {
+    fn new(device: PCIDevice, offset: u8) -> Result<Self, &'static str> {
+        let mut msix = MSIX {
+            device,
+            offset,
+            table: 0,
+            entries: 0,
+        };
+        msix.entries = (msix.control() & MSIX_CONTROL_TABLE_SIZE) as usize + 1;
+
+        let mut tables = MSIX_TABLES.lock();
+        msix.table = match tables.get(&device.address) {
+            Some(&table) => table,
+            None => {
+                let table = msix.locate(MSIX_TABLE)?;
+                PAGING.lock().identity_map_region(
+                    PhysAddr::new(table),
+                    msix.entries as u64 * MSIX_ENTRY_SIZE,
+                    PageTableFlags::PRESENT
+                        | PageTableFlags::WRITABLE
+                        | PageTableFlags::NO_CACHE
+                        | PageTableFlags::WRITE_THROUGH,
+                );
+                tables.insert(device.address, table);
+                table
+            }
+        };
+        Ok(msix)
+    }
+
+    fn control(&self) -> u16 {
+        self.device.read16(self.offset + MSIX_CONTROL)
+    }
+
+    fn set_control(&self, set: u16, clear: u16) {
+        let control = self.control();
+        self.device
+            .write16(self.offset + MSIX_CONTROL, control & !clear | set);
+    }
+
+    /// Get the number of entries in the vector table
+    pub fn table_size(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.control() & MSIX_CONTROL_ENABLE != 0
+    }
+
+    /// Get the physical address of a structure given by a BAR
+    /// indicator and offset register.
+    ///
+    /// The BAR comes from the enumeration, since sizing a live
+    /// BAR would turn off decoding while the device runs.
+    fn locate(&self, reg: u8) -> Result<u64, &'static str> {
+        let raw = self.device.read32(self.offset + reg);
+        let bar = super::get(self.device.address)
+            .and_then(|function| function.bars.get((raw & MSIX_BIR_MASK) as usize).cloned())
+            .and_then(|bar| bar)
+            .ok_or("MSI-X table BAR is not implemented")?;
+        if !bar.is_mmio() || bar.addr() == 0 {
+            return Err("MSI-X table BAR is not memory mapped");
+        }
+        Ok(bar.addr() + u64::from(raw & !MSIX_BIR_MASK))
+    }
+
+    /// Get the physical address of the pending bit array
+    pub fn pending_bit_array(&self) -> Result<u64, &'static str> {
+        self.locate(MSIX_PBA)
+    }
+
+    fn entry(&self, index: usize) -> Result<u64, &'static str> {
+        if index >= self.entries {
+            return Err("MSI-X table entry out of range");
+        }
+        Ok(self.table + index as u64 * MSIX_ENTRY_SIZE)
+    }
+
+    /// Make table entry `index` raise `vector` on this processor.
+    ///
+    /// The entry stays masked until it is unmasked.
+    pub fn configure(&self, index: usize, vector: u8) -> Result<(), &'static str> {
+        let entry = self.entry(index)?;
+        let (address, data) = LocalAPIC::msi_message(vector);
+        unsafe {
+            let control = ptr::read_volatile((entry + MSIX_ENTRY_CONTROL) as *const u32);
+            ptr::write_volatile(
+                (entry + MSIX_ENTRY_CONTROL) as *mut u32,
+                control | MSIX_ENTRY_MASKED,
+            );
+            ptr::write_volatile((entry + MSIX_ENTRY_ADDRESS_LOW) as *mut u32, address as u32);
+            ptr::write_volatile(
+                (entry + MSIX_ENTRY_ADDRESS_HIGH) as *mut u32,
+                (address >> 32) as u32,
+            );
+            ptr::write_volatile((entry + MSIX_ENTRY_DATA) as *mut u32, data);
+        }
+        Ok(())
+    }
+
+    pub fn mask(&self, index: usize) -> Result<(), &'static str> {
+        self.update_entry_control(index, |control| control | MSIX_ENTRY_MASKED)
+    }
+
+    pub fn unmask(&self, index: usize) -> Result<(), &'static str> {
+        self.update_entry_control(index, |control| control & !MSIX_ENTRY_MASKED)
+    }
+
+    fn update_entry_control<F: FnOnce(u32) -> u32>(
+        &self,
+        index: usize,
+        f: F,
+    ) -> Result<(), &'static str> {
+        let control = (self.entry(index)? + MSIX_ENTRY_CONTROL) as *mut u32;
+        unsafe { ptr::write_volatile(control, f(ptr::read_volatile(control))) }
+        Ok(())
+    }
+
+    /// Enable MSI-X with all entries masked by the function mask
+    pub fn enable(&self) {
+        self.set_control(MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK, 0);
+        self.device.disable_intx();
+    }
+
+    pub fn disable(&self) {
+        self.set_control(0, MSIX_CONTROL_ENABLE);
+    }
+
+    /// Mask or unmask all entries at once
+    pub fn set_function_mask(&self, masked: bool) {
+        match masked {
+            true => self.set_control(MSIX_CONTROL_FUNCTION_MASK, 0),
+            false => self.set_control(0, MSIX_CONTROL_FUNCTION_MASK),
+        }
+    }
+
+    /// Allocate a vector for each handler and switch the function to MSI-X.
+    ///
+    /// Handler `i` is bound to table entry `i`. Returns the vectors,
+    /// which are passed to the handlers.
+    pub fn allocate(&self, handlers: &[fn(u8)]) -> Result<Vec<u8>, &'static str> {
+        if handlers.len() > self.table_size() {
+            return Err("Too many MSI-X vectors requested");
+        }
+
+        self.enable();
+        let mut vectors = Vec::new();
+        for (index, &handler) in handlers.iter().enumerate() {
+            let result = IDT::allocate_vector(handler).and_then(|vector| {
+                vectors.push(vector);
+                self.configure(index, vector)
+            });
+            if let Err(err) = result {
+                self.disable();
+                for &vector in vectors.iter() {
+                    IDT::free_vector(vector);
+                }
+                return Err(err);
+            }
+        }
+
+        for index in 0..vectors.len() {
+            self.unmask(index)?;
+        }
+        self.set_function_mask(false);
+        Ok(vectors)
+    }
+}
+
+/// The Advanced Error Reporting capability of a function
+#[derive(Debug, Clone, Copy)]
+pub struct AER {
+    device: PCIDevice,
+    offset: u16,
+}
+
+#[allow(dead_code)]
+impl AER {
+    fn read(&self, reg: u16) -> u32 {
+        self.device.read_extended32(self.offset + reg)
+    }
+
+    fn write(&self, reg: u16, val: u32) {
+        self.device.write_extended32(self.offset + reg, val)
+    }
+
+    pub fn uncorrectable_status(&self) -> u32 {
+        self.read(AER_UNCORRECTABLE_STATUS)
+    }
+
+    pub fn uncorrectable_mask(&self) -> u32 {
+        self.read(AER_UNCORRECTABLE_MASK)
+    }
+
+    pub fn set_uncorrectable_mask(&self, mask: u32) {
+        self.write(AER_UNCORRECTABLE_MASK, mask)
+    }
+
+    /// Get which uncorrectable errors are fatal
+    pub fn uncorrectable_severity(&self) -> u32 {
+        self.read(AER_UNCORRECTABLE_SEVERITY)
+    }
+
+    pub fn correctable_status(&self) -> u32 {
+        self.read(AER_CORRECTABLE_STATUS)
+    }
+
+    pub fn correctable_mask(&self) -> u32 {
+        self.read(AER_CORRECTABLE_MASK)
+    }
+
+    pub fn set_correctable_mask(&self, mask: u32) {
+        self.write(AER_CORRECTABLE_MASK, mask)
+    }
+
+    /// Clear all reported errors; status bits are write-1-to-clear
+    pub fn clear_status(&self) {
+        let uncorrectable = self.uncorrectable_status();
+        self.write(AER_UNCORRECTABLE_STATUS, uncorrectable);
+        let correctable = self.correctable_status();
+        self.write(AER_CORRECTABLE_STATUS, correctable);
+    }
+}
+
+/// The Single Root I/O Virtualization capability of a function
+#[derive(Debug, Clone, Copy)]
+pub struct SRIOV {
+    device: PCIDevice,
+    offset: u16,
+}
+
+#[allow(dead_code)]
+impl SRIOV {
+    fn read32(&self, reg: u16) -> u32 {
+        self.device.read_extended32(self.offset + reg)
+    }
+
+    fn read16(&self, reg: u16) -> u16 {
+        let aligned = reg & !0x3;
+        (self.read32(aligned) >> ((reg - aligned) * 8)) as u16
+    }
+
+    pub fn control(&self) -> u16 {
+        self.read16(SRIOV_CONTROL)
+    }
+
+    pub fn initial_vfs(&self) -> u16 {
+        self.read16(SRIOV_INITIAL_VFS)
+    }
+
+    pub fn total_vfs(&self) -> u16 {
+        self.read16(SRIOV_TOTAL_VFS)
+    }
+
+    pub fn num_vfs(&self) -> u16 {
+        self.read16(SRIOV_NUM_VFS)
+    }
+
+    /// Get the routing ID offset of the first virtual function
+    pub fn vf_offset(&self) -> u16 {
+        self.read16(SRIOV_VF_OFFSET)
+    }
+
+    /// Get the routing ID distance between virtual functions
+    pub fn vf_stride(&self) -> u16 {
+        self.read16(SRIOV_VF_STRIDE)
+    }
+
+    pub fn vf_device_id(&self) -> u16 {
+        self.read16(SRIOV_VF_DEVICE_ID)
+    }
+}
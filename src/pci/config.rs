@@ -0,0 +1,256 @@
+use alloc::{boxed::Box, collections::BTreeSet, prelude::*};
+use core::ptr;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{instructions::port::Port, structures::paging::PageTableFlags, PhysAddr};
+
+use super::PCIDeviceAddress;
+use crate::acpi;
+use crate::paging::PAGING;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+/// The size of the configuration space reachable through ports
+pub const LEGACY_SPACE_SIZE: usize = 256;
+
+/// The size of the configuration space of PCI Express functions
+pub const EXTENDED_SPACE_SIZE: usize = 4096;
+
+/// The size of an MCFG allocation entry
+const MCFG_ENTRY_SIZE: usize = 16;
+
+/// MCFG entries start after the header and 8 reserved bytes
+const MCFG_ENTRIES_OFFSET: usize = 8;
+
+lazy_static! {
+    static ref PORTS: Mutex<(Port<u32>, Port<u32>)> =
+        Mutex::new((Port::new(CONFIG_ADDRESS), Port::new(CONFIG_DATA)));
+    static ref ACCESS: Box<dyn ConfigAccess> = select();
+}
+
+/// A way to reach the configuration space of functions
+pub trait ConfigAccess: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Get the number of bytes of configuration space of a function
+    fn space_size(&self, address: &PCIDeviceAddress) -> usize;
+
+    /// Read a dword at a dword-aligned offset
+    unsafe fn read32(&self, address: &PCIDeviceAddress, offset: u16) -> u32;
+
+    /// Write a dword at a dword-aligned offset
+    unsafe fn write32(&self, address: &PCIDeviceAddress, offset: u16, val: u32);
+
+    /// Write a word at a word-aligned offset
+    unsafe fn write16(&self, address: &PCIDeviceAddress, offset: u16, val: u16);
+
+    unsafe fn write8(&self, address: &PCIDeviceAddress, offset: u16, val: u8);
+}
+
+/// Get the configuration access mechanism in use
+pub fn access() -> &'static dyn ConfigAccess {
+    &**ACCESS
+}
+
+/// Use ECAM if the firmware describes it, ports otherwise
+fn select() -> Box<dyn ConfigAccess> {
+    match ECAM::from_mcfg() {
+        Some(ecam) => {
+            for region in ecam.regions.iter() {
+                log!(
+                    debug: "PCI ECAM at 0x{:x} for buses {}-{}.",
+                    region.base,
+                    region.start_bus,
+                    region.end_bus
+                );
+            }
+            box ecam
+        }
+        None => {
+            log!(debug: "No PCI ECAM found, using configuration ports.");
+            box PortIO
+        }
+    }
+}
+
+/// Configuration mechanism #1 through ports `0xCF8` and `0xCFC`
+pub struct PortIO;
+
+impl PortIO {
+    fn select(address: &PCIDeviceAddress, offset: u16) -> u32 {
+        u32::from(address) | u32::from(offset & 0xFC)
+    }
+}
+
+impl ConfigAccess for PortIO {
+    fn name(&self) -> &'static str {
+        "ports"
+    }
+
+    fn space_size(&self, _address: &PCIDeviceAddress) -> usize {
+        LEGACY_SPACE_SIZE
+    }
+
+    unsafe fn read32(&self, address: &PCIDeviceAddress, offset: u16) -> u32 {
+        assert!(offset & 0x3 == 0);
+        if offset as usize >= LEGACY_SPACE_SIZE {
+            return 0xFFFF_FFFF;
+        }
+        let mut ports = PORTS.lock();
+        ports.0.write(PortIO::select(address, offset));
+        ports.1.read()
+    }
+
+    unsafe fn write32(&self, address: &PCIDeviceAddress, offset: u16, val: u32) {
+        assert!(offset & 0x3 == 0);
+        if offset as usize >= LEGACY_SPACE_SIZE {
+            return;
+        }
+        let mut ports = PORTS.lock();
+        ports.0.write(PortIO::select(address, offset));
+        ports.1.write(val);
+    }
+
+    unsafe fn write16(&self, address: &PCIDeviceAddress, offset: u16, val: u16) {
+        assert!(offset & 0x1 == 0);
+        if offset as usize >= LEGACY_SPACE_SIZE {
+            return;
+        }
+        let mut ports = PORTS.lock();
+        ports.0.write(PortIO::select(address, offset));
+        Port::<u16>::new(CONFIG_DATA + (offset & 0x3)).write(val);
+    }
+
+    unsafe fn write8(&self, address: &PCIDeviceAddress, offset: u16, val: u8) {
+        if offset as usize >= LEGACY_SPACE_SIZE {
+            return;
+        }
+        let mut ports = PORTS.lock();
+        ports.0.write(PortIO::select(address, offset));
+        Port::<u8>::new(CONFIG_DATA + (offset & 0x3)).write(val);
+    }
+}
+
+/// A range of buses with memory-mapped configuration space
+#[derive(Debug, Clone, Copy)]
+struct ECAMRegion {
+    base: u64,
+    segment: u16,
+    start_bus: u8,
+    end_bus: u8,
+}
+
+/// The PCI Express enhanced configuration access mechanism.
+///
+/// Every function has a 4 KiB page of configuration space.
+/// Pages are mapped on first access. Buses outside the
+/// regions from the MCFG table are reached through ports.
+pub struct ECAM {
+    regions: Vec<ECAMRegion>,
+    mapped: Mutex<BTreeSet<u64>>,
+}
+
+impl ECAM {
+    /// Get the regions described by the ACPI MCFG table
+    fn from_mcfg() -> Option<ECAM> {
+        let table = acpi::find_table(b"MCFG")?;
+        let body = table.body();
+        if body.len() < MCFG_ENTRIES_OFFSET {
+            return None;
+        }
+
+        let regions: Vec<ECAMRegion> = body[MCFG_ENTRIES_OFFSET..]
+            .chunks(MCFG_ENTRY_SIZE)
+            .filter(|entry| entry.len() == MCFG_ENTRY_SIZE)
+            .map(|entry| ECAMRegion {
+                base: (0..8).fold(0, |acc, i| acc | (entry[i] as u64) << (8 * i)),
+                segment: entry[8] as u16 | (entry[9] as u16) << 8,
+                start_bus: entry[10],
+                end_bus: entry[11],
+            })
+            // Only the first segment group is reachable so far
+            .filter(|region| region.segment == 0 && region.start_bus <= region.end_bus)
+            .collect();
+
+        if regions.is_empty() {
+            return None;
+        }
+        Some(ECAM {
+            regions,
+            mapped: Mutex::new(BTreeSet::new()),
+        })
+    }
+
+    /// Get the address of a function's page, mapping it if needed
+    fn function_base(&self, address: &PCIDeviceAddress) -> Option<u64> {
+        let bus = address.bus();
+        let region = self
+            .regions
+            .iter()
+            .find(|region| region.start_bus <= bus && bus <= region.end_bus)?;
+        let base = region.base
+            + (u64::from(bus - region.start_bus) << 20
+                | u64::from(address.slot()) << 15
+                | u64::from(address.func()) << 12);
+
+        let mut mapped = self.mapped.lock();
+        if !mapped.contains(&base) {
+            PAGING.lock().identity_map_region(
+                PhysAddr::new(base),
+                EXTENDED_SPACE_SIZE as u64,
+                PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_CACHE
+                    | PageTableFlags::WRITE_THROUGH,
+            );
+            mapped.insert(base);
+        }
+        Some(base)
+    }
+}
+
+impl ConfigAccess for ECAM {
+    fn name(&self) -> &'static str {
+        "ecam"
+    }
+
+    fn space_size(&self, address: &PCIDeviceAddress) -> usize {
+        match self.function_base(address) {
+            Some(_) => EXTENDED_SPACE_SIZE,
+            None => LEGACY_SPACE_SIZE,
+        }
+    }
+
+    unsafe fn read32(&self, address: &PCIDeviceAddress, offset: u16) -> u32 {
+        assert!(offset & 0x3 == 0 && (offset as usize) < EXTENDED_SPACE_SIZE);
+        match self.function_base(address) {
+            Some(base) => ptr::read_volatile((base + u64::from(offset)) as *const u32),
+            None => PortIO.read32(address, offset),
+        }
+    }
+
+    unsafe fn write32(&self, address: &PCIDeviceAddress, offset: u16, val: u32) {
+        assert!(offset & 0x3 == 0 && (offset as usize) < EXTENDED_SPACE_SIZE);
+        match self.function_base(address) {
+            Some(base) => ptr::write_volatile((base + u64::from(offset)) as *mut u32, val),
+            None => PortIO.write32(address, offset, val),
+        }
+    }
+
+    unsafe fn write16(&self, address: &PCIDeviceAddress, offset: u16, val: u16) {
+        assert!(offset & 0x1 == 0 && (offset as usize) < EXTENDED_SPACE_SIZE);
+        match self.function_base(address) {
+            Some(base) => ptr::write_volatile((base + u64::from(offset)) as *mut u16, val),
+            None => PortIO.write16(address, offset, val),
+        }
+    }
+
+    unsafe fn write8(&self, address: &PCIDeviceAddress, offset: u16, val: u8) {
+        assert!((offset as usize) < EXTENDED_SPACE_SIZE);
+        match self.function_base(address) {
+            Some(base) => ptr::write_volatile((base + u64::from(offset)) as *mut u8, val),
+            None => PortIO.write8(address, offset, val),
+        }
+    }
+}
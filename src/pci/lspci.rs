@@ -0,0 +1,174 @@
+use alloc::prelude::*;
+
+use super::names::{describe_class, device_name, prog_if_name, vendor_name};
+use super::{functions, PCIDevice, PCIFunction, PCIBAR};
+use crate::logger::{self, Level};
+
+/// Describe a function in one line, like
+/// "Mass storage controller: Intel Corporation 82371SB ..."
+pub fn describe(device: &PCIDevice) -> String {
+    let class = match describe_class(device.class_id(), device.subclass_id()) {
+        Some(name) => name.to_string(),
+        None => format!(
+            "Class {:02x}{:02x}",
+            device.class_id(),
+            device.subclass_id()
+        ),
+    };
+    let vendor = match vendor_name(device.vendor_id()) {
+        Some(name) => name.to_string(),
+        None => format!("Vendor {:04x}", device.vendor_id()),
+    };
+    let name = match device_name(device.vendor_id(), device.device_id()) {
+        Some(name) => name.to_string(),
+        None => format!("Device {:04x}", device.device_id()),
+    };
+    format!("{}: {} {}", class, vendor, name)
+}
+
+/// Log every function in the style of `lspci -v`.
+///
+/// This is only done while trace messages are enabled
+/// for this module, since the listing is long.
+pub fn dump() {
+    if !logger::enabled(Level::Trace, module_path!()) {
+        return;
+    }
+    for function in functions() {
+        for line in dump_function(function).lines() {
+            log!(trace: "{}", line);
+        }
+    }
+}
+
+fn dump_function(function: &PCIFunction) -> String {
+    let device = &function.device;
+    let mut out = format!(
+        "{} {} [{:04x}:{:04x}] (rev {:02x})\n",
+        function.address(),
+        describe(device),
+        device.vendor_id(),
+        device.device_id(),
+        device.rev_id()
+    );
+
+    match prog_if_name(device.class_id(), device.subclass_id(), device.prog_if()) {
+        Some(name) => out += &format!("\tProgramming interface: {}\n", name),
+        None if device.prog_if() != 0 => {
+            out += &format!("\tProgramming interface: {:02x}\n", device.prog_if())
+        }
+        None => (),
+    }
+    // Pins other than INTA# to INTD# are not valid
+    if let pin @ 1..=4 = function.interrupt_pin {
+        out += &format!(
+            "\tInterrupt: pin {} routed to IRQ {}\n",
+            (b'A' + pin - 1) as char,
+            function.interrupt_line
+        );
+    }
+    if let Some(bus) = function.secondary_bus {
+        out += &format!("\tSecondary bus: {:02x}\n", bus);
+    }
+
+    for (index, bar) in function.bars.iter().enumerate() {
+        if let Some(bar) = bar {
+            out += &format!("\tRegion {}: {}\n", index, describe_bar(bar));
+        }
+    }
+
+    let caps: Vec<String> = device
+        .capabilities()
+        .iter()
+        .map(|cap| format!("[{:02x}] {}", cap.offset, capability_name(cap.id)))
+        .collect();
+    if !caps.is_empty() {
+        out += &format!("\tCapabilities: {}\n", caps.join(", "));
+    }
+    let caps: Vec<String> = device
+        .extended_capabilities()
+        .iter()
+        .map(|cap| format!("[{:03x}] {}", cap.offset, extended_capability_name(cap.id)))
+        .collect();
+    if !caps.is_empty() {
+        out += &format!("\tExtended capabilities: {}\n", caps.join(", "));
+    }
+
+    out
+}
+
+fn describe_bar(bar: &PCIBAR) -> String {
+    if bar.is_iospace() {
+        return format!(
+            "I/O ports at {:04x} [size={}]",
+            bar.addr(),
+            format_size(bar.size())
+        );
+    }
+    format!(
+        "Memory at {:08x} ({}, {}) [size={}]",
+        bar.addr(),
+        if bar.is_64bit() { "64-bit" } else { "32-bit" },
+        if bar.is_prefetchable() {
+            "prefetchable"
+        } else {
+            "non-prefetchable"
+        },
+        format_size(bar.size())
+    )
+}
+
+fn format_size(size: u64) -> String {
+    let units = ["", "K", "M", "G", "T"];
+    let mut size = size;
+    let mut unit = 0;
+    while size >= 1024 && size % 1024 == 0 && unit < units.len() - 1 {
+        size /= 1024;
+        unit += 1;
+    }
+    format!("{}{}", size, units[unit])
+}
+
+fn capability_name(id: u8) -> &'static str {
+    match id {
+        0x01 => "Power Management",
+        0x02 => "AGP",
+        0x03 => "Vital Product Data",
+        0x04 => "Slot Identification",
+        0x05 => "MSI",
+        0x06 => "CompactPCI Hot Swap",
+        0x07 => "PCI-X",
+        0x08 => "HyperTransport",
+        0x09 => "Vendor Specific",
+        0x0A => "Debug port",
+        0x0C => "Hot-plug",
+        0x0D => "Subsystem",
+        0x0E => "AGP 8x",
+        0x0F => "Secure Device",
+        0x10 => "Express",
+        0x11 => "MSI-X",
+        0x12 => "SATA HBA",
+        0x13 => "PCI Advanced Features",
+        _ => "Unknown",
+    }
+}
+
+fn extended_capability_name(id: u16) -> &'static str {
+    match id {
+        0x0001 => "Advanced Error Reporting",
+        0x0002 => "Virtual Channel",
+        0x0003 => "Device Serial Number",
+        0x0004 => "Power Budgeting",
+        0x000B => "Vendor Specific",
+        0x000D => "Access Control Services",
+        0x000E => "Alternative Routing-ID Interpretation",
+        0x000F => "Address Translation Service",
+        0x0010 => "Single Root I/O Virtualization",
+        0x0015 => "Resizable BAR",
+        0x0017 => "Transaction Processing Hints",
+        0x0018 => "Latency Tolerance Reporting",
+        0x0019 => "Secondary PCI Express",
+        0x001E => "L1 PM Substates",
+        _ => "Unknown",
+    }
+}
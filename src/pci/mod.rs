@@ -0,0 +1,570 @@
+//
+// Peripheral Component Interconnect
+//
+// Configuration space is accessed through the memory-mapped
+// ECAM of PCI Express if the ACPI MCFG table describes one,
+// and through the legacy `0xCF8`/`0xCFC` port pair otherwise.
+// All functions, including those behind PCI-PCI bridges, are
+// enumerated once into a registry, which drivers query
+// instead of walking the bus. Memory and I/O BARs the firmware
+// left unassigned get addresses during enumeration.
+//
+// Vendor, device and class names come from a subset of the
+// PCI ID database, which build.rs compiles into tables.
+//
+
+mod capability;
+mod config;
+mod lspci;
+mod names;
+mod registry;
+mod resource;
+
+pub use self::capability::{
+    Capability, ExtendedCapability, PowerManagement, PowerState, AER, CAP_MSI, CAP_MSIX, CAP_PM,
+    EXT_CAP_AER, EXT_CAP_SRIOV, MSI, MSIX, SRIOV,
+};
+pub use self::config::{access, ConfigAccess, EXTENDED_SPACE_SIZE, LEGACY_SPACE_SIZE};
+pub use self::lspci::{describe, dump};
+pub use self::names::{
+    class_name, describe_class, device_name, prog_if_name, subclass_name, vendor_name,
+};
+pub use self::registry::{buses, find, functions, get, init, PCIBusInfo, PCIFunction};
+
+use bitflags::bitflags;
+use core::convert::From;
+use core::fmt;
+use x86_64::{structures::paging::PageTableFlags, PhysAddr};
+
+use crate::paging::PAGING;
+
+const PCIFIELD_VENDOR_ID: u8 = 0x00;
+const PCIFIELD_DEVICE_ID: u8 = 0x02;
+const PCIFIELD_COMMAND: u8 = 0x04;
+const PCIFIELD_STATUS: u8 = 0x06;
+const PCIFIELD_REVISION_ID: u8 = 0x08;
+const PCIFIELD_PROG_IF: u8 = 0x09;
+const PCIFIELD_SUBCLASS: u8 = 0x0A;
+const PCIFIELD_CLASS: u8 = 0x0B;
+const PCIFIELD_HHEADER_TYPE: u8 = 0x0E;
+const PCIFIELD_SECONDARY_BUS_NUMBER: u8 = 0x19;
+const PCIFIELD_BAR0: u8 = 0x10;
+const PCIFIELD_SUBORDINATE_BUS_NUMBER: u8 = 0x1A;
+const PCIFIELD_CAPABILITIES_POINTER: u8 = 0x34;
+const PCIFIELD_INTERRUPT_LINE: u8 = 0x3C;
+const PCIFIELD_INTERRUPT_PIN: u8 = 0x3D;
+
+bitflags! {
+
+    /// Bits of the command register
+    pub struct PCICommand: u16 {
+        const IO_SPACE                = 1 << 0;
+        const MEMORY_SPACE            = 1 << 1;
+        const BUS_MASTER              = 1 << 2;
+        const SPECIAL_CYCLES          = 1 << 3;
+        const MEMORY_WRITE_INVALIDATE = 1 << 4;
+        const VGA_PALETTE_SNOOP       = 1 << 5;
+        const PARITY_ERROR_RESPONSE   = 1 << 6;
+        const SERR                    = 1 << 8;
+        const FAST_BACK_TO_BACK       = 1 << 9;
+        const INTERRUPT_DISABLE       = 1 << 10;
+    }
+}
+
+bitflags! {
+
+    /// Bits of the status register
+    pub struct PCIStatus: u16 {
+        const INTERRUPT                = 1 << 3;
+        const CAPABILITIES             = 1 << 4;
+        const MHZ_66                   = 1 << 5;
+        const FAST_BACK_TO_BACK        = 1 << 7;
+        const MASTER_DATA_PARITY_ERROR = 1 << 8;
+        const SIGNALED_TARGET_ABORT    = 1 << 11;
+        const RECEIVED_TARGET_ABORT    = 1 << 12;
+        const RECEIVED_MASTER_ABORT    = 1 << 13;
+        const SIGNALED_SYSTEM_ERROR    = 1 << 14;
+        const DETECTED_PARITY_ERROR    = 1 << 15;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PCIDeviceAddress {
+    bus: u8,
+    slot: u8,
+    func: u8,
+}
+
+impl PCIDeviceAddress {
+    pub fn new(bus: u8, slot: u8, func: u8) -> Self {
+        PCIDeviceAddress { bus, slot, func }
+    }
+
+    pub fn bus(&self) -> u8 {
+        self.bus
+    }
+
+    pub fn slot(&self) -> u8 {
+        self.slot
+    }
+
+    pub fn func(&self) -> u8 {
+        self.func
+    }
+}
+
+impl From<&PCIDeviceAddress> for u32 {
+    fn from(addr: &PCIDeviceAddress) -> Self {
+        assert!(addr.slot < 1 << 5);
+        assert!(addr.func < 1 << 3);
+
+        ((addr.func as u32) << 8u32)
+            | ((addr.slot as u32) << 11u32)
+            | ((addr.bus as u32) << 16u32)
+            | 1u32 << 31u32
+    }
+}
+
+impl From<PCIDeviceAddress> for u32 {
+    fn from(addr: PCIDeviceAddress) -> Self {
+        assert!(addr.slot < 1 << 5);
+        assert!(addr.func < 1 << 3);
+
+        ((addr.func as u32) << 8u32)
+            | ((addr.slot as u32) << 11u32)
+            | ((addr.bus as u32) << 16u32)
+            | 1u32 << 31u32
+    }
+}
+
+impl fmt::Display for PCIDeviceAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.slot, self.func)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PCIDeviceID {
+    pub vendor_id: u16,
+    pub device_id: u16,
+}
+
+impl PCIDeviceID {
+    fn is_valid(&self) -> bool {
+        if self.vendor_id == 0xFFFF && self.device_id == 0xFFFF {
+            return false;
+        }
+        true
+    }
+}
+
+#[derive(Debug)]
+pub struct PCIFind {
+    vendor_id: u16,
+    device_id: u16,
+    class_id: u8,
+    subclass_id: u8,
+    prog_if: u8,
+    rev_id: u8,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct PCIDeviceType {
+    class_id: u8,
+    subclass_id: u8,
+    prog_if: u8,
+    rev_id: u8,
+}
+
+#[repr(C)]
+union PCIData {
+    val8: [u8; 4],
+    val16: [u16; 2],
+    val32: u32,
+}
+
+impl PCIFind {
+    pub fn new(vendor_id: u16, device_id: u16) -> Self {
+        PCIFind {
+            vendor_id,
+            device_id,
+            class_id: 0xFFu8,
+            subclass_id: 0xFFu8,
+            prog_if: 0xFFu8,
+            rev_id: 0xFFu8,
+        }
+    }
+
+    /// Match any device of the given class and subclass
+    pub fn with_class(class_id: u8, subclass_id: u8) -> Self {
+        PCIFind {
+            vendor_id: 0xFFFF,
+            device_id: 0xFFFF,
+            class_id,
+            subclass_id,
+            prog_if: 0xFFu8,
+            rev_id: 0xFFu8,
+        }
+    }
+
+    /// Test whether a device matches the pattern
+    pub fn matches_device(&self, dev: &PCIDevice) -> bool {
+        self.matches(&dev.id, &dev.dev_type)
+    }
+
+    fn matches(&self, id: &PCIDeviceID, dev_type: &PCIDeviceType) -> bool {
+        if id.vendor_id == 0xFFFF && id.device_id == 0xFFFF {
+            return false;
+        }
+        if self.vendor_id != 0xFFFF && id.vendor_id != self.vendor_id {
+            return false;
+        }
+        if self.device_id != 0xFFFF && id.device_id != self.device_id {
+            return false;
+        }
+        if self.class_id != 0xFF && dev_type.class_id != self.class_id {
+            return false;
+        }
+        if self.subclass_id != 0xFF && dev_type.subclass_id != self.subclass_id {
+            return false;
+        }
+        if self.prog_if != 0xFF && dev_type.prog_if != self.prog_if {
+            return false;
+        }
+        if self.rev_id != 0xFF && dev_type.rev_id != self.rev_id {
+            return false;
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PCIDevice {
+    pub address: PCIDeviceAddress,
+    id: PCIDeviceID,
+    dev_type: PCIDeviceType,
+}
+
+impl PCIDevice {
+    pub unsafe fn pci_read32(address: &PCIDeviceAddress, offset: u8) -> u32 {
+        config::access().read32(address, u16::from(offset))
+    }
+
+    pub unsafe fn pci_write32(address: &PCIDeviceAddress, offset: u8, val: u32) {
+        config::access().write32(address, u16::from(offset), val)
+    }
+
+    pub unsafe fn pci_write16(address: &PCIDeviceAddress, offset: u8, val: u16) {
+        config::access().write16(address, u16::from(offset), val)
+    }
+
+    pub unsafe fn pci_write8(address: &PCIDeviceAddress, offset: u8, val: u8) {
+        config::access().write8(address, u16::from(offset), val)
+    }
+
+    pub unsafe fn pci_read16(address: &PCIDeviceAddress, offset: u8) -> u16 {
+        assert!(offset & 0x1 == 0);
+        let aligned_offset = offset & !0x3;
+        let data_raw = PCIDevice::pci_read32(address, aligned_offset);
+        let data: PCIData = PCIData { val32: data_raw };
+        data.val8[(offset & 0x3) as usize] as u16
+            | ((data.val8[(offset & 0x3) as usize + 1] as u16) << 8)
+    }
+
+    pub unsafe fn pci_read8(address: &PCIDeviceAddress, offset: u8) -> u8 {
+        let aligned_offset = offset & !0x3;
+        let data_raw = PCIDevice::pci_read32(address, aligned_offset);
+        let data: PCIData = PCIData { val32: data_raw };
+        data.val8[(offset & 0x3) as usize]
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.id.vendor_id
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.id.device_id
+    }
+
+    pub fn class_id(&self) -> u8 {
+        self.dev_type.class_id
+    }
+
+    pub fn subclass_id(&self) -> u8 {
+        self.dev_type.subclass_id
+    }
+
+    /// Get the programming interface
+    pub fn prog_if(&self) -> u8 {
+        self.dev_type.prog_if
+    }
+
+    pub fn rev_id(&self) -> u8 {
+        self.dev_type.rev_id
+    }
+
+    pub fn read8(&self, offset: u8) -> u8 {
+        unsafe { PCIDevice::pci_read8(&self.address, offset) }
+    }
+
+    pub fn read16(&self, offset: u8) -> u16 {
+        unsafe { PCIDevice::pci_read16(&self.address, offset) }
+    }
+
+    pub fn read32(&self, offset: u8) -> u32 {
+        unsafe { PCIDevice::pci_read32(&self.address, offset) }
+    }
+
+    pub fn write32(&self, offset: u8, val: u32) {
+        unsafe { PCIDevice::pci_write32(&self.address, offset, val) }
+    }
+
+    pub fn write16(&self, offset: u8, val: u16) {
+        unsafe { PCIDevice::pci_write16(&self.address, offset, val) }
+    }
+
+    #[allow(dead_code)]
+    pub fn write8(&self, offset: u8, val: u8) {
+        unsafe { PCIDevice::pci_write8(&self.address, offset, val) }
+    }
+
+    pub fn command(&self) -> PCICommand {
+        PCICommand::from_bits_truncate(self.read16(PCIFIELD_COMMAND))
+    }
+
+    pub fn set_command(&self, command: PCICommand) {
+        // Keep reserved bits as they are
+        let reserved = self.read16(PCIFIELD_COMMAND) & !PCICommand::all().bits();
+        self.write16(PCIFIELD_COMMAND, reserved | command.bits());
+    }
+
+    /// Set bits in the command register
+    pub fn enable(&self, bits: PCICommand) {
+        self.set_command(self.command() | bits);
+    }
+
+    /// Clear bits in the command register
+    #[allow(dead_code)]
+    pub fn disable(&self, bits: PCICommand) {
+        self.set_command(self.command() - bits);
+    }
+
+    /// Stop the function from raising legacy INTx interrupts
+    pub fn disable_intx(&self) {
+        self.enable(PCICommand::INTERRUPT_DISABLE);
+    }
+
+    pub fn status(&self) -> PCIStatus {
+        PCIStatus::from_bits_truncate(self.read16(PCIFIELD_STATUS))
+    }
+
+    /// Clear error bits in the status register.
+    ///
+    /// They are cleared by writing ones, other bits are read-only.
+    #[allow(dead_code)]
+    pub fn clear_status(&self, bits: PCIStatus) {
+        self.write16(PCIFIELD_STATUS, bits.bits());
+    }
+
+    /// Get the number of bytes of configuration space we can reach
+    pub fn config_space_size(&self) -> usize {
+        config::access().space_size(&self.address)
+    }
+
+    /// Read a dword anywhere in the extended configuration space.
+    ///
+    /// Offsets past the reachable space read as all ones.
+    pub fn read_extended32(&self, offset: u16) -> u32 {
+        if offset as usize >= self.config_space_size() {
+            return 0xFFFF_FFFF;
+        }
+        unsafe { config::access().read32(&self.address, offset) }
+    }
+
+    /// Write a dword anywhere in the extended configuration space
+    pub fn write_extended32(&self, offset: u16, val: u32) {
+        if (offset as usize) < self.config_space_size() {
+            unsafe { config::access().write32(&self.address, offset, val) }
+        }
+    }
+
+    fn get_id(address: &PCIDeviceAddress) -> PCIDeviceID {
+        PCIDeviceID {
+            device_id: unsafe { PCIDevice::pci_read16(address, PCIFIELD_DEVICE_ID) },
+            vendor_id: unsafe { PCIDevice::pci_read16(address, PCIFIELD_VENDOR_ID) },
+        }
+    }
+
+    fn get_type(address: &PCIDeviceAddress) -> PCIDeviceType {
+        PCIDeviceType {
+            class_id: unsafe { PCIDevice::pci_read8(address, PCIFIELD_CLASS) },
+            subclass_id: unsafe { PCIDevice::pci_read8(address, PCIFIELD_SUBCLASS) },
+            prog_if: unsafe { PCIDevice::pci_read8(address, PCIFIELD_PROG_IF) },
+            rev_id: unsafe { PCIDevice::pci_read8(address, PCIFIELD_REVISION_ID) },
+        }
+    }
+
+    /// Read a BAR and find out its size.
+    ///
+    /// Decoding is turned off while the BAR holds the sizing
+    /// pattern, so the function never claims bogus addresses.
+    pub fn get_bar(&self, bar: u8) -> PCIBAR {
+        let offset = PCIFIELD_BAR0 + 4 * bar;
+        let lo = self.read32(offset);
+
+        let mut res = PCIBAR {
+            addr_raw: lo as u64,
+            size_raw: 0,
+        };
+
+        self.without_decode(|| {
+            if res.is_64bit() {
+                let hi = self.read32(offset + 4);
+                res.addr_raw |= (hi as u64) << 32;
+                self.write32(offset, 0xFFFFFFFF);
+                self.write32(offset + 4, 0xFFFFFFFF);
+                let size_lo = self.read32(offset);
+                let size_hi = self.read32(offset + 4);
+                self.write32(offset, lo);
+                self.write32(offset + 4, hi);
+                let mask = (size_hi as u64) << 32 | (size_lo & 0xFFFFFFF0) as u64;
+                res.size_raw = (!mask).wrapping_add(1);
+            } else if res.is_32bit() || res.is_16bit() {
+                self.write32(offset, 0xFFFFFFFF);
+                let size_lo = self.read32(offset);
+                self.write32(offset, lo);
+                let mask = size_lo & 0xFFFFFFF0;
+                res.size_raw = (!mask).wrapping_add(1) as u64;
+            } else if res.is_iospace() {
+                self.write32(offset, 0xFFFFFFFF);
+                let size_lo = self.read32(offset);
+                self.write32(offset, lo);
+                let mut mask = size_lo & 0xFFFFFFFC;
+                // The upper half may be hardwired to zero
+                if mask != 0 && mask >> 16 == 0 {
+                    mask |= 0xFFFF0000;
+                }
+                res.size_raw = (!mask).wrapping_add(1) as u64;
+            }
+        });
+        res
+    }
+
+    /// Move a BAR to a new address.
+    ///
+    /// Decoding is turned off during the update.
+    pub fn set_bar(&self, bar: u8, addr: u64) {
+        let offset = PCIFIELD_BAR0 + 4 * bar;
+        let info = PCIBAR {
+            addr_raw: self.read32(offset) as u64,
+            size_raw: 0,
+        };
+        let flags = match info.is_iospace() {
+            true => info.addr_raw as u32 & 0x3,
+            false => info.addr_raw as u32 & 0xF,
+        };
+
+        self.without_decode(|| {
+            self.write32(offset, addr as u32 & !flags | flags);
+            if info.is_64bit() {
+                self.write32(offset + 4, (addr >> 32) as u32);
+            }
+        });
+    }
+
+    /// Run `f` with I/O and memory decoding turned off
+    fn without_decode<F: FnOnce()>(&self, f: F) {
+        // Host bridges may be needed to reach RAM, so leave them be
+        if self.class_id() == 0x06 && self.subclass_id() == 0x00 {
+            f();
+        } else {
+            let command = self.command();
+            self.set_command(command - PCICommand::IO_SPACE - PCICommand::MEMORY_SPACE);
+            f();
+            self.set_command(command);
+        }
+    }
+}
+
+const PCIBAR_TYPE_IOSPACE: u8 = 0x0 << 1 | 0x1 << 0;
+const PCIBAR_TYPE_16BIT: u8 = 0x1 << 1 | 0x0 << 0;
+const PCIBAR_TYPE_32BIT: u8 = 0x0 << 1 | 0x0 << 0;
+const PCIBAR_TYPE_64BIT: u8 = 0x2 << 1 | 0x0 << 0;
+
+const PCIBAR_PREFETCHABLE: u64 = 1 << 3;
+
+#[derive(Debug, Clone, Copy)]
+pub struct PCIBAR {
+    addr_raw: u64,
+    size_raw: u64,
+}
+
+impl PCIBAR {
+    pub fn addr(&self) -> u64 {
+        match self.is_iospace() {
+            true => self.addr_raw & 0xFFFFFFFFFFFFFFFC,
+            false => self.addr_raw & 0xFFFFFFFFFFFFFFF0,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size_raw & 0xFFFFFFFFFFFFFFFF
+    }
+
+    pub fn get_type(&self) -> u8 {
+        let raw = self.addr_raw as u8;
+        match raw & 0x3 {
+            PCIBAR_TYPE_IOSPACE => raw & 0x3,
+            _ => raw & 0x7,
+        }
+    }
+
+    pub fn is_iospace(&self) -> bool {
+        self.get_type() == PCIBAR_TYPE_IOSPACE
+    }
+
+    pub fn is_16bit(&self) -> bool {
+        self.get_type() == PCIBAR_TYPE_16BIT
+    }
+
+    pub fn is_32bit(&self) -> bool {
+        self.get_type() == PCIBAR_TYPE_32BIT
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        self.get_type() == PCIBAR_TYPE_64BIT
+    }
+
+    pub fn is_mmio(&self) -> bool {
+        self.is_16bit() || self.is_32bit() || self.is_64bit()
+    }
+
+    /// Whether reads have no side effects, so they may be cached
+    pub fn is_prefetchable(&self) -> bool {
+        self.is_mmio() && self.addr_raw & PCIBAR_PREFETCHABLE != 0
+    }
+
+    /// Whether the BAR is implemented but has no address yet
+    pub fn is_unassigned(&self) -> bool {
+        self.size() != 0 && self.addr() == 0
+    }
+
+    pub fn identity_map(&self) -> Result<(), &'static str> {
+        if !self.is_mmio() {
+            return Err("BAR is not mmio");
+        }
+
+        let addr = self.addr();
+        let size = self.size();
+
+        PAGING.lock().identity_map(
+            PhysAddr::new(addr),
+            PhysAddr::new(addr + size),
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+            false,
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+/// A vendor and its devices, sorted by ID
+pub struct VendorEntry {
+    pub id: u16,
+    pub name: &'static str,
+    pub devices: &'static [(u16, &'static str)],
+}
+
+/// A class and its subclasses, sorted by ID
+pub struct ClassEntry {
+    pub id: u8,
+    pub name: &'static str,
+    pub subclasses: &'static [SubclassEntry],
+}
+
+/// A subclass and its programming interfaces, sorted by ID
+pub struct SubclassEntry {
+    pub id: u8,
+    pub name: &'static str,
+    pub prog_ifs: &'static [(u8, &'static str)],
+}
+
+// VENDORS and CLASSES, generated from pci.ids
+include!(concat!(env!("OUT_DIR"), "/pci_ids.rs"));
+
+fn vendor(vendor_id: u16) -> Option<&'static VendorEntry> {
+    VENDORS
+        .binary_search_by_key(&vendor_id, |vendor| vendor.id)
+        .ok()
+        .map(|index| &VENDORS[index])
+}
+
+fn subclass(class_id: u8, subclass_id: u8) -> Option<&'static SubclassEntry> {
+    let class = CLASSES
+        .binary_search_by_key(&class_id, |class| class.id)
+        .ok()
+        .map(|index| &CLASSES[index])?;
+    class
+        .subclasses
+        .binary_search_by_key(&subclass_id, |subclass| subclass.id)
+        .ok()
+        .map(|index| &class.subclasses[index])
+}
+
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    vendor(vendor_id).map(|vendor| vendor.name)
+}
+
+pub fn device_name(vendor_id: u16, device_id: u16) -> Option<&'static str> {
+    let devices = vendor(vendor_id)?.devices;
+    devices
+        .binary_search_by_key(&device_id, |&(id, _)| id)
+        .ok()
+        .map(|index| devices[index].1)
+}
+
+pub fn class_name(class_id: u8) -> Option<&'static str> {
+    CLASSES
+        .binary_search_by_key(&class_id, |class| class.id)
+        .ok()
+        .map(|index| CLASSES[index].name)
+}
+
+pub fn subclass_name(class_id: u8, subclass_id: u8) -> Option<&'static str> {
+    subclass(class_id, subclass_id).map(|subclass| subclass.name)
+}
+
+pub fn prog_if_name(class_id: u8, subclass_id: u8, prog_if: u8) -> Option<&'static str> {
+    let prog_ifs = subclass(class_id, subclass_id)?.prog_ifs;
+    prog_ifs
+        .binary_search_by_key(&prog_if, |&(id, _)| id)
+        .ok()
+        .map(|index| prog_ifs[index].1)
+}
+
+/// Describe a class as precisely as the table allows,
+/// like "SATA controller" or "Bridge"
+pub fn describe_class(class_id: u8, subclass_id: u8) -> Option<&'static str> {
+    subclass_name(class_id, subclass_id).or_else(|| class_name(class_id))
+}
@@ -0,0 +1,228 @@
+use alloc::prelude::*;
+use lazy_static::lazy_static;
+
+use super::resource;
+use super::{
+    PCIDevice, PCIDeviceAddress, PCIFind, PCIBAR, PCIFIELD_HHEADER_TYPE, PCIFIELD_INTERRUPT_LINE,
+    PCIFIELD_INTERRUPT_PIN, PCIFIELD_SECONDARY_BUS_NUMBER, PCIFIELD_SUBORDINATE_BUS_NUMBER,
+};
+
+// Header types
+const HEADER_TYPE_MASK: u8 = 0x7F;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+const HEADER_GENERAL: u8 = 0x00;
+const HEADER_PCI_BRIDGE: u8 = 0x01;
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::enumerate();
+}
+
+/// A function found during enumeration
+#[derive(Debug, Clone)]
+pub struct PCIFunction {
+    pub device: PCIDevice,
+    /// The header type without the multi-function bit
+    #[allow(dead_code)]
+    pub header_type: u8,
+    #[allow(dead_code)]
+    pub multifunction: bool,
+    /// The BARs after enumeration, `None` for unused ones
+    pub bars: Vec<Option<PCIBAR>>,
+    pub interrupt_line: u8,
+    /// The interrupt pin, 1 for INTA# to 4 for INTD#, 0 if none
+    pub interrupt_pin: u8,
+    /// The bus behind a PCI-PCI bridge
+    pub secondary_bus: Option<u8>,
+}
+
+impl PCIFunction {
+    pub fn address(&self) -> PCIDeviceAddress {
+        self.device.address
+    }
+
+    #[allow(dead_code)]
+    pub fn is_bridge(&self) -> bool {
+        self.secondary_bus.is_some()
+    }
+}
+
+/// A bus and the functions on it
+#[derive(Debug, Clone)]
+pub struct PCIBusInfo {
+    pub number: u8,
+    /// The bridge leading to the bus, `None` for root buses
+    pub bridge: Option<PCIDeviceAddress>,
+    /// Indices of the functions on the bus, see `functions`
+    pub functions: Vec<usize>,
+}
+
+struct Registry {
+    functions: Vec<PCIFunction>,
+    buses: Vec<PCIBusInfo>,
+}
+
+impl Registry {
+    fn enumerate() -> Self {
+        let mut registry = Registry {
+            functions: Vec::new(),
+            buses: Vec::new(),
+        };
+
+        // A multi-function host bridge has one root bus per function
+        let host = PCIDeviceAddress::new(0, 0, 0);
+        let header = unsafe { PCIDevice::pci_read8(&host, PCIFIELD_HHEADER_TYPE) };
+        if header & HEADER_MULTIFUNCTION == 0 {
+            registry.scan_bus(0, None);
+        } else {
+            for func in 0..8 {
+                let addr = PCIDeviceAddress::new(0, 0, func);
+                if PCIDevice::get_id(&addr).is_valid() {
+                    registry.scan_bus(func, None);
+                }
+            }
+        }
+
+        // Give addresses to BARs the firmware skipped
+        resource::assign_bars(&mut registry.functions, &registry.buses);
+
+        log!(
+            debug: "PCI enumeration found {} functions on {} buses using {}.",
+            registry.functions.len(),
+            registry.buses.len(),
+            super::access().name()
+        );
+        registry
+    }
+
+    fn scan_bus(&mut self, bus: u8, bridge: Option<PCIDeviceAddress>) {
+        // Misconfigured bridges could lead us in circles
+        if self.buses.iter().any(|info| info.number == bus) {
+            log!(warn: "PCI bus {} is reachable twice, skipping it.", bus);
+            return;
+        }
+        let index = self.buses.len();
+        self.buses.push(PCIBusInfo {
+            number: bus,
+            bridge,
+            functions: Vec::new(),
+        });
+
+        let mut bridges = Vec::new();
+        for slot in 0..32 {
+            let first = PCIDeviceAddress::new(bus, slot, 0);
+            if !PCIDevice::get_id(&first).is_valid() {
+                continue;
+            }
+            let header = unsafe { PCIDevice::pci_read8(&first, PCIFIELD_HHEADER_TYPE) };
+            let funcs = if header & HEADER_MULTIFUNCTION != 0 {
+                8
+            } else {
+                1
+            };
+
+            for func in 0..funcs {
+                let addr = PCIDeviceAddress::new(bus, slot, func);
+                if let Some(function) = Registry::read_function(addr) {
+                    if let Some(secondary) = function.secondary_bus {
+                        bridges.push((addr, secondary));
+                    }
+                    self.buses[index].functions.push(self.functions.len());
+                    self.functions.push(function);
+                }
+            }
+        }
+
+        for (addr, secondary) in bridges {
+            self.scan_bus(secondary, Some(addr));
+        }
+    }
+
+    fn read_function(address: PCIDeviceAddress) -> Option<PCIFunction> {
+        let id = PCIDevice::get_id(&address);
+        if !id.is_valid() {
+            return None;
+        }
+        let device = PCIDevice {
+            address,
+            id,
+            dev_type: PCIDevice::get_type(&address),
+        };
+
+        let header = device.read8(PCIFIELD_HHEADER_TYPE);
+        let header_type = header & HEADER_TYPE_MASK;
+        let (bar_count, secondary_bus) = match header_type {
+            HEADER_GENERAL => (6, None),
+            HEADER_PCI_BRIDGE => {
+                let secondary = device.read8(PCIFIELD_SECONDARY_BUS_NUMBER);
+                let subordinate = device.read8(PCIFIELD_SUBORDINATE_BUS_NUMBER);
+                // Unconfigured bridges have no buses behind them
+                let usable = secondary != 0 && subordinate >= secondary;
+                (2, if usable { Some(secondary) } else { None })
+            }
+            _ => (0, None),
+        };
+
+        let mut bars = Vec::new();
+        let mut bar = 0;
+        while bar < bar_count {
+            let info = device.get_bar(bar);
+            if info.is_64bit() {
+                // The upper half takes the next slot
+                bars.push(Some(info));
+                bars.push(None);
+                bar += 2;
+                continue;
+            }
+            bars.push(if info.size() == 0 { None } else { Some(info) });
+            bar += 1;
+        }
+
+        let function = PCIFunction {
+            device,
+            header_type,
+            multifunction: header & HEADER_MULTIFUNCTION != 0,
+            bars,
+            interrupt_line: device.read8(PCIFIELD_INTERRUPT_LINE),
+            interrupt_pin: device.read8(PCIFIELD_INTERRUPT_PIN),
+            secondary_bus,
+        };
+        log!(
+            debug: "PCI {} {} [{:04x}:{:04x}].",
+            address,
+            super::describe(&device),
+            id.vendor_id,
+            id.device_id
+        );
+        Some(function)
+    }
+}
+
+/// Enumerate the bus, unless that already happened.
+///
+/// Returns the number of functions found.
+pub fn init() -> usize {
+    REGISTRY.functions.len()
+}
+
+/// Get all functions, in bus order
+pub fn functions() -> impl Iterator<Item = &'static PCIFunction> {
+    REGISTRY.functions.iter()
+}
+
+/// Get all buses, parents before the buses behind them
+#[allow(dead_code)]
+pub fn buses() -> impl Iterator<Item = &'static PCIBusInfo> {
+    REGISTRY.buses.iter()
+}
+
+/// Get the function at an address
+pub fn get(address: PCIDeviceAddress) -> Option<&'static PCIFunction> {
+    functions().find(|function| function.address() == address)
+}
+
+/// Get all devices matching a pattern
+pub fn find(find: &PCIFind) -> impl Iterator<Item = PCIDevice> + '_ {
+    functions()
+        .map(|function| function.device)
+        .filter(move |device| find.matches_device(device))
+}
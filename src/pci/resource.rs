@@ -0,0 +1,178 @@
+use alloc::prelude::*;
+use bootloader::bootinfo::{MemoryRegion, MemoryRegionType};
+use core::iter;
+
+use super::{PCIBusInfo, PCIFunction};
+use crate::paging::PAGING;
+
+/// The end of the 32-bit range new BARs may be placed in.
+///
+/// The I/O APIC, the local APIC and the firmware ROM follow.
+const MEMORY_LIMIT: u64 = 0xFEC0_0000;
+
+/// The I/O port range new BARs are placed in
+const IO_WINDOW: (u64, u64) = (0xC000, 0x1_0000);
+
+/// An address range handing out naturally aligned blocks
+struct Window {
+    start: u64,
+    end: u64,
+    /// Ranges in use, as `(start, end)`
+    used: Vec<(u64, u64)>,
+}
+
+impl Window {
+    fn new((start, end): (u64, u64)) -> Self {
+        Window {
+            start,
+            end,
+            used: Vec::new(),
+        }
+    }
+
+    fn reserve(&mut self, start: u64, size: u64) {
+        self.used.push((start, start + size));
+    }
+
+    /// Find the lowest free block of `size` bytes, aligned to its size
+    fn allocate(&mut self, size: u64) -> Option<u64> {
+        let mut addr = align_up(self.start, size);
+        while addr + size <= self.end {
+            let overlap = self
+                .used
+                .iter()
+                .filter(|&&(start, end)| start < addr + size && addr < end)
+                .map(|&(_, end)| end)
+                .max();
+            match overlap {
+                Some(end) => addr = align_up(end, size),
+                None => {
+                    self.reserve(addr, size);
+                    return Some(addr);
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Find the memory range new BARs are placed in.
+///
+/// This is the largest hole in the boot memory map below
+/// `MEMORY_LIMIT`, which is where firmware puts the PCI hole.
+fn memory_window(regions: &[MemoryRegion]) -> Option<(u64, u64)> {
+    let mut occupied: Vec<(u64, u64)> = regions
+        .iter()
+        .filter(|region| region.region_type != MemoryRegionType::Empty)
+        .map(|region| (region.range.start_addr(), region.range.end_addr()))
+        .filter(|&(start, _)| start < MEMORY_LIMIT)
+        .collect();
+    occupied.sort();
+
+    let mut best: Option<(u64, u64)> = None;
+    let mut addr = 0;
+    for (start, end) in occupied
+        .into_iter()
+        .chain(iter::once((MEMORY_LIMIT, MEMORY_LIMIT)))
+    {
+        let larger = match best {
+            Some((best_start, best_end)) => start > addr && start - addr > best_end - best_start,
+            None => start > addr,
+        };
+        if larger {
+            best = Some((addr, start));
+        }
+        addr = addr.max(end);
+    }
+    best
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+/// Give addresses to BARs the firmware left unassigned.
+///
+/// Only functions on root buses are handled, since those behind
+/// bridges would also need the bridge windows to be moved.
+pub(super) fn assign_bars(functions: &mut [PCIFunction], buses: &[PCIBusInfo]) {
+    let regions = PAGING.lock().memory_regions();
+    let mut memory = match memory_window(&regions) {
+        Some(window) => {
+            log!(
+                debug: "PCI memory window: 0x{:x}-0x{:x}.",
+                window.0,
+                window.1
+            );
+            Window::new(window)
+        }
+        None => {
+            log!(warn: "No hole in the memory map for PCI BARs.");
+            Window::new((0, 0))
+        }
+    };
+    // Never hand out RAM or firmware memory
+    for region in regions.iter() {
+        let (start, end) = (region.range.start_addr(), region.range.end_addr());
+        memory.reserve(start, end - start);
+    }
+    let mut io = Window::new(IO_WINDOW);
+    for bar in functions
+        .iter()
+        .flat_map(|function| function.bars.iter())
+        .filter_map(|bar| *bar)
+        .filter(|bar| !bar.is_unassigned())
+    {
+        match bar.is_iospace() {
+            true => io.reserve(bar.addr(), bar.size()),
+            false => memory.reserve(bar.addr(), bar.size()),
+        }
+    }
+
+    for bus in buses.iter() {
+        for &index in bus.functions.iter() {
+            let function = &mut functions[index];
+            for bar in 0..function.bars.len() {
+                let info = match function.bars[bar] {
+                    Some(info) if info.is_unassigned() => info,
+                    _ => continue,
+                };
+                if bus.bridge.is_some() {
+                    log!(
+                        warn: "PCI {}: BAR {} is unassigned behind a bridge.",
+                        function.address(),
+                        bar
+                    );
+                    continue;
+                }
+
+                let window = match info.is_iospace() {
+                    true => &mut io,
+                    false => &mut memory,
+                };
+                let addr = match window.allocate(info.size()) {
+                    Some(addr) => addr,
+                    None => {
+                        log!(
+                            warn: "PCI {}: no room for BAR {} ({} bytes).",
+                            function.address(),
+                            bar,
+                            info.size()
+                        );
+                        continue;
+                    }
+                };
+
+                function.device.set_bar(bar as u8, addr);
+                function.bars[bar] = Some(function.device.get_bar(bar as u8));
+                log!(
+                    debug: "PCI {}: assigned BAR {} to 0x{:x} ({} bytes).",
+                    function.address(),
+                    bar,
+                    addr,
+                    info.size()
+                );
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use pic8259_simple::ChainedPics;
 use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
 
 //
 // Constants
@@ -8,6 +9,12 @@ use spin::Mutex;
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_DATA: u16 = 0xA1;
+
+/// The IRQ line the slave PIC is cascaded on
+const PIC_CASCADE_IRQ: u8 = 2;
+
 //
 // Static PIC structure
 //
@@ -33,4 +40,42 @@ impl PIC8259 {
     pub fn get_chained_pics() -> &'static Mutex<ChainedPics> {
         &PICS
     }
+
+    /// Unmask an IRQ line
+    pub fn unmask(irq: u8) {
+        assert!(irq < 16);
+        // The lock is also taken by interrupt handlers
+        interrupts::without_interrupts(|| {
+            let _pics = PICS.lock();
+            unsafe {
+                if irq < 8 {
+                    PIC8259::update_mask(PIC_1_DATA, |mask| mask & !(1 << irq));
+                } else {
+                    PIC8259::update_mask(PIC_2_DATA, |mask| mask & !(1 << (irq - 8)));
+                    PIC8259::update_mask(PIC_1_DATA, |mask| mask & !(1 << PIC_CASCADE_IRQ));
+                }
+            }
+        });
+    }
+
+    /// Mask an IRQ line
+    pub fn mask(irq: u8) {
+        assert!(irq < 16);
+        interrupts::without_interrupts(|| {
+            let _pics = PICS.lock();
+            unsafe {
+                if irq < 8 {
+                    PIC8259::update_mask(PIC_1_DATA, |mask| mask | 1 << irq);
+                } else {
+                    PIC8259::update_mask(PIC_2_DATA, |mask| mask | 1 << (irq - 8));
+                }
+            }
+        });
+    }
+
+    unsafe fn update_mask<F: FnOnce(u8) -> u8>(port: u16, f: F) {
+        let mut port: Port<u8> = Port::new(port);
+        let mask = port.read();
+        port.write(f(mask));
+    }
 }
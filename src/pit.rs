@@ -1,7 +1,70 @@
 use crate::pic::PIC8259;
+use crate::workqueue::WorkQueues;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
 use x86_64::structures::idt::ExceptionStackFrame;
 
+//
+// Constants
+//
+
+/// The frequency of the PIT input clock in Hz
+const PIT_BASE_FREQUENCY: u32 = 1_193_182;
+
+/// The frequency of the timer interrupt in Hz
+pub const PIT_FREQUENCY: u32 = 100;
+
+const PIT_CHANNEL_0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+
+// Channel 0, lo/hi byte access, mode 3 (square wave)
+const PIT_COM_CHANNEL_0_SQUARE: u8 = 0x36;
+
+/// The number of timer interrupts since boot
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Intel 825x-compatible PIT
+pub struct PIT;
+impl PIT {
+    /// Program channel 0 to fire at `PIT_FREQUENCY`
+    pub fn init() {
+        let divisor = PIT_BASE_FREQUENCY / PIT_FREQUENCY;
+        let mut command: Port<u8> = Port::new(PIT_COMMAND);
+        let mut data: Port<u8> = Port::new(PIT_CHANNEL_0);
+        unsafe {
+            command.write(PIT_COM_CHANNEL_0_SQUARE);
+            data.write(divisor as u8);
+            data.write((divisor >> 8) as u8);
+        }
+    }
+
+    /// Get the number of ticks since boot
+    pub fn ticks() -> usize {
+        TICKS.load(Ordering::Relaxed)
+    }
+
+    /// Convert milliseconds to ticks, rounding up
+    pub fn ms_to_ticks(ms: usize) -> usize {
+        let hz = PIT_FREQUENCY as usize;
+        (ms * hz + 999) / 1000
+    }
+
+    /// Get the tick at which a timeout starting now expires
+    pub fn deadline(timeout: usize) -> usize {
+        PIT::ticks().wrapping_add(timeout)
+    }
+
+    /// Test whether a deadline has passed
+    pub fn has_passed(deadline: usize) -> bool {
+        // Compare the distance so a wrapping tick counter is handled
+        PIT::ticks().wrapping_sub(deadline) < usize::max_value() / 2
+    }
+}
+
 pub extern "x86-interrupt" fn handle_interrupt(_stack_frame: &mut ExceptionStackFrame) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    WorkQueues::run_timers();
+
     unsafe {
         PIC8259::get_chained_pics()
             .lock()
@@ -0,0 +1,638 @@
+//
+// Process lifecycle and signals
+//
+// This keeps track of which processes exist, how they are
+// related and how they ended. Running them is up to the
+// scheduler. Every trap taken from user mode ends with
+// `return_to_user`, which delivers pending signals, and
+// the sigreturn syscall ends a signal handler.
+//
+
+use alloc::{collections::btree_map::BTreeMap, prelude::*};
+use core::mem::size_of;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::cpu::{FPUState, FPU};
+use crate::idt::TrapFrame;
+use crate::sync::WaitQueue;
+use crate::syscall::{is_user_range, is_user_writable_range};
+
+/// A process identifier
+pub type Pid = u32;
+
+/// The kernel itself.
+///
+/// It is the root of the process tree, adopts orphans
+/// and cannot be signaled.
+pub const KERNEL_PID: Pid = 0;
+
+lazy_static! {
+    static ref PROCESSES: Mutex<ProcessTable> = Mutex::new(ProcessTable::new());
+    static ref CHILD_EXITED: WaitQueue = WaitQueue::new();
+}
+
+/// Supported signals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    SIGINT = 2,
+    SIGKILL = 9,
+    SIGSEGV = 11,
+    SIGCHLD = 17,
+}
+
+impl Signal {
+    pub fn from_number(num: u32) -> Option<Signal> {
+        match num {
+            2 => Some(Signal::SIGINT),
+            9 => Some(Signal::SIGKILL),
+            11 => Some(Signal::SIGSEGV),
+            17 => Some(Signal::SIGCHLD),
+            _ => None,
+        }
+    }
+
+    fn mask(self) -> u32 {
+        1 << (self as u32)
+    }
+
+    /// Test whether the signal's handling can be changed
+    fn is_catchable(self) -> bool {
+        self != Signal::SIGKILL
+    }
+
+    /// Test whether the default action terminates the process
+    fn terminates_by_default(self) -> bool {
+        self != Signal::SIGCHLD
+    }
+}
+
+/// What a process does when it receives a signal
+#[derive(Debug, Clone, Copy)]
+pub enum SignalAction {
+    Default,
+    Ignore,
+    /// Call `handler` on the user stack; it returns to `restorer`,
+    /// which is expected to invoke `sigreturn`.
+    Handler {
+        handler: u64,
+        restorer: u64,
+    },
+}
+
+/// How a process ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(i32),
+    Signaled(Signal),
+}
+
+impl ExitStatus {
+    /// Encode the status the way `wait` reports it to userspace
+    pub fn raw(self) -> i32 {
+        match self {
+            ExitStatus::Exited(code) => (code & 0xFF) << 8,
+            ExitStatus::Signaled(sig) => sig as i32,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    Zombie(ExitStatus),
+}
+
+/// Process lifecycle error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    NoSuchProcess,
+    NoChildren,
+    PermissionDenied,
+    InvalidSignal,
+}
+
+/// The user register state saved on kernel entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UserContext {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+}
+
+impl UserContext {
+    /// Take the user registers from a trap frame
+    pub fn from_trap_frame(frame: &TrapFrame) -> Self {
+        UserContext {
+            rax: frame.rax,
+            rbx: frame.rbx,
+            rcx: frame.rcx,
+            rdx: frame.rdx,
+            rsi: frame.rsi,
+            rdi: frame.rdi,
+            rbp: frame.rbp,
+            r8: frame.r8,
+            r9: frame.r9,
+            r10: frame.r10,
+            r11: frame.r11,
+            r12: frame.r12,
+            r13: frame.r13,
+            r14: frame.r14,
+            r15: frame.r15,
+            rip: frame.rip,
+            rsp: frame.rsp,
+            rflags: frame.rflags,
+        }
+    }
+
+    /// Put the user registers back into a trap frame.
+    ///
+    /// The segment selectors are left alone.
+    pub fn store(&self, frame: &mut TrapFrame) {
+        frame.rax = self.rax;
+        frame.rbx = self.rbx;
+        frame.rcx = self.rcx;
+        frame.rdx = self.rdx;
+        frame.rsi = self.rsi;
+        frame.rdi = self.rdi;
+        frame.rbp = self.rbp;
+        frame.r8 = self.r8;
+        frame.r9 = self.r9;
+        frame.r10 = self.r10;
+        frame.r11 = self.r11;
+        frame.r12 = self.r12;
+        frame.r13 = self.r13;
+        frame.r14 = self.r14;
+        frame.r15 = self.r15;
+        frame.rip = self.rip;
+        frame.rsp = self.rsp;
+        frame.rflags = self.rflags;
+    }
+}
+
+/// The frame pushed onto the user stack to run a signal handler.
+///
+/// Only userspace reads `restorer` and `signal`.
+#[repr(C)]
+#[allow(dead_code)]
+struct SignalFrame {
+    /// Return address of the handler
+    restorer: u64,
+    signal: u64,
+    blocked: u64,
+    context: UserContext,
+}
+
+/// Bytes below the user stack pointer the handler must not touch
+const RED_ZONE_SIZE: u64 = 128;
+
+// Flags userspace may not change through `sigreturn`
+const RFLAGS_INTERRUPT: u64 = 1 << 9;
+const RFLAGS_IOPL: u64 = 3 << 12;
+
+struct Process {
+    parent: Pid,
+    children: Vec<Pid>,
+    state: ProcessState,
+    pending: u32,
+    blocked: u32,
+    actions: BTreeMap<u32, SignalAction>,
+    fpu: FPUState,
+}
+
+impl Process {
+    fn new(parent: Pid) -> Self {
+        Process {
+            parent,
+            children: Vec::new(),
+            state: ProcessState::Running,
+            pending: 0,
+            blocked: 0,
+            actions: BTreeMap::new(),
+            fpu: FPUState::new(),
+        }
+    }
+
+    fn action(&self, sig: Signal) -> SignalAction {
+        self.actions
+            .get(&(sig as u32))
+            .cloned()
+            .unwrap_or(SignalAction::Default)
+    }
+}
+
+struct ProcessTable {
+    processes: BTreeMap<Pid, Process>,
+    next_pid: Pid,
+    current: Pid,
+    foreground: Pid,
+}
+
+impl ProcessTable {
+    fn new() -> Self {
+        let mut processes = BTreeMap::new();
+        processes.insert(KERNEL_PID, Process::new(KERNEL_PID));
+        ProcessTable {
+            processes,
+            next_pid: KERNEL_PID + 1,
+            current: KERNEL_PID,
+            foreground: KERNEL_PID,
+        }
+    }
+
+    fn get(&mut self, pid: Pid) -> Result<&mut Process, ProcessError> {
+        self.processes
+            .get_mut(&pid)
+            .ok_or(ProcessError::NoSuchProcess)
+    }
+
+    /// Turn a process into a zombie and hand its children to the kernel.
+    ///
+    /// Returns `false` if the process was not running.
+    fn terminate(&mut self, pid: Pid, status: ExitStatus) -> bool {
+        let (parent, children) = {
+            let proc = match self.processes.get_mut(&pid) {
+                Some(proc) => proc,
+                None => return false,
+            };
+            if proc.state != ProcessState::Running {
+                return false;
+            }
+            proc.state = ProcessState::Zombie(status);
+            proc.pending = 0;
+            (
+                proc.parent,
+                core::mem::replace(&mut proc.children, Vec::new()),
+            )
+        };
+
+        // The kernel never waits, so orphaned zombies are reaped right away
+        for child in children {
+            let zombie = match self.processes.get_mut(&child) {
+                Some(proc) => {
+                    proc.parent = KERNEL_PID;
+                    proc.state != ProcessState::Running
+                }
+                None => continue,
+            };
+            if zombie {
+                self.processes.remove(&child);
+            } else if let Some(kernel) = self.processes.get_mut(&KERNEL_PID) {
+                kernel.children.push(child);
+            }
+        }
+
+        if parent == KERNEL_PID {
+            self.reap(pid);
+        } else if let Some(proc) = self.processes.get_mut(&parent) {
+            proc.pending |= Signal::SIGCHLD.mask();
+        }
+
+        if self.foreground == pid {
+            self.foreground = parent;
+        }
+        true
+    }
+
+    /// Remove a zombie from the table
+    fn reap(&mut self, pid: Pid) -> Option<ExitStatus> {
+        let status = match self.processes.get(&pid)?.state {
+            ProcessState::Zombie(status) => status,
+            ProcessState::Running => return None,
+        };
+        let parent = self.processes.remove(&pid)?.parent;
+        if let Some(proc) = self.processes.get_mut(&parent) {
+            proc.children.retain(|c| *c != pid);
+        }
+        Some(status)
+    }
+}
+
+/// Terminate a process and release what it holds.
+///
+/// Dropping its handles may close channels and wake other
+/// tasks, so this happens without `PROCESSES` held.
+fn terminate(pid: Pid, status: ExitStatus) {
+    let terminated = PROCESSES.lock().terminate(pid, status);
+    if terminated {
+        crate::ipc::release_task_handles(pid);
+        CHILD_EXITED.notify_all();
+    }
+}
+
+/// Get the pid of the running process
+pub fn current() -> Pid {
+    PROCESSES.lock().current
+}
+
+/// Make `pid` the running process.
+///
+/// Called by the scheduler on every switch. This also
+/// switches the FPU state over to the process.
+#[allow(dead_code)]
+pub fn set_current(pid: Pid) {
+    let mut table = PROCESSES.lock();
+    table.current = pid;
+    if let Ok(proc) = table.get(pid) {
+        FPU::switch_to(&mut proc.fpu);
+    }
+}
+
+/// Make `pid` the process that receives keyboard interrupts
+#[allow(dead_code)]
+pub fn set_foreground(pid: Pid) -> Result<(), ProcessError> {
+    let mut table = PROCESSES.lock();
+    table.get(pid)?;
+    table.foreground = pid;
+    Ok(())
+}
+
+/// Create a new process as a child of `parent`
+#[allow(dead_code)]
+pub fn create(parent: Pid) -> Result<Pid, ProcessError> {
+    let mut table = PROCESSES.lock();
+    let (blocked, actions) = {
+        let proc = table.get(parent)?;
+        if proc.state != ProcessState::Running {
+            return Err(ProcessError::NoSuchProcess);
+        }
+        (proc.blocked, proc.actions.clone())
+    };
+
+    let pid = table.next_pid;
+    table.next_pid += 1;
+
+    let mut proc = Process::new(parent);
+    proc.blocked = blocked;
+    proc.actions = actions;
+    table.processes.insert(pid, proc);
+    table.get(parent)?.children.push(pid);
+    Ok(pid)
+}
+
+/// Get the state of a process
+#[allow(dead_code)]
+pub fn state(pid: Pid) -> Result<ProcessState, ProcessError> {
+    Ok(PROCESSES.lock().get(pid)?.state)
+}
+
+/// Terminate a process with an exit code
+pub fn exit(pid: Pid, code: i32) -> Result<(), ProcessError> {
+    if pid == KERNEL_PID {
+        return Err(ProcessError::PermissionDenied);
+    }
+    terminate(pid, ExitStatus::Exited(code));
+    Ok(())
+}
+
+/// Wait for a child of `parent` to terminate and reap it.
+///
+/// With `child` set to `None`, any child will do.
+pub fn wait(parent: Pid, child: Option<Pid>) -> Result<(Pid, ExitStatus), ProcessError> {
+    CHILD_EXITED
+        .wait_until(
+            || {
+                let mut table = PROCESSES.lock();
+                let children = match table.get(parent) {
+                    Ok(proc) => proc.children.clone(),
+                    Err(err) => return Some(Err(err)),
+                };
+                let candidates: Vec<Pid> = match child {
+                    Some(pid) if children.contains(&pid) => vec![pid],
+                    Some(_) => return Some(Err(ProcessError::NoChildren)),
+                    None if children.is_empty() => return Some(Err(ProcessError::NoChildren)),
+                    None => children,
+                };
+                for pid in candidates {
+                    if let Some(status) = table.reap(pid) {
+                        return Some(Ok((pid, status)));
+                    }
+                }
+                None
+            },
+            None,
+        )
+        .unwrap()
+}
+
+/// Send a signal to a process
+pub fn kill(pid: Pid, sig: Signal) -> Result<(), ProcessError> {
+    if pid == KERNEL_PID {
+        return Err(ProcessError::PermissionDenied);
+    }
+    let mut table = PROCESSES.lock();
+    let proc = table.get(pid)?;
+    if proc.state != ProcessState::Running {
+        return Ok(());
+    }
+    if sig == Signal::SIGKILL {
+        drop(table);
+        terminate(pid, ExitStatus::Signaled(sig));
+    } else {
+        proc.pending |= sig.mask();
+    }
+    Ok(())
+}
+
+/// Send a signal the process cannot ignore or hold off.
+///
+/// Used for faults: returning to the faulting instruction
+/// without running a handler would only fault again. If the
+/// signal is blocked or ignored, its default action is used.
+pub fn force_signal(pid: Pid, sig: Signal) -> Result<(), ProcessError> {
+    if pid == KERNEL_PID {
+        return Err(ProcessError::PermissionDenied);
+    }
+    {
+        let mut table = PROCESSES.lock();
+        let proc = table.get(pid)?;
+        let unusable = match proc.action(sig) {
+            SignalAction::Ignore => true,
+            _ => proc.blocked & sig.mask() != 0,
+        };
+        if unusable {
+            proc.actions.insert(sig as u32, SignalAction::Default);
+            proc.blocked &= !sig.mask();
+        }
+    }
+    kill(pid, sig)
+}
+
+/// Send SIGINT to the foreground process.
+///
+/// Called by the TTY layer on the interrupt character.
+pub fn interrupt_foreground() {
+    let pid = PROCESSES.lock().foreground;
+    if pid != KERNEL_PID {
+        kill(pid, Signal::SIGINT).ok();
+    }
+}
+
+/// Change how a process handles a signal and return the previous action
+pub fn sigaction(
+    pid: Pid,
+    sig: Signal,
+    action: SignalAction,
+) -> Result<SignalAction, ProcessError> {
+    if !sig.is_catchable() {
+        return Err(ProcessError::InvalidSignal);
+    }
+    let mut table = PROCESSES.lock();
+    let proc = table.get(pid)?;
+    let old = proc.action(sig);
+    proc.actions.insert(sig as u32, action);
+    Ok(old)
+}
+
+/// Finish a trap taken from user mode.
+///
+/// Pending signals of the current process are delivered
+/// by rewriting `frame`. If the process did not survive
+/// them, it must not be returned to. Without a scheduler
+/// there is no other task to switch to, so the CPU then
+/// just keeps handling interrupts.
+pub fn return_to_user(frame: &mut TrapFrame) {
+    let pid = current();
+    let mut ctx = UserContext::from_trap_frame(frame);
+    if unsafe { deliver_signals(pid, &mut ctx) } {
+        ctx.store(frame);
+        return;
+    }
+
+    log!(info: "Process {} is gone, nothing left to run", pid);
+    x86_64::instructions::interrupts::enable();
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Handle the pending signals of a process about to return to userspace.
+///
+/// Default and ignored signals are dealt with right away. For a signal
+/// with a handler, a `SignalFrame` is pushed onto the user stack and
+/// `ctx` is redirected to the handler.
+///
+/// Returns `false` if the process was terminated.
+pub unsafe fn deliver_signals(pid: Pid, ctx: &mut UserContext) -> bool {
+    loop {
+        let (sig, action, blocked) = {
+            let mut table = PROCESSES.lock();
+            let proc = match table.get(pid) {
+                Ok(proc) => proc,
+                Err(_) => return false,
+            };
+            if proc.state != ProcessState::Running {
+                return false;
+            }
+            let deliverable = proc.pending & !proc.blocked;
+            if deliverable == 0 {
+                return true;
+            }
+            let num = deliverable.trailing_zeros();
+            proc.pending &= !(1 << num);
+            let sig = Signal::from_number(num).unwrap();
+            (sig, proc.action(sig), proc.blocked)
+        };
+
+        match action {
+            SignalAction::Ignore => (),
+            SignalAction::Default if !sig.terminates_by_default() => (),
+            SignalAction::Default => {
+                terminate(pid, ExitStatus::Signaled(sig));
+                return false;
+            }
+            SignalAction::Handler { handler, restorer } => {
+                // The frame is written without `PROCESSES` held
+                if push_signal_frame(ctx, sig, blocked, handler, restorer).is_err() {
+                    // There is no room for the frame on the user stack
+                    terminate(pid, ExitStatus::Signaled(Signal::SIGSEGV));
+                    return false;
+                }
+                return match PROCESSES.lock().get(pid) {
+                    Ok(proc) if proc.state == ProcessState::Running => {
+                        proc.blocked |= sig.mask();
+                        true
+                    }
+                    _ => false,
+                };
+            }
+        }
+    }
+}
+
+/// Return from a signal handler.
+///
+/// Restores the context and signal mask saved by `deliver_signals`.
+/// Fails with `InvalidSignal` if the user stack pointer does not
+/// point at a signal frame in user memory.
+pub unsafe fn sigreturn(pid: Pid, ctx: &mut UserContext) -> Result<(), ProcessError> {
+    // The handler's `ret` already popped the restorer address
+    let addr = ctx
+        .rsp
+        .checked_sub(size_of::<u64>() as u64)
+        .ok_or(ProcessError::InvalidSignal)?;
+    if addr % 8 != 0 || !is_user_range(addr, size_of::<SignalFrame>() as u64) {
+        return Err(ProcessError::InvalidSignal);
+    }
+    let frame = &*(addr as *const SignalFrame);
+
+    let mut restored = frame.context;
+    restored.rflags = (restored.rflags & !RFLAGS_IOPL) | RFLAGS_INTERRUPT;
+    *ctx = restored;
+
+    let mut table = PROCESSES.lock();
+    table.get(pid)?.blocked = frame.blocked as u32 & !Signal::SIGKILL.mask();
+    Ok(())
+}
+
+/// Push a `SignalFrame` and redirect `ctx` to the handler.
+///
+/// Fails if the frame would not be in writable user memory.
+unsafe fn push_signal_frame(
+    ctx: &mut UserContext,
+    sig: Signal,
+    blocked: u32,
+    handler: u64,
+    restorer: u64,
+) -> Result<(), ()> {
+    // Skip the red zone and keep the stack aligned like
+    // a regular call would: rsp + 8 is 16-byte aligned.
+    let top = ctx
+        .rsp
+        .checked_sub(RED_ZONE_SIZE + size_of::<SignalFrame>() as u64)
+        .ok_or(())?;
+    let addr = (top & !0xF)
+        .checked_sub(size_of::<u64>() as u64)
+        .ok_or(())?;
+    if !is_user_writable_range(addr, size_of::<SignalFrame>() as u64) {
+        return Err(());
+    }
+
+    *(addr as *mut SignalFrame) = SignalFrame {
+        restorer,
+        signal: sig as u64,
+        blocked: u64::from(blocked),
+        context: *ctx,
+    };
+
+    ctx.rsp = addr;
+    ctx.rip = handler;
+    ctx.rdi = sig as u64;
+    Ok(())
+}
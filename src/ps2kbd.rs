@@ -1,9 +1,17 @@
 #![allow(dead_code)]
 
+use crate::hal::{
+    BusDevice, Device, DeviceCapabilities, DeviceError, DeviceMatch, DeviceType, Driver, PollFlags,
+    DEVICE_MANAGER,
+};
 use crate::kbc::KBC;
 use crate::pic::PIC8259;
+use crate::workqueue::{Work, WorkQueues};
+use alloc::{collections::VecDeque, prelude::*};
+use core::any::Any;
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
-use pc_keyboard::{layouts::Us104Key, DecodedKey, Keyboard, ScancodeSet1};
+use pc_keyboard::{layouts::Us104Key, DecodedKey, KeyCode, KeyState, Keyboard, ScancodeSet1};
 use spin::Mutex;
 use x86_64::structures::idt::ExceptionStackFrame;
 
@@ -15,8 +23,20 @@ lazy_static! {
     pub static ref KEYBOARD: Mutex<Keyboard<Us104Key, ScancodeSet1>> =
         Mutex::new(Keyboard::new(Us104Key, ScancodeSet1));
     pub static ref KEYBOARD_INITIALIZED: Mutex<bool> = Mutex::new(false);
+    static ref KEY_BUFFER: Mutex<VecDeque<u8>> =
+        Mutex::new(VecDeque::with_capacity(KEY_BUFFER_SIZE));
+    static ref KEYBOARD_MATCHES: Vec<DeviceMatch> = vec![DeviceMatch::PS2(1)];
 }
 
+/// The number of decoded bytes buffered for readers
+const KEY_BUFFER_SIZE: usize = 256;
+
+/// Whether a control key is held down
+static CONTROL_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Whether an Alt key is held down
+static ALT_DOWN: AtomicBool = AtomicBool::new(false);
+
 //
 // Keyboard responses
 //
@@ -66,10 +86,12 @@ const KBD_COM_SELF_TEST: u8 = 0xFF;
 pub struct PS2Keyboard;
 impl PS2Keyboard {
     /// Initialize the PS/2 keyboard
-    pub fn init() {
+    pub fn init(name: &'static str) -> Result<(), &'static str> {
         unsafe {
             // Wait till the KBC is ready
-            KBC::wait_ready();
+            if !KBC::wait_ready() {
+                return Err("keyboard controller is not responding");
+            }
 
             // Run self test
             PS2Keyboard::run_self_test();
@@ -88,8 +110,23 @@ impl PS2Keyboard {
             KBC::wait_ready();
         }
 
+        // Register the device
+        DEVICE_MANAGER
+            .lock()
+            .register_device(name, box PS2KeyboardDevice)
+            .map_err(|_| "device name already taken")?;
+
         // Mark the keyboard as initialized
         *KEYBOARD_INITIALIZED.lock() = true;
+        Ok(())
+    }
+
+    /// Stop the keyboard and unregister its device
+    pub fn shutdown(name: &str) {
+        *KEYBOARD_INITIALIZED.lock() = false;
+        unsafe { KBC::write_byte(KBD_COM_SCAN_OFF) };
+        DEVICE_MANAGER.lock().unregister_device(name);
+        KEY_BUFFER.lock().clear();
     }
 
     unsafe fn run_self_test() {
@@ -166,19 +203,68 @@ impl PS2Keyboard {
     }
 }
 
-fn read_next_key() {
-    let data = unsafe { KBC::read_byte() };
+/// Driver for the keyboard on the first PS/2 port
+pub struct PS2KeyboardDriver;
+
+impl Driver for PS2KeyboardDriver {
+    fn name(&self) -> &'static str {
+        "ps2kbd"
+    }
+
+    fn match_table(&self) -> &[DeviceMatch] {
+        &KEYBOARD_MATCHES
+    }
+
+    fn probe(&self, _dev: &BusDevice) -> Result<(), &'static str> {
+        PS2Keyboard::init("kbd0")
+    }
+
+    fn remove(&self, _dev: &BusDevice) -> Result<(), &'static str> {
+        PS2Keyboard::shutdown("kbd0");
+        Ok(())
+    }
+}
+
+/// Decode a scancode and handle the resulting key.
+///
+/// This runs as deferred work, outside the interrupt handler.
+fn process_scancode(data: usize) {
+    let data = data as u8;
     let mut kbd = KEYBOARD.lock();
     match kbd.add_byte(data) {
         Ok(Some(event)) => {
-            let key = kbd.process_keyevent(event.clone());
-            if key.is_some() {
-                match key.unwrap() {
-                    DecodedKey::RawKey(code) => print!("{:?}", code),
-                    DecodedKey::Unicode(chr) => print!("{}", chr),
+            match (event.code, event.state) {
+                (KeyCode::ControlLeft, state) | (KeyCode::ControlRight, state) => {
+                    CONTROL_DOWN.store(state == KeyState::Down, Ordering::Relaxed);
+                }
+                (KeyCode::AltLeft, state) | (KeyCode::AltRight, state) => {
+                    ALT_DOWN.store(state == KeyState::Down, Ordering::Relaxed);
                 }
-            } else {
-                // println!("[ps2kbd] Key event is none: {:?}; {:?}", event.code, event.state);
+                // Alt+F1 to Alt+F6 switch virtual consoles
+                (code, KeyState::Down) if ALT_DOWN.load(Ordering::Relaxed) => {
+                    if let Some(console) = console_for_key(code) {
+                        crate::tty::switch_console(console);
+                        return;
+                    }
+                }
+                _ => (),
+            }
+
+            let key = kbd.process_keyevent(event);
+            drop(kbd);
+            match key {
+                Some(DecodedKey::Unicode(chr)) => {
+                    let chr = apply_control(chr);
+                    buffer_char(chr);
+                    let mut utf8 = [0u8; 4];
+                    crate::tty::keyboard_input(chr.encode_utf8(&mut utf8).as_bytes());
+                }
+                Some(DecodedKey::RawKey(code)) => {
+                    if let Some(seq) = escape_sequence(code) {
+                        crate::tty::keyboard_input(seq);
+                    }
+                }
+                None => (),
             }
         }
         Ok(None) => (),
@@ -186,16 +272,103 @@ fn read_next_key() {
     };
 }
 
+/// Get the virtual console a function key switches to
+fn console_for_key(code: KeyCode) -> Option<usize> {
+    match code {
+        KeyCode::F1 => Some(0),
+        KeyCode::F2 => Some(1),
+        KeyCode::F3 => Some(2),
+        KeyCode::F4 => Some(3),
+        KeyCode::F5 => Some(4),
+        KeyCode::F6 => Some(5),
+        _ => None,
+    }
+}
+
+/// Turn a character into its control code while Ctrl is held,
+/// so Ctrl+C becomes 0x03. Backspace becomes DEL, like on a
+/// Unix console.
+fn apply_control(chr: char) -> char {
+    match chr {
+        '\x08' => '\x7f',
+        '@'...'_' | 'a'...'z' if CONTROL_DOWN.load(Ordering::Relaxed) => (chr as u8 & 0x1F) as char,
+        _ => chr,
+    }
+}
+
+/// Get the ANSI escape sequence of a key without a character
+fn escape_sequence(code: KeyCode) -> Option<&'static [u8]> {
+    match code {
+        KeyCode::ArrowUp => Some(b"\x1b[A"),
+        KeyCode::ArrowDown => Some(b"\x1b[B"),
+        KeyCode::ArrowRight => Some(b"\x1b[C"),
+        KeyCode::ArrowLeft => Some(b"\x1b[D"),
+        _ => None,
+    }
+}
+
+/// Make a decoded character available to readers
+fn buffer_char(chr: char) {
+    let mut utf8 = [0u8; 4];
+    let mut buf = KEY_BUFFER.lock();
+    for b in chr.encode_utf8(&mut utf8).bytes() {
+        if buf.len() >= KEY_BUFFER_SIZE {
+            break;
+        }
+        buf.push_back(b);
+    }
+}
+
+/// The PS/2 keyboard as a readable character device
+pub struct PS2KeyboardDevice;
+
+impl Device for PS2KeyboardDevice {
+    fn get_type(&self) -> DeviceType {
+        DeviceType::CharDevice
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities::READ | DeviceCapabilities::POLL
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        let mut keys = KEY_BUFFER.lock();
+        let mut count = 0;
+        for b in buf.iter_mut() {
+            match keys.pop_front() {
+                Some(val) => *b = val,
+                None => break,
+            }
+            count += 1;
+        }
+        match count {
+            0 if !buf.is_empty() => Err(DeviceError::WouldBlock),
+            _ => Ok(count),
+        }
+    }
+
+    fn poll(&mut self) -> PollFlags {
+        if KEY_BUFFER.lock().is_empty() {
+            PollFlags::empty()
+        } else {
+            PollFlags::READABLE
+        }
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
 pub extern "x86-interrupt" fn handle_interrupt(_stack_frame: &mut ExceptionStackFrame) {
+    // Read the scancode to acknowledge the key
+    let data = unsafe { KBC::read_byte() };
+
     // Is the keyboard already initialized?
     if *KEYBOARD_INITIALIZED.lock() {
-        // Process the next key
-        read_next_key();
-    } else {
-        // Discard the key
-        unsafe {
-            KBC::read_byte();
-        }
+        // Decode the key later, outside the interrupt handler.
+        // If the queue is full, the key is lost.
+        WorkQueues::queue(Work::new(process_scancode, data as usize)).ok();
     }
 
     // Notify the PIC
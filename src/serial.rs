@@ -1,6 +1,109 @@
-use crate::hal::{Device, DeviceType, DEVICE_MANAGER};
+use crate::hal::{
+    BusDevice, Device, DeviceCapabilities, DeviceError, DeviceMatch, DeviceType, Driver, PollFlags,
+    DEVICE_MANAGER,
+};
+use crate::idt::IDT;
+use crate::tty::Output;
+use crate::workqueue::{Work, WorkQueues};
+use alloc::{collections::VecDeque, prelude::*, sync::Arc};
 use core::any::Any;
-use x86_64::instructions::port::Port;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+lazy_static! {
+    static ref SERIAL_MATCHES: Vec<DeviceMatch> = vec![DeviceMatch::Platform("ns16550")];
+    static ref UARTS: Mutex<Vec<Arc<UART>>> = Mutex::new(Vec::new());
+}
+
+/// IRQ lines our handler is registered for, one bit per line
+static IRQS_REGISTERED: AtomicU16 = AtomicU16::new(0);
+
+//
+// Registers, relative to the base port
+//
+
+const REG_DATA: u16 = 0;
+const REG_INT_ENABLE: u16 = 1;
+const REG_DIVISOR_LO: u16 = 0;
+const REG_DIVISOR_HI: u16 = 1;
+const REG_INT_ID: u16 = 2;
+const REG_FIFO_CTRL: u16 = 2;
+const REG_LINE_CTRL: u16 = 3;
+const REG_MODEM_CTRL: u16 = 4;
+const REG_LINE_STATUS: u16 = 5;
+const REG_MODEM_STATUS: u16 = 6;
+const REG_SCRATCH: u16 = 7;
+
+// Interrupt enable bits
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+const IER_LINE_STATUS: u8 = 1 << 2;
+const IER_MODEM_STATUS: u8 = 1 << 3;
+
+// Interrupt identification
+const IIR_NO_INTERRUPT: u8 = 1 << 0;
+const IIR_ID_MASK: u8 = 0x0E;
+const IIR_MODEM_STATUS: u8 = 0x00;
+const IIR_TX_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_RX_TIMEOUT: u8 = 0x0C;
+const IIR_FIFO_MASK: u8 = 0xC0;
+const IIR_FIFO_WORKING: u8 = 0xC0;
+const IIR_FIFO_BROKEN: u8 = 0x80;
+const IIR_FIFO_64: u8 = 1 << 5;
+
+// FIFO control: enable, clear both, 14-byte threshold
+const FCR_ENABLE: u8 = 0xC7;
+const FCR_ENABLE_64: u8 = 1 << 5;
+
+// Line control
+const LCR_DLAB: u8 = 1 << 7;
+const LCR_TWO_STOP_BITS: u8 = 1 << 2;
+
+// Modem control: DTR, RTS and OUT2, which gates the IRQ line
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT2: u8 = 1 << 3;
+
+// Line status
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_ERRORS: u8 = 0x1E;
+const LSR_TX_EMPTY: u8 = 1 << 5;
+
+// Modem status
+const MSR_CTS: u8 = 1 << 4;
+
+/// The UART input clock divided by 16
+const BASE_BAUD: u32 = 115_200;
+
+/// The size of the receive and transmit rings
+const RING_SIZE: usize = 4096;
+
+/// With flow control, RTS drops when the receive ring fills up to here
+const RX_HIGH_WATER: usize = RING_SIZE * 3 / 4;
+
+/// and comes back when it drains to here
+const RX_LOW_WATER: usize = RING_SIZE / 4;
+
+/// Interrupts handled at once before giving up on a stuck UART
+const MAX_INTERRUPT_LOOPS: usize = 16;
+
+//
+// Serial control requests
+//
+
+/// Get the line settings, packed as by `SerialConfig::pack`
+pub const SERIAL_IOCTL_GET_CONFIG: u32 = 0x2001;
+
+/// Change the line settings, packed as by `SerialConfig::pack`
+pub const SERIAL_IOCTL_SET_CONFIG: u32 = 0x2002;
+
+/// Get the number of received bytes that were lost
+pub const SERIAL_IOCTL_GET_OVERRUNS: u32 = 0x2003;
 
 pub enum SerialPort {
     COM1 = 0x3F8,
@@ -9,127 +112,689 @@ pub enum SerialPort {
     COM4 = 0x2E8,
 }
 
-pub struct SerialDevice {
-    data: Port<u8>,
-    dlab_lo: Port<u8>,
-    dlab_hi: Port<u8>,
-    int_ctrl: Port<u8>,
-    fifo_ctrl: Port<u8>,
-    line_ctrl: Port<u8>,
-    modem_ctrl: Port<u8>,
-    line_status: Port<u8>,
-    modem_status: Port<u8>,
-    scratch: Port<u8>,
+impl SerialPort {
+    /// Get the IRQ line the port conventionally uses
+    pub fn irq(&self) -> u8 {
+        match *self {
+            SerialPort::COM1 | SerialPort::COM3 => 4,
+            SerialPort::COM2 | SerialPort::COM4 => 3,
+        }
+    }
 }
 
-impl SerialDevice {
-    pub fn init(name: &'static str, port: SerialPort) -> Result<(), &str> {
-        let base_port = port as u16;
-
-        // Create the serial device
-        let mut dev = SerialDevice {
-            data: Port::new(base_port),
-            dlab_lo: Port::new(base_port),
-            int_ctrl: Port::new(base_port + 1),
-            dlab_hi: Port::new(base_port + 1),
-            fifo_ctrl: Port::new(base_port + 2),
-            line_ctrl: Port::new(base_port + 3),
-            modem_ctrl: Port::new(base_port + 4),
-            line_status: Port::new(base_port + 5),
-            modem_status: Port::new(base_port + 6),
-            scratch: Port::new(base_port + 7),
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,
+    Space,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    One,
+    /// Two stop bits, or one and a half with 5 data bits
+    Two,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowControl {
+    None,
+    /// Hardware flow control through RTS and CTS
+    RtsCts,
+}
+
+/// Line settings of a serial port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialConfig {
+    pub baud: u32,
+    /// The number of data bits, 5 to 8
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub flow_control: FlowControl,
+}
+
+impl Default for SerialConfig {
+    /// 38400 baud, 8N1, no flow control
+    fn default() -> Self {
+        SerialConfig {
+            baud: 38400,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+impl SerialConfig {
+    fn divisor(&self) -> Result<u16, &'static str> {
+        if self.baud == 0 || BASE_BAUD % self.baud != 0 || BASE_BAUD / self.baud > 0xFFFF {
+            return Err("Unsupported baud rate");
+        }
+        Ok((BASE_BAUD / self.baud) as u16)
+    }
+
+    fn line_control(&self) -> Result<u8, &'static str> {
+        if self.data_bits < 5 || self.data_bits > 8 {
+            return Err("Unsupported number of data bits");
+        }
+        let parity = match self.parity {
+            Parity::None => 0x00,
+            Parity::Odd => 0x08,
+            Parity::Even => 0x18,
+            Parity::Mark => 0x28,
+            Parity::Space => 0x38,
+        };
+        let stop = match self.stop_bits {
+            StopBits::One => 0,
+            StopBits::Two => LCR_TWO_STOP_BITS,
         };
+        Ok(self.data_bits - 5 | stop | parity)
+    }
 
-        // Initialzie the serial device
-        unsafe { dev.init_bus() }
+    /// Pack the settings into an ioctl argument.
+    ///
+    /// Bits 0-31 hold the baud rate, 32-35 the data bits,
+    /// 36-38 the parity, 39 two stop bits and 40 RTS/CTS.
+    pub fn pack(&self) -> usize {
+        let parity = match self.parity {
+            Parity::None => 0,
+            Parity::Odd => 1,
+            Parity::Even => 2,
+            Parity::Mark => 3,
+            Parity::Space => 4,
+        };
+        self.baud as usize
+            | (self.data_bits as usize) << 32
+            | parity << 36
+            | ((self.stop_bits == StopBits::Two) as usize) << 39
+            | ((self.flow_control == FlowControl::RtsCts) as usize) << 40
+    }
 
-        // Register the device
-        DEVICE_MANAGER
-            .lock()
-            .register_device(name, box dev)
-            .expect("Unable to initialize serial device!");
+    pub fn unpack(raw: usize) -> Option<SerialConfig> {
+        let parity = match (raw >> 36) & 0x7 {
+            0 => Parity::None,
+            1 => Parity::Odd,
+            2 => Parity::Even,
+            3 => Parity::Mark,
+            4 => Parity::Space,
+            _ => return None,
+        };
+        Some(SerialConfig {
+            baud: raw as u32,
+            data_bits: ((raw >> 32) & 0xF) as u8,
+            parity,
+            stop_bits: match (raw >> 39) & 1 {
+                0 => StopBits::One,
+                _ => StopBits::Two,
+            },
+            flow_control: match (raw >> 40) & 1 {
+                0 => FlowControl::None,
+                _ => FlowControl::RtsCts,
+            },
+        })
+    }
+}
+
+/// The kind of UART, told apart by its FIFO
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UARTKind {
+    /// No scratch register and no FIFO
+    U8250,
+    /// No FIFO
+    U16450,
+    /// A FIFO that must not be used
+    U16550,
+    /// A working 16-byte FIFO
+    U16550A,
+    /// A working 64-byte FIFO
+    U16750,
+}
+
+impl UARTKind {
+    /// Get the number of bytes the transmitter takes at once
+    fn fifo_size(self) -> usize {
+        match self {
+            UARTKind::U16550A => 16,
+            UARTKind::U16750 => 64,
+            _ => 1,
+        }
+    }
+}
+
+/// A UART and the state its interrupt handler shares
+struct UART {
+    base: u16,
+    kind: UARTKind,
+    irq: Mutex<Option<u8>>,
+    config: Mutex<SerialConfig>,
+    rx: Mutex<VecDeque<u8>>,
+    tx: Mutex<VecDeque<u8>>,
+    /// Received bytes lost to full buffers
+    overruns: AtomicUsize,
+    /// Work queued when input arrives
+    input_work: Mutex<Option<Work>>,
+    /// Whether the input work is queued and input was not read since
+    input_pending: AtomicBool,
+}
+
+impl UART {
+    fn read_reg(&self, reg: u16) -> u8 {
+        let port: Port<u8> = Port::new(self.base + reg);
+        unsafe { port.read() }
+    }
+
+    fn write_reg(&self, reg: u16, val: u8) {
+        let mut port: Port<u8> = Port::new(self.base + reg);
+        unsafe { port.write(val) }
+    }
+
+    /// Write the FIFO control register.
+    ///
+    /// The 16750 only takes the 64-byte FIFO bit with DLAB set.
+    fn write_fifo_ctrl(&self, val: u8) {
+        if val & FCR_ENABLE_64 == 0 {
+            self.write_reg(REG_FIFO_CTRL, val);
+            return;
+        }
+        let line_control = self.read_reg(REG_LINE_CTRL);
+        self.write_reg(REG_LINE_CTRL, line_control | LCR_DLAB);
+        self.write_reg(REG_FIFO_CTRL, val);
+        self.write_reg(REG_LINE_CTRL, line_control);
+    }
+
+    /// Find out whether a UART is present and which kind it is
+    fn detect(base: u16) -> Option<UARTKind> {
+        let probe = UART::new(base, UARTKind::U8250);
+
+        // Nothing answers on a floating bus
+        if probe.read_reg(REG_LINE_STATUS) == 0xFF {
+            return None;
+        }
+
+        // The 8250 lacks the scratch register
+        probe.write_reg(REG_SCRATCH, 0x55);
+        let scratch_a = probe.read_reg(REG_SCRATCH);
+        probe.write_reg(REG_SCRATCH, 0xAA);
+        let scratch_b = probe.read_reg(REG_SCRATCH);
+        if scratch_a != 0x55 || scratch_b != 0xAA {
+            return Some(UARTKind::U8250);
+        }
+
+        // Try to turn the FIFO on and see what the IIR reports
+        probe.write_fifo_ctrl(FCR_ENABLE | FCR_ENABLE_64);
+        let iir = probe.read_reg(REG_INT_ID);
+        probe.write_reg(REG_FIFO_CTRL, 0);
+        Some(match iir & IIR_FIFO_MASK {
+            IIR_FIFO_WORKING if iir & IIR_FIFO_64 != 0 => UARTKind::U16750,
+            IIR_FIFO_WORKING => UARTKind::U16550A,
+            IIR_FIFO_BROKEN => UARTKind::U16550,
+            _ => UARTKind::U16450,
+        })
+    }
+
+    fn new(base: u16, kind: UARTKind) -> Self {
+        UART {
+            base,
+            kind,
+            irq: Mutex::new(None),
+            config: Mutex::new(SerialConfig::default()),
+            rx: Mutex::new(VecDeque::with_capacity(RING_SIZE)),
+            tx: Mutex::new(VecDeque::with_capacity(RING_SIZE)),
+            overruns: AtomicUsize::new(0),
+            input_work: Mutex::new(None),
+            input_pending: AtomicBool::new(false),
+        }
+    }
+
+    /// Get the IRQ line, if interrupts are used.
+    ///
+    /// State shared with the interrupt handler is only ever
+    /// locked with interrupts disabled.
+    fn irq(&self) -> Option<u8> {
+        interrupts::without_interrupts(|| *self.irq.lock())
+    }
+
+    fn flow_control(&self) -> bool {
+        self.config.lock().flow_control == FlowControl::RtsCts
+    }
+
+    /// Program the line settings
+    fn configure(&self, config: SerialConfig) -> Result<(), &'static str> {
+        let divisor = config.divisor()?;
+        let line_control = config.line_control()?;
+
+        interrupts::without_interrupts(|| {
+            self.write_reg(REG_INT_ENABLE, 0);
+            self.write_reg(REG_LINE_CTRL, LCR_DLAB);
+            self.write_reg(REG_DIVISOR_LO, divisor as u8);
+            self.write_reg(REG_DIVISOR_HI, (divisor >> 8) as u8);
+            self.write_reg(REG_LINE_CTRL, line_control);
+            match self.kind {
+                UARTKind::U16550A => self.write_reg(REG_FIFO_CTRL, FCR_ENABLE),
+                UARTKind::U16750 => self.write_fifo_ctrl(FCR_ENABLE | FCR_ENABLE_64),
+                _ => (),
+            }
+            self.write_reg(REG_MODEM_CTRL, MCR_DTR | MCR_RTS | MCR_OUT2);
+            *self.config.lock() = config;
+            self.update_interrupts(!self.tx.lock().is_empty());
+        });
         Ok(())
     }
 
-    unsafe fn init_bus(&mut self) {
-        self.int_ctrl.write(0x00); // Disable INTs
-        self.line_ctrl.write(0x80); // Enable DLAB
-        self.dlab_lo.write(0x03); // Set divisor lo byte
-        self.dlab_hi.write(0x00); // Set divisor hi byte
-        self.line_ctrl.write(0x03); // 8 bits, no parity, 1 stop bit
-        self.fifo_ctrl.write(0xC7); // Enable FIFO, clear with 14-byte threshold
-        self.modem_ctrl.write(0x0B); // Enable IRQs, set RTS/DSR
+    /// Enable the interrupts we need, if we have an IRQ line
+    fn update_interrupts(&self, tx_pending: bool) {
+        if self.irq.lock().is_none() {
+            return;
+        }
+        let mut enable = IER_RX_AVAILABLE | IER_LINE_STATUS;
+        if self.flow_control() {
+            enable |= IER_MODEM_STATUS;
+        }
+        if tx_pending {
+            enable |= IER_TX_EMPTY;
+        }
+        self.write_reg(REG_INT_ENABLE, enable);
     }
 
-    unsafe fn is_empty(&self) -> bool {
-        self.line_status.read() & 0x20 != 0
+    /// Handle all pending interrupts of the UART
+    fn service(&self) {
+        for _ in 0..MAX_INTERRUPT_LOOPS {
+            let iir = self.read_reg(REG_INT_ID);
+            if iir & IIR_NO_INTERRUPT != 0 {
+                break;
+            }
+            match iir & IIR_ID_MASK {
+                IIR_LINE_STATUS => self.check_line_status(),
+                IIR_RX_AVAILABLE | IIR_RX_TIMEOUT => self.receive(),
+                IIR_TX_EMPTY => self.transmit(),
+                IIR_MODEM_STATUS => {
+                    // Reading the status acknowledges the change
+                    self.read_reg(REG_MODEM_STATUS);
+                    self.transmit();
+                }
+                _ => break,
+            }
+        }
+
+        if !self.rx.lock().is_empty() {
+            self.notify_input();
+        }
     }
 
-    unsafe fn has_received(&self) -> bool {
-        self.line_status.read() & 0x1 != 0
+    /// Queue the input work, unless it is already pending.
+    ///
+    /// Must run with interrupts disabled.
+    fn notify_input(&self) {
+        if let Some(work) = *self.input_work.lock() {
+            if !self.input_pending.swap(true, Ordering::AcqRel) {
+                WorkQueues::queue(work).ok();
+            }
+        }
     }
 
-    unsafe fn read_u8_block(&self) -> u8 {
-        while !self.has_received() {}
-        self.data.read()
+    fn check_line_status(&self) {
+        let status = self.read_reg(REG_LINE_STATUS);
+        if status & LSR_OVERRUN != 0 {
+            self.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+        if status & LSR_DATA_READY != 0 {
+            self.receive();
+        }
     }
 
-    unsafe fn read_u8_now(&self) -> Option<u8> {
-        if self.has_received() {
-            Some(self.data.read())
-        } else {
-            None
+    /// Move received bytes from the UART into the receive ring.
+    ///
+    /// Must run with interrupts disabled.
+    fn receive(&self) {
+        let mut rx = self.rx.lock();
+        loop {
+            let status = self.read_reg(REG_LINE_STATUS);
+            if status & LSR_DATA_READY == 0 {
+                break;
+            }
+            let byte = self.read_reg(REG_DATA);
+            // Drop bytes with framing or parity errors
+            if status & LSR_ERRORS & !LSR_OVERRUN != 0 {
+                continue;
+            }
+            if rx.len() < RING_SIZE {
+                rx.push_back(byte);
+            } else {
+                self.overruns.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        if rx.len() >= RX_HIGH_WATER && self.flow_control() {
+            let control = self.read_reg(REG_MODEM_CTRL);
+            self.write_reg(REG_MODEM_CTRL, control & !MCR_RTS);
+        }
+    }
+
+    /// Move bytes from the transmit ring into the UART.
+    ///
+    /// Must run with interrupts disabled.
+    fn transmit(&self) {
+        let mut tx = self.tx.lock();
+        let clear_to_send = !self.flow_control() || self.read_reg(REG_MODEM_STATUS) & MSR_CTS != 0;
+        if clear_to_send && self.read_reg(REG_LINE_STATUS) & LSR_TX_EMPTY != 0 {
+            for _ in 0..self.kind.fifo_size() {
+                match tx.pop_front() {
+                    Some(byte) => self.write_reg(REG_DATA, byte),
+                    None => break,
+                }
+            }
+        }
+        self.update_interrupts(!tx.is_empty());
+    }
+
+    /// Queue bytes for sending.
+    ///
+    /// Without a working IRQ, or with interrupts disabled as in
+    /// fault handlers, the bytes are pushed out by polling.
+    fn send(&self, bytes: &[u8]) {
+        let polled = self.irq().is_none() || !interrupts::are_enabled();
+        for &byte in bytes {
+            loop {
+                let queued = interrupts::without_interrupts(|| {
+                    let mut tx = self.tx.lock();
+                    if tx.len() < RING_SIZE {
+                        tx.push_back(byte);
+                        true
+                    } else {
+                        false
+                    }
+                });
+                if queued {
+                    break;
+                }
+                // The ring is full, help it drain
+                interrupts::without_interrupts(|| self.transmit());
+                core::sync::atomic::spin_loop_hint();
+            }
+        }
+
+        interrupts::without_interrupts(|| self.transmit());
+        if polled {
+            while interrupts::without_interrupts(|| {
+                self.transmit();
+                !self.tx.lock().is_empty()
+            }) {
+                core::sync::atomic::spin_loop_hint();
+            }
         }
     }
 
-    unsafe fn write_u8_block(&mut self, val: u8) {
-        while !self.is_empty() {}
-        self.data.write(val)
+    /// Take received bytes out of the receive ring
+    fn recv(&self, buf: &mut [u8]) -> usize {
+        interrupts::without_interrupts(|| {
+            // Input arriving from now on needs new work
+            self.input_pending.store(false, Ordering::Release);
+
+            // Also picks up bytes if there is no IRQ
+            self.receive();
+
+            let mut rx = self.rx.lock();
+            let mut count = 0;
+            for b in buf.iter_mut() {
+                match rx.pop_front() {
+                    Some(val) => *b = val,
+                    None => break,
+                }
+                count += 1;
+            }
+
+            if rx.len() <= RX_LOW_WATER && self.flow_control() {
+                let control = self.read_reg(REG_MODEM_CTRL);
+                self.write_reg(REG_MODEM_CTRL, control | MCR_RTS);
+            }
+            count
+        })
     }
+}
 
-    unsafe fn write_u8_now(&mut self, val: u8) -> Option<()> {
-        if self.is_empty() {
-            self.data.write(val);
-            Some(())
-        } else {
-            None
+/// Handle an interrupt on a serial IRQ line
+fn handle_interrupt(irq: u8) {
+    for uart in UARTS.lock().iter() {
+        if *uart.irq.lock() == Some(irq) {
+            uart.service();
         }
     }
 }
 
+/// The buffers of a serial port, for layers above the device
+#[derive(Clone)]
+pub struct SerialLine {
+    uart: Arc<UART>,
+}
+
+impl SerialLine {
+    /// Read received bytes without blocking
+    pub fn read(&self, buf: &mut [u8]) -> usize {
+        self.uart.recv(buf)
+    }
+
+    /// Write bytes as they are
+    pub fn write(&self, val: &[u8]) {
+        self.uart.send(val);
+    }
+
+    /// Queue `work` whenever input arrives.
+    ///
+    /// It is queued again only after a read.
+    pub fn set_input_work(&self, work: Option<Work>) {
+        interrupts::without_interrupts(|| *self.uart.input_work.lock() = work);
+    }
+}
+
+impl Output for SerialLine {
+    fn write(&mut self, val: &[u8]) {
+        self.uart.send(val);
+    }
+}
+
+/// A serial port as a readable and writable character device
+pub struct SerialDevice {
+    uart: Arc<UART>,
+}
+
+impl SerialDevice {
+    pub fn init(name: &'static str, port: SerialPort) -> Result<(), &'static str> {
+        let irq = port.irq();
+        SerialDevice::init_at(name, port as u16, Some(irq))
+    }
+
+    /// Test whether a UART answers at a base port.
+    ///
+    /// This resets the FIFO, so the port must not be in use yet.
+    pub fn is_present(base_port: u16) -> bool {
+        UART::detect(base_port).is_some()
+    }
+
+    fn init_at(name: &'static str, base_port: u16, irq: Option<u8>) -> Result<(), &'static str> {
+        let kind = UART::detect(base_port).ok_or("No UART found")?;
+        let uart = Arc::new(UART::new(base_port, kind));
+        uart.configure(SerialConfig::default())?;
+
+        // Register the device
+        let dev = SerialDevice { uart: uart.clone() };
+        DEVICE_MANAGER
+            .lock()
+            .register_device(name, box dev)
+            .map_err(|_| "device name already taken")?;
+
+        // Take interrupts if the line is ours, poll otherwise
+        interrupts::without_interrupts(|| UARTS.lock().push(uart.clone()));
+        if let Some(irq) = irq {
+            let bit = 1 << irq;
+            let registered = IRQS_REGISTERED.load(Ordering::Acquire) & bit != 0
+                || IDT::register_irq(irq, handle_interrupt).is_ok();
+            if registered {
+                IRQS_REGISTERED.fetch_or(bit, Ordering::AcqRel);
+                interrupts::without_interrupts(|| {
+                    *uart.irq.lock() = Some(irq);
+                    uart.update_interrupts(!uart.tx.lock().is_empty());
+                });
+            }
+        }
+
+        log!(
+            debug: "Serial port {} at 0x{:x} is a {:?}{}.",
+            name,
+            base_port,
+            kind,
+            match uart.irq() {
+                Some(_) => "",
+                None => ", polling",
+            }
+        );
+        Ok(())
+    }
+
+    /// Stop the interrupts of a serial port and unregister it
+    fn shutdown(name: &str) -> Result<(), &'static str> {
+        let device = DEVICE_MANAGER
+            .lock()
+            .unregister_device(name)
+            .ok_or("device is not registered")?;
+        let mut device = device.lock();
+        if let Some(serial) = device.as_any().downcast_mut::<SerialDevice>() {
+            let uart = serial.uart.clone();
+            interrupts::without_interrupts(|| {
+                uart.write_reg(REG_INT_ENABLE, 0);
+                UARTS.lock().retain(|other| !Arc::ptr_eq(other, &uart));
+            });
+        }
+        Ok(())
+    }
+
+    pub fn config(&self) -> SerialConfig {
+        interrupts::without_interrupts(|| *self.uart.config.lock())
+    }
+
+    /// Change the line settings
+    pub fn configure(&mut self, config: SerialConfig) -> Result<(), &'static str> {
+        self.uart.configure(config)
+    }
+
+    /// Get a handle to the port's buffers
+    pub fn line(&self) -> SerialLine {
+        SerialLine {
+            uart: self.uart.clone(),
+        }
+    }
+
+    pub fn kind(&self) -> UARTKind {
+        self.uart.kind
+    }
+}
+
 impl Device for SerialDevice {
     fn get_type(&self) -> DeviceType {
         DeviceType::CharDevice
     }
 
-    fn write_byte(&mut self, at: usize, val: u8) {
-        unsafe { self.write_u8_block(val) }
+    fn capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities::READ
+            | DeviceCapabilities::WRITE
+            | DeviceCapabilities::CONTROL
+            | DeviceCapabilities::POLL
     }
 
-    fn write_bytes(&mut self, at: usize, val: &[u8], len: usize) {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        match self.uart.recv(buf) {
+            0 if !buf.is_empty() => Err(DeviceError::WouldBlock),
+            count => Ok(count),
+        }
+    }
+
+    fn write_bytes(&mut self, val: &[u8]) -> Result<usize, DeviceError> {
+        let mut out = Vec::with_capacity(val.len());
         let mut last_byte = 0_u8;
-        for b in val {
-            if last_byte != b'\r' && *b == b'\n' {
-                self.write_byte(0, b'\r');
+        for &b in val {
+            if last_byte != b'\r' && b == b'\n' {
+                out.push(b'\r');
+            }
+            out.push(b);
+            last_byte = b;
+        }
+        self.uart.send(&out);
+        Ok(val.len())
+    }
+
+    fn ioctl(&mut self, request: u32, arg: usize) -> Result<usize, DeviceError> {
+        match request {
+            SERIAL_IOCTL_GET_CONFIG => Ok(self.config().pack()),
+            SERIAL_IOCTL_SET_CONFIG => {
+                let config = SerialConfig::unpack(arg).ok_or(DeviceError::InvalidArgument)?;
+                self.configure(config)
+                    .map(|_| 0)
+                    .map_err(|_| DeviceError::InvalidArgument)
             }
-            self.write_byte(0, *b);
-            last_byte = *b;
+            SERIAL_IOCTL_GET_OVERRUNS => Ok(self.uart.overruns.load(Ordering::Relaxed)),
+            _ => Err(DeviceError::Unsupported),
         }
     }
 
+    fn poll(&mut self) -> PollFlags {
+        let uart = &self.uart;
+        interrupts::without_interrupts(|| {
+            uart.receive();
+            let mut flags = PollFlags::empty();
+            if !uart.rx.lock().is_empty() {
+                flags |= PollFlags::READABLE;
+            }
+            if uart.tx.lock().len() < RING_SIZE {
+                flags |= PollFlags::WRITABLE;
+            }
+            flags
+        })
+    }
+
     fn as_any(&mut self) -> &mut dyn Any {
         self
     }
 }
 
+/// Driver for 16550-compatible UARTs
+pub struct SerialDriver;
+
+impl Driver for SerialDriver {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn match_table(&self) -> &[DeviceMatch] {
+        &SERIAL_MATCHES
+    }
+
+    fn probe(&self, dev: &BusDevice) -> Result<(), &'static str> {
+        match *dev {
+            BusDevice::Platform(ref dev) => {
+                SerialDevice::init_at(dev.name, dev.base as u16, dev.irq)
+            }
+            _ => Err("not a platform device"),
+        }
+    }
+
+    fn remove(&self, dev: &BusDevice) -> Result<(), &'static str> {
+        match *dev {
+            BusDevice::Platform(ref dev) => SerialDevice::shutdown(dev.name),
+            _ => Err("not a platform device"),
+        }
+    }
+}
+
 unsafe impl Sync for SerialDevice {}
 unsafe impl Send for SerialDevice {}
 
 impl core::fmt::Write for SerialDevice {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let bytes = s.as_bytes();
-        let len = s.len();
-        self.write_bytes(0, bytes, len);
-        Ok(())
+        self.write_bytes(s.as_bytes())
+            .map(|_| ())
+            .map_err(|_| core::fmt::Error)
     }
 }
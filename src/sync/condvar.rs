@@ -0,0 +1,68 @@
+use super::{BlockingMutexGuard, WaitQueue};
+
+/// A condition variable for use with `BlockingMutex`
+#[allow(dead_code)]
+pub struct Condvar {
+    queue: WaitQueue,
+}
+
+#[allow(dead_code)]
+impl Condvar {
+    pub fn new() -> Self {
+        Condvar {
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Release the mutex and sleep until notified, then lock it again
+    pub fn wait<'a, T>(&self, guard: BlockingMutexGuard<'a, T>) -> BlockingMutexGuard<'a, T> {
+        self.wait_timeout(guard, None).0
+    }
+
+    /// Like `wait`, but gives up after `timeout` ticks.
+    ///
+    /// The returned flag is `false` on timeout.
+    pub fn wait_timeout<'a, T>(
+        &self,
+        guard: BlockingMutexGuard<'a, T>,
+        timeout: Option<usize>,
+    ) -> (BlockingMutexGuard<'a, T>, bool) {
+        let mutex = guard.mutex;
+        let deadline = timeout.map(crate::pit::PIT::deadline);
+
+        // Register before unlocking, so a notification
+        // sent right after the unlock is not missed.
+        let notified = {
+            let waiter = self.queue.register();
+            drop(guard);
+            waiter.sleep(deadline)
+        };
+
+        (mutex.lock(), notified)
+    }
+
+    /// Wait until `cond` holds for the protected data
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: BlockingMutexGuard<'a, T>,
+        mut cond: F,
+    ) -> BlockingMutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while cond(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wake one waiting task
+    pub fn notify_one(&self) {
+        self.queue.notify_one();
+    }
+
+    /// Wake all waiting tasks
+    pub fn notify_all(&self) {
+        self.queue.notify_all();
+    }
+}
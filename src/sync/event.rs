@@ -0,0 +1,77 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::WaitQueue;
+
+/// An event tasks can wait for.
+///
+/// A manual-reset event stays set and releases all waiters
+/// until it is reset. An auto-reset event releases a single
+/// waiter and is reset by it.
+pub struct Event {
+    signaled: AtomicBool,
+    auto_reset: bool,
+    queue: WaitQueue,
+}
+
+impl Event {
+    pub fn new(auto_reset: bool) -> Self {
+        Event {
+            signaled: AtomicBool::new(false),
+            auto_reset,
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Signal the event.
+    ///
+    /// This is safe to call from interrupt handlers.
+    pub fn set(&self) {
+        self.signaled.store(true, Ordering::Release);
+        if self.auto_reset {
+            self.queue.notify_one();
+        } else {
+            self.queue.notify_all();
+        }
+    }
+
+    /// Clear the event
+    #[allow(dead_code)]
+    pub fn reset(&self) {
+        self.signaled.store(false, Ordering::Release);
+    }
+
+    /// Test whether the event is signaled
+    pub fn is_set(&self) -> bool {
+        self.signaled.load(Ordering::Acquire)
+    }
+
+    /// Sleep until the event is signaled
+    #[allow(dead_code)]
+    pub fn wait(&self) {
+        self.queue.wait_until(|| self.try_wait(), None);
+    }
+
+    /// Sleep until the event is signaled or `timeout` ticks have passed.
+    ///
+    /// Returns `false` on timeout.
+    pub fn wait_timeout(&self, timeout: usize) -> bool {
+        self.queue
+            .wait_until(|| self.try_wait(), Some(timeout))
+            .is_some()
+    }
+
+    fn try_wait(&self) -> Option<()> {
+        let signaled = if self.auto_reset {
+            self.signaled
+                .compare_exchange(true, false, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        } else {
+            self.is_set()
+        };
+        if signaled {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
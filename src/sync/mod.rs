@@ -0,0 +1,166 @@
+//
+// Sleeping synchronization primitives
+//
+// Everything here is built on `WaitQueue`. A waiting task
+// registers itself on a queue and then sleeps until it is
+// notified or its deadline (in PIT ticks) has passed.
+//
+
+mod condvar;
+mod event;
+mod mutex;
+mod semaphore;
+
+pub use self::condvar::Condvar;
+pub use self::event::Event;
+pub use self::mutex::{BlockingMutex, BlockingMutexGuard};
+pub use self::semaphore::Semaphore;
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::pit::PIT;
+
+/// Give up the CPU until something may have changed.
+///
+/// Without a scheduler there is nobody to switch to, so this
+/// halts until the next interrupt. The PIT guarantees that
+/// happens at least once per tick. With interrupts disabled,
+/// halting would never return, so it only spins.
+pub fn idle() {
+    if interrupts::are_enabled() {
+        x86_64::instructions::hlt();
+    } else {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+/// A queue of waiting tasks
+pub struct WaitQueue {
+    waiters: Mutex<VecDeque<Arc<AtomicBool>>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        WaitQueue {
+            waiters: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Register the caller as a waiter.
+    ///
+    /// Registering before testing the wait condition makes sure
+    /// a notification sent in between is not lost.
+    pub fn register(&self) -> Waiter {
+        let token = Arc::new(AtomicBool::new(false));
+        self.with_waiters(|waiters| waiters.push_back(token.clone()));
+        Waiter { queue: self, token }
+    }
+
+    /// Wake the longest waiting task.
+    ///
+    /// Returns `false` if nobody was waiting.
+    pub fn notify_one(&self) -> bool {
+        match self.with_waiters(|waiters| waiters.pop_front()) {
+            Some(token) => {
+                token.store(true, Ordering::Release);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake all waiting tasks and return how many there were
+    pub fn notify_all(&self) -> usize {
+        self.with_waiters(|waiters| {
+            let count = waiters.len();
+            for token in waiters.drain(..) {
+                token.store(true, Ordering::Release);
+            }
+            count
+        })
+    }
+
+    /// Wait until `cond` produces a value or `timeout` ticks have passed.
+    ///
+    /// Returns `None` on timeout.
+    pub fn wait_until<F, R>(&self, mut cond: F, timeout: Option<usize>) -> Option<R>
+    where
+        F: FnMut() -> Option<R>,
+    {
+        let deadline = timeout.map(PIT::deadline);
+        loop {
+            let waiter = self.register();
+            if let Some(res) = cond() {
+                return Some(res);
+            }
+            if !waiter.sleep(deadline) {
+                // Give the condition one last chance
+                return cond();
+            }
+        }
+    }
+
+    /// Provide a closure with access to the waiters.
+    ///
+    /// Interrupt handlers notify queues too, so interrupts
+    /// stay disabled while the lock is held.
+    fn with_waiters<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut VecDeque<Arc<AtomicBool>>) -> R,
+    {
+        interrupts::without_interrupts(|| f(&mut *self.waiters.lock()))
+    }
+}
+
+/// A task registered on a `WaitQueue`
+pub struct Waiter<'a> {
+    queue: &'a WaitQueue,
+    token: Arc<AtomicBool>,
+}
+
+impl<'a> Waiter<'a> {
+    /// Test whether the waiter has been notified
+    pub fn is_notified(&self) -> bool {
+        self.token.load(Ordering::Acquire)
+    }
+
+    /// Sleep until notified or until `deadline` has passed.
+    ///
+    /// With interrupts disabled, the PIT stands still and
+    /// nothing can send a notification, so a timed sleep
+    /// times out right away. An untimed one returns at once
+    /// instead, leaving the caller to spin on its condition.
+    ///
+    /// Returns `false` on timeout.
+    pub fn sleep(&self, deadline: Option<usize>) -> bool {
+        if !interrupts::are_enabled() && !self.is_notified() {
+            if deadline.is_some() {
+                return false;
+            }
+            core::sync::atomic::spin_loop_hint();
+            return true;
+        }
+        while !self.is_notified() {
+            if let Some(deadline) = deadline {
+                if PIT::has_passed(deadline) {
+                    return false;
+                }
+            }
+            idle();
+        }
+        true
+    }
+}
+
+impl<'a> Drop for Waiter<'a> {
+    fn drop(&mut self) {
+        if !self.is_notified() {
+            let token = &self.token;
+            self.queue
+                .with_waiters(|waiters| waiters.retain(|t| !Arc::ptr_eq(t, token)));
+        }
+    }
+}
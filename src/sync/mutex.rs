@@ -0,0 +1,85 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use super::WaitQueue;
+
+/// A mutex that puts contending tasks to sleep instead of spinning.
+///
+/// Unlike `spin::Mutex`, this must not be used from
+/// interrupt handlers, since they cannot sleep.
+#[allow(dead_code)]
+pub struct BlockingMutex<T> {
+    locked: AtomicBool,
+    queue: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for BlockingMutex<T> {}
+unsafe impl<T: Send> Sync for BlockingMutex<T> {}
+
+#[allow(dead_code)]
+impl<T> BlockingMutex<T> {
+    pub fn new(data: T) -> Self {
+        BlockingMutex {
+            locked: AtomicBool::new(false),
+            queue: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Lock the mutex, sleeping while it is held elsewhere.
+    ///
+    /// With interrupts disabled, this spins instead.
+    pub fn lock(&self) -> BlockingMutexGuard<T> {
+        self.queue.wait_until(|| self.try_lock(), None).unwrap()
+    }
+
+    /// Lock the mutex, giving up after `timeout` ticks
+    pub fn lock_timeout(&self, timeout: usize) -> Option<BlockingMutexGuard<T>> {
+        self.queue.wait_until(|| self.try_lock(), Some(timeout))
+    }
+
+    /// Lock the mutex without sleeping
+    pub fn try_lock(&self) -> Option<BlockingMutexGuard<T>> {
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            Some(BlockingMutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+        self.queue.notify_one();
+    }
+}
+
+/// Exclusive access to the data of a `BlockingMutex`
+pub struct BlockingMutexGuard<'a, T: 'a> {
+    pub(super) mutex: &'a BlockingMutex<T>,
+}
+
+impl<'a, T> Deref for BlockingMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for BlockingMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for BlockingMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
@@ -0,0 +1,63 @@
+use spin::Mutex;
+
+use super::WaitQueue;
+
+/// A counting semaphore
+#[allow(dead_code)]
+pub struct Semaphore {
+    count: Mutex<usize>,
+    queue: WaitQueue,
+}
+
+#[allow(dead_code)]
+impl Semaphore {
+    pub fn new(count: usize) -> Self {
+        Semaphore {
+            count: Mutex::new(count),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Take a unit, sleeping until one is available
+    pub fn acquire(&self) {
+        self.queue.wait_until(|| self.try_acquire_opt(), None);
+    }
+
+    /// Take a unit, giving up after `timeout` ticks.
+    ///
+    /// Returns `false` on timeout.
+    pub fn acquire_timeout(&self, timeout: usize) -> bool {
+        self.queue
+            .wait_until(|| self.try_acquire_opt(), Some(timeout))
+            .is_some()
+    }
+
+    /// Take a unit without sleeping
+    pub fn try_acquire(&self) -> bool {
+        let mut count = self.count.lock();
+        if *count == 0 {
+            return false;
+        }
+        *count -= 1;
+        true
+    }
+
+    /// Return a unit and wake a waiter
+    pub fn release(&self) {
+        *self.count.lock() += 1;
+        self.queue.notify_one();
+    }
+
+    /// Get the number of available units
+    pub fn count(&self) -> usize {
+        *self.count.lock()
+    }
+
+    fn try_acquire_opt(&self) -> Option<()> {
+        if self.try_acquire() {
+            Some(())
+        } else {
+            None
+        }
+    }
+}
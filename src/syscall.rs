@@ -0,0 +1,359 @@
+//
+// System calls
+//
+// Userspace enters the kernel with `int 0x80`. The syscall
+// number goes in rax and the arguments in rdi, rsi, rdx,
+// r10, r8 and r9. The result comes back in rax; values
+// between -4095 and -1 are negated error codes.
+//
+
+use alloc::prelude::*;
+use core::{mem::size_of, slice};
+use x86_64::{instructions::interrupts, VirtAddr};
+
+use crate::idt::TrapFrame;
+use crate::ipc::{task_handles, Handle, HandleTable, IPCError, Rights};
+use crate::paging::PAGING;
+use crate::process::{self, Pid, ProcessError, Signal, SignalAction, UserContext};
+
+/// The interrupt vector of the syscall gate
+pub const INT_SYSCALL: u8 = 0x80;
+
+/// The virtual address range user tasks may hand to the kernel.
+///
+/// The kernel identity maps physical memory in the lower
+/// half, so user mappings are kept well above any of it.
+pub const USER_START: u64 = 0x0000_4000_0000_0000;
+pub const USER_END: u64 = 0x0000_7FFF_FFFF_F000;
+
+/// The largest message payload `send` accepts
+const MAX_MESSAGE_SIZE: u64 = 64 * 1024;
+
+/// The largest number of handles a message can carry
+const MAX_TRANSFER: u64 = 16;
+
+/// Syscall error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    /// There is no syscall with that number
+    NoSuchSyscall,
+    /// A pointer argument is outside user memory
+    BadAddress,
+    /// An argument is out of range
+    InvalidArgument,
+    IPC(IPCError),
+    Process(ProcessError),
+}
+
+impl SyscallError {
+    /// The error code reported to userspace
+    pub fn code(self) -> i64 {
+        match self {
+            SyscallError::NoSuchSyscall => 38,
+            SyscallError::BadAddress => 14,
+            SyscallError::InvalidArgument => 22,
+            SyscallError::IPC(IPCError::WouldBlock) => 11,
+            SyscallError::IPC(IPCError::Disconnected) => 32,
+            SyscallError::IPC(IPCError::InvalidHandle) => 9,
+            SyscallError::IPC(IPCError::AccessDenied) => 13,
+            SyscallError::IPC(IPCError::WrongType) => 22,
+            SyscallError::IPC(IPCError::TooLarge) => 90,
+            SyscallError::Process(ProcessError::NoSuchProcess) => 3,
+            SyscallError::Process(ProcessError::NoChildren) => 10,
+            SyscallError::Process(ProcessError::PermissionDenied) => 1,
+            SyscallError::Process(ProcessError::InvalidSignal) => 22,
+        }
+    }
+}
+
+impl From<IPCError> for SyscallError {
+    fn from(err: IPCError) -> Self {
+        SyscallError::IPC(err)
+    }
+}
+
+impl From<ProcessError> for SyscallError {
+    fn from(err: ProcessError) -> Self {
+        SyscallError::Process(err)
+    }
+}
+
+type SyscallResult = Result<u64, SyscallError>;
+
+/// A syscall implementation.
+///
+/// It gets the whole frame so calls like `sigreturn`
+/// can replace the user context.
+type Syscall = fn(&mut TrapFrame) -> SyscallResult;
+
+pub const SYS_CLOSE: u64 = 0;
+pub const SYS_DUPLICATE: u64 = 1;
+pub const SYS_PIPE: u64 = 2;
+pub const SYS_CHANNEL: u64 = 3;
+pub const SYS_READ: u64 = 4;
+pub const SYS_WRITE: u64 = 5;
+pub const SYS_SEND: u64 = 6;
+pub const SYS_RECV: u64 = 7;
+pub const SYS_EXIT: u64 = 8;
+pub const SYS_WAIT: u64 = 9;
+pub const SYS_KILL: u64 = 10;
+pub const SYS_SIGACTION: u64 = 11;
+pub const SYS_SIGRETURN: u64 = 12;
+
+/// Get the implementation of a syscall number
+fn syscall(number: u64) -> Option<Syscall> {
+    let syscall: Syscall = match number {
+        SYS_CLOSE => sys_close,
+        SYS_DUPLICATE => sys_duplicate,
+        SYS_PIPE => sys_pipe,
+        SYS_CHANNEL => sys_channel,
+        SYS_READ => sys_read,
+        SYS_WRITE => sys_write,
+        SYS_SEND => sys_send,
+        SYS_RECV => sys_recv,
+        SYS_EXIT => sys_exit,
+        SYS_WAIT => sys_wait,
+        SYS_KILL => sys_kill,
+        SYS_SIGACTION => sys_sigaction,
+        SYS_SIGRETURN => sys_sigreturn,
+        _ => return None,
+    };
+    Some(syscall)
+}
+
+/// Run the syscall requested by a `TrapFrame`.
+///
+/// Called by the syscall gate. Interrupts are enabled
+/// while the syscall runs, since it may block.
+pub fn dispatch(frame: &mut TrapFrame) {
+    interrupts::enable();
+    let res = match syscall(frame.rax) {
+        Some(syscall) => syscall(frame),
+        None => Err(SyscallError::NoSuchSyscall),
+    };
+    interrupts::disable();
+
+    frame.rax = match res {
+        Ok(value) => value,
+        Err(err) => (-err.code()) as u64,
+    };
+}
+
+/// Test whether a range lies in user memory that user mode may read
+pub fn is_user_range(addr: u64, len: u64) -> bool {
+    is_user_accessible(addr, len, false)
+}
+
+/// Test whether a range lies in user memory that user mode may write
+pub fn is_user_writable_range(addr: u64, len: u64) -> bool {
+    is_user_accessible(addr, len, true)
+}
+
+fn is_user_accessible(addr: u64, len: u64, write: bool) -> bool {
+    match addr.checked_add(len) {
+        Some(end) if addr >= USER_START && end <= USER_END => {
+            PAGING
+                .lock()
+                .is_user_accessible(VirtAddr::new(addr), len, write)
+        }
+        _ => false,
+    }
+}
+
+/// Check that `len` values of type `T` at `addr` are user memory
+fn check_user_slice<T>(addr: u64, len: u64, write: bool) -> Result<(), SyscallError> {
+    let size = len
+        .checked_mul(size_of::<T>() as u64)
+        .ok_or(SyscallError::BadAddress)?;
+    if !is_user_accessible(addr, size, write) || addr % core::mem::align_of::<T>() as u64 != 0 {
+        return Err(SyscallError::BadAddress);
+    }
+    Ok(())
+}
+
+/// Borrow a user buffer
+unsafe fn user_slice<'a, T>(addr: u64, len: u64) -> Result<&'a [T], SyscallError> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    check_user_slice::<T>(addr, len, false)?;
+    Ok(slice::from_raw_parts(addr as *const T, len as usize))
+}
+
+/// Borrow a user buffer mutably
+unsafe fn user_slice_mut<'a, T>(addr: u64, len: u64) -> Result<&'a mut [T], SyscallError> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    check_user_slice::<T>(addr, len, true)?;
+    Ok(slice::from_raw_parts_mut(addr as *mut T, len as usize))
+}
+
+fn handle(arg: u64) -> Result<Handle, SyscallError> {
+    if arg > u64::from(u32::max_value()) {
+        return Err(SyscallError::IPC(IPCError::InvalidHandle));
+    }
+    Ok(Handle(arg as u32))
+}
+
+/// close(handle)
+fn sys_close(frame: &mut TrapFrame) -> SyscallResult {
+    let table = task_handles(process::current());
+    table.lock().close(handle(frame.rdi)?)?;
+    Ok(0)
+}
+
+/// duplicate(handle, rights) -> handle
+fn sys_duplicate(frame: &mut TrapFrame) -> SyscallResult {
+    let rights = Rights::from_bits(frame.rsi as u32).ok_or(SyscallError::InvalidArgument)?;
+    let table = task_handles(process::current());
+    let dup = table.lock().duplicate(handle(frame.rdi)?, rights)?;
+    Ok(u64::from(dup.0))
+}
+
+/// pipe(handles: *mut [u32; 2])
+fn sys_pipe(frame: &mut TrapFrame) -> SyscallResult {
+    let out = unsafe { user_slice_mut::<u32>(frame.rdi, 2)? };
+    let table = task_handles(process::current());
+    let (read, write) = table.lock().create_pipe();
+    out[0] = read.0;
+    out[1] = write.0;
+    Ok(0)
+}
+
+/// channel(handles: *mut [u32; 2])
+fn sys_channel(frame: &mut TrapFrame) -> SyscallResult {
+    let out = unsafe { user_slice_mut::<u32>(frame.rdi, 2)? };
+    let table = task_handles(process::current());
+    let (send, recv) = table.lock().create_channel();
+    out[0] = send.0;
+    out[1] = recv.0;
+    Ok(0)
+}
+
+/// read(handle, buf, len) -> count
+fn sys_read(frame: &mut TrapFrame) -> SyscallResult {
+    let buf = unsafe { user_slice_mut::<u8>(frame.rsi, frame.rdx)? };
+    let table = task_handles(process::current());
+    Ok(HandleTable::read(&table, handle(frame.rdi)?, buf)? as u64)
+}
+
+/// write(handle, buf, len) -> count
+fn sys_write(frame: &mut TrapFrame) -> SyscallResult {
+    let buf = unsafe { user_slice::<u8>(frame.rsi, frame.rdx)? };
+    let table = task_handles(process::current());
+    Ok(HandleTable::write(&table, handle(frame.rdi)?, buf)? as u64)
+}
+
+/// send(handle, data, len, handles: *const u32, count)
+fn sys_send(frame: &mut TrapFrame) -> SyscallResult {
+    if frame.rdx > MAX_MESSAGE_SIZE || frame.r8 > MAX_TRANSFER {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let data = unsafe { user_slice::<u8>(frame.rsi, frame.rdx)? }.to_vec();
+    let transfer: Vec<Handle> = unsafe { user_slice::<u32>(frame.r10, frame.r8)? }
+        .iter()
+        .map(|h| Handle(*h))
+        .collect();
+    let table = task_handles(process::current());
+    HandleTable::send(&table, handle(frame.rdi)?, data, &transfer)?;
+    Ok(0)
+}
+
+/// recv(handle, buf, len, handles: *mut u32, count, received: *mut u64) -> size
+///
+/// A message with more than `len` bytes or `count` handles
+/// fails with `TooLarge` and stays queued. The number of
+/// received handles is stored in `received`, the payload size
+/// is returned.
+fn sys_recv(frame: &mut TrapFrame) -> SyscallResult {
+    let buf = unsafe { user_slice_mut::<u8>(frame.rsi, frame.rdx)? };
+    let handles = unsafe { user_slice_mut::<u32>(frame.r10, frame.r8)? };
+    let received = unsafe { user_slice_mut::<u64>(frame.r9, 1)? };
+
+    let table = task_handles(process::current());
+    let (data, objects) = HandleTable::recv(&table, handle(frame.rdi)?, buf.len(), handles.len())?;
+
+    buf[..data.len()].copy_from_slice(&data);
+    for (slot, h) in handles.iter_mut().zip(objects.iter()) {
+        *slot = h.0;
+    }
+    received[0] = objects.len() as u64;
+    Ok(data.len() as u64)
+}
+
+/// exit(code)
+fn sys_exit(frame: &mut TrapFrame) -> SyscallResult {
+    process::exit(process::current(), frame.rdi as i32)?;
+    Ok(0)
+}
+
+/// wait(pid, status: *mut i32) -> pid
+///
+/// A pid of -1 waits for any child. The status is
+/// encoded like `ExitStatus::raw` does it.
+fn sys_wait(frame: &mut TrapFrame) -> SyscallResult {
+    let child = match frame.rdi as i64 {
+        -1 => None,
+        pid if pid >= 0 && pid <= i64::from(Pid::max_value()) => Some(pid as Pid),
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    let status = unsafe { user_slice_mut::<i32>(frame.rsi, 1)? };
+    let (pid, exit) = process::wait(process::current(), child)?;
+    status[0] = exit.raw();
+    Ok(u64::from(pid))
+}
+
+/// kill(pid, signal)
+fn sys_kill(frame: &mut TrapFrame) -> SyscallResult {
+    if frame.rdi > u64::from(Pid::max_value()) {
+        return Err(ProcessError::NoSuchProcess.into());
+    }
+    let sig = signal(frame.rsi)?;
+    process::kill(frame.rdi as Pid, sig)?;
+    Ok(0)
+}
+
+/// sigaction(signal, action, handler, restorer)
+///
+/// The action is 0 for the default, 1 to ignore the
+/// signal and 2 to call `handler`, which returns to
+/// `restorer`.
+fn sys_sigaction(frame: &mut TrapFrame) -> SyscallResult {
+    let sig = signal(frame.rdi)?;
+    let action = match frame.rsi {
+        0 => SignalAction::Default,
+        1 => SignalAction::Ignore,
+        2 => SignalAction::Handler {
+            handler: frame.rdx,
+            restorer: frame.r10,
+        },
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    process::sigaction(process::current(), sig, action)?;
+    Ok(0)
+}
+
+/// sigreturn()
+///
+/// Restores the context from before the signal handler
+/// ran, including rax. Without a valid signal frame on
+/// the stack, there is nothing to return to, so the
+/// process gets a SIGSEGV.
+fn sys_sigreturn(frame: &mut TrapFrame) -> SyscallResult {
+    let pid = process::current();
+    let mut ctx = UserContext::from_trap_frame(frame);
+    if let Err(err) = unsafe { process::sigreturn(pid, &mut ctx) } {
+        process::force_signal(pid, Signal::SIGSEGV).ok();
+        return Err(err.into());
+    }
+    ctx.store(frame);
+    Ok(frame.rax)
+}
+
+fn signal(arg: u64) -> Result<Signal, SyscallError> {
+    if arg > u64::from(u32::max_value()) {
+        return Err(ProcessError::InvalidSignal.into());
+    }
+    Signal::from_number(arg as u32).ok_or_else(|| ProcessError::InvalidSignal.into())
+}
@@ -0,0 +1,252 @@
+use alloc::{collections::VecDeque, prelude::*};
+
+use super::termios::{
+    InputFlags, LocalFlags, OutputFlags, Termios, VEOF, VERASE, VINTR, VKILL, VWERASE,
+};
+use crate::hal::DeviceError;
+use crate::process::Signal;
+
+/// The longest line canonical mode edits; further input is dropped
+const MAX_LINE: usize = 4095;
+
+/// The most input buffered for readers
+const MAX_INPUT: usize = 16384;
+
+/// Turns raw input into what readers see, and echoes it
+pub struct LineDiscipline {
+    termios: Termios,
+    /// The line being edited in canonical mode
+    line: Vec<u8>,
+    /// Input ready for readers.
+    ///
+    /// In canonical mode, each entry is a line; an empty one
+    /// stands for end of file.
+    ready: VecDeque<Vec<u8>>,
+}
+
+impl LineDiscipline {
+    pub fn new(termios: Termios) -> Self {
+        LineDiscipline {
+            termios,
+            line: Vec::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    pub fn termios(&self) -> Termios {
+        self.termios
+    }
+
+    pub fn set_termios(&mut self, termios: Termios) {
+        // A line being edited is handed over as is
+        if self.termios.is_canonical() && !termios.is_canonical() && !self.line.is_empty() {
+            let line = core::mem::replace(&mut self.line, Vec::new());
+            self.ready.push_back(line);
+        }
+        self.termios = termios;
+    }
+
+    /// Drop all input, finished or not
+    pub fn flush_input(&mut self) {
+        self.line.clear();
+        self.ready.clear();
+    }
+
+    /// Test whether a read would return something
+    pub fn has_input(&self) -> bool {
+        !self.ready.is_empty()
+    }
+
+    fn buffered(&self) -> usize {
+        self.ready.iter().map(|chunk| chunk.len()).sum()
+    }
+
+    /// Handle a byte of input.
+    ///
+    /// Whatever should be echoed is added to `echo`. Returns
+    /// the signal to send to the foreground process, if any.
+    pub fn input(&mut self, byte: u8, echo: &mut Vec<u8>) -> Option<Signal> {
+        let iflag = self.termios.iflag;
+        let lflag = self.termios.lflag;
+        let cc = self.termios.cc;
+
+        let byte = match byte {
+            b'\r' if iflag.contains(InputFlags::IGNCR) => return None,
+            b'\r' if iflag.contains(InputFlags::ICRNL) => b'\n',
+            b'\n' if iflag.contains(InputFlags::INLCR) => b'\r',
+            byte => byte,
+        };
+
+        if lflag.contains(LocalFlags::ISIG) && byte == cc[VINTR] {
+            self.flush_input();
+            self.echo_char(byte, echo);
+            self.echo_newline(echo);
+            return Some(Signal::SIGINT);
+        }
+
+        if !self.termios.is_canonical() {
+            if self.buffered() < MAX_INPUT {
+                match self.ready.back_mut() {
+                    Some(chunk) => chunk.push(byte),
+                    None => self.ready.push_back(vec![byte]),
+                }
+                self.echo_char(byte, echo);
+            }
+            return None;
+        }
+
+        match byte {
+            _ if byte == cc[VERASE] || byte == 0x08 => {
+                self.erase_char(echo);
+            }
+            _ if byte == cc[VWERASE] => {
+                while self.line.last() == Some(&b' ') {
+                    self.erase_char(echo);
+                }
+                while self.line.last().map_or(false, |&b| b != b' ') {
+                    self.erase_char(echo);
+                }
+            }
+            _ if byte == cc[VKILL] => {
+                if lflag.contains(LocalFlags::ECHOK) {
+                    while !self.line.is_empty() {
+                        self.erase_char(echo);
+                    }
+                } else {
+                    self.line.clear();
+                    self.echo_char(byte, echo);
+                    self.echo_newline(echo);
+                }
+            }
+            _ if byte == cc[VEOF] => {
+                // On an empty line, this makes a read return 0
+                self.finish_line();
+            }
+            b'\n' => {
+                self.line.push(b'\n');
+                self.finish_line();
+                self.echo_newline(echo);
+            }
+            _ if self.line.len() < MAX_LINE => {
+                self.line.push(byte);
+                self.echo_char(byte, echo);
+            }
+            _ => (),
+        }
+        None
+    }
+
+    fn finish_line(&mut self) {
+        let line = core::mem::replace(&mut self.line, Vec::new());
+        if self.buffered() + line.len() <= MAX_INPUT {
+            self.ready.push_back(line);
+        }
+    }
+
+    /// Remove the last character of the line, with all its bytes
+    fn erase_char(&mut self, echo: &mut Vec<u8>) {
+        // UTF-8 continuation bytes belong to the character before
+        while let Some(byte) = self.line.pop() {
+            if byte & 0xC0 != 0x80 {
+                self.echo_erase(byte, echo);
+                break;
+            }
+        }
+    }
+
+    fn echo_erase(&self, byte: u8, echo: &mut Vec<u8>) {
+        let lflag = self.termios.lflag;
+        if !lflag.contains(LocalFlags::ECHO) {
+            return;
+        }
+        if !lflag.contains(LocalFlags::ECHOE) {
+            echo.push(self.termios.cc[VERASE]);
+            return;
+        }
+        let width = if is_control(byte) && lflag.contains(LocalFlags::ECHOCTL) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..width {
+            echo.extend_from_slice(b"\x08 \x08");
+        }
+    }
+
+    fn echo_char(&self, byte: u8, echo: &mut Vec<u8>) {
+        let lflag = self.termios.lflag;
+        if !lflag.contains(LocalFlags::ECHO) {
+            return;
+        }
+        if is_control(byte) && lflag.contains(LocalFlags::ECHOCTL) {
+            echo.push(b'^');
+            echo.push(byte ^ 0x40);
+        } else {
+            echo.push(byte);
+        }
+    }
+
+    fn echo_newline(&self, echo: &mut Vec<u8>) {
+        if self
+            .termios
+            .lflag
+            .intersects(LocalFlags::ECHO | LocalFlags::ECHONL)
+        {
+            echo.push(b'\n');
+        }
+    }
+
+    /// Read input.
+    ///
+    /// In canonical mode, this returns at most one line and
+    /// `Ok(0)` at end of file. Fails with `WouldBlock` if
+    /// there is nothing to read.
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let canonical = self.termios.is_canonical();
+        let mut count = 0;
+        while count < buf.len() {
+            let chunk = match self.ready.front_mut() {
+                Some(chunk) => chunk,
+                None if count == 0 => return Err(DeviceError::WouldBlock),
+                None => break,
+            };
+            let len = core::cmp::min(chunk.len(), buf.len() - count);
+            buf[count..count + len].copy_from_slice(&chunk[..len]);
+            chunk.drain(..len);
+            count += len;
+            if chunk.is_empty() {
+                self.ready.pop_front();
+            }
+            if canonical {
+                break;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Process output for the terminal
+    pub fn output(&self, val: &[u8]) -> Vec<u8> {
+        let oflag = self.termios.oflag;
+        if !oflag.contains(OutputFlags::OPOST | OutputFlags::ONLCR) {
+            return val.to_vec();
+        }
+        let mut out = Vec::with_capacity(val.len());
+        let mut last_byte = 0_u8;
+        for &b in val {
+            if last_byte != b'\r' && b == b'\n' {
+                out.push(b'\r');
+            }
+            out.push(b);
+            last_byte = b;
+        }
+        out
+    }
+}
+
+/// Test whether a byte is echoed as `^X`
+fn is_control(byte: u8) -> bool {
+    (byte < 0x20 && byte != b'\t' && byte != b'\n') || byte == 0x7F
+}
@@ -0,0 +1,291 @@
+//
+// Terminals
+//
+// A TTY sits between an input source and its outputs. Input
+// from the keyboard or a serial line goes through the line
+// discipline, which edits lines, echoes and raises signals
+// as the termios settings say, and waits there for readers.
+// Output is processed the same way and then handed to every
+// output attached to the TTY.
+//
+// The keyboard feeds the TTY of the virtual console being
+// shown. Alt+F1 to Alt+F6 switch between them.
+//
+
+mod discipline;
+mod termios;
+
+pub use self::discipline::LineDiscipline;
+pub use self::termios::{
+    InputFlags, LocalFlags, OutputFlags, Termios, NCCS, VEOF, VERASE, VINTR, VKILL, VWERASE,
+};
+
+use alloc::{prelude::*, sync::Arc};
+use core::any::Any;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::hal::{Device, DeviceCapabilities, DeviceError, DeviceType, PollFlags, DEVICE_MANAGER};
+use crate::serial::{SerialDevice, SerialLine};
+use crate::vgaterm::{self, Console, CONSOLE_COUNT};
+use crate::workqueue::Work;
+
+lazy_static! {
+    static ref TTYS: Mutex<Vec<TTY>> = Mutex::new(Vec::new());
+}
+
+/// The device names of the virtual consoles
+const CONSOLE_NAMES: [&str; CONSOLE_COUNT] = ["tty1", "tty2", "tty3", "tty4", "tty5", "tty6"];
+
+//
+// TTY control requests
+//
+
+/// Get the termios settings, packed as by `Termios::pack`
+pub const TTY_IOCTL_GET_TERMIOS: u32 = 0x3001;
+
+/// Change the termios settings, packed as by `Termios::pack`
+pub const TTY_IOCTL_SET_TERMIOS: u32 = 0x3002;
+
+/// Drop all buffered input
+pub const TTY_IOCTL_FLUSH_INPUT: u32 = 0x3003;
+
+/// Get the index of the virtual console being shown
+pub const TTY_IOCTL_GET_CONSOLE: u32 = 0x3004;
+
+/// Show the virtual console with the given index
+pub const TTY_IOCTL_SET_CONSOLE: u32 = 0x3005;
+
+/// Something a TTY writes to
+pub trait Output: Send {
+    fn write(&mut self, val: &[u8]);
+}
+
+/// An output written to from more than one place, like a TTY
+/// and the kernel log
+pub struct SharedOutput<O: Output>(Arc<Mutex<O>>);
+
+impl<O: Output> SharedOutput<O> {
+    pub fn new(output: O) -> Self {
+        SharedOutput(Arc::new(Mutex::new(output)))
+    }
+}
+
+impl<O: Output> Clone for SharedOutput<O> {
+    fn clone(&self) -> Self {
+        SharedOutput(self.0.clone())
+    }
+}
+
+impl<O: Output> Output for SharedOutput<O> {
+    fn write(&mut self, val: &[u8]) {
+        interrupts::without_interrupts(|| self.0.lock().write(val));
+    }
+}
+
+/// A terminal and its line discipline
+struct TTY {
+    name: &'static str,
+    discipline: LineDiscipline,
+    outputs: Vec<Box<dyn Output>>,
+    /// The serial line input comes from, if not the keyboard
+    serial: Option<SerialLine>,
+}
+
+impl TTY {
+    fn write(&mut self, val: &[u8]) {
+        if val.is_empty() {
+            return;
+        }
+        let out = self.discipline.output(val);
+        for output in self.outputs.iter_mut() {
+            output.write(&out);
+        }
+    }
+
+    /// Feed input through the line discipline.
+    ///
+    /// Returns whether to interrupt the foreground process.
+    fn input(&mut self, val: &[u8]) -> bool {
+        let mut echo = Vec::new();
+        let mut interrupt = false;
+        for &byte in val {
+            interrupt |= self.discipline.input(byte, &mut echo).is_some();
+        }
+        self.write(&echo);
+        interrupt
+    }
+}
+
+/// Set up the virtual consoles and a TTY on the first serial port
+pub fn init() -> Result<(), &'static str> {
+    for (index, &name) in CONSOLE_NAMES.iter().enumerate() {
+        let output: Box<dyn Output> = box Console(index);
+        add(name, vec![output], None)?;
+    }
+
+    let line = DEVICE_MANAGER
+        .lock()
+        .with_device_cast("com1", |dev: &mut SerialDevice| dev.line());
+    match line {
+        Some(line) => attach_serial("ttyS0", line),
+        None => Err("com1 is not a serial port"),
+    }
+}
+
+/// Create a TTY and register it as a device
+fn add(
+    name: &'static str,
+    outputs: Vec<Box<dyn Output>>,
+    serial: Option<SerialLine>,
+) -> Result<usize, &'static str> {
+    let index = {
+        let mut ttys = TTYS.lock();
+        ttys.push(TTY {
+            name,
+            discipline: LineDiscipline::new(Termios::default()),
+            outputs,
+            serial,
+        });
+        ttys.len() - 1
+    };
+    let dev = TTYDevice { index };
+    DEVICE_MANAGER
+        .lock()
+        .register_device(name, box dev)
+        .map_err(|_| "device name already taken")?;
+    Ok(index)
+}
+
+/// Create a TTY on a serial line
+pub fn attach_serial(name: &'static str, line: SerialLine) -> Result<(), &'static str> {
+    let output: Box<dyn Output> = box line.clone();
+    let index = add(name, vec![output], Some(line.clone()))?;
+    line.set_input_work(Some(Work::new(serial_input, index)));
+    Ok(())
+}
+
+/// Send the output of a TTY to another place as well
+pub fn attach_output(name: &str, output: Box<dyn Output>) -> Result<(), &'static str> {
+    let mut ttys = TTYS.lock();
+    let tty = ttys
+        .iter_mut()
+        .find(|tty| tty.name == name)
+        .ok_or("no such TTY")?;
+    tty.outputs.push(output);
+    Ok(())
+}
+
+/// Feed keyboard input to the virtual console being shown.
+///
+/// Called by the keyboard driver.
+pub fn keyboard_input(val: &[u8]) {
+    input(vgaterm::active_console(), val);
+}
+
+/// Show a virtual console; the keyboard goes with it
+pub fn switch_console(console: usize) {
+    vgaterm::switch_console(console);
+}
+
+/// Move input from a serial line into its TTY.
+///
+/// This runs as deferred work whenever the line receives data.
+fn serial_input(index: usize) {
+    let line = match TTYS.lock().get(index) {
+        Some(tty) => tty.serial.clone(),
+        None => None,
+    };
+    if let Some(line) = line {
+        let mut buf = [0u8; 64];
+        loop {
+            let count = line.read(&mut buf);
+            if count == 0 {
+                break;
+            }
+            input(index, &buf[..count]);
+        }
+    }
+}
+
+fn input(index: usize, val: &[u8]) {
+    let interrupt = match TTYS.lock().get_mut(index) {
+        Some(tty) => tty.input(val),
+        None => false,
+    };
+    // Signals take the process table lock, so not while holding ours
+    if interrupt {
+        crate::process::interrupt_foreground();
+    }
+}
+
+/// A TTY as a readable and writable character device
+pub struct TTYDevice {
+    index: usize,
+}
+
+impl TTYDevice {
+    /// Call `f` with the TTY
+    fn with_tty<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut TTY) -> R,
+    {
+        f(&mut TTYS.lock()[self.index])
+    }
+}
+
+impl Device for TTYDevice {
+    fn get_type(&self) -> DeviceType {
+        DeviceType::CharDevice
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities::READ
+            | DeviceCapabilities::WRITE
+            | DeviceCapabilities::CONTROL
+            | DeviceCapabilities::POLL
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, DeviceError> {
+        self.with_tty(|tty| tty.discipline.read(buf))
+    }
+
+    fn write_bytes(&mut self, val: &[u8]) -> Result<usize, DeviceError> {
+        self.with_tty(|tty| tty.write(val));
+        Ok(val.len())
+    }
+
+    fn ioctl(&mut self, request: u32, arg: usize) -> Result<usize, DeviceError> {
+        match request {
+            TTY_IOCTL_GET_TERMIOS => Ok(self.with_tty(|tty| tty.discipline.termios().pack())),
+            TTY_IOCTL_SET_TERMIOS => {
+                let termios = Termios::unpack(arg).ok_or(DeviceError::InvalidArgument)?;
+                self.with_tty(|tty| tty.discipline.set_termios(termios));
+                Ok(0)
+            }
+            TTY_IOCTL_FLUSH_INPUT => {
+                self.with_tty(|tty| tty.discipline.flush_input());
+                Ok(0)
+            }
+            TTY_IOCTL_GET_CONSOLE => Ok(vgaterm::active_console()),
+            TTY_IOCTL_SET_CONSOLE if arg < CONSOLE_COUNT => {
+                switch_console(arg);
+                Ok(0)
+            }
+            TTY_IOCTL_SET_CONSOLE => Err(DeviceError::InvalidArgument),
+            _ => Err(DeviceError::Unsupported),
+        }
+    }
+
+    fn poll(&mut self) -> PollFlags {
+        match self.with_tty(|tty| tty.discipline.has_input()) {
+            true => PollFlags::READABLE | PollFlags::WRITABLE,
+            false => PollFlags::WRITABLE,
+        }
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
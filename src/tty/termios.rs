@@ -0,0 +1,144 @@
+use bitflags::bitflags;
+
+bitflags! {
+
+    /// Input processing
+    pub struct InputFlags: u8 {
+        /// Ignore carriage returns
+        const IGNCR = 0b_0000_0001;
+        /// Turn carriage returns into line feeds
+        const ICRNL = 0b_0000_0010;
+        /// Turn line feeds into carriage returns
+        const INLCR = 0b_0000_0100;
+    }
+}
+
+bitflags! {
+
+    /// Output processing
+    pub struct OutputFlags: u8 {
+        /// Process output at all
+        const OPOST = 0b_0000_0001;
+        /// Turn line feeds into carriage return and line feed
+        const ONLCR = 0b_0000_0010;
+    }
+}
+
+bitflags! {
+
+    /// Line discipline behavior
+    pub struct LocalFlags: u8 {
+        /// Send signals for the interrupt character
+        const ISIG    = 0b_0000_0001;
+        /// Edit input a line at a time
+        const ICANON  = 0b_0000_0010;
+        /// Echo input
+        const ECHO    = 0b_0000_0100;
+        /// Visually erase characters
+        const ECHOE   = 0b_0000_1000;
+        /// Visually erase killed lines
+        const ECHOK   = 0b_0001_0000;
+        /// Echo line feeds even without `ECHO`
+        const ECHONL  = 0b_0010_0000;
+        /// Echo control characters as `^X`
+        const ECHOCTL = 0b_0100_0000;
+    }
+}
+
+//
+// Control characters, as indices into `Termios::cc`
+//
+
+/// Interrupt the foreground process
+pub const VINTR: usize = 0;
+
+/// End a line without a line feed, or signal end of file
+pub const VEOF: usize = 1;
+
+/// Erase a character
+pub const VERASE: usize = 2;
+
+/// Erase the line
+pub const VKILL: usize = 3;
+
+/// Erase a word
+pub const VWERASE: usize = 4;
+
+/// The number of control characters
+pub const NCCS: usize = 5;
+
+/// Terminal settings, after POSIX termios
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Termios {
+    pub iflag: InputFlags,
+    pub oflag: OutputFlags,
+    pub lflag: LocalFlags,
+    pub cc: [u8; NCCS],
+}
+
+impl Default for Termios {
+    /// Canonical mode with echo and signals, like a fresh Unix terminal
+    fn default() -> Self {
+        Termios {
+            iflag: InputFlags::ICRNL,
+            oflag: OutputFlags::OPOST | OutputFlags::ONLCR,
+            lflag: LocalFlags::ISIG
+                | LocalFlags::ICANON
+                | LocalFlags::ECHO
+                | LocalFlags::ECHOE
+                | LocalFlags::ECHOK
+                | LocalFlags::ECHOCTL,
+            // ^C, ^D, DEL, ^U, ^W
+            cc: [0x03, 0x04, 0x7F, 0x15, 0x17],
+        }
+    }
+}
+
+impl Termios {
+    /// Get settings that pass every byte through untouched
+    #[allow(dead_code)]
+    pub fn raw() -> Self {
+        let mut termios = Termios::default();
+        termios.make_raw();
+        termios
+    }
+
+    /// Turn off line editing, echo, signals and all translation
+    pub fn make_raw(&mut self) {
+        self.iflag = InputFlags::empty();
+        self.oflag.remove(OutputFlags::OPOST);
+        self.lflag
+            .remove(LocalFlags::ISIG | LocalFlags::ICANON | LocalFlags::ECHO | LocalFlags::ECHONL);
+    }
+
+    pub fn is_canonical(&self) -> bool {
+        self.lflag.contains(LocalFlags::ICANON)
+    }
+
+    /// Pack the settings into an ioctl argument.
+    ///
+    /// Bits 0-7 hold the input flags, 8-15 the output flags,
+    /// 16-23 the local flags and 24-63 the control characters.
+    pub fn pack(&self) -> usize {
+        let mut raw = self.iflag.bits() as usize
+            | (self.oflag.bits() as usize) << 8
+            | (self.lflag.bits() as usize) << 16;
+        for (index, &chr) in self.cc.iter().enumerate() {
+            raw |= (chr as usize) << (24 + 8 * index);
+        }
+        raw
+    }
+
+    pub fn unpack(raw: usize) -> Option<Termios> {
+        let mut cc = [0; NCCS];
+        for (index, chr) in cc.iter_mut().enumerate() {
+            *chr = (raw >> (24 + 8 * index)) as u8;
+        }
+        Some(Termios {
+            iflag: InputFlags::from_bits(raw as u8)?,
+            oflag: OutputFlags::from_bits((raw >> 8) as u8)?,
+            lflag: LocalFlags::from_bits((raw >> 16) as u8)?,
+            cc,
+        })
+    }
+}
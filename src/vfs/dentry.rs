@@ -0,0 +1,219 @@
+use alloc::{collections::BTreeMap, prelude::*, sync::Arc, sync::Weak};
+use spin::Mutex;
+
+use super::{FileType, FsError, INode, Metadata};
+
+/// A name in the directory tree.
+///
+/// Dentries cache the inodes found by lookups, unless the
+/// directory's inode opts out. A filesystem
+/// mounted on a dentry hides the dentry's own inode. The root
+/// of a mounted filesystem takes the name and parent of its
+/// mount point, so `..` leads out of the filesystem.
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn INode>,
+    parent: Option<Weak<Dentry>>,
+    children: Mutex<BTreeMap<String, Arc<Dentry>>>,
+    mounted: Mutex<Option<Arc<Dentry>>>,
+}
+
+/// Check that a name can be used for a directory entry
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+impl Dentry {
+    pub(super) fn new_root(inode: Arc<dyn INode>) -> Arc<Dentry> {
+        Arc::new(Dentry {
+            name: String::from("/"),
+            inode,
+            parent: None,
+            children: Mutex::new(BTreeMap::new()),
+            mounted: Mutex::new(None),
+        })
+    }
+
+    fn new_child(parent: &Arc<Dentry>, name: &str, inode: Arc<dyn INode>) -> Arc<Dentry> {
+        let child = Arc::new(Dentry {
+            name: name.to_string(),
+            inode,
+            parent: Some(Arc::downgrade(parent)),
+            children: Mutex::new(BTreeMap::new()),
+            mounted: Mutex::new(None),
+        });
+        if parent.inode.cache_lookups() {
+            parent
+                .children
+                .lock()
+                .insert(name.to_string(), child.clone());
+        }
+        child
+    }
+
+    #[allow(dead_code)]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode(&self) -> &Arc<dyn INode> {
+        &self.inode
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.inode.metadata()
+    }
+
+    /// Get the parent directory.
+    ///
+    /// The root directory has no parent.
+    pub fn parent(&self) -> Option<Arc<Dentry>> {
+        self.parent.as_ref().and_then(|parent| parent.upgrade())
+    }
+
+    /// Get the absolute path of the dentry
+    #[allow(dead_code)]
+    pub fn path(dentry: &Arc<Dentry>) -> String {
+        let mut names = Vec::new();
+        let mut current = dentry.clone();
+        while let Some(parent) = current.parent() {
+            names.push(current.name.clone());
+            current = parent;
+        }
+
+        if names.is_empty() {
+            return String::from("/");
+        }
+        names
+            .iter()
+            .rev()
+            .fold(String::new(), |path, name| path + "/" + name)
+    }
+
+    /// Get the root of the filesystem mounted here, if any
+    pub fn mounted(&self) -> Option<Arc<Dentry>> {
+        self.mounted.lock().clone()
+    }
+
+    /// Get the dentry that is visible at this place in the tree
+    pub fn follow_mounts(dentry: &Arc<Dentry>) -> Arc<Dentry> {
+        let mut current = dentry.clone();
+        while let Some(root) = current.mounted() {
+            current = root;
+        }
+        current
+    }
+
+    /// Test whether a filesystem is mounted here or below
+    pub fn has_mounts(&self) -> bool {
+        self.mounted.lock().is_some()
+            || self
+                .children
+                .lock()
+                .values()
+                .any(|child| child.has_mounts())
+    }
+
+    pub(super) fn mount(mountpoint: &Arc<Dentry>, root: Arc<dyn INode>) -> Result<(), FsError> {
+        let mut mounted = mountpoint.mounted.lock();
+        if mounted.is_some() {
+            return Err(FsError::Busy);
+        }
+        *mounted = Some(Arc::new(Dentry {
+            name: mountpoint.name.clone(),
+            inode: root,
+            parent: mountpoint.parent.clone(),
+            children: Mutex::new(BTreeMap::new()),
+            mounted: Mutex::new(None),
+        }));
+        Ok(())
+    }
+
+    pub(super) fn unmount(&self) {
+        *self.mounted.lock() = None;
+    }
+
+    /// Find an entry of this directory
+    pub fn lookup(parent: &Arc<Dentry>, name: &str) -> Result<Arc<Dentry>, FsError> {
+        if let Some(child) = parent.children.lock().get(name) {
+            return Ok(child.clone());
+        }
+        let inode = parent.inode.lookup(name)?;
+        Ok(Dentry::new_child(parent, name, inode))
+    }
+
+    /// Create a file or directory in this directory
+    pub fn create(
+        parent: &Arc<Dentry>,
+        name: &str,
+        file_type: FileType,
+    ) -> Result<Arc<Dentry>, FsError> {
+        check_name(name)?;
+        if Dentry::lookup(parent, name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = parent.inode.create(name, file_type)?;
+        Ok(Dentry::new_child(parent, name, inode))
+    }
+
+    /// Create a symbolic link in this directory
+    pub fn symlink(parent: &Arc<Dentry>, name: &str, target: &str) -> Result<Arc<Dentry>, FsError> {
+        check_name(name)?;
+        if Dentry::lookup(parent, name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = parent.inode.symlink(name, target)?;
+        Ok(Dentry::new_child(parent, name, inode))
+    }
+
+    /// Remove an entry from this directory
+    pub fn unlink(parent: &Arc<Dentry>, name: &str) -> Result<(), FsError> {
+        check_name(name)?;
+        let child = Dentry::lookup(parent, name)?;
+        if child.has_mounts() {
+            return Err(FsError::Busy);
+        }
+        parent.inode.unlink(name)?;
+        parent.children.lock().remove(name);
+        Ok(())
+    }
+
+    /// Move an entry of this directory to another directory
+    pub fn rename(
+        old_parent: &Arc<Dentry>,
+        old_name: &str,
+        new_parent: &Arc<Dentry>,
+        new_name: &str,
+    ) -> Result<(), FsError> {
+        check_name(old_name)?;
+        check_name(new_name)?;
+        let child = Dentry::lookup(old_parent, old_name)?;
+        if child.has_mounts() {
+            return Err(FsError::Busy);
+        }
+        match Dentry::lookup(new_parent, new_name) {
+            Ok(ref existing) if existing.has_mounts() => return Err(FsError::Busy),
+            Ok(_) | Err(FsError::NotFound) => (),
+            Err(err) => return Err(err),
+        }
+
+        // A directory cannot be moved below itself
+        let mut ancestor = Some(new_parent.clone());
+        while let Some(dir) = ancestor {
+            if Arc::ptr_eq(&dir, &child) {
+                return Err(FsError::InvalidArgument);
+            }
+            ancestor = dir.parent();
+        }
+
+        old_parent
+            .inode
+            .rename(old_name, &new_parent.inode, new_name)?;
+        old_parent.children.lock().remove(old_name);
+        new_parent.children.lock().remove(new_name);
+        Ok(())
+    }
+}
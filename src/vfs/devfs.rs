@@ -0,0 +1,190 @@
+use alloc::{prelude::*, sync::Arc};
+use core::any::Any;
+
+use super::{DirEntry, FileSystem, FileType, FsError, INode, Metadata};
+use crate::block::{BLK_IOCTL_GET_SECTOR_COUNT, BLK_IOCTL_GET_SECTOR_SIZE};
+use crate::hal::{Device, DeviceCapabilities, DeviceError, DeviceType, PollFlags, DEVICE_MANAGER};
+
+/// A filesystem showing the devices of the `DeviceManager`.
+///
+/// The directory is generated on every access and lookups
+/// are not cached, so devices come and go without further work.
+pub struct DevFS {
+    root: Arc<DevDir>,
+}
+
+impl DevFS {
+    pub fn new() -> Self {
+        DevFS {
+            root: Arc::new(DevDir),
+        }
+    }
+}
+
+impl FileSystem for DevFS {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn INode> {
+        self.root.clone()
+    }
+}
+
+/// Get a stable inode number for a device name
+fn inode_for(name: &str) -> u64 {
+    // FNV-1a; inode 1 belongs to the directory
+    let hash = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    hash | 2
+}
+
+struct DevDir;
+
+impl INode for DevDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: 1,
+            file_type: FileType::Directory,
+            size: 0,
+            mode: 0o755,
+            links: 2,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn INode>, FsError> {
+        let name = DEVICE_MANAGER
+            .lock()
+            .device_names()
+            .into_iter()
+            .find(|&dev| dev == name)
+            .ok_or(FsError::NotFound)?;
+        Ok(Arc::new(DevNode::new(name)))
+    }
+
+    fn cache_lookups(&self) -> bool {
+        // Devices are registered and removed behind our back
+        false
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let names = DEVICE_MANAGER.lock().device_names();
+        Ok(names
+            .into_iter()
+            .map(|name| DirEntry {
+                name: name.to_string(),
+                inode: inode_for(name),
+                file_type: DevNode::new(name).file_type,
+            })
+            .collect())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// A device file
+struct DevNode {
+    name: &'static str,
+    /// The type of the device when it was looked up
+    file_type: FileType,
+}
+
+impl DevNode {
+    fn new(name: &'static str) -> Self {
+        let mut node = DevNode {
+            name,
+            file_type: FileType::CharDevice,
+        };
+        if let Ok(DeviceType::BlockDevice) = node.with_device(|dev| Ok(dev.get_type())) {
+            node.file_type = FileType::BlockDevice;
+        }
+        node
+    }
+
+    /// Run `f` on the device, failing if it is gone
+    fn with_device<F, R>(&self, f: F) -> Result<R, FsError>
+    where
+        F: FnOnce(&mut (dyn Device + Sync + Send)) -> Result<R, DeviceError>,
+    {
+        // Drivers may look up devices themselves during I/O,
+        // so the manager must not stay locked.
+        let dev = DEVICE_MANAGER
+            .lock()
+            .get_device(self.name)
+            .ok_or(FsError::NotFound)?;
+        let mut dev = dev.lock();
+        f(&mut **dev).map_err(FsError::from)
+    }
+}
+
+impl INode for DevNode {
+    fn metadata(&self) -> Metadata {
+        let file_type = self.file_type;
+        let size = if file_type == FileType::BlockDevice {
+            self.with_device(|dev| {
+                let count = dev.ioctl(BLK_IOCTL_GET_SECTOR_COUNT, 0)?;
+                let size = dev.ioctl(BLK_IOCTL_GET_SECTOR_SIZE, 0)?;
+                Ok(count as u64 * size as u64)
+            })
+            .unwrap_or(0)
+        } else {
+            0
+        };
+
+        Metadata {
+            inode: inode_for(self.name),
+            file_type,
+            size,
+            mode: 0o660,
+            links: 1,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.with_device(|dev| {
+            // Stream devices ignore the position
+            if dev
+                .capabilities()
+                .contains(DeviceCapabilities::RANDOM_ACCESS)
+            {
+                dev.read_at(offset as usize, buf)
+            } else {
+                dev.read(buf)
+            }
+        })
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        self.with_device(|dev| {
+            if dev
+                .capabilities()
+                .contains(DeviceCapabilities::RANDOM_ACCESS)
+            {
+                dev.write_at(offset as usize, buf)
+            } else {
+                dev.write_bytes(buf)
+            }
+        })
+    }
+
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        // Opening a device for truncation leaves it alone
+        Ok(())
+    }
+
+    fn ioctl(&self, request: u32, arg: usize) -> Result<usize, FsError> {
+        self.with_device(|dev| dev.ioctl(request, arg))
+    }
+
+    fn poll(&self) -> PollFlags {
+        self.with_device(|dev| Ok(dev.poll()))
+            .unwrap_or(PollFlags::ERROR)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
@@ -0,0 +1,288 @@
+use alloc::prelude::*;
+
+use super::inode::{Inode, FLAG_INDEX};
+use super::{read_u16, read_u32, write_u16, write_u32, Volume};
+use crate::vfs::{FileType, FsError};
+
+const ENTRY_HEADER_SIZE: usize = 8;
+const MAX_NAME_LENGTH: usize = 255;
+
+/// The largest record length stored as is.
+///
+/// A record spanning a whole 64 KiB block is stored as this.
+const MAX_REC_LEN: usize = 65535;
+
+// File types in directory entries
+const TYPE_UNKNOWN: u8 = 0;
+const TYPE_FILE: u8 = 1;
+const TYPE_DIRECTORY: u8 = 2;
+const TYPE_CHAR_DEVICE: u8 = 3;
+const TYPE_BLOCK_DEVICE: u8 = 4;
+const TYPE_SYMLINK: u8 = 7;
+
+/// Get the directory entry type of a file type
+fn entry_type(file_type: FileType) -> u8 {
+    match file_type {
+        FileType::File => TYPE_FILE,
+        FileType::Directory => TYPE_DIRECTORY,
+        FileType::CharDevice => TYPE_CHAR_DEVICE,
+        FileType::BlockDevice => TYPE_BLOCK_DEVICE,
+        FileType::Symlink => TYPE_SYMLINK,
+    }
+}
+
+/// Get the file type of a directory entry type, if it is known
+fn file_type(entry_type: u8) -> Option<FileType> {
+    match entry_type {
+        TYPE_FILE => Some(FileType::File),
+        TYPE_DIRECTORY => Some(FileType::Directory),
+        TYPE_CHAR_DEVICE => Some(FileType::CharDevice),
+        TYPE_BLOCK_DEVICE => Some(FileType::BlockDevice),
+        TYPE_SYMLINK => Some(FileType::Symlink),
+        _ => None,
+    }
+}
+
+/// Get the space an entry with a name of `len` bytes needs
+fn entry_size(len: usize) -> usize {
+    (ENTRY_HEADER_SIZE + len + 3) & !3
+}
+
+/// An entry read from a directory
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub ino: u32,
+    pub name: String,
+    /// The type if the volume records it in entries
+    pub file_type: Option<FileType>,
+    /// The file block holding the entry
+    block: u64,
+    offset: usize,
+    /// The offset of the previous entry in the same block
+    prev: Option<usize>,
+}
+
+impl Entry {
+    pub fn is_dot(&self) -> bool {
+        self.name == "." || self.name == ".."
+    }
+}
+
+impl Volume {
+    /// Read the record length of the entry at `offset`.
+    ///
+    /// With 64 KiB blocks, record lengths need 17 bits. The low
+    /// two bits, which are zero otherwise, then hold the top ones.
+    fn read_rec_len(&self, data: &[u8], offset: usize) -> usize {
+        let len = read_u16(data, offset + 4) as usize;
+        if self.block_size < 65536 {
+            len
+        } else if len == 0 || len == MAX_REC_LEN {
+            self.block_size
+        } else {
+            (len & !3) | ((len & 3) << 16)
+        }
+    }
+
+    /// Write the record length of the entry at `offset`
+    fn write_rec_len(&self, data: &mut [u8], offset: usize, len: usize) {
+        let len = if len > MAX_REC_LEN { MAX_REC_LEN } else { len };
+        write_u16(data, offset + 4, len as u16);
+    }
+
+    /// Read the entries of a directory, including `.` and `..`
+    pub fn read_dir(&self, dir: &Inode) -> Result<Vec<Entry>, FsError> {
+        let mut entries = Vec::new();
+        let blocks = (dir.size + self.block_size as u64 - 1) / self.block_size as u64;
+
+        for index in 0..blocks {
+            let block = self.map_block(dir, index)?;
+            if block == 0 {
+                continue;
+            }
+            let data = self.read_block(block)?;
+
+            let mut offset = 0;
+            let mut prev = None;
+            while offset + ENTRY_HEADER_SIZE <= data.len() {
+                let ino = read_u32(&data, offset);
+                let rec_len = self.read_rec_len(&data, offset);
+                let name_len = data[offset + 6] as usize;
+                if rec_len < ENTRY_HEADER_SIZE
+                    || rec_len % 4 != 0
+                    || offset + rec_len > data.len()
+                    || ENTRY_HEADER_SIZE + name_len > rec_len
+                {
+                    log!(error: "{}: ext2 directory entry is damaged.", self.dev.name());
+                    return Err(FsError::Corrupted);
+                }
+
+                if ino != 0 {
+                    let name =
+                        &data[offset + ENTRY_HEADER_SIZE..offset + ENTRY_HEADER_SIZE + name_len];
+                    entries.push(Entry {
+                        ino,
+                        name: String::from_utf8_lossy(name).into_owned(),
+                        file_type: if self.filetype {
+                            file_type(data[offset + 7])
+                        } else {
+                            None
+                        },
+                        block: index,
+                        offset,
+                        prev,
+                    });
+                }
+                prev = Some(offset);
+                offset += rec_len;
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn find_entry(&self, dir: &Inode, name: &str) -> Result<Entry, FsError> {
+        self.read_dir(dir)?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .ok_or(FsError::NotFound)
+    }
+
+    /// Write an entry into a record of a directory block
+    fn encode_entry(
+        &self,
+        data: &mut [u8],
+        offset: usize,
+        rec_len: usize,
+        ino: u32,
+        name: &str,
+        file_type: FileType,
+    ) {
+        write_u32(data, offset, ino);
+        self.write_rec_len(data, offset, rec_len);
+        data[offset + 6] = name.len() as u8;
+        data[offset + 7] = if self.filetype {
+            entry_type(file_type)
+        } else {
+            TYPE_UNKNOWN
+        };
+        let start = offset + ENTRY_HEADER_SIZE;
+        data[start..start + name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    /// Add an entry to a directory, growing it if needed
+    pub fn add_entry(
+        &mut self,
+        dir_ino: u32,
+        dir: &mut Inode,
+        name: &str,
+        ino: u32,
+        file_type: FileType,
+    ) -> Result<(), FsError> {
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(FsError::InvalidPath);
+        }
+        let needed = entry_size(name.len());
+        // Changing the entries invalidates a hashed index
+        dir.flags &= !FLAG_INDEX;
+
+        let blocks = dir.size / self.block_size as u64;
+        for index in 0..blocks {
+            let block = self.map_block(dir, index)?;
+            if block == 0 {
+                continue;
+            }
+            let mut data = self.read_block(block)?;
+
+            let mut offset = 0;
+            while offset + ENTRY_HEADER_SIZE <= data.len() {
+                let entry_ino = read_u32(&data, offset);
+                let rec_len = self.read_rec_len(&data, offset);
+                if rec_len < ENTRY_HEADER_SIZE || offset + rec_len > data.len() {
+                    return Err(FsError::Corrupted);
+                }
+                let used = if entry_ino == 0 {
+                    0
+                } else {
+                    entry_size(data[offset + 6] as usize)
+                };
+                if used > rec_len {
+                    return Err(FsError::Corrupted);
+                }
+
+                // Split the record if its slack fits the entry
+                if rec_len - used >= needed {
+                    if used > 0 {
+                        self.write_rec_len(&mut data, offset, used);
+                    }
+                    let at = offset + used;
+                    self.encode_entry(&mut data, at, rec_len - used, ino, name, file_type);
+                    self.write_block(block, &data)?;
+                    dir.mtime = super::now();
+                    dir.ctime = dir.mtime;
+                    return self.write_inode(dir_ino, dir);
+                }
+                offset += rec_len;
+            }
+        }
+
+        // Append a block holding only the new entry
+        let block = self.map_block_alloc(dir_ino, dir, blocks)?;
+        let mut data = vec![0u8; self.block_size];
+        let size = self.block_size;
+        self.encode_entry(&mut data, 0, size, ino, name, file_type);
+        self.write_block(block, &data)?;
+        dir.size += self.block_size as u64;
+        dir.mtime = super::now();
+        dir.ctime = dir.mtime;
+        self.write_inode(dir_ino, dir)
+    }
+
+    /// Remove an entry from a directory
+    pub fn remove_entry(
+        &mut self,
+        dir_ino: u32,
+        dir: &mut Inode,
+        entry: &Entry,
+    ) -> Result<(), FsError> {
+        let block = self.map_block(dir, entry.block)?;
+        let mut data = self.read_block(block)?;
+        match entry.prev {
+            // The previous record takes over the space
+            Some(prev) => {
+                let prev_len = self.read_rec_len(&data, prev);
+                let rec_len = self.read_rec_len(&data, entry.offset);
+                self.write_rec_len(&mut data, prev, prev_len + rec_len);
+            }
+            // The first record of a block stays as an empty one
+            None => write_u32(&mut data, entry.offset, 0),
+        }
+        self.write_block(block, &data)?;
+
+        dir.flags &= !FLAG_INDEX;
+        dir.mtime = super::now();
+        dir.ctime = dir.mtime;
+        self.write_inode(dir_ino, dir)
+    }
+
+    /// Write the `.` and `..` entries of a new directory
+    pub fn init_dir(&mut self, ino: u32, dir: &mut Inode, parent: u32) -> Result<(), FsError> {
+        let block = self.map_block_alloc(ino, dir, 0)?;
+        let mut data = vec![0u8; self.block_size];
+        let dot_size = entry_size(1);
+        let rest = self.block_size - dot_size;
+        self.encode_entry(&mut data, 0, dot_size, ino, ".", FileType::Directory);
+        self.encode_entry(&mut data, dot_size, rest, parent, "..", FileType::Directory);
+        self.write_block(block, &data)?;
+        dir.size = self.block_size as u64;
+        Ok(())
+    }
+
+    /// Point the `..` entry of a directory at a new parent
+    pub fn set_dir_parent(&mut self, dir: &Inode, parent: u32) -> Result<(), FsError> {
+        let entry = self.find_entry(dir, "..")?;
+        let block = self.map_block(dir, entry.block)?;
+        let mut data = self.read_block(block)?;
+        write_u32(&mut data, entry.offset, parent);
+        self.write_block(block, &data)
+    }
+}
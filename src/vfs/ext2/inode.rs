@@ -0,0 +1,314 @@
+use alloc::prelude::*;
+
+use super::{read_u16, read_u32, write_u16, write_u32, Volume};
+use crate::vfs::{FileType, FsError};
+
+// File types in the mode
+pub const MODE_TYPE_MASK: u16 = 0xF000;
+pub const MODE_CHAR_DEVICE: u16 = 0x2000;
+pub const MODE_DIRECTORY: u16 = 0x4000;
+pub const MODE_BLOCK_DEVICE: u16 = 0x6000;
+pub const MODE_FILE: u16 = 0x8000;
+pub const MODE_SYMLINK: u16 = 0xA000;
+
+/// The directory has a hashed index, which we do not maintain
+pub const FLAG_INDEX: u32 = 0x1000;
+
+const DIRECT_BLOCKS: usize = 12;
+const INDIRECT: usize = 12;
+const DOUBLE_INDIRECT: usize = 13;
+const TRIPLE_INDIRECT: usize = 14;
+/// Symlinks shorter than this are stored in the block pointers
+pub const FAST_SYMLINK_SIZE: usize = 60;
+
+/// An inode as stored on disk
+#[derive(Debug, Clone)]
+pub struct Inode {
+    pub mode: u16,
+    pub size: u64,
+    pub atime: u32,
+    pub ctime: u32,
+    pub mtime: u32,
+    pub dtime: u32,
+    pub links: u16,
+    /// The number of 512 byte sectors in use
+    pub sectors: u32,
+    pub flags: u32,
+    pub block: [u32; 15],
+    pub file_acl: u32,
+    /// The raw inode, which keeps the fields we do not use
+    raw: Vec<u8>,
+}
+
+impl Inode {
+    /// Create an inode that is not yet on disk
+    pub fn new(volume: &Volume, mode: u16) -> Self {
+        let time = super::now();
+        Inode {
+            mode,
+            size: 0,
+            atime: time,
+            ctime: time,
+            mtime: time,
+            dtime: 0,
+            links: 1,
+            sectors: 0,
+            flags: 0,
+            block: [0; 15],
+            file_acl: 0,
+            raw: vec![0u8; volume.inode_size],
+        }
+    }
+
+    fn parse(raw: Vec<u8>) -> Self {
+        let mut block = [0u32; 15];
+        for (i, ptr) in block.iter_mut().enumerate() {
+            *ptr = read_u32(&raw, 40 + i * 4);
+        }
+        let mode = read_u16(&raw, 0);
+        let mut size = read_u32(&raw, 4) as u64;
+        // The high half of the size is only valid for files
+        if mode & MODE_TYPE_MASK == MODE_FILE {
+            size |= (read_u32(&raw, 108) as u64) << 32;
+        }
+
+        Inode {
+            mode,
+            size,
+            atime: read_u32(&raw, 8),
+            ctime: read_u32(&raw, 12),
+            mtime: read_u32(&raw, 16),
+            dtime: read_u32(&raw, 20),
+            links: read_u16(&raw, 26),
+            sectors: read_u32(&raw, 28),
+            flags: read_u32(&raw, 32),
+            block,
+            file_acl: read_u32(&raw, 104),
+            raw,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut raw = self.raw.clone();
+        write_u16(&mut raw, 0, self.mode);
+        write_u32(&mut raw, 4, self.size as u32);
+        write_u32(&mut raw, 8, self.atime);
+        write_u32(&mut raw, 12, self.ctime);
+        write_u32(&mut raw, 16, self.mtime);
+        write_u32(&mut raw, 20, self.dtime);
+        write_u16(&mut raw, 26, self.links);
+        write_u32(&mut raw, 28, self.sectors);
+        write_u32(&mut raw, 32, self.flags);
+        for (i, &ptr) in self.block.iter().enumerate() {
+            write_u32(&mut raw, 40 + i * 4, ptr);
+        }
+        if self.is_file() {
+            write_u32(&mut raw, 108, (self.size >> 32) as u32);
+        }
+        raw
+    }
+
+    pub fn file_type(&self) -> FileType {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            MODE_CHAR_DEVICE => FileType::CharDevice,
+            MODE_BLOCK_DEVICE => FileType::BlockDevice,
+            _ => FileType::File,
+        }
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_FILE
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.mode & MODE_TYPE_MASK == MODE_DIRECTORY
+    }
+
+    /// Test whether a symlink keeps its target in the block pointers
+    pub fn is_fast_symlink(&self, block_size: usize) -> bool {
+        // An extended attribute block counts towards the sectors
+        let acl_sectors = if self.file_acl != 0 {
+            (block_size / 512) as u32
+        } else {
+            0
+        };
+        self.mode & MODE_TYPE_MASK == MODE_SYMLINK && self.sectors == acl_sectors
+    }
+
+    /// Get the target of a fast symlink
+    pub fn fast_symlink(&self) -> Vec<u8> {
+        let len = (self.size as usize).min(FAST_SYMLINK_SIZE);
+        self.raw[40..40 + len].to_vec()
+    }
+
+    /// Store the target of a fast symlink
+    pub fn set_fast_symlink(&mut self, target: &[u8]) {
+        let mut raw = [0u8; FAST_SYMLINK_SIZE];
+        raw[..target.len()].copy_from_slice(target);
+        for (i, ptr) in self.block.iter_mut().enumerate() {
+            *ptr = read_u32(&raw, i * 4);
+        }
+        self.size = target.len() as u64;
+    }
+}
+
+impl Volume {
+    fn inode_pos(&self, ino: u32) -> Result<u64, FsError> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(FsError::Corrupted);
+        }
+        let group = &self.groups[self.group_of(ino)];
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+        Ok(self.block_pos(group.inode_table) + index * self.inode_size as u64)
+    }
+
+    pub fn read_inode(&self, ino: u32) -> Result<Inode, FsError> {
+        let mut raw = vec![0u8; self.inode_size];
+        self.read_bytes(self.inode_pos(ino)?, &mut raw)?;
+        Ok(Inode::parse(raw))
+    }
+
+    pub fn write_inode(&self, ino: u32, inode: &Inode) -> Result<(), FsError> {
+        self.check_writable()?;
+        self.write_bytes(self.inode_pos(ino)?, &inode.encode())
+    }
+
+    /// The number of block pointers in an indirect block
+    fn pointers_per_block(&self) -> u64 {
+        (self.block_size / 4) as u64
+    }
+
+    /// Get the number of blocks addressed below a pointer of `depth`
+    fn span(&self, depth: u32) -> u64 {
+        self.pointers_per_block().pow(depth)
+    }
+
+    /// Get the top-level pointer, its depth and the index below it
+    fn locate(&self, index: u64) -> Result<(usize, u32, u64), FsError> {
+        let mut index = index;
+        if index < DIRECT_BLOCKS as u64 {
+            return Ok((index as usize, 0, 0));
+        }
+        index -= DIRECT_BLOCKS as u64;
+        for (slot, depth) in [(INDIRECT, 1), (DOUBLE_INDIRECT, 2), (TRIPLE_INDIRECT, 3)].iter() {
+            if index < self.span(*depth) {
+                return Ok((*slot, *depth, index));
+            }
+            index -= self.span(*depth);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Get the block holding a block of a file, 0 for holes
+    pub fn map_block(&self, inode: &Inode, index: u64) -> Result<u32, FsError> {
+        let (slot, depth, mut rest) = self.locate(index)?;
+        let mut block = inode.block[slot];
+        for level in (0..depth).rev() {
+            if block == 0 {
+                return Ok(0);
+            }
+            let span = self.span(level);
+            let pointers = self.read_block(block)?;
+            block = read_u32(&pointers, (rest / span) as usize * 4);
+            rest %= span;
+        }
+        Ok(block)
+    }
+
+    /// Get the block holding a block of a file, allocating it if needed
+    pub fn map_block_alloc(
+        &mut self,
+        ino: u32,
+        inode: &mut Inode,
+        index: u64,
+    ) -> Result<u32, FsError> {
+        let (slot, depth, mut rest) = self.locate(index)?;
+        let goal = self.group_of(ino);
+        let sectors = (self.block_size / 512) as u32;
+
+        if inode.block[slot] == 0 {
+            inode.block[slot] = self.allocate_block(goal)?;
+            inode.sectors += sectors;
+        }
+        let mut block = inode.block[slot];
+        for level in (0..depth).rev() {
+            let span = self.span(level);
+            let offset = (rest / span) as usize * 4;
+            rest %= span;
+
+            let mut pointers = self.read_block(block)?;
+            let mut next = read_u32(&pointers, offset);
+            if next == 0 {
+                next = self.allocate_block(goal)?;
+                inode.sectors += sectors;
+                write_u32(&mut pointers, offset, next);
+                self.write_block(block, &pointers)?;
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// Free all blocks of a file from block `keep` on
+    pub fn truncate_blocks(&mut self, inode: &mut Inode, keep: u64) -> Result<(), FsError> {
+        let mut start = 0;
+        for slot in 0..15 {
+            let depth = match slot {
+                INDIRECT => 1,
+                DOUBLE_INDIRECT => 2,
+                TRIPLE_INDIRECT => 3,
+                _ => 0,
+            };
+            let span = self.span(depth);
+            if inode.block[slot] != 0 && start + span > keep {
+                let freed = self.free_tree(inode.block[slot], depth, start, keep)?;
+                inode.sectors = inode.sectors.saturating_sub(freed);
+                if start >= keep {
+                    inode.block[slot] = 0;
+                }
+            }
+            start += span;
+        }
+        Ok(())
+    }
+
+    /// Free the blocks below a pointer that lie at or after `keep`.
+    ///
+    /// `start` is the first file block below the pointer. The
+    /// pointer's own block is freed if it lies entirely after
+    /// `keep`. Returns the number of sectors freed.
+    fn free_tree(&mut self, block: u32, depth: u32, start: u64, keep: u64) -> Result<u32, FsError> {
+        let sectors = (self.block_size / 512) as u32;
+        let mut freed = 0;
+
+        if depth > 0 {
+            let span = self.span(depth - 1);
+            let mut pointers = self.read_block(block)?;
+            let mut changed = false;
+            for i in 0..self.pointers_per_block() {
+                let child_start = start + i * span;
+                let offset = i as usize * 4;
+                let child = read_u32(&pointers, offset);
+                if child == 0 || child_start + span <= keep {
+                    continue;
+                }
+                freed += self.free_tree(child, depth - 1, child_start, keep)?;
+                if child_start >= keep {
+                    write_u32(&mut pointers, offset, 0);
+                    changed = true;
+                }
+            }
+            if changed && start < keep {
+                self.write_block(block, &pointers)?;
+            }
+        }
+
+        if start >= keep {
+            self.free_block(block)?;
+            freed += sectors;
+        }
+        Ok(freed)
+    }
+}
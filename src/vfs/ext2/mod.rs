@@ -0,0 +1,475 @@
+//
+// ext2 Filesystem
+//
+// The volume is split into block groups, each with a block
+// bitmap, an inode bitmap and a table of inodes. Inodes
+// address their data through twelve direct blocks and
+// three levels of indirect blocks. Directories are files
+// holding a list of variable-length entries.
+//
+// Volumes using incompatible features we do not know are
+// rejected. Unknown read-only features and volumes marked
+// as erroneous are mounted read-only.
+//
+
+mod dir;
+mod inode;
+mod node;
+
+use alloc::{collections::BTreeMap, prelude::*, sync::Arc};
+use spin::Mutex;
+
+use self::node::Ext2Node;
+use super::{FileSystem, FsError, INode};
+use crate::block::BlockDeviceHandle;
+use crate::cmos::CMOS;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+
+// Filesystem states
+const STATE_VALID: u16 = 0x0001;
+const STATE_ERROR: u16 = 0x0002;
+
+// Incompatible features
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// The only incompatible feature we support
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+
+// Read-only compatible features
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+const RO_COMPAT_BTREE_DIR: u32 = 0x0004;
+const RO_COMPAT_SUPPORTED: u32 =
+    RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_BTREE_DIR;
+
+/// The inode of the root directory
+const ROOT_INODE: u32 = 2;
+const GOOD_OLD_INODE_SIZE: usize = 128;
+const GOOD_OLD_FIRST_INODE: u32 = 11;
+const GROUP_DESCRIPTOR_SIZE: usize = 32;
+const MAX_BLOCK_SIZE: usize = 65536;
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    buf[offset] as u16 | (buf[offset + 1] as u16) << 8
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    (0..4).fold(0, |acc, i| acc | (buf[offset + i] as u32) << (8 * i))
+}
+
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset] = value as u8;
+    buf[offset + 1] = (value >> 8) as u8;
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    for i in 0..4 {
+        buf[offset + i] = (value >> (8 * i)) as u8;
+    }
+}
+
+/// Get the current time as stored in inodes
+fn now() -> u32 {
+    CMOS::read_date_time().as_unix_time() as u32
+}
+
+/// A block group descriptor
+#[derive(Debug, Clone)]
+struct Group {
+    block_bitmap: u32,
+    inode_bitmap: u32,
+    inode_table: u32,
+    free_blocks: u16,
+    free_inodes: u16,
+    used_dirs: u16,
+}
+
+/// The layout and allocation state of a volume
+struct Volume {
+    dev: BlockDeviceHandle,
+    /// The superblock as read, updated in place
+    superblock: Vec<u8>,
+    block_size: usize,
+    blocks_count: u32,
+    inodes_count: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    first_inode: u32,
+    /// The block holding the first group descriptor
+    descriptor_block: u32,
+    filetype: bool,
+    read_only: bool,
+    groups: Vec<Group>,
+    /// The number of live nodes of each open inode
+    open: BTreeMap<u32, usize>,
+    /// Unlinked inodes that are freed once they are closed
+    orphans: Vec<u32>,
+}
+
+impl Volume {
+    /// Read the superblock and group descriptors and check them
+    fn open(dev: BlockDeviceHandle) -> Result<Self, FsError> {
+        let mut superblock = vec![0u8; SUPERBLOCK_SIZE];
+        dev.read_bytes(SUPERBLOCK_OFFSET, &mut superblock)?;
+        if read_u16(&superblock, 56) != MAGIC {
+            return Err(FsError::Unsupported);
+        }
+
+        let log_block_size = read_u32(&superblock, 24);
+        if log_block_size > 6 {
+            return Err(FsError::Corrupted);
+        }
+        let block_size = 1024 << log_block_size;
+        if block_size % dev.sector_size() != 0 || block_size > MAX_BLOCK_SIZE {
+            return Err(FsError::Unsupported);
+        }
+
+        let name = dev.name();
+        let revision = read_u32(&superblock, 76);
+        let (inode_size, first_inode, incompat, ro_compat) = if revision == 0 {
+            (GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INODE, 0, 0)
+        } else {
+            (
+                read_u16(&superblock, 88) as usize,
+                read_u32(&superblock, 84),
+                read_u32(&superblock, 96),
+                read_u32(&superblock, 100),
+            )
+        };
+
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            log!(
+                error: "{}: unsupported ext2 features {:#x}.",
+                name,
+                incompat & !INCOMPAT_SUPPORTED
+            );
+            return Err(FsError::Unsupported);
+        }
+        let mut read_only = false;
+        if ro_compat & !RO_COMPAT_SUPPORTED != 0 {
+            log!(warn: "{}: unsupported ext2 features, mounting read-only.", name);
+            read_only = true;
+        }
+        let state = read_u16(&superblock, 58);
+        if state & STATE_ERROR != 0 {
+            log!(warn: "{}: ext2 volume has errors, mounting read-only.", name);
+            read_only = true;
+        } else if state & STATE_VALID == 0 {
+            log!(warn: "{}: ext2 volume was not cleanly unmounted.", name);
+        }
+
+        let blocks_count = read_u32(&superblock, 4);
+        let inodes_count = read_u32(&superblock, 0);
+        let first_data_block = read_u32(&superblock, 20);
+        let blocks_per_group = read_u32(&superblock, 32);
+        let inodes_per_group = read_u32(&superblock, 40);
+        if blocks_per_group == 0
+            || inodes_per_group == 0
+            || blocks_per_group as usize > block_size * 8
+            || inodes_per_group as usize > block_size * 8
+            || !inode_size.is_power_of_two()
+            || inode_size < GOOD_OLD_INODE_SIZE
+            || inode_size > block_size
+            || first_data_block >= blocks_count
+            || blocks_count as u64 * block_size as u64
+                > dev.sector_count() * dev.sector_size() as u64
+        {
+            return Err(FsError::Corrupted);
+        }
+
+        let group_count =
+            ((blocks_count - first_data_block + blocks_per_group - 1) / blocks_per_group) as usize;
+        if group_count as u64 * inodes_per_group as u64 != inodes_count as u64 {
+            return Err(FsError::Corrupted);
+        }
+
+        let mut volume = Volume {
+            dev,
+            superblock,
+            block_size,
+            blocks_count,
+            inodes_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            first_inode,
+            descriptor_block: first_data_block + 1,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            read_only,
+            groups: Vec::new(),
+            open: BTreeMap::new(),
+            orphans: Vec::new(),
+        };
+        volume.read_groups(group_count)?;
+        Ok(volume)
+    }
+
+    /// Read and check the group descriptors
+    fn read_groups(&mut self, count: usize) -> Result<(), FsError> {
+        let mut table = vec![0u8; count * GROUP_DESCRIPTOR_SIZE];
+        self.read_bytes(self.block_pos(self.descriptor_block), &mut table)?;
+
+        for (index, raw) in table.chunks(GROUP_DESCRIPTOR_SIZE).enumerate() {
+            let group = Group {
+                block_bitmap: read_u32(raw, 0),
+                inode_bitmap: read_u32(raw, 4),
+                inode_table: read_u32(raw, 8),
+                free_blocks: read_u16(raw, 12),
+                free_inodes: read_u16(raw, 14),
+                used_dirs: read_u16(raw, 16),
+            };
+
+            let table_blocks = (self.inodes_per_group as usize * self.inode_size + self.block_size
+                - 1)
+                / self.block_size;
+            let valid = |block: u32| block >= self.first_data_block && block < self.blocks_count;
+            if !valid(group.block_bitmap)
+                || !valid(group.inode_bitmap)
+                || !valid(group.inode_table)
+                || group.inode_table as usize + table_blocks > self.blocks_count as usize
+            {
+                log!(error: "{}: ext2 group {} is damaged.", self.dev.name(), index);
+                return Err(FsError::Corrupted);
+            }
+            self.groups.push(group);
+        }
+        Ok(())
+    }
+
+    /// Write a group descriptor and the free counts of the superblock
+    fn write_group(&mut self, index: usize) -> Result<(), FsError> {
+        let group = &self.groups[index];
+        let mut raw = [0u8; GROUP_DESCRIPTOR_SIZE];
+        let pos = self.block_pos(self.descriptor_block) + (index * GROUP_DESCRIPTOR_SIZE) as u64;
+        self.read_bytes(pos, &mut raw)?;
+        write_u16(&mut raw, 12, group.free_blocks);
+        write_u16(&mut raw, 14, group.free_inodes);
+        write_u16(&mut raw, 16, group.used_dirs);
+        self.write_bytes(pos, &raw)?;
+
+        let free_blocks = self.groups.iter().map(|g| g.free_blocks as u32).sum();
+        let free_inodes = self.groups.iter().map(|g| g.free_inodes as u32).sum();
+        write_u32(&mut self.superblock, 12, free_blocks);
+        write_u32(&mut self.superblock, 16, free_inodes);
+        self.write_bytes(SUPERBLOCK_OFFSET, &self.superblock)
+    }
+
+    /// Record a mount in the superblock
+    fn mark_mounted(&mut self) -> Result<(), FsError> {
+        if self.read_only {
+            return Ok(());
+        }
+        let count = read_u16(&self.superblock, 52);
+        write_u16(&mut self.superblock, 52, count.wrapping_add(1));
+        write_u32(&mut self.superblock, 44, now());
+        self.write_bytes(SUPERBLOCK_OFFSET, &self.superblock)
+    }
+
+    fn check_writable(&self) -> Result<(), FsError> {
+        if self.read_only {
+            Err(FsError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    //
+    // Block access
+    //
+
+    fn block_pos(&self, block: u32) -> u64 {
+        block as u64 * self.block_size as u64
+    }
+
+    fn read_bytes(&self, pos: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.dev.read_bytes(pos, buf).map_err(FsError::from)
+    }
+
+    fn write_bytes(&self, pos: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.dev.write_bytes(pos, buf).map_err(FsError::from)
+    }
+
+    fn read_block(&self, block: u32) -> Result<Vec<u8>, FsError> {
+        if block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+        let mut buf = vec![0u8; self.block_size];
+        self.read_bytes(self.block_pos(block), &mut buf)?;
+        Ok(buf)
+    }
+
+    fn write_block(&self, block: u32, buf: &[u8]) -> Result<(), FsError> {
+        if block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+        self.write_bytes(self.block_pos(block), buf)
+    }
+
+    //
+    // Allocation
+    //
+
+    /// Find a clear bit in `first..limit` of a bitmap block, set it and return its index
+    fn take_bit(&self, bitmap: u32, first: u32, limit: u32) -> Result<Option<u32>, FsError> {
+        let mut bits = self.read_block(bitmap)?;
+        let found = (first..limit).find(|&bit| bits[bit as usize / 8] & (1 << (bit % 8)) == 0);
+        if let Some(bit) = found {
+            bits[bit as usize / 8] |= 1 << (bit % 8);
+            self.write_block(bitmap, &bits)?;
+        }
+        Ok(found)
+    }
+
+    /// Clear a bit in a bitmap block, failing if it was clear
+    fn clear_bit(&self, bitmap: u32, bit: u32) -> Result<(), FsError> {
+        let mut bits = self.read_block(bitmap)?;
+        let mask = 1 << (bit % 8);
+        if bits[bit as usize / 8] & mask == 0 {
+            return Err(FsError::Corrupted);
+        }
+        bits[bit as usize / 8] &= !mask;
+        self.write_block(bitmap, &bits)
+    }
+
+    /// Get the number of blocks in a group; the last one may be short
+    fn blocks_in_group(&self, index: usize) -> u32 {
+        let start = self.first_data_block + index as u32 * self.blocks_per_group;
+        (self.blocks_count - start).min(self.blocks_per_group)
+    }
+
+    /// Allocate a zeroed block, preferably in the group `goal`
+    fn allocate_block(&mut self, goal: usize) -> Result<u32, FsError> {
+        self.check_writable()?;
+        let count = self.groups.len();
+        for index in (0..count).map(|i| (goal + i) % count) {
+            if self.groups[index].free_blocks == 0 {
+                continue;
+            }
+            let limit = self.blocks_in_group(index);
+            let bit = match self.take_bit(self.groups[index].block_bitmap, 0, limit)? {
+                Some(bit) => bit,
+                // The counts were wrong
+                None => continue,
+            };
+
+            self.groups[index].free_blocks -= 1;
+            self.write_group(index)?;
+            let block = self.first_data_block + index as u32 * self.blocks_per_group + bit;
+            self.write_block(block, &vec![0u8; self.block_size])?;
+            return Ok(block);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&mut self, block: u32) -> Result<(), FsError> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(FsError::Corrupted);
+        }
+        let relative = block - self.first_data_block;
+        let index = (relative / self.blocks_per_group) as usize;
+        self.clear_bit(
+            self.groups[index].block_bitmap,
+            relative % self.blocks_per_group,
+        )?;
+        self.groups[index].free_blocks += 1;
+        self.write_group(index)
+    }
+
+    /// Allocate an inode, preferably in the group `goal`
+    fn allocate_inode(&mut self, goal: usize, directory: bool) -> Result<u32, FsError> {
+        self.check_writable()?;
+        let count = self.groups.len();
+        for index in (0..count).map(|i| (goal + i) % count) {
+            if self.groups[index].free_inodes == 0 {
+                continue;
+            }
+            // Reserved inodes are never handed out, even if marked free
+            let group_start = index as u32 * self.inodes_per_group;
+            let first = self.first_inode.saturating_sub(group_start + 1);
+            let bitmap = self.groups[index].inode_bitmap;
+            let bit = match self.take_bit(bitmap, first, self.inodes_per_group)? {
+                Some(bit) => bit,
+                None => continue,
+            };
+            let ino = group_start + bit + 1;
+
+            self.groups[index].free_inodes -= 1;
+            if directory {
+                self.groups[index].used_dirs += 1;
+            }
+            self.write_group(index)?;
+            return Ok(ino);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_inode(&mut self, ino: u32, directory: bool) -> Result<(), FsError> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(FsError::Corrupted);
+        }
+        let index = ((ino - 1) / self.inodes_per_group) as usize;
+        self.clear_bit(
+            self.groups[index].inode_bitmap,
+            (ino - 1) % self.inodes_per_group,
+        )?;
+        self.groups[index].free_inodes += 1;
+        if directory {
+            self.groups[index].used_dirs = self.groups[index].used_dirs.saturating_sub(1);
+        }
+        self.write_group(index)
+    }
+
+    /// Get the group an inode lives in
+    fn group_of(&self, ino: u32) -> usize {
+        ((ino - 1) / self.inodes_per_group) as usize
+    }
+
+    /// Pick a group for a new directory, spreading them out
+    fn group_for_directory(&self) -> usize {
+        (0..self.groups.len())
+            .filter(|&index| self.groups[index].free_inodes > 0)
+            .min_by_key(|&index| self.groups[index].used_dirs)
+            .unwrap_or(0)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.dev.sync()?;
+        Ok(())
+    }
+}
+
+/// An ext2 filesystem
+pub struct Ext2FS {
+    volume: Arc<Mutex<Volume>>,
+    root: Arc<Ext2Node>,
+}
+
+impl Ext2FS {
+    /// Open the ext2 volume on a block device
+    pub fn new(dev: BlockDeviceHandle) -> Result<Self, FsError> {
+        let mut volume = Volume::open(dev)?;
+        volume.mark_mounted()?;
+        let volume = Arc::new(Mutex::new(volume));
+        let root = Ext2Node::new(&volume, ROOT_INODE)?;
+        Ok(Ext2FS { volume, root })
+    }
+}
+
+impl FileSystem for Ext2FS {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn INode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.volume.lock().sync()
+    }
+}
@@ -0,0 +1,496 @@
+use alloc::{prelude::*, sync::Arc};
+use core::any::Any;
+use spin::Mutex;
+
+use super::inode::{
+    Inode, FAST_SYMLINK_SIZE, MODE_DIRECTORY, MODE_FILE, MODE_SYMLINK, MODE_TYPE_MASK,
+};
+use super::Volume;
+use crate::vfs::{DirEntry, FileType, FsError, INode, Metadata};
+
+/// A file, directory or link of an ext2 volume.
+///
+/// Nodes only hold the inode number, everything else is
+/// read from the inode table when needed.
+pub struct Ext2Node {
+    volume: Arc<Mutex<Volume>>,
+    ino: u32,
+    file_type: FileType,
+}
+
+impl Ext2Node {
+    pub(super) fn new(volume: &Arc<Mutex<Volume>>, ino: u32) -> Result<Arc<Self>, FsError> {
+        let mut guard = volume.lock();
+        let inode = guard.read_inode(ino)?;
+        if inode.links == 0 {
+            return Err(FsError::Corrupted);
+        }
+        *guard.open.entry(ino).or_insert(0) += 1;
+        Ok(Arc::new(Ext2Node {
+            volume: volume.clone(),
+            ino,
+            file_type: inode.file_type(),
+        }))
+    }
+
+    fn node(&self, ino: u32) -> Result<Arc<dyn INode>, FsError> {
+        Ok(Ext2Node::new(&self.volume, ino)?)
+    }
+
+    fn check_directory(&self) -> Result<(), FsError> {
+        if self.file_type != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        Ok(())
+    }
+
+    /// Create an inode and link it into this directory
+    fn create_inode(
+        &self,
+        volume: &mut Volume,
+        name: &str,
+        mode: u16,
+        init: &dyn Fn(&mut Volume, u32, &mut Inode) -> Result<(), FsError>,
+    ) -> Result<u32, FsError> {
+        volume.check_writable()?;
+        let mut dir = volume.read_inode(self.ino)?;
+        // An unlinked directory that is still open stays empty
+        if dir.links == 0 {
+            return Err(FsError::NotFound);
+        }
+        match volume.find_entry(&dir, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => (),
+            Err(err) => return Err(err),
+        }
+
+        let directory = mode & MODE_TYPE_MASK == MODE_DIRECTORY;
+        let goal = if directory {
+            volume.group_for_directory()
+        } else {
+            volume.group_of(self.ino)
+        };
+        let ino = volume.allocate_inode(goal, directory)?;
+        let mut inode = Inode::new(volume, mode);
+
+        let result = init(volume, ino, &mut inode)
+            .and_then(|_| volume.write_inode(ino, &inode))
+            .and_then(|_| volume.add_entry(self.ino, &mut dir, name, ino, inode.file_type()));
+        if let Err(err) = result {
+            // Give back what was allocated
+            volume.truncate_blocks(&mut inode, 0)?;
+            volume.free_inode(ino, directory)?;
+            return Err(err);
+        }
+        Ok(ino)
+    }
+
+    /// Drop a link to an inode, freeing it with the last one.
+    ///
+    /// An inode that is still open becomes an orphan: its data
+    /// stays usable through the open nodes until the last of
+    /// them is dropped.
+    fn release(volume: &mut Volume, ino: u32, inode: &mut Inode) -> Result<(), FsError> {
+        // Directories also lose the link of their `.` entry
+        inode.links = if inode.is_directory() {
+            0
+        } else {
+            inode.links.saturating_sub(1)
+        };
+        inode.ctime = super::now();
+
+        if inode.links > 0 {
+            volume.write_inode(ino, inode)
+        } else if volume.open.contains_key(&ino) {
+            volume.write_inode(ino, inode)?;
+            volume.orphans.push(ino);
+            Ok(())
+        } else {
+            Ext2Node::free(volume, ino, inode)
+        }
+    }
+
+    /// Free an inode without links along with its blocks
+    fn free(volume: &mut Volume, ino: u32, inode: &mut Inode) -> Result<(), FsError> {
+        if !inode.is_fast_symlink(volume.block_size) {
+            volume.truncate_blocks(inode, 0)?;
+        }
+        inode.size = 0;
+        inode.dtime = super::now();
+        volume.write_inode(ino, inode)?;
+        volume.free_inode(ino, inode.is_directory())
+    }
+
+    /// Check that a directory holds nothing but `.` and `..`
+    fn check_empty(volume: &Volume, inode: &Inode) -> Result<(), FsError> {
+        if inode.is_directory() && volume.read_dir(inode)?.iter().any(|entry| !entry.is_dot()) {
+            return Err(FsError::NotEmpty);
+        }
+        Ok(())
+    }
+
+    /// Write zeros over the end of the last block after `size`
+    fn zero_tail(volume: &Volume, inode: &Inode, size: u64, end: u64) -> Result<(), FsError> {
+        let block_size = volume.block_size as u64;
+        let block_end = (size + block_size - 1) / block_size * block_size;
+        let end = end.min(block_end);
+        if size % block_size == 0 || end <= size {
+            return Ok(());
+        }
+        let block = volume.map_block(inode, size / block_size)?;
+        if block != 0 {
+            let zeros = vec![0u8; (end - size) as usize];
+            volume.write_bytes(volume.block_pos(block) + size % block_size, &zeros)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Ext2Node {
+    fn drop(&mut self) {
+        let mut volume = self.volume.lock();
+        let last = match volume.open.get_mut(&self.ino) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if !last {
+            return;
+        }
+        volume.open.remove(&self.ino);
+
+        // Free the inode if it was unlinked while open
+        let ino = self.ino;
+        match volume.orphans.iter().position(|orphan| *orphan == ino) {
+            Some(index) => volume.orphans.swap_remove(index),
+            None => return,
+        };
+        let res = volume
+            .read_inode(ino)
+            .and_then(|mut inode| Ext2Node::free(&mut volume, ino, &mut inode));
+        if let Err(err) = res {
+            log!(error: "{}: unable to free ext2 inode {}: {}", volume.dev.name(), ino, err);
+        }
+    }
+}
+
+impl INode for Ext2Node {
+    fn metadata(&self) -> Metadata {
+        let inode = self.volume.lock().read_inode(self.ino);
+        match inode {
+            Ok(inode) => Metadata {
+                inode: self.ino as u64,
+                file_type: inode.file_type(),
+                size: inode.size,
+                mode: inode.mode & !MODE_TYPE_MASK,
+                links: inode.links as u32,
+            },
+            Err(_) => Metadata {
+                inode: self.ino as u64,
+                file_type: self.file_type,
+                size: 0,
+                mode: 0,
+                links: 0,
+            },
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        match self.file_type {
+            FileType::File => (),
+            FileType::Directory => return Err(FsError::IsDirectory),
+            _ => return Err(FsError::Unsupported),
+        }
+        let volume = self.volume.lock();
+        let inode = volume.read_inode(self.ino)?;
+        if offset >= inode.size {
+            return Ok(0);
+        }
+
+        let count = (buf.len() as u64).min(inode.size - offset) as usize;
+        let block_size = volume.block_size as u64;
+        let mut done = 0;
+        while done < count {
+            let at = offset + done as u64;
+            let within = at % block_size;
+            let chunk = ((block_size - within) as usize).min(count - done);
+            let target = &mut buf[done..done + chunk];
+            match volume.map_block(&inode, at / block_size)? {
+                // Holes read as zeros
+                0 => {
+                    for b in target.iter_mut() {
+                        *b = 0;
+                    }
+                }
+                block => volume.read_bytes(volume.block_pos(block) + within, target)?,
+            }
+            done += chunk;
+        }
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        match self.file_type {
+            FileType::File => (),
+            FileType::Directory => return Err(FsError::IsDirectory),
+            _ => return Err(FsError::Unsupported),
+        }
+        let mut volume = self.volume.lock();
+        volume.check_writable()?;
+        let mut inode = volume.read_inode(self.ino)?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Data written into the hole after the end must read as zeros
+        if offset > inode.size {
+            Ext2Node::zero_tail(&volume, &inode, inode.size, offset)?;
+        }
+
+        let block_size = volume.block_size as u64;
+        let mut done = 0;
+        while done < buf.len() {
+            let at = offset + done as u64;
+            let within = at % block_size;
+            let chunk = ((block_size - within) as usize).min(buf.len() - done);
+            let block = volume.map_block_alloc(self.ino, &mut inode, at / block_size)?;
+            volume.write_bytes(volume.block_pos(block) + within, &buf[done..done + chunk])?;
+            done += chunk;
+        }
+
+        inode.size = inode.size.max(offset + buf.len() as u64);
+        inode.mtime = super::now();
+        inode.ctime = inode.mtime;
+        volume.write_inode(self.ino, &inode)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        match self.file_type {
+            FileType::File => (),
+            FileType::Directory => return Err(FsError::IsDirectory),
+            _ => return Err(FsError::Unsupported),
+        }
+        let mut volume = self.volume.lock();
+        volume.check_writable()?;
+        let mut inode = volume.read_inode(self.ino)?;
+
+        if size < inode.size {
+            let block_size = volume.block_size as u64;
+            let keep = (size + block_size - 1) / block_size;
+            volume.truncate_blocks(&mut inode, keep)?;
+            Ext2Node::zero_tail(&volume, &inode, size, inode.size)?;
+        }
+        // Growing leaves a hole
+        inode.size = size;
+        inode.mtime = super::now();
+        inode.ctime = inode.mtime;
+        volume.write_inode(self.ino, &inode)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn INode>, FsError> {
+        self.check_directory()?;
+        let ino = {
+            let volume = self.volume.lock();
+            let dir = volume.read_inode(self.ino)?;
+            volume.find_entry(&dir, name)?.ino
+        };
+        self.node(ino)
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn INode>, FsError> {
+        self.check_directory()?;
+        let parent = self.ino;
+        let ino = {
+            let mut volume = self.volume.lock();
+            match file_type {
+                FileType::File => {
+                    self.create_inode(&mut volume, name, MODE_FILE | 0o644, &|_, _, _| Ok(()))?
+                }
+                FileType::Directory => {
+                    let ino = self.create_inode(
+                        &mut volume,
+                        name,
+                        MODE_DIRECTORY | 0o755,
+                        &|volume, ino, inode| {
+                            inode.links = 2;
+                            volume.init_dir(ino, inode, parent)
+                        },
+                    )?;
+                    // The `..` entry links to this directory
+                    let mut dir = volume.read_inode(parent)?;
+                    dir.links += 1;
+                    volume.write_inode(parent, &dir)?;
+                    ino
+                }
+                _ => return Err(FsError::Unsupported),
+            }
+        };
+        self.node(ino)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn INode>, FsError> {
+        self.check_directory()?;
+        let ino = {
+            let mut volume = self.volume.lock();
+            let init = |volume: &mut Volume, ino: u32, inode: &mut Inode| -> Result<(), FsError> {
+                let bytes = target.as_bytes();
+                if bytes.len() < FAST_SYMLINK_SIZE {
+                    inode.set_fast_symlink(bytes);
+                    return Ok(());
+                }
+                if bytes.len() > volume.block_size {
+                    return Err(FsError::InvalidPath);
+                }
+                let block = volume.map_block_alloc(ino, inode, 0)?;
+                volume.write_bytes(volume.block_pos(block), bytes)?;
+                inode.size = bytes.len() as u64;
+                Ok(())
+            };
+            self.create_inode(&mut volume, name, MODE_SYMLINK | 0o777, &init)?
+        };
+        self.node(ino)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.check_directory()?;
+        let mut volume = self.volume.lock();
+        volume.check_writable()?;
+        let mut dir = volume.read_inode(self.ino)?;
+        let entry = volume.find_entry(&dir, name)?;
+        let mut inode = volume.read_inode(entry.ino)?;
+        Ext2Node::check_empty(&volume, &inode)?;
+
+        volume.remove_entry(self.ino, &mut dir, &entry)?;
+        if inode.is_directory() {
+            dir.links = dir.links.saturating_sub(1);
+            volume.write_inode(self.ino, &dir)?;
+        }
+        Ext2Node::release(&mut volume, entry.ino, &mut inode)
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        target: &Arc<dyn INode>,
+        new_name: &str,
+    ) -> Result<(), FsError> {
+        let target = target
+            .as_any()
+            .downcast_ref::<Ext2Node>()
+            .ok_or(FsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.volume, &target.volume) {
+            return Err(FsError::CrossDevice);
+        }
+        self.check_directory()?;
+        target.check_directory()?;
+
+        let mut volume = self.volume.lock();
+        volume.check_writable()?;
+        let source = volume.read_inode(self.ino)?;
+        let entry = volume.find_entry(&source, old_name)?;
+        let moved = volume.read_inode(entry.ino)?;
+
+        let mut dest = volume.read_inode(target.ino)?;
+        match volume.find_entry(&dest, new_name) {
+            Ok(ref existing) if existing.ino == entry.ino => return Ok(()),
+            Ok(existing) => {
+                let mut old = volume.read_inode(existing.ino)?;
+                match (moved.is_directory(), old.is_directory()) {
+                    (true, false) => return Err(FsError::NotDirectory),
+                    (false, true) => return Err(FsError::IsDirectory),
+                    _ => (),
+                }
+                Ext2Node::check_empty(&volume, &old)?;
+                volume.remove_entry(target.ino, &mut dest, &existing)?;
+                if old.is_directory() {
+                    dest.links = dest.links.saturating_sub(1);
+                    volume.write_inode(target.ino, &dest)?;
+                }
+                Ext2Node::release(&mut volume, existing.ino, &mut old)?;
+            }
+            Err(FsError::NotFound) => (),
+            Err(err) => return Err(err),
+        }
+
+        // Link the new name before removing the old one
+        let file_type = moved.file_type();
+        volume.add_entry(target.ino, &mut dest, new_name, entry.ino, file_type)?;
+        // The source may be the target, so read it again
+        let mut source = volume.read_inode(self.ino)?;
+        let entry = volume.find_entry(&source, old_name)?;
+        volume.remove_entry(self.ino, &mut source, &entry)?;
+
+        if moved.is_directory() && self.ino != target.ino {
+            volume.set_dir_parent(&moved, target.ino)?;
+            source.links = source.links.saturating_sub(1);
+            volume.write_inode(self.ino, &source)?;
+            let mut dest = volume.read_inode(target.ino)?;
+            dest.links += 1;
+            volume.write_inode(target.ino, &dest)?;
+        }
+        Ok(())
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        self.check_directory()?;
+        let volume = self.volume.lock();
+        let dir = volume.read_inode(self.ino)?;
+        volume
+            .read_dir(&dir)?
+            .into_iter()
+            .filter(|entry| !entry.is_dot())
+            .map(|entry| -> Result<DirEntry, FsError> {
+                // Older volumes keep the type in the inode only
+                let file_type = match entry.file_type {
+                    Some(file_type) => file_type,
+                    None => volume.read_inode(entry.ino)?.file_type(),
+                };
+                Ok(DirEntry {
+                    name: entry.name,
+                    inode: entry.ino as u64,
+                    file_type,
+                })
+            })
+            .collect()
+    }
+
+    fn readlink(&self) -> Result<String, FsError> {
+        if self.file_type != FileType::Symlink {
+            return Err(FsError::InvalidPath);
+        }
+        let volume = self.volume.lock();
+        let inode = volume.read_inode(self.ino)?;
+        let target = if inode.is_fast_symlink(volume.block_size) {
+            inode.fast_symlink()
+        } else {
+            let len = (inode.size as usize).min(volume.block_size);
+            let mut target = vec![0u8; len];
+            let block = volume.map_block(&inode, 0)?;
+            if block == 0 {
+                return Err(FsError::Corrupted);
+            }
+            volume.read_bytes(volume.block_pos(block), &mut target)?;
+            target
+        };
+        String::from_utf8(target).map_err(|_| FsError::Corrupted)
+    }
+
+    fn set_mode(&self, mode: u16) -> Result<(), FsError> {
+        let volume = self.volume.lock();
+        let mut inode = volume.read_inode(self.ino)?;
+        inode.mode = (inode.mode & MODE_TYPE_MASK) | (mode & !MODE_TYPE_MASK);
+        inode.ctime = super::now();
+        volume.write_inode(self.ino, &inode)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.volume.lock().sync()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
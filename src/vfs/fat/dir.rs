@@ -0,0 +1,468 @@
+use alloc::prelude::*;
+
+use super::{read_u16, read_u32, write_u16, write_u32, DirLocation, Volume, ENTRY_SIZE};
+use crate::cmos::CMOS;
+use crate::vfs::FsError;
+
+// Attributes
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+/// Marks a long name entry
+pub const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// The first name byte of the entry after the last one
+const ENTRY_END: u8 = 0x00;
+/// The first name byte of a deleted entry
+const ENTRY_FREE: u8 = 0xE5;
+/// Stands for a first name byte of 0xE5
+const ENTRY_KANJI_E5: u8 = 0x05;
+
+// Case flags of short names
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+/// Marks the long name entry holding the end of the name
+const LFN_LAST: u8 = 0x40;
+const LFN_SEQUENCE_MASK: u8 = 0x1F;
+const LFN_CHARS: usize = 13;
+/// The offsets of the characters in a long name entry
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const LFN_MAX_LENGTH: usize = 255;
+
+/// Characters allowed in short names besides letters and digits
+const SHORT_NAME_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+
+/// An entry read from a directory
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// The long name if there is one, the short name otherwise
+    pub name: String,
+    pub short_name: [u8; 11],
+    pub attr: u8,
+    pub cluster: u32,
+    pub size: u32,
+    /// The position of the short entry
+    pub pos: u64,
+    /// The positions of all slots, long name entries first
+    pub slots: Vec<u64>,
+}
+
+impl Entry {
+    pub fn is_directory(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    fn is_dot(&self) -> bool {
+        self.short_name == *b".          " || self.short_name == *b"..         "
+    }
+}
+
+/// Get the checksum of a short name stored in its long name entries
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &b| (sum >> 1 | sum << 7).wrapping_add(b))
+}
+
+/// Get the displayed form of a short name with its case flags
+fn short_name_string(short_name: &[u8; 11], case: u8) -> String {
+    let convert = |bytes: &[u8], lower: bool| -> String {
+        let text = bytes
+            .iter()
+            .map(|&b| b as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+        if lower {
+            text.to_ascii_lowercase()
+        } else {
+            text
+        }
+    };
+
+    let mut raw = *short_name;
+    if raw[0] == ENTRY_KANJI_E5 {
+        raw[0] = ENTRY_FREE;
+    }
+    let base = convert(&raw[0..8], case & CASE_LOWER_BASE != 0);
+    let ext = convert(&raw[8..11], case & CASE_LOWER_EXT != 0);
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+fn is_short_char(b: u8) -> bool {
+    b.is_ascii_uppercase() || b.is_ascii_digit() || SHORT_NAME_SPECIAL.contains(&b) || b >= 0x80
+}
+
+/// Get the short name of a name that needs no long name
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let bytes = name.as_bytes();
+    let (base, ext) = match name.rfind('.') {
+        Some(index) => (&bytes[..index], &bytes[index + 1..]),
+        None => (bytes, &[][..]),
+    };
+    let valid = |part: &[u8], max: usize| {
+        part.len() <= max && part.iter().all(|&b| b.is_ascii() && is_short_char(b))
+    };
+    if base.is_empty() || !valid(base, 8) || !valid(ext, 3) || name.ends_with('.') {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base);
+    short[8..8 + ext.len()].copy_from_slice(ext);
+    Some(short)
+}
+
+/// Get the short name `BASE~N.EXT` for a long name
+fn numbered_short_name(name: &str, number: usize) -> [u8; 11] {
+    let clean = |part: &str| -> Vec<u8> {
+        part.bytes()
+            .filter(|&b| b != b' ' && b != b'.')
+            .map(|b| {
+                let b = b.to_ascii_uppercase();
+                if b.is_ascii() && is_short_char(b) {
+                    b
+                } else {
+                    b'_'
+                }
+            })
+            .collect()
+    };
+
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(index) => (clean(&trimmed[..index]), clean(&trimmed[index + 1..])),
+        None => (clean(trimmed), Vec::new()),
+    };
+
+    let tail = format!("~{}", number);
+    let keep = base.len().min(8 - tail.len());
+    let mut short = [b' '; 11];
+    short[..keep].copy_from_slice(&base[..keep]);
+    short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+    let ext_len = ext.len().min(3);
+    short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+    short
+}
+
+/// Get the FAT time and date of now
+fn timestamp() -> (u16, u16) {
+    let now = CMOS::read_date_time();
+    let time = (now.hour as u16) << 11 | (now.minute as u16) << 5 | (now.second as u16) / 2;
+    // Dates count from 1980
+    let year = now.year.max(1980).min(2107) - 1980;
+    let date = year << 9 | (now.month as u16) << 5 | now.day_of_month as u16;
+    (time, date)
+}
+
+/// Fill in a short entry
+fn encode_short(slot: &mut [u8], short_name: &[u8; 11], attr: u8, cluster: u32, size: u32) {
+    let (time, date) = timestamp();
+    for b in slot.iter_mut() {
+        *b = 0;
+    }
+    slot[0..11].copy_from_slice(short_name);
+    slot[11] = attr;
+    // Creation time, creation date and access date
+    write_u16(slot, 14, time);
+    write_u16(slot, 16, date);
+    write_u16(slot, 18, date);
+    write_u16(slot, 20, (cluster >> 16) as u16);
+    write_u16(slot, 22, time);
+    write_u16(slot, 24, date);
+    write_u16(slot, 26, cluster as u16);
+    write_u32(slot, 28, size);
+}
+
+impl Volume {
+    /// Read all slots of a directory with their positions
+    fn read_slots(&self, dir: DirLocation) -> Result<Vec<(u64, [u8; ENTRY_SIZE])>, FsError> {
+        let regions = match dir {
+            DirLocation::FixedRoot => {
+                let start = self.root_start * self.sector_size as u64;
+                vec![(start, self.root_entries * ENTRY_SIZE)]
+            }
+            DirLocation::Chain(first) => self
+                .chain(first)?
+                .into_iter()
+                .map(|cluster| (self.cluster_pos(cluster), self.cluster_size))
+                .collect(),
+        };
+
+        let mut slots = Vec::new();
+        for (start, len) in regions {
+            let mut data = vec![0u8; len];
+            self.read_bytes(start, &mut data)?;
+            for (i, chunk) in data.chunks(ENTRY_SIZE).enumerate() {
+                let mut slot = [0u8; ENTRY_SIZE];
+                slot.copy_from_slice(chunk);
+                slots.push((start + (i * ENTRY_SIZE) as u64, slot));
+            }
+        }
+        Ok(slots)
+    }
+
+    /// Read the entries of a directory, including `.` and `..`
+    fn read_all_entries(&self, dir: DirLocation) -> Result<Vec<Entry>, FsError> {
+        let mut entries = Vec::new();
+        // The long name entries seen since the last short entry
+        let mut long: Vec<(u64, [u8; ENTRY_SIZE])> = Vec::new();
+
+        for (pos, slot) in self.read_slots(dir)? {
+            match slot[0] {
+                ENTRY_END => break,
+                ENTRY_FREE => {
+                    long.clear();
+                    continue;
+                }
+                _ => (),
+            }
+
+            if slot[11] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                // A new name starts with its last entry
+                let expected = match long.last() {
+                    Some(prev) if slot[13] == prev.1[13] => {
+                        (prev.1[0] & LFN_SEQUENCE_MASK).saturating_sub(1)
+                    }
+                    _ => 0,
+                };
+                if slot[0] & LFN_LAST != 0 {
+                    long.clear();
+                    long.push((pos, slot));
+                } else if expected != 0 && slot[0] & LFN_SEQUENCE_MASK == expected {
+                    long.push((pos, slot));
+                } else {
+                    long.clear();
+                }
+                continue;
+            }
+            if slot[11] & ATTR_VOLUME_ID != 0 {
+                long.clear();
+                continue;
+            }
+
+            let mut short_name = [0u8; 11];
+            short_name.copy_from_slice(&slot[0..11]);
+            let complete = long
+                .last()
+                .map_or(false, |last| last.1[0] & LFN_SEQUENCE_MASK == 1);
+            let name = if complete && long[0].1[13] == checksum(&short_name) {
+                // The entries are stored from the end of the name
+                let units: Vec<u16> = long
+                    .iter()
+                    .rev()
+                    .flat_map(|&(_, ref lfn)| LFN_OFFSETS.iter().map(move |&o| read_u16(lfn, o)))
+                    .take_while(|&unit| unit != 0)
+                    .collect();
+                core::char::decode_utf16(units.iter().cloned())
+                    .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+                    .collect()
+            } else {
+                long.clear();
+                short_name_string(&short_name, slot[12])
+            };
+
+            let mut slots: Vec<u64> = long.iter().map(|&(pos, _)| pos).collect();
+            slots.push(pos);
+            long.clear();
+
+            entries.push(Entry {
+                name,
+                short_name,
+                attr: slot[11],
+                cluster: (read_u16(&slot, 20) as u32) << 16 | read_u16(&slot, 26) as u32,
+                size: read_u32(&slot, 28),
+                pos,
+                slots,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Read the entries of a directory, without `.` and `..`
+    pub(super) fn read_dir(&self, dir: DirLocation) -> Result<Vec<Entry>, FsError> {
+        let mut entries = self.read_all_entries(dir)?;
+        entries.retain(|entry| !entry.is_dot());
+        Ok(entries)
+    }
+
+    /// Find an entry by name, ignoring case like other systems do
+    pub(super) fn find_entry(&self, dir: DirLocation, name: &str) -> Result<Entry, FsError> {
+        let upper = name.to_uppercase();
+        self.read_dir(dir)?
+            .into_iter()
+            .find(|entry| {
+                entry.name.to_uppercase() == upper
+                    || short_name_string(&entry.short_name, 0) == upper
+            })
+            .ok_or(FsError::NotFound)
+    }
+
+    /// Add an entry to a directory, growing it if needed
+    pub(super) fn add_entry(
+        &mut self,
+        dir: DirLocation,
+        name: &str,
+        attr: u8,
+        cluster: u32,
+        size: u32,
+    ) -> Result<Entry, FsError> {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        if units.len() > LFN_MAX_LENGTH
+            || name.trim_end_matches(|c| c == ' ' || c == '.').is_empty()
+        {
+            return Err(FsError::InvalidPath);
+        }
+
+        // Names that fit into 8.3 need no long name entries
+        let (short_name, long_count) = match exact_short_name(name) {
+            Some(short) => (short, 0),
+            None => {
+                let existing = self.read_all_entries(dir)?;
+                let short = (1..1_000_000)
+                    .map(|number| numbered_short_name(name, number))
+                    .find(|short| existing.iter().all(|entry| entry.short_name != *short))
+                    .ok_or(FsError::NoSpace)?;
+                (short, (units.len() + LFN_CHARS - 1) / LFN_CHARS)
+            }
+        };
+
+        let slots = self.find_free_slots(dir, long_count + 1)?;
+
+        // The long name entries come first, starting with the end of the name
+        let sum = checksum(&short_name);
+        for (i, &pos) in slots[..long_count].iter().enumerate() {
+            let sequence = long_count - i;
+            let mut slot = [0u8; ENTRY_SIZE];
+            slot[0] = sequence as u8 | if i == 0 { LFN_LAST } else { 0 };
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = sum;
+            for (j, &offset) in LFN_OFFSETS.iter().enumerate() {
+                let index = (sequence - 1) * LFN_CHARS + j;
+                // The name ends with a zero, the rest is padding
+                let unit = if index < units.len() {
+                    units[index]
+                } else if index == units.len() {
+                    0
+                } else {
+                    0xFFFF
+                };
+                write_u16(&mut slot, offset, unit);
+            }
+            self.write_bytes(pos, &slot)?;
+        }
+
+        let pos = slots[long_count];
+        let mut slot = [0u8; ENTRY_SIZE];
+        encode_short(&mut slot, &short_name, attr, cluster, size);
+        self.write_bytes(pos, &slot)?;
+
+        Ok(Entry {
+            name: name.to_string(),
+            short_name,
+            attr,
+            cluster,
+            size,
+            pos,
+            slots,
+        })
+    }
+
+    /// Find consecutive free slots in a directory, growing it if needed
+    fn find_free_slots(&mut self, dir: DirLocation, count: usize) -> Result<Vec<u64>, FsError> {
+        loop {
+            let slots = self.read_slots(dir)?;
+            let mut run: Vec<u64> = Vec::new();
+            let mut ended = false;
+            for (pos, slot) in slots {
+                // Everything after the end marker is free as well
+                ended = ended || slot[0] == ENTRY_END;
+                if ended || slot[0] == ENTRY_FREE {
+                    run.push(pos);
+                    if run.len() == count {
+                        return Ok(run);
+                    }
+                } else {
+                    run.clear();
+                }
+            }
+
+            let chain = match dir {
+                DirLocation::FixedRoot => return Err(FsError::NoSpace),
+                DirLocation::Chain(first) => self.chain(first)?,
+            };
+            // A directory holds at most 65536 entries
+            if (chain.len() + 1) * self.cluster_size > 65536 * ENTRY_SIZE {
+                return Err(FsError::NoSpace);
+            }
+            let last = *chain.last().ok_or(FsError::Corrupted)?;
+            self.allocate(Some(last))?;
+        }
+    }
+
+    /// Mark all slots of an entry as deleted
+    pub(super) fn remove_entry(&self, entry: &Entry) -> Result<(), FsError> {
+        for &pos in &entry.slots {
+            self.write_bytes(pos, &[ENTRY_FREE])?;
+        }
+        Ok(())
+    }
+
+    /// Update the first cluster, size and write time of an entry
+    pub(super) fn update_entry(&self, pos: u64, cluster: u32, size: u32) -> Result<(), FsError> {
+        let mut slot = [0u8; ENTRY_SIZE];
+        self.read_bytes(pos, &mut slot)?;
+        let (time, date) = timestamp();
+        write_u16(&mut slot, 18, date);
+        write_u16(&mut slot, 20, (cluster >> 16) as u16);
+        write_u16(&mut slot, 22, time);
+        write_u16(&mut slot, 24, date);
+        write_u16(&mut slot, 26, cluster as u16);
+        write_u32(&mut slot, 28, size);
+        self.write_bytes(pos, &slot)
+    }
+
+    /// Change the attributes of an entry
+    pub(super) fn set_attr(&self, pos: u64, attr: u8) -> Result<(), FsError> {
+        self.write_bytes(pos + 11, &[attr])
+    }
+
+    /// Write the `.` and `..` entries of a new directory
+    pub(super) fn init_dir(&self, cluster: u32, parent: u32) -> Result<(), FsError> {
+        let mut slots = [0u8; 2 * ENTRY_SIZE];
+        encode_short(
+            &mut slots[..ENTRY_SIZE],
+            b".          ",
+            ATTR_DIRECTORY,
+            cluster,
+            0,
+        );
+        encode_short(
+            &mut slots[ENTRY_SIZE..],
+            b"..         ",
+            ATTR_DIRECTORY,
+            parent,
+            0,
+        );
+        self.write_bytes(self.cluster_pos(cluster), &slots)
+    }
+
+    /// Point the `..` entry of a directory at a new parent
+    pub(super) fn set_dir_parent(&self, cluster: u32, parent: u32) -> Result<(), FsError> {
+        let pos = self.cluster_pos(cluster) + ENTRY_SIZE as u64;
+        let mut slot = [0u8; ENTRY_SIZE];
+        self.read_bytes(pos, &mut slot)?;
+        if slot[0..11] != *b"..         " {
+            return Err(FsError::Corrupted);
+        }
+        write_u16(&mut slot, 20, (parent >> 16) as u16);
+        write_u16(&mut slot, 26, parent as u16);
+        self.write_bytes(pos, &slot)
+    }
+}
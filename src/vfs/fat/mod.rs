@@ -0,0 +1,508 @@
+//
+// FAT Filesystem
+//
+// FAT12, FAT16 and FAT32 volumes on a block device. A file
+// is a chain of clusters, linked through the allocation
+// table, which the volume keeps in several identical
+// copies. Long names are stored as VFAT entries in front
+// of the short 8.3 entry of a file.
+//
+
+mod dir;
+mod node;
+
+use alloc::{
+    collections::BTreeMap,
+    prelude::*,
+    sync::{Arc, Weak},
+};
+use spin::Mutex;
+
+use self::node::FatNode;
+use super::{FileSystem, FsError, INode};
+use crate::block::BlockDeviceHandle;
+
+const BOOT_SIGNATURE_OFFSET: usize = 510;
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_NEXT_FREE: usize = 492;
+/// Marks a field of the FSInfo sector as unknown
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+/// The first cluster of the data region
+const FIRST_CLUSTER: u32 = 2;
+
+/// The size of a directory entry
+const ENTRY_SIZE: usize = 32;
+
+/// The variant of a FAT volume
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    FAT12,
+    FAT16,
+    FAT32,
+}
+
+impl FatType {
+    /// Get the type for a number of clusters, as the specification does
+    fn for_cluster_count(count: u32) -> Self {
+        if count < 4085 {
+            FatType::FAT12
+        } else if count < 65525 {
+            FatType::FAT16
+        } else {
+            FatType::FAT32
+        }
+    }
+
+    /// Get the smallest value marking the end of a chain
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::FAT12 => 0xFF8,
+            FatType::FAT16 => 0xFFF8,
+            FatType::FAT32 => 0x0FFF_FFF8,
+        }
+    }
+
+    /// Get the value written to end a chain
+    fn end_marker(self) -> u32 {
+        match self {
+            FatType::FAT12 => 0xFFF,
+            FatType::FAT16 => 0xFFFF,
+            FatType::FAT32 => 0x0FFF_FFFF,
+        }
+    }
+
+    /// Get the number of bytes a table with `entries` entries takes
+    fn table_size(self, entries: u64) -> u64 {
+        match self {
+            FatType::FAT12 => (entries * 3 + 1) / 2,
+            FatType::FAT16 => entries * 2,
+            FatType::FAT32 => entries * 4,
+        }
+    }
+}
+
+/// Where the entries of a directory are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DirLocation {
+    /// The fixed-size root directory of FAT12 and FAT16
+    FixedRoot,
+    /// A cluster chain
+    Chain(u32),
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    buf[offset] as u16 | (buf[offset + 1] as u16) << 8
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    (0..4).fold(0, |acc, i| acc | (buf[offset + i] as u32) << (8 * i))
+}
+
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset] = value as u8;
+    buf[offset + 1] = (value >> 8) as u8;
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    for i in 0..4 {
+        buf[offset + i] = (value >> (8 * i)) as u8;
+    }
+}
+
+/// The layout and allocation state of a volume
+struct Volume {
+    dev: BlockDeviceHandle,
+    fat_type: FatType,
+    sector_size: usize,
+    cluster_size: usize,
+    /// The first sector of the first FAT
+    fat_start: u64,
+    /// The size of one FAT in sectors
+    fat_sectors: u64,
+    fat_count: u64,
+    /// The first sector of the fixed root directory
+    root_start: u64,
+    root_entries: usize,
+    /// The first cluster of the root directory on FAT32
+    root_cluster: u32,
+    /// The first sector of cluster 2
+    data_start: u64,
+    cluster_count: u32,
+    fsinfo_sector: Option<u64>,
+    /// Where to start looking for a free cluster
+    next_free: u32,
+    free_count: Option<u32>,
+    /// The nodes handed out, by the position of their entry.
+    ///
+    /// This keeps one node per file, so open files see
+    /// changes made through other paths.
+    nodes: BTreeMap<u64, Weak<FatNode>>,
+    next_inode: u64,
+}
+
+impl Volume {
+    /// Read the boot sector and check that it describes a FAT volume
+    fn open(dev: BlockDeviceHandle) -> Result<Self, FsError> {
+        let mut boot = vec![0u8; dev.sector_size()];
+        dev.read(0, &mut boot)?;
+        if boot.len() < 512
+            || boot[BOOT_SIGNATURE_OFFSET..BOOT_SIGNATURE_OFFSET + 2] != BOOT_SIGNATURE
+        {
+            return Err(FsError::Unsupported);
+        }
+        // The boot code starts with a jump
+        if boot[0] != 0xEB && boot[0] != 0xE9 {
+            return Err(FsError::Unsupported);
+        }
+
+        let sector_size = read_u16(&boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as usize;
+        let reserved = read_u16(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entries = read_u16(&boot, 17) as usize;
+        let total = match read_u16(&boot, 19) {
+            0 => read_u32(&boot, 32) as u64,
+            total => total as u64,
+        };
+        let fat_sectors = match read_u16(&boot, 22) {
+            0 => read_u32(&boot, 36) as u64,
+            size => size as u64,
+        };
+
+        if sector_size != dev.sector_size()
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fat_count == 0
+            || fat_sectors == 0
+            || total > dev.sector_count()
+        {
+            return Err(FsError::Unsupported);
+        }
+
+        let root_sectors = ((root_entries * ENTRY_SIZE + sector_size - 1) / sector_size) as u64;
+        let root_start = reserved + fat_count * fat_sectors;
+        let data_start = root_start + root_sectors;
+        if data_start >= total {
+            return Err(FsError::Corrupted);
+        }
+        let cluster_count = ((total - data_start) / sectors_per_cluster as u64) as u32;
+        let fat_type = FatType::for_cluster_count(cluster_count);
+
+        // Every cluster needs an entry, after the two reserved ones
+        if fat_type.table_size(cluster_count as u64 + 2) > fat_sectors * sector_size as u64 {
+            return Err(FsError::Corrupted);
+        }
+
+        let mut volume = Volume {
+            dev,
+            fat_type,
+            sector_size,
+            cluster_size: sector_size * sectors_per_cluster,
+            fat_start: reserved,
+            fat_sectors,
+            fat_count,
+            root_start,
+            root_entries,
+            root_cluster: 0,
+            data_start,
+            cluster_count,
+            fsinfo_sector: None,
+            next_free: FIRST_CLUSTER,
+            free_count: None,
+            nodes: BTreeMap::new(),
+            next_inode: 2,
+        };
+
+        if fat_type == FatType::FAT32 {
+            if root_entries != 0 {
+                return Err(FsError::Corrupted);
+            }
+            volume.root_cluster = read_u32(&boot, 44);
+            if !volume.is_data_cluster(volume.root_cluster) {
+                return Err(FsError::Corrupted);
+            }
+            volume.read_fsinfo(read_u16(&boot, 48) as u64)?;
+        } else if root_entries == 0 {
+            return Err(FsError::Corrupted);
+        }
+
+        Ok(volume)
+    }
+
+    /// Pick up the allocation hints of FAT32
+    fn read_fsinfo(&mut self, sector: u64) -> Result<(), FsError> {
+        if sector == 0 || sector >= self.fat_start {
+            return Ok(());
+        }
+        let mut info = vec![0u8; self.sector_size];
+        self.dev.read(sector, &mut info)?;
+        if read_u32(&info, 0) != FSINFO_LEAD_SIGNATURE
+            || read_u32(&info, 484) != FSINFO_STRUCT_SIGNATURE
+        {
+            return Ok(());
+        }
+
+        self.fsinfo_sector = Some(sector);
+        let free_count = read_u32(&info, FSINFO_FREE_COUNT);
+        if free_count <= self.cluster_count {
+            self.free_count = Some(free_count);
+        }
+        let next_free = read_u32(&info, FSINFO_NEXT_FREE);
+        if self.is_data_cluster(next_free) {
+            self.next_free = next_free;
+        }
+        Ok(())
+    }
+
+    /// Write the allocation hints back to the FSInfo sector
+    fn write_fsinfo(&self) -> Result<(), FsError> {
+        let sector = match self.fsinfo_sector {
+            Some(sector) => sector,
+            None => return Ok(()),
+        };
+        let mut info = vec![0u8; self.sector_size];
+        self.dev.read(sector, &mut info)?;
+        write_u32(
+            &mut info,
+            FSINFO_FREE_COUNT,
+            self.free_count.unwrap_or(FSINFO_UNKNOWN),
+        );
+        write_u32(&mut info, FSINFO_NEXT_FREE, self.next_free);
+        self.dev.write(sector, &info)?;
+        Ok(())
+    }
+
+    /// Get the location of the root directory
+    fn root_location(&self) -> DirLocation {
+        match self.fat_type {
+            FatType::FAT32 => DirLocation::Chain(self.root_cluster),
+            _ => DirLocation::FixedRoot,
+        }
+    }
+
+    //
+    // Byte access
+    //
+
+    /// Read bytes at a position on the volume
+    fn read_bytes(&self, pos: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        self.dev.read_bytes(pos, buf).map_err(FsError::from)
+    }
+
+    /// Write bytes at a position on the volume
+    fn write_bytes(&self, pos: u64, buf: &[u8]) -> Result<(), FsError> {
+        self.dev.write_bytes(pos, buf).map_err(FsError::from)
+    }
+
+    //
+    // Allocation table
+    //
+
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        cluster >= FIRST_CLUSTER && cluster < self.cluster_count + FIRST_CLUSTER
+    }
+
+    /// Get the position of a cluster's data
+    fn cluster_pos(&self, cluster: u32) -> u64 {
+        let sectors_per_cluster = (self.cluster_size / self.sector_size) as u64;
+        let sector = self.data_start + (cluster - FIRST_CLUSTER) as u64 * sectors_per_cluster;
+        sector * self.sector_size as u64
+    }
+
+    /// Get the position of a cluster's entry in one copy of the table
+    fn fat_entry_pos(&self, copy: u64, cluster: u32) -> u64 {
+        let start = (self.fat_start + copy * self.fat_sectors) * self.sector_size as u64;
+        let offset = match self.fat_type {
+            FatType::FAT12 => cluster as u64 + cluster as u64 / 2,
+            FatType::FAT16 => cluster as u64 * 2,
+            FatType::FAT32 => cluster as u64 * 4,
+        };
+        start + offset
+    }
+
+    /// Get the table entry of a cluster
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let pos = self.fat_entry_pos(0, cluster);
+        match self.fat_type {
+            FatType::FAT12 => {
+                // Entries are 12 bits, two of them share three bytes
+                let mut buf = [0u8; 2];
+                self.read_bytes(pos, &mut buf)?;
+                let value = read_u16(&buf, 0) as u32;
+                if cluster & 1 == 0 {
+                    Ok(value & 0xFFF)
+                } else {
+                    Ok(value >> 4)
+                }
+            }
+            FatType::FAT16 => {
+                let mut buf = [0u8; 2];
+                self.read_bytes(pos, &mut buf)?;
+                Ok(read_u16(&buf, 0) as u32)
+            }
+            FatType::FAT32 => {
+                let mut buf = [0u8; 4];
+                self.read_bytes(pos, &mut buf)?;
+                Ok(read_u32(&buf, 0) & 0x0FFF_FFFF)
+            }
+        }
+    }
+
+    /// Set the table entry of a cluster in every copy of the table
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        for copy in 0..self.fat_count {
+            let pos = self.fat_entry_pos(copy, cluster);
+            match self.fat_type {
+                FatType::FAT12 => {
+                    let mut buf = [0u8; 2];
+                    self.read_bytes(pos, &mut buf)?;
+                    let old = read_u16(&buf, 0);
+                    let value = value as u16 & 0xFFF;
+                    let new = if cluster & 1 == 0 {
+                        (old & 0xF000) | value
+                    } else {
+                        (old & 0x000F) | value << 4
+                    };
+                    write_u16(&mut buf, 0, new);
+                    self.write_bytes(pos, &buf)?;
+                }
+                FatType::FAT16 => {
+                    let mut buf = [0u8; 2];
+                    write_u16(&mut buf, 0, value as u16);
+                    self.write_bytes(pos, &buf)?;
+                }
+                FatType::FAT32 => {
+                    // The top four bits are reserved and kept
+                    let mut buf = [0u8; 4];
+                    self.read_bytes(pos, &mut buf)?;
+                    let old = read_u32(&buf, 0);
+                    write_u32(&mut buf, 0, (old & 0xF000_0000) | (value & 0x0FFF_FFFF));
+                    self.write_bytes(pos, &buf)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the clusters of a chain, in order
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        if cluster == 0 {
+            return Ok(clusters);
+        }
+
+        loop {
+            // A chain longer than the volume must contain a loop
+            if !self.is_data_cluster(cluster) || clusters.len() >= self.cluster_count as usize {
+                return Err(FsError::Corrupted);
+            }
+            clusters.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+            if cluster >= self.fat_type.end_of_chain() {
+                return Ok(clusters);
+            }
+        }
+    }
+
+    /// Allocate a zeroed cluster and append it to `prev`
+    fn allocate(&mut self, prev: Option<u32>) -> Result<u32, FsError> {
+        let count = self.cluster_count;
+        let start = if self.is_data_cluster(self.next_free) {
+            self.next_free - FIRST_CLUSTER
+        } else {
+            0
+        };
+
+        let mut found = None;
+        for i in 0..count {
+            let cluster = (start + i) % count + FIRST_CLUSTER;
+            if self.fat_entry(cluster)? == 0 {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FsError::NoSpace)?;
+
+        self.set_fat_entry(cluster, self.fat_type.end_marker())?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, cluster)?;
+        }
+        self.write_bytes(self.cluster_pos(cluster), &vec![0u8; self.cluster_size])?;
+
+        self.next_free = if cluster + 1 < count + FIRST_CLUSTER {
+            cluster + 1
+        } else {
+            FIRST_CLUSTER
+        };
+        self.free_count = self.free_count.map(|free| free.saturating_sub(1));
+        Ok(cluster)
+    }
+
+    /// Cut a chain after `keep` clusters, freeing the rest.
+    ///
+    /// Returns the first cluster, which is 0 if nothing is kept.
+    fn truncate_chain(&mut self, first: u32, keep: usize) -> Result<u32, FsError> {
+        let clusters = self.chain(first)?;
+        if clusters.len() <= keep {
+            return Ok(first);
+        }
+
+        if keep > 0 {
+            self.set_fat_entry(clusters[keep - 1], self.fat_type.end_marker())?;
+        }
+        for &cluster in &clusters[keep..] {
+            self.set_fat_entry(cluster, 0)?;
+        }
+        self.free_count = self
+            .free_count
+            .map(|free| free + (clusters.len() - keep) as u32);
+        if keep > 0 {
+            Ok(first)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.write_fsinfo()?;
+        self.dev.sync()?;
+        Ok(())
+    }
+}
+
+/// A FAT12, FAT16 or FAT32 filesystem
+pub struct FatFS {
+    volume: Arc<Mutex<Volume>>,
+    root: Arc<FatNode>,
+}
+
+impl FatFS {
+    /// Open the FAT volume on a block device
+    pub fn new(dev: BlockDeviceHandle) -> Result<Self, FsError> {
+        let volume = Volume::open(dev)?;
+        let root_location = volume.root_location();
+        let volume = Arc::new(Mutex::new(volume));
+        let root = FatNode::new_root(&volume, root_location);
+        Ok(FatFS { volume, root })
+    }
+}
+
+impl FileSystem for FatFS {
+    fn name(&self) -> &'static str {
+        "fat"
+    }
+
+    fn root(&self) -> Arc<dyn INode> {
+        self.root.clone()
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.volume.lock().sync()
+    }
+}
@@ -0,0 +1,480 @@
+use alloc::{prelude::*, sync::Arc};
+use core::any::Any;
+use spin::Mutex;
+
+use super::dir::{Entry, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_READ_ONLY};
+use super::{DirLocation, Volume};
+use crate::vfs::{DirEntry, FileType, FsError, INode, Metadata};
+
+/// A file or directory of a FAT volume
+pub struct FatNode {
+    volume: Arc<Mutex<Volume>>,
+    inode: u64,
+    file_type: FileType,
+    is_root: bool,
+    state: Mutex<NodeState>,
+}
+
+struct NodeState {
+    cluster: u32,
+    size: u32,
+    attr: u8,
+    /// The position of the short entry, `None` for the root and removed files
+    entry: Option<u64>,
+}
+
+impl FatNode {
+    pub(super) fn new_root(volume: &Arc<Mutex<Volume>>, location: DirLocation) -> Arc<Self> {
+        let cluster = match location {
+            DirLocation::FixedRoot => 0,
+            DirLocation::Chain(cluster) => cluster,
+        };
+        Arc::new(FatNode {
+            volume: volume.clone(),
+            inode: 1,
+            file_type: FileType::Directory,
+            is_root: true,
+            state: Mutex::new(NodeState {
+                cluster,
+                size: 0,
+                attr: ATTR_DIRECTORY,
+                entry: None,
+            }),
+        })
+    }
+
+    /// Get the node of an entry, reusing a node that is still alive
+    fn get(&self, volume: &mut Volume, entry: &Entry) -> Arc<FatNode> {
+        if let Some(node) = volume.nodes.get(&entry.pos).and_then(|node| node.upgrade()) {
+            return node;
+        }
+
+        let node = Arc::new(FatNode {
+            volume: self.volume.clone(),
+            inode: volume.next_inode,
+            file_type: if entry.is_directory() {
+                FileType::Directory
+            } else {
+                FileType::File
+            },
+            is_root: false,
+            state: Mutex::new(NodeState {
+                cluster: entry.cluster,
+                size: entry.size,
+                attr: entry.attr,
+                entry: Some(entry.pos),
+            }),
+        });
+        volume.next_inode += 1;
+        volume.nodes.insert(entry.pos, Arc::downgrade(&node));
+        node
+    }
+
+    /// Get where the entries of this directory are stored
+    fn location(&self) -> Result<DirLocation, FsError> {
+        let state = self.state.lock();
+        match (self.is_root, state.cluster) {
+            (true, 0) => Ok(DirLocation::FixedRoot),
+            // A removed directory has no clusters left
+            (false, 0) => Err(FsError::NotFound),
+            (_, cluster) => Ok(DirLocation::Chain(cluster)),
+        }
+    }
+
+    /// Get the cluster `..` entries refer to for this directory
+    fn parent_cluster(&self) -> u32 {
+        if self.is_root {
+            0
+        } else {
+            self.state.lock().cluster
+        }
+    }
+
+    /// Detach a node from a removed entry
+    fn forget(volume: &mut Volume, entry: &Entry) {
+        if let Some(node) = volume
+            .nodes
+            .remove(&entry.pos)
+            .and_then(|node| node.upgrade())
+        {
+            let mut state = node.state.lock();
+            state.cluster = 0;
+            state.size = 0;
+            state.entry = None;
+        }
+    }
+
+    /// Check that a directory holds nothing but `.` and `..`
+    fn check_empty(volume: &Volume, entry: &Entry) -> Result<(), FsError> {
+        if entry.is_directory()
+            && !volume
+                .read_dir(DirLocation::Chain(entry.cluster))?
+                .is_empty()
+        {
+            return Err(FsError::NotEmpty);
+        }
+        Ok(())
+    }
+
+    /// Write the size and first cluster back to the entry
+    fn write_back(volume: &Volume, state: &NodeState) -> Result<(), FsError> {
+        match state.entry {
+            Some(pos) => volume.update_entry(pos, state.cluster, state.size),
+            None => Ok(()),
+        }
+    }
+
+    /// Get the cluster chain of a file, growing it to cover `end` bytes
+    fn extend_chain(
+        volume: &mut Volume,
+        state: &mut NodeState,
+        end: u64,
+    ) -> Result<Vec<u32>, FsError> {
+        let cluster_size = volume.cluster_size as u64;
+        let needed = ((end + cluster_size - 1) / cluster_size) as usize;
+
+        let mut clusters = volume.chain(state.cluster)?;
+        while clusters.len() < needed {
+            let cluster = volume.allocate(clusters.last().cloned())?;
+            if state.cluster == 0 {
+                state.cluster = cluster;
+            }
+            clusters.push(cluster);
+        }
+        Ok(clusters)
+    }
+
+    /// Write data into a file, allocating clusters as needed
+    fn write_data(
+        volume: &mut Volume,
+        state: &mut NodeState,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<(), FsError> {
+        let cluster_size = volume.cluster_size as u64;
+        let clusters = FatNode::extend_chain(volume, state, offset + buf.len() as u64)?;
+
+        let mut done = 0;
+        while done < buf.len() {
+            let at = offset + done as u64;
+            let index = (at / cluster_size) as usize;
+            let within = at % cluster_size;
+            let count = ((cluster_size - within) as usize).min(buf.len() - done);
+            let pos = volume.cluster_pos(clusters[index]) + within;
+            volume.write_bytes(pos, &buf[done..done + count])?;
+            done += count;
+        }
+        Ok(())
+    }
+
+    /// Fill the bytes from `start` to `end` with zeros.
+    ///
+    /// Files can grow by up to 4 GiB at once, so the zeros
+    /// are written from a single cluster-sized buffer.
+    fn zero_fill(
+        volume: &mut Volume,
+        state: &mut NodeState,
+        start: u64,
+        end: u64,
+    ) -> Result<(), FsError> {
+        let cluster_size = volume.cluster_size as u64;
+        let clusters = FatNode::extend_chain(volume, state, end)?;
+        let zeros = vec![0u8; cluster_size as usize];
+
+        let mut at = start;
+        while at < end {
+            let index = (at / cluster_size) as usize;
+            let within = at % cluster_size;
+            let count = (cluster_size - within).min(end - at);
+            let pos = volume.cluster_pos(clusters[index]) + within;
+            volume.write_bytes(pos, &zeros[..count as usize])?;
+            at += count;
+        }
+        Ok(())
+    }
+}
+
+impl INode for FatNode {
+    fn metadata(&self) -> Metadata {
+        let state = self.state.lock();
+        let writable = if state.attr & ATTR_READ_ONLY != 0 {
+            0
+        } else {
+            0o200
+        };
+        let (mode, size) = match self.file_type {
+            FileType::Directory => (0o555 | writable, 0),
+            _ => (0o444 | writable, state.size as u64),
+        };
+
+        Metadata {
+            inode: self.inode,
+            file_type: self.file_type,
+            size,
+            mode,
+            links: 1,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if self.file_type == FileType::Directory {
+            return Err(FsError::IsDirectory);
+        }
+        let volume = self.volume.lock();
+        let (first, size) = {
+            let state = self.state.lock();
+            (state.cluster, state.size as u64)
+        };
+        if offset >= size {
+            return Ok(0);
+        }
+
+        let count = (buf.len() as u64).min(size - offset) as usize;
+        let cluster_size = volume.cluster_size as u64;
+        let clusters = volume.chain(first)?;
+        let mut done = 0;
+        while done < count {
+            let at = offset + done as u64;
+            let index = (at / cluster_size) as usize;
+            let within = at % cluster_size;
+            let cluster = *clusters.get(index).ok_or(FsError::Corrupted)?;
+            let chunk = ((cluster_size - within) as usize).min(count - done);
+            volume.read_bytes(
+                volume.cluster_pos(cluster) + within,
+                &mut buf[done..done + chunk],
+            )?;
+            done += chunk;
+        }
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        if self.file_type == FileType::Directory {
+            return Err(FsError::IsDirectory);
+        }
+        let mut volume = self.volume.lock();
+        let mut state = self.state.lock();
+        if state.entry.is_none() {
+            return Err(FsError::NotFound);
+        }
+        if state.attr & ATTR_READ_ONLY != 0 {
+            return Err(FsError::ReadOnly);
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // Sizes are stored in 32 bits
+        let end = offset + buf.len() as u64;
+        if end > u32::max_value() as u64 {
+            return Err(FsError::NoSpace);
+        }
+
+        // The old end of the last cluster may hold stale data
+        let size = state.size as u64;
+        if offset > size {
+            FatNode::zero_fill(&mut volume, &mut state, size, offset)?;
+        }
+        FatNode::write_data(&mut volume, &mut state, offset, buf)?;
+
+        state.size = state.size.max(end as u32);
+        FatNode::write_back(&volume, &state)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        if self.file_type == FileType::Directory {
+            return Err(FsError::IsDirectory);
+        }
+        if size > u32::max_value() as u64 {
+            return Err(FsError::NoSpace);
+        }
+        let mut volume = self.volume.lock();
+        let mut state = self.state.lock();
+        if state.entry.is_none() {
+            return Err(FsError::NotFound);
+        }
+        if state.attr & ATTR_READ_ONLY != 0 {
+            return Err(FsError::ReadOnly);
+        }
+
+        let old = state.size as u64;
+        if size > old {
+            FatNode::zero_fill(&mut volume, &mut state, old, size)?;
+        } else {
+            let cluster_size = volume.cluster_size as u64;
+            let keep = ((size + cluster_size - 1) / cluster_size) as usize;
+            state.cluster = volume.truncate_chain(state.cluster, keep)?;
+        }
+
+        state.size = size as u32;
+        FatNode::write_back(&volume, &state)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn INode>, FsError> {
+        if self.file_type != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        let mut volume = self.volume.lock();
+        let entry = volume.find_entry(self.location()?, name)?;
+        Ok(self.get(&mut volume, &entry))
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn INode>, FsError> {
+        if self.file_type != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        let mut volume = self.volume.lock();
+        let location = self.location()?;
+        match volume.find_entry(location, name) {
+            Ok(_) => return Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => (),
+            Err(err) => return Err(err),
+        }
+
+        let entry = match file_type {
+            FileType::File => volume.add_entry(location, name, ATTR_ARCHIVE, 0, 0)?,
+            FileType::Directory => {
+                let cluster = volume.allocate(None)?;
+                let entry = volume
+                    .init_dir(cluster, self.parent_cluster())
+                    .and_then(|_| volume.add_entry(location, name, ATTR_DIRECTORY, cluster, 0));
+                match entry {
+                    Ok(entry) => entry,
+                    Err(err) => {
+                        volume.truncate_chain(cluster, 0)?;
+                        return Err(err);
+                    }
+                }
+            }
+            _ => return Err(FsError::Unsupported),
+        };
+        Ok(self.get(&mut volume, &entry))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        if self.file_type != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        let mut volume = self.volume.lock();
+        let entry = volume.find_entry(self.location()?, name)?;
+        FatNode::check_empty(&volume, &entry)?;
+
+        volume.remove_entry(&entry)?;
+        volume.truncate_chain(entry.cluster, 0)?;
+        FatNode::forget(&mut volume, &entry);
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        target: &Arc<dyn INode>,
+        new_name: &str,
+    ) -> Result<(), FsError> {
+        let target = target
+            .as_any()
+            .downcast_ref::<FatNode>()
+            .ok_or(FsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.volume, &target.volume) {
+            return Err(FsError::CrossDevice);
+        }
+        if target.file_type != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+
+        let mut volume = self.volume.lock();
+        let source = self.location()?;
+        let dest = target.location()?;
+        let entry = volume.find_entry(source, old_name)?;
+
+        let replaced = match volume.find_entry(dest, new_name) {
+            // Renaming to a name differing only in case finds the entry itself
+            Ok(ref existing) if existing.pos == entry.pos => None,
+            Ok(existing) => {
+                match (entry.is_directory(), existing.is_directory()) {
+                    (true, false) => return Err(FsError::NotDirectory),
+                    (false, true) => return Err(FsError::IsDirectory),
+                    _ => (),
+                }
+                FatNode::check_empty(&volume, &existing)?;
+                Some(existing)
+            }
+            Err(FsError::NotFound) => None,
+            Err(err) => return Err(err),
+        };
+
+        // Write the new entry first, so a failure keeps the old one.
+        // A replaced target's entry is taken over in place.
+        let new_pos = match replaced {
+            Some(ref existing) => {
+                volume.update_entry(existing.pos, entry.cluster, entry.size)?;
+                volume.set_attr(existing.pos, entry.attr)?;
+                FatNode::forget(&mut volume, existing);
+                existing.pos
+            }
+            None => {
+                volume
+                    .add_entry(dest, new_name, entry.attr, entry.cluster, entry.size)?
+                    .pos
+            }
+        };
+        volume.remove_entry(&entry)?;
+        if let Some(existing) = replaced {
+            volume.truncate_chain(existing.cluster, 0)?;
+        }
+        if entry.is_directory() && source != dest {
+            volume.set_dir_parent(entry.cluster, target.parent_cluster())?;
+        }
+
+        // Open files follow the entry
+        if let Some(node) = volume
+            .nodes
+            .remove(&entry.pos)
+            .and_then(|node| node.upgrade())
+        {
+            node.state.lock().entry = Some(new_pos);
+            volume.nodes.insert(new_pos, Arc::downgrade(&node));
+        }
+        Ok(())
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        if self.file_type != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        let mut volume = self.volume.lock();
+        let entries = volume.read_dir(self.location()?)?;
+        Ok(entries
+            .iter()
+            .map(|entry| {
+                let node = self.get(&mut volume, entry);
+                DirEntry {
+                    name: entry.name.clone(),
+                    inode: node.inode,
+                    file_type: node.file_type,
+                }
+            })
+            .collect())
+    }
+
+    fn set_mode(&self, mode: u16) -> Result<(), FsError> {
+        // FAT only knows whether a file is read-only
+        let volume = self.volume.lock();
+        let mut state = self.state.lock();
+        let pos = state.entry.ok_or(FsError::Unsupported)?;
+        if mode & 0o222 == 0 {
+            state.attr |= ATTR_READ_ONLY;
+        } else {
+            state.attr &= !ATTR_READ_ONLY;
+        }
+        volume.set_attr(pos, state.attr)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        self.volume.lock().sync()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
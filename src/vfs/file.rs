@@ -0,0 +1,153 @@
+use alloc::{prelude::*, sync::Arc};
+use bitflags::bitflags;
+use spin::Mutex;
+
+use super::{Dentry, DirEntry, FileType, FsError, Metadata};
+use crate::hal::PollFlags;
+
+bitflags! {
+
+    /// How a file is opened
+    pub struct OpenFlags: u32 {
+        const READ      = 0b_0000_0001;
+        const WRITE     = 0b_0000_0010;
+        const CREATE    = 0b_0000_0100;
+        /// With `CREATE`, fail if the file exists
+        const EXCLUSIVE = 0b_0000_1000;
+        const TRUNCATE  = 0b_0001_0000;
+        /// Every write goes to the end of the file
+        const APPEND    = 0b_0010_0000;
+        /// Fail unless the file is a directory
+        const DIRECTORY = 0b_0100_0000;
+        /// Do not follow a symbolic link in the last component
+        const NOFOLLOW  = 0b_1000_0000;
+    }
+}
+
+/// A position to seek to
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// An open file description.
+///
+/// Descriptions are shared by everyone holding the file
+/// open through them, including the file offset.
+pub struct OpenFile {
+    dentry: Arc<Dentry>,
+    flags: OpenFlags,
+    offset: Mutex<u64>,
+}
+
+#[allow(dead_code)]
+impl OpenFile {
+    pub(super) fn new(dentry: Arc<Dentry>, flags: OpenFlags) -> Result<Self, FsError> {
+        let file_type = dentry.metadata().file_type;
+        if flags.contains(OpenFlags::DIRECTORY) && file_type != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+        if file_type == FileType::Directory && flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::IsDirectory);
+        }
+        let truncate = OpenFlags::TRUNCATE | OpenFlags::WRITE;
+        if flags.contains(truncate) && file_type == FileType::File {
+            dentry.inode().truncate(0)?;
+        }
+
+        Ok(OpenFile {
+            dentry,
+            flags,
+            offset: Mutex::new(0),
+        })
+    }
+
+    pub fn dentry(&self) -> &Arc<Dentry> {
+        &self.dentry
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    pub fn metadata(&self) -> Metadata {
+        self.dentry.metadata()
+    }
+
+    /// Read at the file offset and advance it
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::BadAccess);
+        }
+        let mut offset = self.offset.lock();
+        let read = self.dentry.inode().read_at(*offset, buf)?;
+        *offset += read as u64;
+        Ok(read)
+    }
+
+    /// Write at the file offset and advance it
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::BadAccess);
+        }
+        let mut offset = self.offset.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *offset = self.dentry.metadata().size;
+        }
+        let written = self.dentry.inode().write_at(*offset, buf)?;
+        *offset += written as u64;
+        Ok(written)
+    }
+
+    /// Read at a position without moving the file offset
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::READ) {
+            return Err(FsError::BadAccess);
+        }
+        self.dentry.inode().read_at(offset, buf)
+    }
+
+    /// Write at a position without moving the file offset
+    pub fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::BadAccess);
+        }
+        self.dentry.inode().write_at(offset, buf)
+    }
+
+    /// Move the file offset, returning the new offset
+    pub fn seek(&self, pos: SeekFrom) -> Result<u64, FsError> {
+        let mut offset = self.offset.lock();
+        let (base, delta) = match pos {
+            SeekFrom::Start(start) => (start as i64, 0),
+            SeekFrom::Current(delta) => (*offset as i64, delta),
+            SeekFrom::End(delta) => (self.dentry.metadata().size as i64, delta),
+        };
+        let new = base.checked_add(delta).ok_or(FsError::InvalidArgument)?;
+        if new < 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        *offset = new as u64;
+        Ok(*offset)
+    }
+
+    /// List the directory
+    pub fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        self.dentry.inode().readdir()
+    }
+
+    pub fn ioctl(&self, request: u32, arg: usize) -> Result<usize, FsError> {
+        self.dentry.inode().ioctl(request, arg)
+    }
+
+    pub fn poll(&self) -> PollFlags {
+        self.dentry.inode().poll()
+    }
+
+    pub fn sync(&self) -> Result<(), FsError> {
+        self.dentry.inode().sync()
+    }
+}
@@ -0,0 +1,275 @@
+use alloc::{prelude::*, sync::Arc};
+use core::str;
+
+use super::{FileSystem, FileType, FsError, INode, TmpFS};
+
+/// The archive built into the kernel.
+///
+/// Rebuild it from the `initramfs` directory with
+/// `scripts/mkinitramfs`.
+static ARCHIVE: &'static [u8] = include_bytes!("../initramfs.tar");
+
+const CPIO_MAGIC: &[u8] = b"070701";
+/// The same format, with checksums we ignore
+const CPIO_MAGIC_CRC: &[u8] = b"070702";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+const TAR_BLOCK_SIZE: usize = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+// File type bits of a mode
+const MODE_TYPE_MASK: u32 = 0o170_000;
+const MODE_DIRECTORY: u32 = 0o040_000;
+const MODE_FILE: u32 = 0o100_000;
+const MODE_SYMLINK: u32 = 0o120_000;
+
+/// A file in an archive
+struct Entry<'a> {
+    path: String,
+    file_type: FileType,
+    mode: u16,
+    /// The contents of a file or the target of a link
+    data: &'a [u8],
+}
+
+/// Unpack the built-in archive into a new tmpfs
+pub fn load() -> Result<TmpFS, FsError> {
+    let fs = TmpFS::new();
+    let count = unpack(ARCHIVE, &fs.root())?;
+    log!(debug: "Unpacked {} initramfs entries.", count);
+    Ok(fs)
+}
+
+/// Unpack a cpio (newc) or ustar archive into a directory.
+///
+/// Returns the number of entries unpacked. Entries of
+/// other types, like device nodes, are skipped.
+pub fn unpack(archive: &[u8], root: &Arc<dyn INode>) -> Result<usize, FsError> {
+    let entries = if is_cpio(archive) {
+        parse_cpio(archive)?
+    } else if archive.len() >= TAR_BLOCK_SIZE && &archive[257..262] == TAR_MAGIC {
+        parse_tar(archive)?
+    } else {
+        return Err(FsError::Corrupted);
+    };
+
+    let mut count = 0;
+    for entry in entries {
+        let path = entry.path.trim_start_matches("./").trim_matches('/');
+        if path.is_empty() || path == "." {
+            continue;
+        }
+        let (dir, name) = match path.rfind('/') {
+            Some(index) => (make_dirs(root, &path[..index])?, &path[index + 1..]),
+            None => (root.clone(), path),
+        };
+        if name == ".." {
+            return Err(FsError::InvalidPath);
+        }
+
+        let node = match entry.file_type {
+            FileType::Directory => match dir.lookup(name) {
+                Ok(node) => node,
+                Err(FsError::NotFound) => dir.create(name, FileType::Directory)?,
+                Err(err) => return Err(err),
+            },
+            FileType::File => {
+                let node = dir.create(name, FileType::File)?;
+                node.write_at(0, entry.data)?;
+                node
+            }
+            FileType::Symlink => {
+                let target = str::from_utf8(entry.data).map_err(|_| FsError::Corrupted)?;
+                dir.symlink(name, target)?
+            }
+            _ => continue,
+        };
+        node.set_mode(entry.mode)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Find a directory below `root`, creating missing ones.
+///
+/// Paths leaving `root` through `..` are rejected.
+fn make_dirs(root: &Arc<dyn INode>, path: &str) -> Result<Arc<dyn INode>, FsError> {
+    let mut dir = root.clone();
+    for name in path
+        .split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+    {
+        if name == ".." {
+            return Err(FsError::InvalidPath);
+        }
+        dir = match dir.lookup(name) {
+            Ok(node) => node,
+            Err(FsError::NotFound) => dir.create(name, FileType::Directory)?,
+            Err(err) => return Err(err),
+        };
+    }
+    Ok(dir)
+}
+
+/// Get the file type of a mode, if we can unpack it
+fn file_type(mode: u32) -> Option<FileType> {
+    match mode & MODE_TYPE_MASK {
+        MODE_DIRECTORY => Some(FileType::Directory),
+        MODE_FILE => Some(FileType::File),
+        MODE_SYMLINK => Some(FileType::Symlink),
+        _ => None,
+    }
+}
+
+fn align(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) / alignment * alignment
+}
+
+//
+// cpio
+//
+// Each entry is a header of ASCII hex numbers, followed by
+// the name and the data, each padded to four bytes.
+//
+
+fn is_cpio(header: &[u8]) -> bool {
+    header.starts_with(CPIO_MAGIC) || header.starts_with(CPIO_MAGIC_CRC)
+}
+
+fn parse_hex(field: &[u8]) -> Result<u32, FsError> {
+    let field = str::from_utf8(field).map_err(|_| FsError::Corrupted)?;
+    u32::from_str_radix(field, 16).map_err(|_| FsError::Corrupted)
+}
+
+fn parse_cpio(archive: &[u8]) -> Result<Vec<Entry>, FsError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let header = archive
+            .get(offset..offset + CPIO_HEADER_SIZE)
+            .ok_or(FsError::Corrupted)?;
+        if !is_cpio(header) {
+            return Err(FsError::Corrupted);
+        }
+        // The fields follow the six byte magic
+        let field = |index: usize| parse_hex(&header[6 + index * 8..14 + index * 8]);
+        let mode = field(1)?;
+        let size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+
+        let name_start = offset + CPIO_HEADER_SIZE;
+        let name = archive
+            .get(name_start..name_start + name_size)
+            .ok_or(FsError::Corrupted)?;
+        // The name size includes the terminating zero
+        let name = name.split(|&b| b == 0).next().unwrap_or(&[]);
+        let name = str::from_utf8(name).map_err(|_| FsError::Corrupted)?;
+
+        let data_start = align(name_start + name_size, 4);
+        let data = archive
+            .get(data_start..data_start + size)
+            .ok_or(FsError::Corrupted)?;
+        offset = align(data_start + size, 4);
+
+        if name == CPIO_TRAILER {
+            break;
+        }
+        if let Some(file_type) = file_type(mode) {
+            entries.push(Entry {
+                path: name.to_string(),
+                file_type,
+                mode: (mode & 0o7777) as u16,
+                data,
+            });
+        }
+    }
+
+    Ok(entries)
+}
+
+//
+// ustar
+//
+// Each entry is a 512 byte header with octal numbers,
+// followed by the data padded to 512 bytes. The archive
+// ends with two zero blocks.
+//
+
+fn parse_octal(field: &[u8]) -> Result<usize, FsError> {
+    field
+        .iter()
+        .skip_while(|&&b| b == b' ')
+        .take_while(|&&b| b != 0 && b != b' ')
+        .try_fold(0usize, |value, &b| match b {
+            b'0'..=b'7' => Ok(value * 8 + (b - b'0') as usize),
+            _ => Err(FsError::Corrupted),
+        })
+}
+
+/// Get a zero-terminated string field
+fn tar_string(field: &[u8]) -> Result<&str, FsError> {
+    let field = field.split(|&b| b == 0).next().unwrap_or(&[]);
+    str::from_utf8(field).map_err(|_| FsError::Corrupted)
+}
+
+fn parse_tar(archive: &[u8]) -> Result<Vec<Entry>, FsError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while let Some(header) = archive.get(offset..offset + TAR_BLOCK_SIZE) {
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if &header[257..262] != TAR_MAGIC {
+            return Err(FsError::Corrupted);
+        }
+
+        // The checksum is taken with its own field set to spaces
+        let checksum = parse_octal(&header[148..156])?;
+        let sum = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| (if i >= 148 && i < 156 { b' ' } else { b }) as usize)
+            .sum::<usize>();
+        if sum != checksum {
+            return Err(FsError::Corrupted);
+        }
+
+        let mode = parse_octal(&header[100..108])? as u32;
+        let size = parse_octal(&header[124..136])?;
+        let data_start = offset + TAR_BLOCK_SIZE;
+        let data = archive
+            .get(data_start..data_start + size)
+            .ok_or(FsError::Corrupted)?;
+        offset = data_start + align(size, TAR_BLOCK_SIZE);
+
+        // Long names are split into a prefix and a name
+        let prefix = tar_string(&header[345..500])?;
+        let name = tar_string(&header[0..100])?;
+        let path = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+
+        let (file_type, data) = match header[156] {
+            b'0' | 0 => (FileType::File, data),
+            b'2' => (FileType::Symlink, tar_string(&header[157..257])?.as_bytes()),
+            b'5' => (FileType::Directory, data),
+            kind => {
+                log!(warn: "initramfs: skipping {} of type {}.", path, kind as char);
+                continue;
+            }
+        };
+        entries.push(Entry {
+            path,
+            file_type,
+            mode: (mode & 0o7777) as u16,
+            data,
+        });
+    }
+
+    Ok(entries)
+}
@@ -0,0 +1,481 @@
+//
+// Virtual File System
+//
+// Filesystems provide inodes. The VFS keeps a tree of
+// dentries on top of them, which caches name lookups and
+// records where filesystems are mounted. Paths are resolved
+// by walking that tree from the root.
+//
+// At boot, the initramfs is unpacked into a tmpfs and
+// mounted on `/`. Should that fail, `/` stays a small
+// synthetic directory holding the mount points. Devices
+// from the `DeviceManager` appear under `/dev`, and block
+// devices holding FAT or ext2 filesystems under `/mnt`.
+//
+
+mod dentry;
+mod devfs;
+mod ext2;
+mod fat;
+mod file;
+mod initramfs;
+mod path;
+mod rootfs;
+mod tmpfs;
+
+pub use self::dentry::Dentry;
+pub use self::devfs::DevFS;
+pub use self::ext2::Ext2FS;
+pub use self::fat::FatFS;
+pub use self::file::{OpenFile, OpenFlags, SeekFrom};
+pub use self::tmpfs::TmpFS;
+
+use alloc::{prelude::*, sync::Arc};
+use core::any::Any;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::block::{self, BlockDeviceHandle};
+use crate::hal::{DeviceError, PollFlags};
+
+lazy_static! {
+    static ref ROOT: Arc<Dentry> = Dentry::new_root(rootfs::RootFS::new().root());
+    static ref MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+}
+
+/// The number of symbolic links followed while resolving a path
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+/// Filesystem error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// No such file or directory
+    NotFound,
+    /// A path component is not a directory
+    NotDirectory,
+    /// The operation does not apply to directories
+    IsDirectory,
+    /// The name is already taken
+    AlreadyExists,
+    /// The directory is not empty
+    NotEmpty,
+    /// The path is malformed
+    InvalidPath,
+    /// An argument is out of range
+    InvalidArgument,
+    /// Too many symbolic links were encountered
+    TooManyLinks,
+    /// The filesystem does not support the operation
+    Unsupported,
+    /// The filesystem or file is read-only
+    ReadOnly,
+    /// The filesystem is full
+    NoSpace,
+    /// The file would exceed the largest size the filesystem supports
+    FileTooLarge,
+    /// The file or filesystem is in use
+    Busy,
+    /// The on-disk structures are damaged
+    Corrupted,
+    /// The file was not opened with the required access
+    BadAccess,
+    /// The operation would cross filesystems
+    CrossDevice,
+    /// The underlying device failed
+    Device(DeviceError),
+}
+
+impl From<DeviceError> for FsError {
+    fn from(err: DeviceError) -> Self {
+        FsError::Device(err)
+    }
+}
+
+impl core::fmt::Display for FsError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let s = match *self {
+            FsError::NotFound => "no such file or directory",
+            FsError::NotDirectory => "not a directory",
+            FsError::IsDirectory => "is a directory",
+            FsError::AlreadyExists => "file exists",
+            FsError::NotEmpty => "directory not empty",
+            FsError::InvalidPath => "invalid path",
+            FsError::InvalidArgument => "invalid argument",
+            FsError::TooManyLinks => "too many levels of symbolic links",
+            FsError::Unsupported => "operation not supported",
+            FsError::ReadOnly => "read-only file system",
+            FsError::NoSpace => "no space left on device",
+            FsError::FileTooLarge => "file too large",
+            FsError::Busy => "resource busy",
+            FsError::Corrupted => "file system corrupted",
+            FsError::BadAccess => "bad file access mode",
+            FsError::CrossDevice => "cross-device link",
+            FsError::Device(err) => return write!(f, "device error: {}", err),
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// The type of an inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+/// Information about an inode
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    /// The inode number, unique within its filesystem
+    pub inode: u64,
+    pub file_type: FileType,
+    pub size: u64,
+    /// Permission bits
+    pub mode: u16,
+    pub links: u32,
+}
+
+/// A directory entry
+#[allow(dead_code)]
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType,
+}
+
+/// A file, directory or other object of a filesystem.
+///
+/// Inodes are shared, so they manage their own locking.
+pub trait INode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Read data at a position, returning the number of bytes read
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(unsupported_for(self.metadata().file_type))
+    }
+
+    /// Write data at a position, returning the number of bytes written
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(unsupported_for(self.metadata().file_type))
+    }
+
+    /// Change the size of a file
+    fn truncate(&self, _size: u64) -> Result<(), FsError> {
+        Err(unsupported_for(self.metadata().file_type))
+    }
+
+    /// Find an entry of a directory
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn INode>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Test whether the entries found by `lookup` may be cached.
+    ///
+    /// Directories whose entries change without going through
+    /// the VFS return `false`.
+    fn cache_lookups(&self) -> bool {
+        true
+    }
+
+    /// Create a file or directory in a directory
+    fn create(&self, _name: &str, _file_type: FileType) -> Result<Arc<dyn INode>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Create a symbolic link in a directory
+    fn symlink(&self, _name: &str, _target: &str) -> Result<Arc<dyn INode>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Remove an entry from a directory
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Move an entry of this directory into `target`.
+    ///
+    /// An existing entry named `new_name` is replaced.
+    fn rename(
+        &self,
+        _old_name: &str,
+        _target: &Arc<dyn INode>,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// List the entries of a directory, without `.` and `..`
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotDirectory)
+    }
+
+    /// Get the target of a symbolic link
+    fn readlink(&self) -> Result<String, FsError> {
+        Err(FsError::InvalidPath)
+    }
+
+    /// Perform a device-specific control operation
+    fn ioctl(&self, _request: u32, _arg: usize) -> Result<usize, FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Test whether the inode is ready for reading or writing
+    fn poll(&self) -> PollFlags {
+        PollFlags::READABLE | PollFlags::WRITABLE
+    }
+
+    /// Change the permission bits
+    fn set_mode(&self, _mode: u16) -> Result<(), FsError> {
+        Err(FsError::Unsupported)
+    }
+
+    /// Write back cached data
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// Get the error for a file operation on an inode that lacks it
+fn unsupported_for(file_type: FileType) -> FsError {
+    match file_type {
+        FileType::Directory => FsError::IsDirectory,
+        _ => FsError::Unsupported,
+    }
+}
+
+/// A mountable filesystem
+pub trait FileSystem: Send + Sync {
+    /// Get the name of the filesystem type
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn INode>;
+
+    /// Write back all cached data
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// A mounted filesystem
+struct Mount {
+    path: String,
+    fs: Arc<dyn FileSystem>,
+    mountpoint: Arc<Dentry>,
+}
+
+/// Set up the root directory and mount `/dev` and `/tmp`
+pub fn init() -> Result<(), FsError> {
+    lazy_static::initialize(&ROOT);
+    match initramfs::load() {
+        Ok(fs) => mount("/", Arc::new(fs))?,
+        Err(err) => log!(error: "Unable to unpack the initramfs: {}", err),
+    }
+
+    for &path in ["/dev", "/tmp"].iter() {
+        match mkdir(path) {
+            Ok(_) | Err(FsError::AlreadyExists) => (),
+            Err(err) => return Err(err),
+        }
+    }
+    mount("/dev", Arc::new(DevFS::new()))?;
+    mount("/tmp", Arc::new(TmpFS::new()))
+}
+
+/// Open the filesystem on a block device, trying every driver
+pub fn probe(dev: &BlockDeviceHandle) -> Option<Arc<dyn FileSystem>> {
+    match FatFS::new(dev.clone()) {
+        Ok(fs) => return Some(Arc::new(fs)),
+        Err(FsError::Unsupported) => (),
+        Err(err) => log!(warn: "{}: damaged FAT filesystem: {}", dev.name(), err),
+    }
+    match Ext2FS::new(dev.clone()) {
+        Ok(fs) => return Some(Arc::new(fs)),
+        Err(FsError::Unsupported) => (),
+        Err(err) => log!(warn: "{}: damaged ext2 filesystem: {}", dev.name(), err),
+    }
+    None
+}
+
+/// Mount every block device holding a known filesystem under `/mnt`.
+///
+/// Returns the number of filesystems mounted.
+pub fn automount() -> usize {
+    let mut mounted = 0;
+    for dev in block::devices() {
+        // Partitioned disks are mounted through their partitions
+        if !dev.is_partition() && !block::partitions(&dev).is_empty() {
+            continue;
+        }
+        let fs = match probe(&dev) {
+            Some(fs) => fs,
+            None => continue,
+        };
+
+        let path = format!("/mnt/{}", dev.name());
+        let result = match mkdir(&path) {
+            Ok(_) | Err(FsError::AlreadyExists) => mount(&path, fs),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => mounted += 1,
+            Err(err) => log!(error: "Unable to mount {}: {}", dev.name(), err),
+        }
+    }
+    mounted
+}
+
+/// Get the root dentry
+pub fn root() -> Arc<Dentry> {
+    Dentry::follow_mounts(&ROOT)
+}
+
+/// Resolve a path, following a final symbolic link
+pub fn lookup(path: &str) -> Result<Arc<Dentry>, FsError> {
+    path::resolve(&root(), path, true)
+}
+
+/// Resolve a path without following a final symbolic link
+pub fn lookup_nofollow(path: &str) -> Result<Arc<Dentry>, FsError> {
+    path::resolve(&root(), path, false)
+}
+
+/// Mount a filesystem on a directory
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let mountpoint = lookup(path)?;
+    if mountpoint.metadata().file_type != FileType::Directory {
+        return Err(FsError::NotDirectory);
+    }
+
+    Dentry::mount(&mountpoint, fs.root())?;
+    log!(debug: "Mounted {} on {}.", fs.name(), path);
+    MOUNTS.lock().push(Mount {
+        path: path.to_string(),
+        fs,
+        mountpoint,
+    });
+    Ok(())
+}
+
+/// Unmount the filesystem mounted on a directory
+#[allow(dead_code)]
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let target = lookup(path)?;
+    let mut mounts = MOUNTS.lock();
+
+    // `lookup` returns the root of the mounted filesystem
+    let index = mounts
+        .iter()
+        .rposition(|m| {
+            m.mountpoint
+                .mounted()
+                .map_or(false, |d| Arc::ptr_eq(&d, &target))
+        })
+        .ok_or(FsError::InvalidPath)?;
+    if target.has_mounts() {
+        return Err(FsError::Busy);
+    }
+
+    let mount = mounts.remove(index);
+    mount.fs.sync()?;
+    mount.mountpoint.unmount();
+    Ok(())
+}
+
+/// Get the mounted filesystems as (path, type name) pairs
+#[allow(dead_code)]
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|m| (m.path.clone(), m.fs.name()))
+        .collect()
+}
+
+/// Write back the data of all mounted filesystems
+#[allow(dead_code)]
+pub fn sync_all() -> Result<(), FsError> {
+    for mount in MOUNTS.lock().iter() {
+        mount.fs.sync()?;
+    }
+    Ok(())
+}
+
+/// Open a file
+#[allow(dead_code)]
+pub fn open(path: &str, flags: OpenFlags) -> Result<Arc<OpenFile>, FsError> {
+    let follow = !flags.contains(OpenFlags::NOFOLLOW);
+    let dentry = match path::resolve(&root(), path, follow) {
+        Ok(dentry) => {
+            if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) {
+                return Err(FsError::AlreadyExists);
+            }
+            dentry
+        }
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = path::resolve_parent(&root(), path)?;
+            Dentry::create(&parent, name, FileType::File)?
+        }
+        Err(err) => return Err(err),
+    };
+
+    OpenFile::new(dentry, flags).map(Arc::new)
+}
+
+/// Create a directory
+pub fn mkdir(path: &str) -> Result<Arc<Dentry>, FsError> {
+    let (parent, name) = path::resolve_parent(&root(), path)?;
+    Dentry::create(&parent, name, FileType::Directory)
+}
+
+/// Create a symbolic link at `path` pointing to `target`
+#[allow(dead_code)]
+pub fn symlink(target: &str, path: &str) -> Result<Arc<Dentry>, FsError> {
+    let (parent, name) = path::resolve_parent(&root(), path)?;
+    Dentry::symlink(&parent, name, target)
+}
+
+/// Remove a file, link or empty directory
+#[allow(dead_code)]
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let (parent, name) = path::resolve_parent(&root(), path)?;
+    Dentry::unlink(&parent, name)
+}
+
+/// Move a file or directory
+#[allow(dead_code)]
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), FsError> {
+    let (old_parent, old_name) = path::resolve_parent(&root(), old_path)?;
+    let (new_parent, new_name) = path::resolve_parent(&root(), new_path)?;
+    Dentry::rename(&old_parent, old_name, &new_parent, new_name)
+}
+
+/// Change the permission bits of a file
+#[allow(dead_code)]
+pub fn chmod(path: &str, mode: u16) -> Result<(), FsError> {
+    lookup(path)?.inode().set_mode(mode & 0o7777)
+}
+
+/// Get the target of a symbolic link
+#[allow(dead_code)]
+pub fn readlink(path: &str) -> Result<String, FsError> {
+    lookup_nofollow(path)?.inode().readlink()
+}
+
+/// List a directory
+#[allow(dead_code)]
+pub fn readdir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    lookup(path)?.inode().readdir()
+}
+
+/// Get information about a file
+#[allow(dead_code)]
+pub fn stat(path: &str) -> Result<Metadata, FsError> {
+    Ok(lookup(path)?.metadata())
+}
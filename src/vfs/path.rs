@@ -0,0 +1,84 @@
+use alloc::sync::Arc;
+
+use super::{Dentry, FileType, FsError, MAX_SYMLINK_DEPTH};
+
+/// Resolve a path relative to `base`.
+///
+/// Absolute paths start at the root. A symbolic link in the
+/// last component is only followed if `follow` is set.
+pub fn resolve(base: &Arc<Dentry>, path: &str, follow: bool) -> Result<Arc<Dentry>, FsError> {
+    let mut links = 0;
+    walk(base, path, follow, &mut links)
+}
+
+/// Resolve all but the last component of a path.
+///
+/// Returns the parent directory and the last component,
+/// which must be a plain name.
+pub fn resolve_parent<'a>(
+    base: &Arc<Dentry>,
+    path: &'a str,
+) -> Result<(Arc<Dentry>, &'a str), FsError> {
+    let trimmed = path.trim_end_matches('/');
+    let (dir, name) = match trimmed.rfind('/') {
+        Some(0) => ("/", &trimmed[1..]),
+        Some(index) => (&trimmed[..index], &trimmed[index + 1..]),
+        None => (".", trimmed),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+
+    let parent = resolve(base, dir, true)?;
+    if parent.metadata().file_type != FileType::Directory {
+        return Err(FsError::NotDirectory);
+    }
+    Ok((parent, name))
+}
+
+fn walk(
+    base: &Arc<Dentry>,
+    path: &str,
+    follow: bool,
+    links: &mut usize,
+) -> Result<Arc<Dentry>, FsError> {
+    if path.is_empty() {
+        return Err(FsError::InvalidPath);
+    }
+
+    let mut current = if path.starts_with('/') {
+        super::root()
+    } else {
+        Dentry::follow_mounts(base)
+    };
+
+    let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+    while let Some(component) = components.next() {
+        if current.metadata().file_type != FileType::Directory {
+            return Err(FsError::NotDirectory);
+        }
+
+        let next = match component {
+            "." => continue,
+            // The root is its own parent
+            ".." => current.parent().unwrap_or_else(|| current.clone()),
+            name => Dentry::lookup(&current, name)?,
+        };
+        let next = Dentry::follow_mounts(&next);
+
+        let last = components.peek().is_none();
+        if next.metadata().file_type == FileType::Symlink && (follow || !last) {
+            *links += 1;
+            if *links > MAX_SYMLINK_DEPTH {
+                return Err(FsError::TooManyLinks);
+            }
+            // Relative targets start in the directory holding the link
+            let target = next.inode().readlink()?;
+            current = walk(&current, &target, true, links)?;
+        } else {
+            current = next;
+        }
+    }
+
+    Ok(current)
+}
@@ -0,0 +1,113 @@
+use alloc::{collections::BTreeMap, prelude::*, sync::Arc};
+use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use super::{DirEntry, FileSystem, FileType, FsError, INode, Metadata};
+
+/// The directories that exist from the start
+const ROOT_DIRECTORIES: &[&str] = &["dev", "mnt"];
+
+/// The filesystem behind `/` until a real one is mounted.
+///
+/// It only holds directories to mount other filesystems on.
+pub struct RootFS {
+    root: Arc<RootDir>,
+}
+
+impl RootFS {
+    pub fn new() -> Self {
+        let next_inode = Arc::new(AtomicUsize::new(1));
+        let root = RootDir::new(next_inode);
+        for name in ROOT_DIRECTORIES {
+            root.create(name, FileType::Directory).unwrap();
+        }
+        RootFS { root }
+    }
+}
+
+impl FileSystem for RootFS {
+    fn name(&self) -> &'static str {
+        "rootfs"
+    }
+
+    fn root(&self) -> Arc<dyn INode> {
+        self.root.clone()
+    }
+}
+
+struct RootDir {
+    inode: u64,
+    next_inode: Arc<AtomicUsize>,
+    entries: Mutex<BTreeMap<String, Arc<RootDir>>>,
+}
+
+impl RootDir {
+    fn new(next_inode: Arc<AtomicUsize>) -> Arc<Self> {
+        Arc::new(RootDir {
+            inode: next_inode.fetch_add(1, Ordering::Relaxed) as u64,
+            next_inode,
+            entries: Mutex::new(BTreeMap::new()),
+        })
+    }
+}
+
+impl INode for RootDir {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.inode,
+            file_type: FileType::Directory,
+            size: 0,
+            mode: 0o755,
+            links: 2,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn INode>, FsError> {
+        match self.entries.lock().get(name) {
+            Some(dir) => Ok(dir.clone()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn INode>, FsError> {
+        if file_type != FileType::Directory {
+            return Err(FsError::Unsupported);
+        }
+        let mut entries = self.entries.lock();
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let dir = RootDir::new(self.next_inode.clone());
+        entries.insert(name.to_string(), dir.clone());
+        Ok(dir)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut entries = self.entries.lock();
+        match entries.get(name) {
+            Some(dir) if !dir.entries.lock().is_empty() => return Err(FsError::NotEmpty),
+            Some(_) => (),
+            None => return Err(FsError::NotFound),
+        }
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(self
+            .entries
+            .lock()
+            .iter()
+            .map(|(name, dir)| DirEntry {
+                name: name.clone(),
+                inode: dir.inode,
+                file_type: FileType::Directory,
+            })
+            .collect())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
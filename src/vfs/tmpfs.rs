@@ -0,0 +1,366 @@
+use alloc::{collections::BTreeMap, prelude::*, sync::Arc};
+use core::any::Any;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use super::{DirEntry, FileSystem, FileType, FsError, INode, Metadata};
+
+/// The largest file tmpfs holds.
+///
+/// Files live on the kernel heap, and a failed allocation
+/// takes down the kernel, so sizes are kept well below it.
+const TMPFS_MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The most file data one tmpfs holds in total
+const TMPFS_MAX_SIZE: usize = 64 * 1024 * 1024;
+
+/// A filesystem keeping all data in memory.
+///
+/// The contents are lost when the filesystem is dropped.
+pub struct TmpFS {
+    root: Arc<TmpNode>,
+}
+
+impl TmpFS {
+    pub fn new() -> Self {
+        let shared = Arc::new(Shared {
+            next_inode: AtomicUsize::new(1),
+            used: AtomicUsize::new(0),
+        });
+        TmpFS {
+            root: TmpNode::new(&shared, FileType::Directory, 0o755, Data::empty_directory()),
+        }
+    }
+}
+
+impl FileSystem for TmpFS {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> Arc<dyn INode> {
+        self.root.clone()
+    }
+}
+
+/// State shared by all nodes of one filesystem
+struct Shared {
+    next_inode: AtomicUsize,
+    /// Bytes of file data held by all nodes
+    used: AtomicUsize,
+}
+
+impl Shared {
+    /// Account for `bytes` more file data
+    fn reserve(&self, bytes: usize) -> Result<(), FsError> {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let new = match used.checked_add(bytes) {
+                Some(new) if new <= TMPFS_MAX_SIZE => new,
+                _ => return Err(FsError::NoSpace),
+            };
+            match self
+                .used
+                .compare_exchange(used, new, Ordering::Relaxed, Ordering::Relaxed)
+            {
+                Ok(_) => return Ok(()),
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+enum Data {
+    File(Vec<u8>),
+    Directory(BTreeMap<String, Arc<TmpNode>>),
+    Symlink(String),
+}
+
+impl Data {
+    fn empty_directory() -> Self {
+        Data::Directory(BTreeMap::new())
+    }
+
+    fn entries(&mut self) -> Result<&mut BTreeMap<String, Arc<TmpNode>>, FsError> {
+        match *self {
+            Data::Directory(ref mut entries) => Ok(entries),
+            _ => Err(FsError::NotDirectory),
+        }
+    }
+
+    fn file(&mut self) -> Result<&mut Vec<u8>, FsError> {
+        match *self {
+            Data::File(ref mut data) => Ok(data),
+            Data::Directory(_) => Err(FsError::IsDirectory),
+            Data::Symlink(_) => Err(FsError::Unsupported),
+        }
+    }
+}
+
+struct TmpNode {
+    inode: u64,
+    file_type: FileType,
+    fs: Arc<Shared>,
+    mode: Mutex<u16>,
+    data: Mutex<Data>,
+}
+
+impl TmpNode {
+    fn new(shared: &Arc<Shared>, file_type: FileType, mode: u16, data: Data) -> Arc<Self> {
+        Arc::new(TmpNode {
+            inode: shared.next_inode.fetch_add(1, Ordering::Relaxed) as u64,
+            file_type,
+            fs: shared.clone(),
+            mode: Mutex::new(mode),
+            data: Mutex::new(data),
+        })
+    }
+
+    /// Add a new node to this directory
+    fn insert(&self, name: &str, node: Arc<TmpNode>) -> Result<Arc<dyn INode>, FsError> {
+        let mut data = self.data.lock();
+        let entries = data.entries()?;
+        if entries.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        entries.insert(name.to_string(), node.clone());
+        Ok(node)
+    }
+
+    fn is_empty_directory(&self) -> bool {
+        match *self.data.lock() {
+            Data::Directory(ref entries) => entries.is_empty(),
+            _ => false,
+        }
+    }
+}
+
+impl Drop for TmpNode {
+    fn drop(&mut self) {
+        if let Data::File(ref data) = *self.data.lock() {
+            self.fs.release(data.len());
+        }
+    }
+}
+
+/// Move `old_name` from `source` to `new_name` in `target`.
+///
+/// Without a target, the entry stays in `source`.
+fn move_entry(
+    dir: &TmpNode,
+    source: &mut BTreeMap<String, Arc<TmpNode>>,
+    target: Option<(&TmpNode, &mut BTreeMap<String, Arc<TmpNode>>)>,
+    old_name: &str,
+    new_name: &str,
+) -> Result<(), FsError> {
+    let node = source.get(old_name).cloned().ok_or(FsError::NotFound)?;
+    let existing = match target {
+        Some((_, ref entries)) => entries.get(new_name).cloned(),
+        None => source.get(new_name).cloned(),
+    };
+
+    if let Some(existing) = existing {
+        if Arc::ptr_eq(&existing, &node) {
+            return Ok(());
+        }
+        match (node.file_type, existing.file_type) {
+            (FileType::Directory, FileType::Directory) => {
+                // Both directories are locked already, and contain entries
+                let locked = core::ptr::eq(&*existing, dir)
+                    || target
+                        .as_ref()
+                        .map_or(false, |t| core::ptr::eq(&*existing, t.0));
+                if locked || !existing.is_empty_directory() {
+                    return Err(FsError::NotEmpty);
+                }
+            }
+            (FileType::Directory, _) => return Err(FsError::NotDirectory),
+            (_, FileType::Directory) => return Err(FsError::IsDirectory),
+            _ => (),
+        }
+    }
+
+    source.remove(old_name);
+    match target {
+        Some((_, entries)) => entries.insert(new_name.to_string(), node),
+        None => source.insert(new_name.to_string(), node),
+    };
+    Ok(())
+}
+
+impl INode for TmpNode {
+    fn metadata(&self) -> Metadata {
+        let data = self.data.lock();
+        let (size, links) = match *data {
+            Data::File(ref data) => (data.len() as u64, 1),
+            Data::Symlink(ref target) => (target.len() as u64, 1),
+            Data::Directory(ref entries) => {
+                let subdirs = entries
+                    .values()
+                    .filter(|node| node.file_type == FileType::Directory)
+                    .count();
+                (0, 2 + subdirs as u32)
+            }
+        };
+
+        Metadata {
+            inode: self.inode,
+            file_type: self.file_type,
+            size,
+            mode: *self.mode.lock(),
+            links,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut data = self.data.lock();
+        let file = data.file()?;
+        if offset >= file.len() as u64 {
+            return Ok(0);
+        }
+        let start = offset as usize;
+        let count = buf.len().min(file.len() - start);
+        buf[..count].copy_from_slice(&file[start..start + count]);
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut data = self.data.lock();
+        let file = data.file()?;
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(FsError::InvalidArgument)?;
+        if end > TMPFS_MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge);
+        }
+        let (start, end) = (offset as usize, end as usize);
+        // Writing past the end leaves a hole of zeros
+        if end > file.len() {
+            self.fs.reserve(end - file.len())?;
+            file.resize(end, 0);
+        }
+        file[start..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, size: u64) -> Result<(), FsError> {
+        if size > TMPFS_MAX_FILE_SIZE {
+            return Err(FsError::FileTooLarge);
+        }
+        let size = size as usize;
+        let mut data = self.data.lock();
+        let file = data.file()?;
+        if size > file.len() {
+            self.fs.reserve(size - file.len())?;
+        } else {
+            self.fs.release(file.len() - size);
+        }
+        file.resize(size, 0);
+        // Give the memory back along with the quota
+        file.shrink_to_fit();
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn INode>, FsError> {
+        let mut data = self.data.lock();
+        match data.entries()?.get(name) {
+            Some(node) => Ok(node.clone()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create(&self, name: &str, file_type: FileType) -> Result<Arc<dyn INode>, FsError> {
+        let node = match file_type {
+            FileType::File => TmpNode::new(&self.fs, file_type, 0o644, Data::File(Vec::new())),
+            FileType::Directory => {
+                TmpNode::new(&self.fs, file_type, 0o755, Data::empty_directory())
+            }
+            _ => return Err(FsError::Unsupported),
+        };
+        self.insert(name, node)
+    }
+
+    fn symlink(&self, name: &str, target: &str) -> Result<Arc<dyn INode>, FsError> {
+        let data = Data::Symlink(target.to_string());
+        let node = TmpNode::new(&self.fs, FileType::Symlink, 0o777, data);
+        self.insert(name, node)
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut data = self.data.lock();
+        let entries = data.entries()?;
+        match entries.get(name) {
+            Some(node) if node.file_type == FileType::Directory && !node.is_empty_directory() => {
+                return Err(FsError::NotEmpty)
+            }
+            Some(_) => (),
+            None => return Err(FsError::NotFound),
+        }
+        entries.remove(name);
+        Ok(())
+    }
+
+    fn rename(
+        &self,
+        old_name: &str,
+        target: &Arc<dyn INode>,
+        new_name: &str,
+    ) -> Result<(), FsError> {
+        let target = target
+            .as_any()
+            .downcast_ref::<TmpNode>()
+            .ok_or(FsError::CrossDevice)?;
+        if !Arc::ptr_eq(&self.fs, &target.fs) {
+            return Err(FsError::CrossDevice);
+        }
+
+        if core::ptr::eq(self, target) {
+            let mut data = self.data.lock();
+            return move_entry(self, data.entries()?, None, old_name, new_name);
+        }
+
+        // Lock in address order, so two renames cannot deadlock
+        let (mut source, mut dest) = if (self as *const TmpNode) < (target as *const TmpNode) {
+            let source = self.data.lock();
+            (source, target.data.lock())
+        } else {
+            let dest = target.data.lock();
+            (self.data.lock(), dest)
+        };
+        let target_entries = Some((target, dest.entries()?));
+        move_entry(self, source.entries()?, target_entries, old_name, new_name)
+    }
+
+    fn readdir(&self) -> Result<Vec<DirEntry>, FsError> {
+        let mut data = self.data.lock();
+        Ok(data
+            .entries()?
+            .iter()
+            .map(|(name, node)| DirEntry {
+                name: name.clone(),
+                inode: node.inode,
+                file_type: node.file_type,
+            })
+            .collect())
+    }
+
+    fn readlink(&self) -> Result<String, FsError> {
+        match *self.data.lock() {
+            Data::Symlink(ref target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidPath),
+        }
+    }
+
+    fn set_mode(&self, mode: u16) -> Result<(), FsError> {
+        *self.mode.lock() = mode;
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
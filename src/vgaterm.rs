@@ -1,4 +1,4 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, prelude::*};
 use core::ptr;
 use core::ptr::NonNull;
 use core::ptr::Unique;
@@ -6,7 +6,8 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::instructions::port::Port;
 
-use crate::hal::DEVICE_MANAGER;
+use crate::hal::{BusDevice, DeviceMatch, Driver, DEVICE_MANAGER};
+use crate::tty::Output;
 
 /// The address of the framebuffer in memory.
 pub const VGA_PTR: usize = 0xB8000;
@@ -15,6 +16,10 @@ const VGA_SIZE: usize = VGA_WIDTH * VGA_HEIGHT;
 const VGA_WIDTH: usize = 80;
 const VGA_HEIGHT: usize = 25;
 
+lazy_static! {
+    static ref VGA_MATCHES: Vec<DeviceMatch> = vec![DeviceMatch::Platform("vga-text")];
+}
+
 macro_rules! color {
     ($fc:expr, $bc:expr) => {
         bc << 4 | fc
@@ -35,30 +40,67 @@ macro_rules! offset {
 
 type TerminalBuffer = Unique<[u16; VGA_SIZE]>;
 
-pub struct TerminalDevice {
+/// The number of virtual consoles sharing the screen
+pub const CONSOLE_COUNT: usize = 6;
+
+lazy_static! {
+    static ref CONSOLES: Mutex<Consoles> = Mutex::new(Consoles {
+        screens: Vec::new(),
+        active: 0,
+        hardware: VGA_PTR,
+    });
+}
+
+/// The virtual consoles and which one is shown
+struct Consoles {
+    screens: Vec<Screen>,
+    active: usize,
+    /// The address of the hardware buffer
+    hardware: usize,
+}
+
+/// The contents of a virtual console
+struct Screen {
     x: usize,
     y: usize,
     color: u8,
+    /// The hardware buffer while shown, `backing` otherwise
     buf: TerminalBuffer,
+    backing: Box<[u16; VGA_SIZE]>,
+    shown: bool,
 }
 
-impl TerminalDevice {
-    pub fn init(name: &'static str, ptr: usize) -> Result<(), &str> {
-        let mut term = TerminalDevice {
+impl Screen {
+    fn new() -> Self {
+        let mut backing = box [chattr!(b' ', 0x07_u8); VGA_SIZE];
+        Screen {
             x: 0,
             y: 0,
             color: 0x07,
-            buf: Unique::new(ptr as *mut _).unwrap(),
-        };
-        term.clear();
-        DEVICE_MANAGER
-            .lock()
-            .register_device(name, box term)
-            .unwrap();
-        Ok(())
+            buf: Unique::new(&mut *backing as *mut _).unwrap(),
+            backing,
+            shown: false,
+        }
     }
 
-    pub fn clear(&mut self) {
+    /// Draw to the hardware buffer from now on
+    fn show(&mut self, hardware: usize) {
+        let hw = hardware as *mut [u16; VGA_SIZE];
+        unsafe { ptr::copy_nonoverlapping(&*self.backing, hw, 1) };
+        self.buf = Unique::new(hw).unwrap();
+        self.shown = true;
+        self.update_physical_cursor();
+    }
+
+    /// Save the hardware buffer and draw to the backing buffer from now on
+    fn hide(&mut self) {
+        let backing = &mut *self.backing as *mut _;
+        unsafe { ptr::copy_nonoverlapping(self.buf.as_ptr(), backing, 1) };
+        self.buf = Unique::new(backing).unwrap();
+        self.shown = false;
+    }
+
+    fn clear(&mut self) {
         let chr = chattr!(b' ', self.color);
         let buf = unsafe { self.buf.as_mut() };
         #[allow(clippy::needless_range_loop)]
@@ -116,6 +158,13 @@ impl TerminalDevice {
         }
     }
 
+    fn write_bytes(&mut self, val: &[u8]) {
+        for b in val {
+            self.write_u8(*b);
+        }
+        self.update_physical_cursor();
+    }
+
     fn new_line(&mut self) {
         self.x = 0;
         if self.y < VGA_HEIGHT - 1 {
@@ -141,6 +190,10 @@ impl TerminalDevice {
     }
 
     fn update_physical_cursor(&mut self) {
+        // Hidden consoles keep their cursor to themselves
+        if !self.shown {
+            return;
+        }
         let off = offset!(self.x, self.y);
         let mut addr = Port::new(0x03D4);
         let mut data = Port::new(0x03D5);
@@ -153,23 +206,159 @@ impl TerminalDevice {
     }
 }
 
-use crate::hal::{Device, DeviceType};
+/// Write to a virtual console, whether it is shown or not
+pub fn write_console(console: usize, val: &[u8]) {
+    if let Some(screen) = CONSOLES.lock().screens.get_mut(console) {
+        screen.write_bytes(val);
+    }
+}
+
+/// Show a virtual console.
+///
+/// Returns `false` if there is no such console.
+pub fn switch_console(console: usize) -> bool {
+    let mut consoles = CONSOLES.lock();
+    if console >= consoles.screens.len() {
+        return false;
+    }
+    let (active, hardware) = (consoles.active, consoles.hardware);
+    if console != active {
+        consoles.screens[active].hide();
+        consoles.screens[console].show(hardware);
+        consoles.active = console;
+    }
+    true
+}
+
+/// Show and clear the first virtual console from a panic or OOM handler.
+///
+/// The failing code may hold the console lock and will
+/// never release it, so a taken lock is broken first.
+/// The returned console is written to directly, without
+/// going through the device manager.
+pub fn panic_console() -> Console {
+    if CONSOLES.try_lock().is_none() {
+        unsafe { CONSOLES.force_unlock() };
+    }
+    switch_console(0);
+    if let Some(screen) = CONSOLES.lock().screens.get_mut(0) {
+        screen.clear();
+        screen.update_physical_cursor();
+    }
+    Console(0)
+}
+
+/// Get the virtual console being shown
+pub fn active_console() -> usize {
+    CONSOLES.lock().active
+}
+
+/// A virtual console as an output of the TTY layer
+pub struct Console(pub usize);
+
+impl Output for Console {
+    fn write(&mut self, val: &[u8]) {
+        write_console(self.0, val);
+    }
+}
+
+impl core::fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        write_console(self.0, s.as_bytes());
+        Ok(())
+    }
+}
+
+/// The VGA text screen, showing one of the virtual consoles.
+///
+/// Writes go to the first console, which the kernel logs to.
+pub struct TerminalDevice {
+    console: usize,
+}
+
+impl TerminalDevice {
+    pub fn init(name: &'static str, ptr: usize) -> Result<(), &'static str> {
+        {
+            let mut consoles = CONSOLES.lock();
+            if consoles.screens.is_empty() {
+                consoles.hardware = ptr;
+                consoles.screens = (0..CONSOLE_COUNT).map(|_| Screen::new()).collect();
+                consoles.screens[0].show(ptr);
+            }
+        }
+
+        let mut term = TerminalDevice { console: 0 };
+        term.clear();
+        DEVICE_MANAGER
+            .lock()
+            .register_device(name, box term)
+            .map_err(|_| "device name already taken")
+    }
+
+    pub fn clear(&mut self) {
+        if let Some(screen) = CONSOLES.lock().screens.get_mut(self.console) {
+            screen.clear();
+            screen.update_physical_cursor();
+        }
+    }
+
+    /// Call `f` with the console's screen
+    fn with_screen<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Screen) -> R,
+    {
+        f(&mut CONSOLES.lock().screens[self.console])
+    }
+}
+
+use crate::hal::{Device, DeviceCapabilities, DeviceError, DeviceType, PollFlags};
 use core::any::Any;
 
+//
+// Terminal control requests
+//
+
+/// Clear the screen
+pub const TERM_IOCTL_CLEAR: u32 = 0x01;
+
+/// Set the color attribute to the low byte of the argument
+pub const TERM_IOCTL_SET_COLOR: u32 = 0x02;
+
+/// Get the screen size as `width << 16 | height`
+pub const TERM_IOCTL_GET_SIZE: u32 = 0x03;
+
 impl Device for TerminalDevice {
     fn get_type(&self) -> DeviceType {
         return DeviceType::CharDevice;
     }
 
-    fn write_byte(&mut self, at: usize, val: u8) {
-        self.write_u8(val);
+    fn capabilities(&self) -> DeviceCapabilities {
+        DeviceCapabilities::WRITE | DeviceCapabilities::CONTROL | DeviceCapabilities::POLL
     }
 
-    fn write_bytes(&mut self, at: usize, val: &[u8], len: usize) {
-        for b in val {
-            self.write_byte(0, *b);
+    fn write_bytes(&mut self, val: &[u8]) -> Result<usize, DeviceError> {
+        self.with_screen(|screen| screen.write_bytes(val));
+        Ok(val.len())
+    }
+
+    fn ioctl(&mut self, request: u32, arg: usize) -> Result<usize, DeviceError> {
+        match request {
+            TERM_IOCTL_CLEAR => {
+                self.clear();
+                Ok(0)
+            }
+            TERM_IOCTL_SET_COLOR if arg <= 0xFF => {
+                self.with_screen(|screen| screen.color = arg as u8);
+                Ok(0)
+            }
+            TERM_IOCTL_SET_COLOR => Err(DeviceError::InvalidArgument),
+            TERM_IOCTL_GET_SIZE => Ok(VGA_WIDTH << 16 | VGA_HEIGHT),
+            _ => Err(DeviceError::Unsupported),
         }
-        self.update_physical_cursor();
+    }
+
+    fn poll(&mut self) -> PollFlags {
+        PollFlags::WRITABLE
     }
 
     fn as_any(&mut self) -> &mut dyn Any {
@@ -181,11 +370,30 @@ unsafe impl Send for TerminalDevice {}
 
 unsafe impl Sync for TerminalDevice {}
 
+/// Driver for the VGA text mode buffer
+pub struct VGATextDriver;
+
+impl Driver for VGATextDriver {
+    fn name(&self) -> &'static str {
+        "vgaterm"
+    }
+
+    fn match_table(&self) -> &[DeviceMatch] {
+        &VGA_MATCHES
+    }
+
+    fn probe(&self, dev: &BusDevice) -> Result<(), &'static str> {
+        match *dev {
+            BusDevice::Platform(ref dev) => TerminalDevice::init(dev.name, dev.base),
+            _ => Err("not a platform device"),
+        }
+    }
+}
+
 impl core::fmt::Write for TerminalDevice {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        let bytes = s.as_bytes();
-        let len = s.len();
-        self.write_bytes(0, bytes, len);
-        Ok(())
+        self.write_bytes(s.as_bytes())
+            .map(|_| ())
+            .map_err(|_| core::fmt::Error)
     }
 }
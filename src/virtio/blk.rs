@@ -0,0 +1,389 @@
+use alloc::prelude::*;
+use core::cmp::min;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::{Buffer, Transport, VirtQueue, VIRTIO_VENDOR_ID};
+use crate::block::{self, BlockDevice, DiskNames};
+use crate::dma::DMABuffer;
+use crate::hal::{BusDevice, DeviceError, DeviceMatch, Driver};
+use crate::pci::{PCIDevice, PCIFind};
+use crate::pit::PIT;
+
+// PCI device ids
+const VIRTIO_BLK_LEGACY_ID: u16 = 0x1001;
+const VIRTIO_BLK_MODERN_ID: u16 = 0x1042;
+
+lazy_static! {
+    static ref VIRTIO_BLK_MATCHES: Vec<DeviceMatch> = vec![
+        DeviceMatch::PCI(PCIFind::new(VIRTIO_VENDOR_ID, VIRTIO_BLK_LEGACY_ID)),
+        DeviceMatch::PCI(PCIFind::new(VIRTIO_VENDOR_ID, VIRTIO_BLK_MODERN_ID)),
+    ];
+    /// The disk registered for each bound device
+    static ref BOUND: Mutex<Vec<(BusDevice, &'static str)>> = Mutex::new(Vec::new());
+    static ref NAMES: Mutex<DiskNames> = Mutex::new(DiskNames::new("vd"));
+}
+
+/// The number of disks registered so far
+static DISKS: AtomicUsize = AtomicUsize::new(0);
+
+/// Requests always address 512-byte sectors
+const VIRTIO_BLK_SECTOR_SIZE: usize = 512;
+
+/// The largest number of sectors a single request transfers
+const VIRTIO_BLK_REQUEST_SECTORS: usize = 128;
+
+/// The largest queue we set up
+const VIRTIO_BLK_QUEUE_SIZE: u16 = 128;
+
+/// The time a request may take, in milliseconds
+const VIRTIO_BLK_TIMEOUT_MS: usize = 5000;
+
+// Feature bits
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// Request types
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+
+/// Header and status of a request share one buffer
+const REQUEST_HEADER_SIZE: usize = 16;
+const REQUEST_STATUS_OFFSET: usize = REQUEST_HEADER_SIZE;
+
+/// An in-flight request
+struct Request {
+    head: u16,
+    offset: usize,
+    meta: DMABuffer,
+    data: Option<DMABuffer>,
+    done: bool,
+}
+
+/// A virtio block device
+pub struct VirtioBlk {
+    transport: Transport,
+    queue: VirtQueue,
+    features: u64,
+    capacity: u64,
+    read_only: bool,
+    can_flush: bool,
+}
+
+/// Driver for virtio block devices.
+///
+/// Devices take the lowest free name of `vda` to `vdz`.
+pub struct VirtioBlkDriver;
+
+impl Driver for VirtioBlkDriver {
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn match_table(&self) -> &[DeviceMatch] {
+        &VIRTIO_BLK_MATCHES
+    }
+
+    fn probe(&self, dev: &BusDevice) -> Result<(), &'static str> {
+        let pci = match *dev {
+            BusDevice::PCI(ref dev) => dev,
+            _ => return Err("not a PCI device"),
+        };
+        let name = NAMES.lock().alloc().ok_or("out of disk names")?;
+        let disk = match VirtioBlk::new(pci) {
+            Ok(disk) => disk,
+            Err(err) => {
+                NAMES.lock().free(name);
+                return Err(err);
+            }
+        };
+
+        log!(
+            debug: "Virtio {} ({} transport): {} sectors{}.",
+            name,
+            if disk.transport.is_modern() { "modern" } else { "legacy" },
+            disk.capacity,
+            if disk.read_only { ", read-only" } else { "" }
+        );
+        if block::register(name, box disk).is_err() {
+            NAMES.lock().free(name);
+            return Err("unable to register the disk");
+        }
+        DISKS.fetch_add(1, Ordering::Relaxed);
+        BOUND.lock().push((*dev, name));
+        Ok(())
+    }
+
+    fn remove(&self, dev: &BusDevice) -> Result<(), &'static str> {
+        let name = {
+            let mut bound = BOUND.lock();
+            let index = bound
+                .iter()
+                .position(|(other, _)| other.same_as(dev))
+                .ok_or("device is not bound")?;
+            bound.remove(index).1
+        };
+        // Unregistering resets the device
+        let result = block::unregister(name).map_err(|_| "unable to unregister the disk");
+        NAMES.lock().free(name);
+        result
+    }
+}
+
+impl VirtioBlkDriver {
+    /// Get the number of disks registered so far
+    pub fn disks() -> usize {
+        DISKS.load(Ordering::Relaxed)
+    }
+}
+
+impl VirtioBlk {
+    fn new(dev: &PCIDevice) -> Result<Self, &'static str> {
+        let transport = Transport::probe(dev)?;
+        let features = transport.begin_init(VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH)?;
+        let queue = match transport.setup_queue(0, VIRTIO_BLK_QUEUE_SIZE) {
+            Ok(queue) => queue,
+            Err(err) => {
+                transport.fail();
+                return Err(err);
+            }
+        };
+        transport.driver_ok();
+
+        let capacity = transport.config_read(0, 8);
+        Ok(VirtioBlk {
+            transport,
+            queue,
+            features,
+            capacity,
+            read_only: features & VIRTIO_BLK_F_RO != 0,
+            can_flush: features & VIRTIO_BLK_F_FLUSH != 0,
+        })
+    }
+
+    /// Queue a request, returning `None` if the queue is full
+    fn submit(
+        &mut self,
+        kind: u32,
+        sector: u64,
+        offset: usize,
+        data: Option<DMABuffer>,
+    ) -> Option<Request> {
+        let mut meta = DMABuffer::new(REQUEST_HEADER_SIZE + 1, 16);
+        meta.write::<u32>(0, kind);
+        meta.write::<u64>(8, sector);
+        meta.write::<u8>(REQUEST_STATUS_OFFSET, 0xFF);
+
+        let mut buffers = vec![Buffer {
+            addr: meta.phys_addr(),
+            len: REQUEST_HEADER_SIZE as u32,
+            writable: false,
+        }];
+        if let Some(ref data) = data {
+            buffers.push(Buffer {
+                addr: data.phys_addr(),
+                len: data.len() as u32,
+                writable: kind == VIRTIO_BLK_T_IN,
+            });
+        }
+        buffers.push(Buffer {
+            addr: meta.phys_addr() + REQUEST_STATUS_OFFSET as u64,
+            len: 1,
+            writable: true,
+        });
+
+        let head = self.queue.add(&buffers)?;
+        Some(Request {
+            head,
+            offset,
+            meta,
+            data,
+            done: false,
+        })
+    }
+
+    /// Wait for all requests to be used by the device
+    fn complete(&mut self, requests: &mut Vec<Request>) -> Result<(), DeviceError> {
+        let deadline = PIT::deadline(PIT::ms_to_ticks(VIRTIO_BLK_TIMEOUT_MS));
+        let mut pending = requests.len();
+
+        while pending > 0 {
+            match self.queue.pop_used() {
+                Some((head, _)) => {
+                    if let Some(req) = requests.iter_mut().find(|r| r.head == head && !r.done) {
+                        req.done = true;
+                        pending -= 1;
+                    }
+                }
+                None if PIT::has_passed(deadline) => {
+                    // The pending requests' buffers are still the device's
+                    // until a reset takes them back. Only then may the
+                    // caller free them.
+                    log!(warn: "Virtio block request timed out, resetting the device.");
+                    if let Err(err) = self.reset() {
+                        log!(error: "Unable to reinitialize the virtio block device: {}", err);
+                        // The device may still write to the buffers
+                        core::mem::forget(core::mem::replace(requests, Vec::new()));
+                    }
+                    return Err(DeviceError::Timeout);
+                }
+                None => core::sync::atomic::spin_loop_hint(),
+            }
+        }
+
+        if requests
+            .iter()
+            .any(|r| r.meta.read::<u8>(REQUEST_STATUS_OFFSET) != VIRTIO_BLK_S_OK)
+        {
+            return Err(DeviceError::IOError);
+        }
+        Ok(())
+    }
+
+    /// Reset the device and set up a fresh queue.
+    ///
+    /// After the reset, the device no longer touches the old
+    /// queue or any buffer in it, so all descriptor chains are
+    /// reclaimed at once by dropping the queue.
+    fn reset(&mut self) -> Result<(), &'static str> {
+        let features = self.transport.begin_init(self.features)?;
+        if features != self.features {
+            self.transport.fail();
+            return Err("Virtio device changed its features");
+        }
+        self.queue = match self.transport.setup_queue(0, VIRTIO_BLK_QUEUE_SIZE) {
+            Ok(queue) => queue,
+            Err(err) => {
+                self.transport.fail();
+                return Err(err);
+            }
+        };
+        self.transport.driver_ok();
+        Ok(())
+    }
+
+    /// Transfer `len` bytes, keeping as many requests in flight as fit
+    fn transfer<F, D>(
+        &mut self,
+        lba: u64,
+        len: usize,
+        write: bool,
+        mut fill: F,
+        mut drain: D,
+    ) -> Result<(), DeviceError>
+    where
+        F: FnMut(usize, &mut DMABuffer),
+        D: FnMut(usize, &DMABuffer),
+    {
+        let kind = if write {
+            VIRTIO_BLK_T_OUT
+        } else {
+            VIRTIO_BLK_T_IN
+        };
+        let chunk = VIRTIO_BLK_REQUEST_SECTORS * VIRTIO_BLK_SECTOR_SIZE;
+        let mut offset = 0;
+
+        while offset < len {
+            let mut requests = Vec::new();
+            while offset < len {
+                let size = min(chunk, len - offset);
+                let mut data = DMABuffer::new(size, 16);
+                fill(offset, &mut data);
+                let sector = lba + (offset / VIRTIO_BLK_SECTOR_SIZE) as u64;
+                match self.submit(kind, sector, offset, Some(data)) {
+                    Some(req) => requests.push(req),
+                    None => break,
+                }
+                offset += size;
+            }
+            if requests.is_empty() {
+                return Err(DeviceError::WouldBlock);
+            }
+
+            self.transport.notify(&self.queue);
+            self.complete(&mut requests)?;
+
+            for req in requests.iter() {
+                drain(req.offset, req.data.as_ref().unwrap());
+            }
+        }
+        Ok(())
+    }
+
+    fn check(&self, lba: u64, len: usize) -> Result<(), DeviceError> {
+        if len % VIRTIO_BLK_SECTOR_SIZE != 0 {
+            return Err(DeviceError::InvalidArgument);
+        }
+        match lba.checked_add((len / VIRTIO_BLK_SECTOR_SIZE) as u64) {
+            Some(end) if end <= self.capacity => Ok(()),
+            _ => Err(DeviceError::OutOfRange),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn sector_size(&self) -> usize {
+        VIRTIO_BLK_SECTOR_SIZE
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_sectors(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        self.check(lba, buf.len())?;
+        let len = buf.len();
+        self.transfer(
+            lba,
+            len,
+            false,
+            |_, _| (),
+            |start, dma| {
+                let size = dma.len();
+                buf[start..start + size].copy_from_slice(dma.as_slice());
+            },
+        )
+    }
+
+    fn write_sectors(&mut self, lba: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        self.check(lba, buf.len())?;
+        if self.read_only {
+            return Err(DeviceError::Unsupported);
+        }
+        self.transfer(
+            lba,
+            buf.len(),
+            true,
+            |start, dma| {
+                let size = dma.len();
+                dma.as_mut_slice()
+                    .copy_from_slice(&buf[start..start + size]);
+            },
+            |_, _| (),
+        )
+    }
+
+    fn flush(&mut self) -> Result<(), DeviceError> {
+        if !self.can_flush {
+            return Ok(());
+        }
+        let mut req = match self.submit(VIRTIO_BLK_T_FLUSH, 0, 0, None) {
+            Some(req) => vec![req],
+            None => return Err(DeviceError::WouldBlock),
+        };
+        self.transport.notify(&self.queue);
+        self.complete(&mut req)
+    }
+
+    fn shutdown(&mut self) -> Result<(), DeviceError> {
+        // The queue may only be freed once the device let go of it
+        self.transport.reset().map_err(|err| {
+            log!(error: "Unable to stop the virtio block device: {}", err);
+            DeviceError::Timeout
+        })
+    }
+}
@@ -0,0 +1,390 @@
+//
+// Virtio
+//
+// Virtio devices on PCI come with one of two transports.
+// Legacy devices expose their registers in an I/O BAR,
+// modern devices describe memory-mapped register blocks
+// with vendor-specific PCI capabilities. Transitional
+// devices offer both, in which case we use the modern one.
+//
+
+mod blk;
+mod queue;
+
+pub use self::blk::{VirtioBlk, VirtioBlkDriver};
+pub use self::queue::{Buffer, VirtQueue};
+
+use core::ptr;
+use x86_64::instructions::port::Port;
+
+use crate::pci::{PCICommand, PCIDevice};
+use crate::pit::PIT;
+
+/// The PCI vendor id of virtio devices
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// The time a device may take to reset, in milliseconds
+const RESET_TIMEOUT_MS: usize = 1000;
+
+/// The device conforms to virtio 1.0
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Legacy I/O registers
+const LEGACY_DEVICE_FEATURES: u16 = 0x00;
+const LEGACY_DRIVER_FEATURES: u16 = 0x04;
+const LEGACY_QUEUE_ADDRESS: u16 = 0x08;
+const LEGACY_QUEUE_SIZE: u16 = 0x0C;
+const LEGACY_QUEUE_SELECT: u16 = 0x0E;
+const LEGACY_QUEUE_NOTIFY: u16 = 0x10;
+const LEGACY_DEVICE_STATUS: u16 = 0x12;
+const LEGACY_ISR_STATUS: u16 = 0x13;
+const LEGACY_DEVICE_CONFIG: u16 = 0x14;
+
+// Modern common configuration registers
+const COMMON_DEVICE_FEATURE_SELECT: usize = 0x00;
+const COMMON_DEVICE_FEATURE: usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: usize = 0x08;
+const COMMON_DRIVER_FEATURE: usize = 0x0C;
+const COMMON_DEVICE_STATUS: usize = 0x14;
+const COMMON_QUEUE_SELECT: usize = 0x16;
+const COMMON_QUEUE_SIZE: usize = 0x18;
+const COMMON_QUEUE_ENABLE: usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: usize = 0x1E;
+const COMMON_QUEUE_DESC: usize = 0x20;
+const COMMON_QUEUE_DRIVER: usize = 0x28;
+const COMMON_QUEUE_DEVICE: usize = 0x30;
+
+// PCI capabilities
+const PCI_STATUS: u8 = 0x06;
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+const PCI_CAP_POINTER: u8 = 0x34;
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+
+// Virtio capability types
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+unsafe fn mmio_read<T: Copy>(addr: usize) -> T {
+    ptr::read_volatile(addr as *const T)
+}
+
+unsafe fn mmio_write<T: Copy>(addr: usize, val: T) {
+    ptr::write_volatile(addr as *mut T, val)
+}
+
+/// Write a 64-bit register as two 32-bit halves
+unsafe fn mmio_write64(addr: usize, val: u64) {
+    mmio_write(addr, val as u32);
+    mmio_write(addr + 4, (val >> 32) as u32);
+}
+
+/// How the driver talks to a virtio device
+pub enum Transport {
+    Legacy {
+        io: u16,
+    },
+    Modern {
+        common: usize,
+        notify: usize,
+        notify_multiplier: u32,
+        isr: usize,
+        device: usize,
+    },
+}
+
+impl Transport {
+    /// Set up the transport of a virtio PCI device
+    pub fn probe(dev: &PCIDevice) -> Result<Transport, &'static str> {
+        dev.enable(PCICommand::IO_SPACE | PCICommand::MEMORY_SPACE | PCICommand::BUS_MASTER);
+
+        if let Some(transport) = Transport::probe_modern(dev)? {
+            return Ok(transport);
+        }
+
+        let bar = dev.get_bar(0);
+        if !bar.is_iospace() {
+            return Err("Virtio device has no legacy I/O BAR");
+        }
+        Ok(Transport::Legacy {
+            io: bar.addr() as u16,
+        })
+    }
+
+    /// Find the register blocks of a modern device
+    fn probe_modern(dev: &PCIDevice) -> Result<Option<Transport>, &'static str> {
+        if dev.read16(PCI_STATUS) & PCI_STATUS_CAP_LIST == 0 {
+            return Ok(None);
+        }
+
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device = None;
+        let mut mapped = [false; 6];
+
+        let mut cap = dev.read8(PCI_CAP_POINTER) & !0x3;
+        while cap != 0 {
+            if dev.read8(cap) == PCI_CAP_ID_VENDOR {
+                let cfg_type = dev.read8(cap + 3);
+                let bar = dev.read8(cap + 4);
+                let offset = dev.read32(cap + 8) as usize;
+
+                if bar < 6
+                    && cfg_type >= VIRTIO_PCI_CAP_COMMON_CFG
+                    && cfg_type <= VIRTIO_PCI_CAP_DEVICE_CFG
+                {
+                    let pci_bar = dev.get_bar(bar);
+                    if !mapped[bar as usize] {
+                        pci_bar.identity_map()?;
+                        mapped[bar as usize] = true;
+                    }
+                    let addr = pci_bar.addr() as usize + offset;
+                    match cfg_type {
+                        VIRTIO_PCI_CAP_COMMON_CFG => common = common.or(Some(addr)),
+                        VIRTIO_PCI_CAP_NOTIFY_CFG => {
+                            let multiplier = dev.read32(cap + 16);
+                            notify = notify.or(Some((addr, multiplier)));
+                        }
+                        VIRTIO_PCI_CAP_ISR_CFG => isr = isr.or(Some(addr)),
+                        _ => device = device.or(Some(addr)),
+                    }
+                }
+            }
+            cap = dev.read8(cap + 1) & !0x3;
+        }
+
+        match (common, notify, isr, device) {
+            (Some(common), Some((notify, notify_multiplier)), Some(isr), Some(device)) => {
+                Ok(Some(Transport::Modern {
+                    common,
+                    notify,
+                    notify_multiplier,
+                    isr,
+                    device,
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn is_modern(&self) -> bool {
+        match *self {
+            Transport::Modern { .. } => true,
+            Transport::Legacy { .. } => false,
+        }
+    }
+
+    pub fn status(&self) -> u8 {
+        unsafe {
+            match *self {
+                Transport::Legacy { io } => Port::<u8>::new(io + LEGACY_DEVICE_STATUS).read(),
+                Transport::Modern { common, .. } => mmio_read(common + COMMON_DEVICE_STATUS),
+            }
+        }
+    }
+
+    pub fn set_status(&self, status: u8) {
+        unsafe {
+            match *self {
+                Transport::Legacy { io } => {
+                    Port::<u8>::new(io + LEGACY_DEVICE_STATUS).write(status)
+                }
+                Transport::Modern { common, .. } => {
+                    mmio_write(common + COMMON_DEVICE_STATUS, status)
+                }
+            }
+        }
+    }
+
+    fn add_status(&self, bits: u8) {
+        let status = self.status();
+        self.set_status(status | bits);
+    }
+
+    /// Get the features the device offers
+    pub fn device_features(&self) -> u64 {
+        unsafe {
+            match *self {
+                Transport::Legacy { io } => {
+                    Port::<u32>::new(io + LEGACY_DEVICE_FEATURES).read() as u64
+                }
+                Transport::Modern { common, .. } => {
+                    mmio_write::<u32>(common + COMMON_DEVICE_FEATURE_SELECT, 0);
+                    let lo: u32 = mmio_read(common + COMMON_DEVICE_FEATURE);
+                    mmio_write::<u32>(common + COMMON_DEVICE_FEATURE_SELECT, 1);
+                    let hi: u32 = mmio_read(common + COMMON_DEVICE_FEATURE);
+                    lo as u64 | (hi as u64) << 32
+                }
+            }
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        unsafe {
+            match *self {
+                Transport::Legacy { io } => {
+                    Port::<u32>::new(io + LEGACY_DRIVER_FEATURES).write(features as u32)
+                }
+                Transport::Modern { common, .. } => {
+                    mmio_write::<u32>(common + COMMON_DRIVER_FEATURE_SELECT, 0);
+                    mmio_write(common + COMMON_DRIVER_FEATURE, features as u32);
+                    mmio_write::<u32>(common + COMMON_DRIVER_FEATURE_SELECT, 1);
+                    mmio_write(common + COMMON_DRIVER_FEATURE, (features >> 32) as u32);
+                }
+            }
+        }
+    }
+
+    /// Reset the device.
+    ///
+    /// Afterwards, it no longer touches any queue. If this
+    /// fails, the device may still access its queues.
+    pub fn reset(&self) -> Result<(), &'static str> {
+        let deadline = PIT::deadline(PIT::ms_to_ticks(RESET_TIMEOUT_MS));
+        self.set_status(0);
+        while self.status() != 0 {
+            if PIT::has_passed(deadline) {
+                return Err("Virtio device did not reset");
+            }
+            core::sync::atomic::spin_loop_hint();
+        }
+        Ok(())
+    }
+
+    /// Reset the device and negotiate features.
+    ///
+    /// Returns the features both sides support. Queues must
+    /// be set up afterwards, followed by `driver_ok`.
+    pub fn begin_init(&self, wanted: u64) -> Result<u64, &'static str> {
+        self.reset()?;
+        self.add_status(STATUS_ACKNOWLEDGE);
+        self.add_status(STATUS_DRIVER);
+
+        let mut wanted = wanted;
+        if self.is_modern() {
+            wanted |= VIRTIO_F_VERSION_1;
+        }
+        let features = self.device_features() & wanted;
+        self.set_driver_features(features);
+
+        if self.is_modern() {
+            if features & VIRTIO_F_VERSION_1 == 0 {
+                self.add_status(STATUS_FAILED);
+                return Err("Virtio device does not support version 1");
+            }
+            self.add_status(STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 {
+                self.add_status(STATUS_FAILED);
+                return Err("Virtio device rejected the features");
+            }
+        }
+        Ok(features)
+    }
+
+    /// Tell the device the driver is ready
+    pub fn driver_ok(&self) {
+        self.add_status(STATUS_DRIVER_OK);
+    }
+
+    /// Mark the device as unusable
+    pub fn fail(&self) {
+        self.add_status(STATUS_FAILED);
+    }
+
+    /// Create a virtqueue with at most `max_size` entries
+    pub fn setup_queue(&self, index: u16, max_size: u16) -> Result<VirtQueue, &'static str> {
+        unsafe {
+            match *self {
+                Transport::Legacy { io } => {
+                    Port::<u16>::new(io + LEGACY_QUEUE_SELECT).write(index);
+                    // Legacy devices dictate the queue size
+                    let size = Port::<u16>::new(io + LEGACY_QUEUE_SIZE).read();
+                    if size == 0 {
+                        return Err("Virtqueue does not exist");
+                    }
+                    let queue = VirtQueue::new(index, size);
+                    let pfn = (queue.desc_addr() >> 12) as u32;
+                    Port::<u32>::new(io + LEGACY_QUEUE_ADDRESS).write(pfn);
+                    Ok(queue)
+                }
+                Transport::Modern { common, .. } => {
+                    mmio_write(common + COMMON_QUEUE_SELECT, index);
+                    let max: u16 = mmio_read(common + COMMON_QUEUE_SIZE);
+                    if max == 0 {
+                        return Err("Virtqueue does not exist");
+                    }
+                    let mut size = core::cmp::min(max, max_size);
+                    while !size.is_power_of_two() {
+                        size &= size - 1;
+                    }
+                    let mut queue = VirtQueue::new(index, size);
+                    mmio_write(common + COMMON_QUEUE_SIZE, size);
+                    mmio_write64(common + COMMON_QUEUE_DESC, queue.desc_addr());
+                    mmio_write64(common + COMMON_QUEUE_DRIVER, queue.avail_addr());
+                    mmio_write64(common + COMMON_QUEUE_DEVICE, queue.used_addr());
+                    queue.notify_off = mmio_read(common + COMMON_QUEUE_NOTIFY_OFF);
+                    mmio_write::<u16>(common + COMMON_QUEUE_ENABLE, 1);
+                    Ok(queue)
+                }
+            }
+        }
+    }
+
+    /// Tell the device a queue has new buffers
+    pub fn notify(&self, queue: &VirtQueue) {
+        unsafe {
+            match *self {
+                Transport::Legacy { io } => {
+                    Port::<u16>::new(io + LEGACY_QUEUE_NOTIFY).write(queue.index())
+                }
+                Transport::Modern {
+                    notify,
+                    notify_multiplier,
+                    ..
+                } => {
+                    let addr = notify + queue.notify_off as usize * notify_multiplier as usize;
+                    mmio_write(addr, queue.index());
+                }
+            }
+        }
+    }
+
+    /// Read and acknowledge the interrupt status
+    #[allow(dead_code)]
+    pub fn isr(&self) -> u8 {
+        unsafe {
+            match *self {
+                Transport::Legacy { io } => Port::<u8>::new(io + LEGACY_ISR_STATUS).read(),
+                Transport::Modern { isr, .. } => mmio_read(isr),
+            }
+        }
+    }
+
+    /// Read a byte of the device-specific configuration
+    pub fn config_read8(&self, offset: usize) -> u8 {
+        unsafe {
+            match *self {
+                Transport::Legacy { io } => {
+                    Port::<u8>::new(io + LEGACY_DEVICE_CONFIG + offset as u16).read()
+                }
+                Transport::Modern { device, .. } => mmio_read(device + offset),
+            }
+        }
+    }
+
+    /// Read a little-endian value of `size` bytes from the device configuration
+    pub fn config_read(&self, offset: usize, size: usize) -> u64 {
+        (0..size).fold(0u64, |acc, i| {
+            acc | (self.config_read8(offset + i) as u64) << (8 * i)
+        })
+    }
+}
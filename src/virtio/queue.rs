@@ -0,0 +1,163 @@
+use alloc::prelude::*;
+use core::sync::atomic::{fence, Ordering};
+
+use crate::dma::DMABuffer;
+
+const DESC_SIZE: usize = 16;
+const USED_ELEM_SIZE: usize = 8;
+
+/// The descriptor continues in `next`
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+/// The device writes to the buffer
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+/// Ask the device not to interrupt on used buffers
+const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+/// A buffer handed to the device
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+    /// The device writes to the buffer instead of reading it
+    pub writable: bool,
+}
+
+/// A split virtqueue.
+///
+/// The descriptor table, available ring and used ring live
+/// in one allocation with the layout legacy devices expect.
+pub struct VirtQueue {
+    index: u16,
+    size: u16,
+    mem: DMABuffer,
+    avail_offset: usize,
+    used_offset: usize,
+    /// Unused descriptors
+    free: Vec<u16>,
+    /// The length of each in-flight descriptor chain, by head
+    chains: Vec<u16>,
+    last_used: u16,
+    /// Transport-specific notification offset
+    pub(super) notify_off: u16,
+}
+
+fn align(value: usize, to: usize) -> usize {
+    (value + to - 1) & !(to - 1)
+}
+
+impl VirtQueue {
+    pub fn new(index: u16, size: u16) -> Self {
+        assert!(size.is_power_of_two());
+        let n = size as usize;
+        let avail_offset = DESC_SIZE * n;
+        let used_offset = align(avail_offset + 6 + 2 * n, 4096);
+        let total = align(used_offset + 6 + USED_ELEM_SIZE * n, 4096);
+
+        let mut queue = VirtQueue {
+            index,
+            size,
+            mem: DMABuffer::new(total, 4096),
+            avail_offset,
+            used_offset,
+            free: (0..size).rev().collect(),
+            chains: vec![0; n],
+            last_used: 0,
+            notify_off: 0,
+        };
+        // We poll the used ring
+        queue
+            .mem
+            .write::<u16>(queue.avail_offset, VIRTQ_AVAIL_F_NO_INTERRUPT);
+        queue
+    }
+
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn desc_addr(&self) -> u64 {
+        self.mem.phys_addr()
+    }
+
+    pub fn avail_addr(&self) -> u64 {
+        self.mem.phys_addr() + self.avail_offset as u64
+    }
+
+    pub fn used_addr(&self) -> u64 {
+        self.mem.phys_addr() + self.used_offset as u64
+    }
+
+    /// Make a chain of buffers available to the device.
+    ///
+    /// Returns the head descriptor, which identifies the
+    /// chain when it is used, or `None` if the queue is full.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free.len() {
+            return None;
+        }
+
+        let descs: Vec<u16> = (0..buffers.len())
+            .map(|_| self.free.pop().unwrap())
+            .collect();
+        for (i, buf) in buffers.iter().enumerate() {
+            let offset = DESC_SIZE * descs[i] as usize;
+            let mut flags = if buf.writable { VIRTQ_DESC_F_WRITE } else { 0 };
+            let next = if i + 1 < descs.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+                descs[i + 1]
+            } else {
+                0
+            };
+            self.mem.write::<u64>(offset, buf.addr);
+            self.mem.write::<u32>(offset + 8, buf.len);
+            self.mem.write::<u16>(offset + 12, flags);
+            self.mem.write::<u16>(offset + 14, next);
+        }
+
+        let head = descs[0];
+        self.chains[head as usize] = descs.len() as u16;
+
+        // Publish the chain, then the new index
+        let idx = self.mem.read::<u16>(self.avail_offset + 2);
+        let slot = (idx % self.size) as usize;
+        self.mem
+            .write::<u16>(self.avail_offset + 4 + 2 * slot, head);
+        fence(Ordering::SeqCst);
+        self.mem
+            .write::<u16>(self.avail_offset + 2, idx.wrapping_add(1));
+        fence(Ordering::SeqCst);
+
+        Some(head)
+    }
+
+    /// Test whether the device has used a chain we have not collected
+    pub fn has_used(&self) -> bool {
+        fence(Ordering::SeqCst);
+        self.mem.read::<u16>(self.used_offset + 2) != self.last_used
+    }
+
+    /// Collect a used chain.
+    ///
+    /// Returns its head and the number of bytes the device wrote.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+
+        let slot = (self.last_used % self.size) as usize;
+        let offset = self.used_offset + 4 + USED_ELEM_SIZE * slot;
+        let head = self.mem.read::<u32>(offset) as u16;
+        let len = self.mem.read::<u32>(offset + 4);
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // Return the chain's descriptors
+        let mut desc = head;
+        for _ in 0..self.chains[head as usize] {
+            self.free.push(desc);
+            desc = self.mem.read::<u16>(DESC_SIZE * desc as usize + 14);
+        }
+        self.chains[head as usize] = 0;
+
+        Some((head, len))
+    }
+}
@@ -0,0 +1,210 @@
+//
+// Deferred work
+//
+// Interrupt handlers only do what cannot wait, like reading
+// a data port, and queue the rest as work items. Workers
+// run the items later with interrupts enabled.
+//
+// Queues live in fixed-size buffers, so queueing work
+// from an interrupt handler never allocates.
+//
+
+use alloc::prelude::*;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::pit::PIT;
+
+/// The number of CPUs with a workqueue
+const MAX_CPUS: usize = 1;
+
+/// The number of items a workqueue can hold
+const QUEUE_SIZE: usize = 256;
+
+/// The number of delayed items that can be pending
+const MAX_DELAYED: usize = 64;
+
+lazy_static! {
+    static ref QUEUES: Vec<Mutex<WorkQueue>> = (0..MAX_CPUS)
+        .map(|_| Mutex::new(WorkQueue::new()))
+        .collect();
+    static ref DELAYED: Mutex<[Option<DelayedWork>; MAX_DELAYED]> = Mutex::new([None; MAX_DELAYED]);
+}
+
+/// A unit of deferred work
+#[derive(Clone, Copy)]
+pub struct Work {
+    func: fn(usize),
+    data: usize,
+}
+
+impl Work {
+    /// Create a work item that calls `func` with `data`
+    pub fn new(func: fn(usize), data: usize) -> Self {
+        Work { func, data }
+    }
+
+    fn run(self) {
+        (self.func)(self.data)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct DelayedWork {
+    deadline: usize,
+    cpu: usize,
+    work: Work,
+}
+
+/// A ring buffer of work items
+struct WorkQueue {
+    items: [Option<Work>; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+    dropped: usize,
+}
+
+impl WorkQueue {
+    fn new() -> Self {
+        WorkQueue {
+            items: [None; QUEUE_SIZE],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, work: Work) -> Result<(), Work> {
+        if self.len == QUEUE_SIZE {
+            self.dropped += 1;
+            return Err(work);
+        }
+        self.items[(self.head + self.len) % QUEUE_SIZE] = Some(work);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Work> {
+        if self.len == 0 {
+            return None;
+        }
+        let work = self.items[self.head].take();
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        work
+    }
+}
+
+/// Kernel workqueues
+pub struct WorkQueues;
+impl WorkQueues {
+    /// Set up the queues.
+    ///
+    /// This must happen before the first interrupt handler
+    /// queues work, since the setup allocates.
+    pub fn init() {
+        lazy_static::initialize(&QUEUES);
+        lazy_static::initialize(&DELAYED);
+    }
+
+    /// Queue work on the current CPU
+    pub fn queue(work: Work) -> Result<(), Work> {
+        WorkQueues::queue_on(current_cpu(), work)
+    }
+
+    /// Queue work on a specific CPU
+    pub fn queue_on(cpu: usize, work: Work) -> Result<(), Work> {
+        interrupts::without_interrupts(|| QUEUES[cpu].lock().push(work))
+    }
+
+    /// Queue work on the current CPU once `delay` ticks have passed
+    #[allow(dead_code)]
+    pub fn queue_delayed(work: Work, delay: usize) -> Result<(), Work> {
+        let delayed = DelayedWork {
+            deadline: PIT::deadline(delay),
+            cpu: current_cpu(),
+            work,
+        };
+        interrupts::without_interrupts(|| {
+            let mut slots = DELAYED.lock();
+            match slots.iter_mut().find(|slot| slot.is_none()) {
+                Some(slot) => {
+                    *slot = Some(delayed);
+                    Ok(())
+                }
+                None => Err(work),
+            }
+        })
+    }
+
+    /// Move due delayed work onto the workqueues.
+    ///
+    /// Called by the timer interrupt handler on every tick.
+    pub fn run_timers() {
+        let mut slots = DELAYED.lock();
+        for slot in slots.iter_mut() {
+            let due = match *slot {
+                Some(ref delayed) => PIT::has_passed(delayed.deadline),
+                None => false,
+            };
+            if due {
+                let delayed = slot.take().unwrap();
+                if let Err(work) = QUEUES[delayed.cpu].lock().push(delayed.work) {
+                    // Try again on the next tick
+                    *slot = Some(DelayedWork { work, ..delayed });
+                }
+            }
+        }
+    }
+
+    /// Run all work queued on the current CPU.
+    ///
+    /// Returns the number of items run.
+    pub fn run_pending() -> usize {
+        let cpu = current_cpu();
+        let mut count = 0;
+        while let Some(work) = interrupts::without_interrupts(|| QUEUES[cpu].lock().pop()) {
+            work.run();
+            count += 1;
+        }
+        count
+    }
+
+    /// Get the number of items dropped because a queue was full
+    #[allow(dead_code)]
+    pub fn dropped() -> usize {
+        QUEUES
+            .iter()
+            .map(|q| interrupts::without_interrupts(|| q.lock().dropped))
+            .sum()
+    }
+
+    /// Service the current CPU's workqueue forever.
+    ///
+    /// Until there are kernel threads, the boot CPU runs this
+    /// as its idle loop.
+    pub fn run_worker() -> ! {
+        let cpu = current_cpu();
+        loop {
+            WorkQueues::run_pending();
+
+            // Work queued by an interrupt between the check and
+            // the halt would wait for the next one. `sti` only
+            // takes effect after `hlt`, so nothing slips through.
+            interrupts::disable();
+            if QUEUES[cpu].lock().len == 0 {
+                unsafe { asm!("sti; hlt" :::: "volatile") };
+            } else {
+                interrupts::enable();
+            }
+        }
+    }
+}
+
+/// Get the index of the executing CPU.
+///
+/// Only the boot processor runs so far.
+fn current_cpu() -> usize {
+    0
+}